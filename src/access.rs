@@ -0,0 +1,159 @@
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessAction {
+    Publish,
+    Play,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleKind {
+    Allow,
+    Deny,
+}
+
+/// A single nginx-style rule such as `allow 10.0.0.0/8`, `deny 192.0.2.1` or `deny all`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct AccessRule {
+    pub kind: RuleKind,
+    // None matches every address
+    pub network: Option<IpNet>,
+}
+
+impl TryFrom<String> for AccessRule {
+    type Error = String;
+
+    fn try_from(rule: String) -> Result<Self, Self::Error> {
+        let (kind, target) = rule.trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| format!("invalid access rule '{}'", rule))?;
+
+        let kind = match kind {
+            "allow" => RuleKind::Allow,
+            "deny" => RuleKind::Deny,
+            _ => return Err(format!("access rule '{}' must start with allow or deny", rule)),
+        };
+
+        let target = target.trim();
+        let network = if target == "all" {
+            None
+        } else if let Ok(network) = target.parse::<IpNet>() {
+            Some(network)
+        } else {
+            let ip = target.parse::<IpAddr>()
+                .map_err(|_| format!("invalid address or CIDR in access rule '{}'", rule))?;
+            Some(IpNet::from(ip))
+        };
+
+        Ok(Self { kind, network })
+    }
+}
+
+impl AccessRule {
+    fn matches(&self, ip: IpAddr) -> bool {
+        self.network.is_none_or(|network| network.contains(&ip))
+    }
+}
+
+/// Ordered allow/deny rules per action. The first matching rule wins; no match allows.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AccessConfig {
+    pub publish: Vec<AccessRule>,
+    pub play: Vec<AccessRule>,
+}
+
+impl AccessConfig {
+    pub fn allows(&self, action: AccessAction, ip: IpAddr) -> bool {
+        let rules = match action {
+            AccessAction::Publish => &self.publish,
+            AccessAction::Play => &self.play,
+        };
+
+        // Dual-stack listeners report IPv4 clients as ::ffff:a.b.c.d
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            IpAddr::V4(_) => ip,
+        };
+
+        rules.iter()
+            .find(|rule| rule.matches(ip))
+            .is_none_or(|rule| rule.kind == RuleKind::Allow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(rule: &str) -> Result<AccessRule, String> {
+        AccessRule::try_from(rule.to_string())
+    }
+
+    fn config(publish: &[&str]) -> AccessConfig {
+        AccessConfig {
+            publish: publish.iter().map(|r| rule(r).unwrap()).collect(),
+            play: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn parses_rules() {
+        let allow = rule("allow 10.0.0.0/8").unwrap();
+        assert_eq!((allow.kind, allow.network), (RuleKind::Allow, Some("10.0.0.0/8".parse().unwrap())));
+        let deny = rule(" deny \t192.0.2.1 ").unwrap();
+        assert_eq!((deny.kind, deny.network), (RuleKind::Deny, Some("192.0.2.1/32".parse().unwrap())));
+        assert_eq!(rule("deny 2001:db8::/32").unwrap().network, Some("2001:db8::/32".parse().unwrap()));
+        assert_eq!(rule("allow ::1").unwrap().network, Some("::1/128".parse().unwrap()));
+        assert_eq!(rule("deny all").unwrap(), AccessRule { kind: RuleKind::Deny, network: None });
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        let bad_rules = [
+            "", "allow", "deny ", "permit 10.0.0.0/8", "Allow all",
+            "allow 10.0.0.0/33", "deny 300.0.0.1", "allow everyone", "deny 10.0.0.0/8 extra",
+        ];
+        for bad in bad_rules {
+            assert!(rule(bad).is_err(), "{:?} should be rejected", bad);
+        }
+
+        let error = serde_json::from_str::<AccessConfig>(r#"{ "publish": ["allow 10.0.0.0/8", "deny nobody"] }"#).unwrap_err();
+        assert!(error.to_string().contains("deny nobody"));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let access = config(&["deny 10.0.0.5", "allow 10.0.0.0/8", "deny all"]);
+        assert!(!access.allows(AccessAction::Publish, "10.0.0.5".parse().unwrap()));
+        assert!(access.allows(AccessAction::Publish, "10.1.2.3".parse().unwrap()));
+        assert!(!access.allows(AccessAction::Publish, "192.0.2.1".parse().unwrap()));
+
+        // Reordered, the broader rule shadows the narrower one
+        let access = config(&["allow 10.0.0.0/8", "deny 10.0.0.5", "deny all"]);
+        assert!(access.allows(AccessAction::Publish, "10.0.0.5".parse().unwrap()));
+    }
+
+    #[test]
+    fn allows_without_a_matching_rule() {
+        let access = config(&["deny 192.0.2.0/24"]);
+        assert!(access.allows(AccessAction::Publish, "198.51.100.1".parse().unwrap()));
+        assert!(!access.allows(AccessAction::Publish, "192.0.2.7".parse().unwrap()));
+        // Each action has its own rules
+        assert!(access.allows(AccessAction::Play, "192.0.2.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn matches_ipv4_mapped_addresses_as_ipv4() {
+        let access = config(&["allow 127.0.0.1", "deny all"]);
+        assert!(access.allows(AccessAction::Publish, "::ffff:127.0.0.1".parse().unwrap()));
+        assert!(!access.allows(AccessAction::Publish, "::ffff:192.0.2.1".parse().unwrap()));
+
+        let access = config(&["allow ::1", "deny all"]);
+        assert!(access.allows(AccessAction::Publish, "::1".parse().unwrap()));
+        assert!(!access.allows(AccessAction::Publish, "127.0.0.1".parse().unwrap()));
+    }
+}
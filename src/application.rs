@@ -0,0 +1,229 @@
+use crate::access::AccessConfig;
+use crate::config::Config;
+use crate::error::{Result, StreamError};
+use crate::hls::{ffmpeg, ladder::{self, RenditionConfig}, playlist::PlaylistManager, HlsMuxer, HlsOutputs, HlsSegmentFormat};
+use crate::hooks::{HookClient, HookConfig};
+use crate::pipeline::{self, spawn_pipelines, PipelineConfig};
+use crate::record::spawn_recorder;
+use crate::registry::{LiveStream, StreamRegistry};
+use crate::relay::edge::Origin;
+use crate::relay::push::{render_target, spawn_pushes};
+use crate::rtmp::client::RtmpUrl;
+use crate::stream_key::StreamKey;
+use serde::Deserialize;
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use tokio::sync::Mutex;
+use tracing::error;
+
+/// Per-application settings, selected by the `app` field of the connect command.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ApplicationConfig {
+    pub publish: bool,
+    pub play: bool,
+    pub hls: bool,
+    // Overrides `Config::streams_dir` for this application's HLS output
+    pub hls_dir: Option<PathBuf>,
+    // Overrides `Config::hls_segment_format` for this application
+    pub hls_segment_format: Option<HlsSegmentFormat>,
+    // Overrides `Config::dash` for this application
+    pub dash: Option<bool>,
+    // Overrides `Config::renditions` for this application
+    pub renditions: Option<Vec<RenditionConfig>>,
+    // Overrides `Config::ffmpeg_args` for this application
+    pub ffmpeg_args: Option<Vec<String>>,
+    // Extra FFmpeg commands run on every published stream, e.g. to write thumbnails
+    pub pipelines: Vec<PipelineConfig>,
+    // When set, published streams are also recorded as FLV into this directory
+    pub record_dir: Option<PathBuf>,
+    // Overrides the server-wide hooks
+    pub hooks: Option<HookConfig>,
+    pub access: AccessConfig,
+    // RTMP URLs every published stream is relayed to; `{app}` and `{key}` are substituted
+    pub push: Vec<String>,
+    // Origin application URL (e.g. rtmp://origin.example.com/live) that streams requested
+    // here but not published here are pulled from
+    pub origin: Option<String>,
+    // Publishing `<key><backup_suffix>` feeds `<key>` as a backup that takes over while the
+    // primary publisher is gone
+    pub backup_suffix: Option<String>,
+}
+
+impl Default for ApplicationConfig {
+    fn default() -> Self {
+        Self {
+            publish: true,
+            play: true,
+            hls: true,
+            hls_dir: None,
+            hls_segment_format: None,
+            dash: None,
+            renditions: None,
+            ffmpeg_args: None,
+            pipelines: Vec::new(),
+            record_dir: None,
+            hooks: None,
+            access: AccessConfig::default(),
+            push: Vec::new(),
+            origin: None,
+            backup_suffix: None,
+        }
+    }
+}
+
+/// An application resolved against the server config, ready to serve connections.
+pub struct Application {
+    pub name: String,
+    pub settings: ApplicationConfig,
+    // Server config with this application's overrides applied
+    pub config: Config,
+    pub hooks: HookClient,
+    pub origin: Option<Origin>,
+    hls: HlsOutputs,
+}
+
+impl Application {
+    pub fn new(name: &str, settings: ApplicationConfig, server_config: &Config) -> Result<Self> {
+        let mut config = server_config.clone();
+        // Applications share streams_dir, so each writes under its own subdirectory
+        config.streams_dir = match &settings.hls_dir {
+            Some(hls_dir) => hls_dir.clone(),
+            None => server_config.streams_dir.join(name),
+        };
+        if let Some(hls_segment_format) = settings.hls_segment_format {
+            config.hls_segment_format = hls_segment_format;
+        }
+        if let Some(dash) = settings.dash {
+            config.dash = dash;
+        }
+        if let Some(renditions) = &settings.renditions {
+            config.renditions = renditions.clone();
+        }
+        if let Some(ffmpeg_args) = &settings.ffmpeg_args {
+            config.ffmpeg_args = Some(ffmpeg_args.clone());
+        }
+        if let Some(hooks) = &settings.hooks {
+            config.hooks = hooks.clone();
+        }
+        if config.dash && (config.hls_muxer != HlsMuxer::Native || config.hls_segment_format != HlsSegmentFormat::Fmp4) {
+            return Err(StreamError::Config(format!("Application '{}': DASH output needs the native HLS muxer with fmp4 segments", name)));
+        }
+        if !config.renditions.is_empty() && config.hls_muxer != HlsMuxer::Ffmpeg {
+            return Err(StreamError::Config(format!("Application '{}': renditions are transcoded by the FFmpeg HLS muxer", name)));
+        }
+        ladder::validate(&config.renditions).map_err(|e| StreamError::Config(format!("Application '{}': {}", name, e)))?;
+        if let Some(ffmpeg_args) = &config.ffmpeg_args {
+            if config.hls_muxer != HlsMuxer::Ffmpeg || !config.renditions.is_empty() {
+                return Err(StreamError::Config(format!("Application '{}': ffmpeg_args needs the FFmpeg HLS muxer, without renditions", name)));
+            }
+            ffmpeg::validate_args(ffmpeg_args, &ffmpeg::HLS_PLACEHOLDERS, &["stream_dir"])
+                .map_err(|e| StreamError::Config(format!("Application '{}' ffmpeg_args: {}", name, e)))?;
+        }
+        pipeline::validate(&settings.pipelines).map_err(|e| StreamError::Config(format!("Application '{}': {}", name, e)))?;
+
+        for target in &settings.push {
+            RtmpUrl::parse(&render_target(target, name, "key"))
+                .map_err(|e| StreamError::Config(format!("Application '{}' push target: {}", name, e)))?;
+        }
+
+        let origin = settings
+            .origin
+            .as_deref()
+            .map(Origin::new)
+            .transpose()
+            .map_err(|e| StreamError::Config(format!("Application '{}' origin: {}", name, e)))?;
+
+        let hooks = HookClient::new(config.hooks.clone())?;
+
+        Ok(Self {
+            name: name.to_string(),
+            settings,
+            config,
+            hooks,
+            origin,
+            hls: HlsOutputs::default(),
+        })
+    }
+
+    /// Starts this application's outputs (HLS, recording, push relays) for a newly published stream.
+    pub async fn start_outputs(&self, stream: &Arc<LiveStream>) {
+        if self.settings.hls {
+            if let Err(e) = self.hls.start(stream, self.config.clone()).await {
+                error!("Failed to start HLS for {}/{}: {}", self.name, stream.key, e);
+            }
+        }
+
+        if let Some(record_dir) = &self.settings.record_dir {
+            spawn_recorder(stream, record_dir);
+        }
+
+        spawn_pushes(stream, &self.settings.push);
+        spawn_pipelines(stream, &self.config, &self.settings.pipelines);
+    }
+
+    // Whether this application runs FFmpeg on its streams
+    fn uses_ffmpeg(&self) -> bool {
+        (self.settings.hls && self.config.hls_muxer == HlsMuxer::Ffmpeg) || !self.settings.pipelines.is_empty()
+    }
+
+    /// The LL-HLS playlist of one of this application's streams, while it is being written.
+    pub fn hls_playlist(&self, stream_key: &StreamKey) -> Option<Arc<Mutex<PlaylistManager>>> {
+        self.hls.playlist(stream_key)
+    }
+
+    /// Pulls a stream that isn't live here from this application's origin, if it has one.
+    pub async fn pull_from_origin(self: &Arc<Self>, registry: &StreamRegistry, stream_key: &StreamKey) -> Option<Arc<LiveStream>> {
+        self.origin.as_ref()?.pull(self, registry, stream_key).await
+    }
+
+    /// Keeps a pulled stream alive for an HLS request, starting the pull in the background
+    /// if the stream isn't live yet.
+    pub fn request_hls(self: &Arc<Self>, registry: &StreamRegistry, stream_key: &StreamKey) {
+        let Some(origin) = &self.origin else {
+            return;
+        };
+
+        if registry.get(&self.name, stream_key).is_some() {
+            origin.touch(stream_key);
+            return;
+        }
+
+        let application = self.clone();
+        let registry = registry.clone();
+        let stream_key = stream_key.clone();
+        tokio::spawn(async move {
+            application.pull_from_origin(&registry, &stream_key).await;
+        });
+    }
+}
+
+pub type Applications = Arc<HashMap<String, Arc<Application>>>;
+
+/// Splits a path under `/stream` into the application it belongs to and the rest of the path
+/// (`<key>/<file>`). Only applications writing HLS to the default streams_dir are served there.
+pub fn split_hls_path<'a>(applications: &'a Applications, path: &'a str) -> Option<(&'a Arc<Application>, &'a str)> {
+    applications
+        .values()
+        .filter(|application| application.settings.hls && application.settings.hls_dir.is_none())
+        .filter_map(|application| {
+            let rest = path.strip_prefix(application.name.as_str())?.strip_prefix('/')?;
+            Some((application, rest))
+        })
+        // `live/inst/key` belongs to `live/inst` rather than `live`
+        .max_by_key(|(application, _)| application.name.len())
+}
+
+pub fn build_applications(config: &Config) -> Result<Applications> {
+    let mut applications = HashMap::new();
+
+    for (name, settings) in &config.applications {
+        let application = Application::new(name, settings.clone(), config)?;
+        applications.insert(name.clone(), Arc::new(application));
+    }
+
+    if applications.values().any(|application| application.uses_ffmpeg()) {
+        ffmpeg::check_binary(&config.ffmpeg_path).map_err(StreamError::Config)?;
+    }
+
+    Ok(Arc::new(applications))
+}
@@ -1,21 +1,117 @@
-use std::path::PathBuf;
+use crate::application::ApplicationConfig;
+use crate::error::{Result, StreamError};
+use crate::hls::{ladder::RenditionConfig, HlsMuxer, HlsSegmentFormat};
+use crate::hooks::HookConfig;
+use crate::playout::PlayoutConfig;
+use crate::proxy_protocol::ProxyProtocolConfig;
+use crate::relay::pull::PullConfig;
+use crate::rtmp::tls::TlsConfig;
+use crate::stream_key::StreamKey;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub rtmp_port: u16,
     pub http_port: u16,
     pub streams_dir: PathBuf,
     pub max_streams: usize,
+    // RTMPT tunnels open at once; further `/open` requests get 503
+    pub max_rtmpt_tunnels: usize,
     pub segment_duration: u32,
     pub playlist_size: usize,
+    pub hls_muxer: HlsMuxer,
+    pub hls_segment_format: HlsSegmentFormat,
+    // Also describe the fMP4 segments in an MPEG-DASH manifest
+    pub dash: bool,
+    // Adaptive bitrate ladder transcoded by FFmpeg; the stream is copied as is when empty
+    pub renditions: Vec<RenditionConfig>,
+    // Milliseconds; LL-HLS partial segments of about this length are written when set
+    pub hls_part_duration: u32,
+    // Seconds a stream's HLS playlist is kept open for its publisher to reconnect; 0 disables
+    pub reconnect_grace: u64,
+    // FFmpeg binary; looked up in PATH unless it contains a directory
+    pub ffmpeg_path: PathBuf,
+    // Arguments FFmpeg is run with for HLS, with placeholders such as `{input}`; the
+    // built-in command when unset
+    pub ffmpeg_args: Option<Vec<String>>,
+    // Times in a row FFmpeg is restarted after exiting on its own before HLS output gives up
+    pub ffmpeg_max_restarts: u32,
+    pub hooks: HookConfig,
+    pub applications: HashMap<String, ApplicationConfig>,
+    pub proxy_protocol: ProxyProtocolConfig,
+    // Optional TLS-terminated (rtmps://) listener
+    pub rtmps: Option<TlsConfig>,
+    // Upstream streams to play and republish locally
+    pub pulls: Vec<PullConfig>,
+    // Local files published as live streams at startup
+    pub playouts: Vec<PlayoutConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            rtmp_port: 1935,
+            http_port: 8080,
+            streams_dir: PathBuf::from("./streams"),
+            max_streams: 100,
+            max_rtmpt_tunnels: 100,
+            segment_duration: 2,
+            playlist_size: 6,
+            hls_muxer: HlsMuxer::default(),
+            hls_segment_format: HlsSegmentFormat::default(),
+            dash: false,
+            renditions: Vec::new(),
+            hls_part_duration: 0,
+            reconnect_grace: 0,
+            ffmpeg_path: PathBuf::from("ffmpeg"),
+            ffmpeg_args: None,
+            ffmpeg_max_restarts: 5,
+            hooks: HookConfig::default(),
+            applications: HashMap::from([("live".to_string(), ApplicationConfig::default())]),
+            proxy_protocol: ProxyProtocolConfig::default(),
+            rtmps: None,
+            pulls: Vec::new(),
+            playouts: Vec::new(),
+        }
+    }
 }
 
 impl Config {
-    pub fn stream_dir(&self, stream_key: &str) -> PathBuf {
-        self.streams_dir.join(stream_key)
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| StreamError::Config(format!("Failed to read {}: {}", path.display(), e)))?;
+
+        let config: Config = serde_json::from_str(&content)
+            .map_err(|e| StreamError::Config(format!("Failed to parse {}: {}", path.display(), e)))?;
+
+        if config.applications.is_empty() {
+            return Err(StreamError::Config("At least one application must be configured".to_string()));
+        }
+
+        if config.proxy_protocol.enabled && config.proxy_protocol.trusted_proxies.is_empty() {
+            return Err(StreamError::Config("proxy_protocol needs trusted_proxies, or any client could forge its address".to_string()));
+        }
+
+        if config.hls_part_duration > 0 {
+            if config.hls_muxer != HlsMuxer::Native {
+                return Err(StreamError::Config("hls_part_duration needs the native HLS muxer".to_string()));
+            }
+            if config.hls_part_duration >= config.segment_duration * 1000 {
+                return Err(StreamError::Config("hls_part_duration must be shorter than segment_duration".to_string()));
+            }
+        }
+
+        Ok(config)
     }
 
-    pub fn playlist_path(&self, stream_key: &str) -> PathBuf {
+    pub fn stream_dir(&self, stream_key: &StreamKey) -> PathBuf {
+        self.streams_dir.join(stream_key.as_str())
+    }
+
+    pub fn playlist_path(&self, stream_key: &StreamKey) -> PathBuf {
         self.stream_dir(stream_key).join("playlist.m3u8")
     }
-} 
\ No newline at end of file
+}
@@ -0,0 +1,348 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::hls::{fmp4::MediaInfo, playlist::Segment};
+
+// Segment timestamps are in milliseconds
+const TIMESCALE: u32 = 1000;
+
+#[derive(Debug)]
+struct Period {
+    first_sequence: u64,
+    // Seconds from the manifest's availability start
+    start: f64,
+    // Timestamp of the first segment, in milliseconds
+    first_timestamp: u32,
+}
+
+/// A stream's live MPEG-DASH manifest, describing the fMP4 segments written for HLS. Each
+/// discontinuity or change of init segment starts a new period.
+#[derive(Debug, Default)]
+pub struct DashManifest {
+    // Wall-clock time at which the first segment started
+    availability_start: Option<SystemTime>,
+    // Seconds from `availability_start` to the end of the last segment
+    elapsed: f64,
+    periods: VecDeque<Period>,
+    last_init: Option<String>,
+    // By init segment
+    media_info: HashMap<String, MediaInfo>,
+}
+
+impl DashManifest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_init(&mut self, init: String, media_info: MediaInfo) {
+        self.media_info.insert(init, media_info);
+    }
+
+    pub fn remove_init(&mut self, init: &str) {
+        self.media_info.remove(init);
+    }
+
+    /// Places a new segment on the manifest's timeline. Time the stream was away for before a
+    /// discontinuity is skipped over, so segments never show up as available before they are
+    /// written.
+    pub fn add_segment(&mut self, segment: &Segment) {
+        let now = SystemTime::now();
+        let availability_start = *self
+            .availability_start
+            .get_or_insert_with(|| now - std::time::Duration::from_secs_f64(segment.duration));
+
+        if self.periods.is_empty() || segment.discontinuity || segment.init != self.last_init {
+            let mut start = self.elapsed;
+            if segment.discontinuity {
+                let written = now.duration_since(availability_start).unwrap_or_default().as_secs_f64();
+                start = start.max(written - segment.duration);
+            }
+            self.periods.push_back(Period {
+                first_sequence: segment.sequence,
+                start,
+                first_timestamp: segment.timestamp,
+            });
+            self.elapsed = start;
+            self.last_init = segment.init.clone();
+        }
+        self.elapsed += segment.duration;
+    }
+
+    /// Forgets periods that ended before the given segment.
+    pub fn expire(&mut self, first_sequence: u64) {
+        while self.periods.get(1).is_some_and(|period| period.first_sequence <= first_sequence) {
+            self.periods.pop_front();
+        }
+    }
+
+    /// The manifest for the segments currently in the playlist.
+    pub fn render(&self, segments: &VecDeque<Segment>, segment_duration: u32, target_duration: u32) -> String {
+        let availability_start = self.availability_start.unwrap_or(UNIX_EPOCH);
+        let time_shift_buffer: f64 = segments.iter().map(|segment| segment.duration).sum();
+
+        let mut mpd = String::new();
+        mpd.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let _ = writeln!(
+            mpd,
+            "<MPD xmlns=\"urn:mpeg:dash:schema:mpd:2011\" profiles=\"urn:mpeg:dash:profile:isoff-live:2011\" type=\"dynamic\" \
+             availabilityStartTime=\"{}\" publishTime=\"{}\" minimumUpdatePeriod=\"{}\" minBufferTime=\"{}\" \
+             timeShiftBufferDepth=\"{}\" suggestedPresentationDelay=\"{}\" maxSegmentDuration=\"{}\">",
+            format_date_time(availability_start),
+            format_date_time(SystemTime::now()),
+            format_duration(segment_duration as f64),
+            format_duration(segment_duration as f64),
+            format_duration(time_shift_buffer),
+            format_duration(3.0 * target_duration as f64),
+            format_duration(target_duration as f64),
+        );
+
+        for (index, period) in self.periods.iter().enumerate() {
+            let next_sequence = self.periods.get(index + 1).map_or(u64::MAX, |next| next.first_sequence);
+            let period_segments: Vec<&Segment> = segments
+                .iter()
+                .filter(|segment| segment.sequence >= period.first_sequence && segment.sequence < next_sequence)
+                .collect();
+            if !period_segments.is_empty() {
+                self.render_period(&mut mpd, period, &period_segments);
+            }
+        }
+
+        mpd.push_str("</MPD>\n");
+        mpd
+    }
+
+    fn render_period(&self, mpd: &mut String, period: &Period, segments: &[&Segment]) {
+        let Some(init) = &segments[0].init else {
+            return;
+        };
+        let media_info = self.media_info.get(init).cloned().unwrap_or_default();
+        let has_video = media_info.codecs.split(',').any(|codec| !codec.starts_with("mp4a"));
+        let bandwidth = segments
+            .iter()
+            .filter(|segment| segment.duration > 0.0)
+            .map(|segment| (segment.size as f64 * 8.0 / segment.duration) as u64)
+            .max()
+            .unwrap_or(0)
+            .max(1);
+
+        let _ = writeln!(mpd, "  <Period id=\"{}\" start=\"{}\">", period.first_sequence, format_duration(period.start));
+        mpd.push_str("    <AdaptationSet segmentAlignment=\"true\" startWithSAP=\"1\">\n");
+        let _ = write!(
+            mpd,
+            "      <Representation id=\"0\" mimeType=\"{}\" codecs=\"{}\" bandwidth=\"{}\"",
+            if has_video { "video/mp4" } else { "audio/mp4" },
+            media_info.codecs,
+            bandwidth
+        );
+        if media_info.width > 0 && media_info.height > 0 {
+            let _ = write!(mpd, " width=\"{}\" height=\"{}\"", media_info.width, media_info.height);
+        }
+        mpd.push_str(">\n");
+        let _ = writeln!(
+            mpd,
+            "        <SegmentTemplate timescale=\"{}\" presentationTimeOffset=\"{}\" initialization=\"{}\" media=\"segment_$Number%06d$.m4s\" startNumber=\"{}\">",
+            TIMESCALE, period.first_timestamp, init, segments[0].sequence
+        );
+        mpd.push_str("          <SegmentTimeline>\n");
+
+        // Runs of equal durations are folded into one entry
+        let mut runs: Vec<(u32, u32, u32)> = Vec::new();
+        for segment in segments {
+            let duration = (segment.duration * TIMESCALE as f64).round() as u32;
+            match runs.last_mut() {
+                Some((start, run_duration, repeat))
+                    if *run_duration == duration && start.wrapping_add(duration * (*repeat + 1)) == segment.timestamp =>
+                {
+                    *repeat += 1;
+                }
+                _ => runs.push((segment.timestamp, duration, 0)),
+            }
+        }
+        for (start, duration, repeat) in runs {
+            if repeat > 0 {
+                let _ = writeln!(mpd, "            <S t=\"{}\" d=\"{}\" r=\"{}\"/>", start, duration, repeat);
+            } else {
+                let _ = writeln!(mpd, "            <S t=\"{}\" d=\"{}\"/>", start, duration);
+            }
+        }
+
+        mpd.push_str("          </SegmentTimeline>\n");
+        mpd.push_str("        </SegmentTemplate>\n");
+        mpd.push_str("      </Representation>\n");
+        mpd.push_str("    </AdaptationSet>\n");
+        mpd.push_str("  </Period>\n");
+    }
+}
+
+// xs:duration, e.g. PT12.480S
+fn format_duration(seconds: f64) -> String {
+    format!("PT{:.3}S", seconds.max(0.0))
+}
+
+// xs:dateTime in UTC, e.g. 2024-05-01T12:00:00.000Z
+fn format_date_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, time_of_day) = (seconds / 86400, seconds % 86400);
+
+    // Days since 1970-01-01 to a civil date (Howard Hinnant's civil_from_days)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn segment(sequence: u64, timestamp: u32, duration: f64, init: &str) -> Segment {
+        Segment {
+            filename: format!("segment_{:06}.m4s", sequence),
+            duration,
+            sequence,
+            discontinuity: false,
+            init: Some(init.to_string()),
+            parts: Vec::new(),
+            timestamp,
+            size: 250_000,
+        }
+    }
+
+    fn media_info(codecs: &str, width: u16, height: u16) -> MediaInfo {
+        MediaInfo { codecs: codecs.to_string(), width, height }
+    }
+
+    fn periods(manifest: &DashManifest) -> Vec<(u64, f64, u32)> {
+        manifest.periods.iter().map(|period| (period.first_sequence, period.start, period.first_timestamp)).collect()
+    }
+
+    #[test]
+    fn formats_dates_and_durations() {
+        assert_eq!(format_date_time(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(format_date_time(UNIX_EPOCH + Duration::from_millis(951_868_799_500)), "2000-02-29T23:59:59.500Z");
+        assert_eq!(format_date_time(UNIX_EPOCH + Duration::from_secs(1_714_564_800)), "2024-05-01T12:00:00.000Z");
+        assert_eq!(format_date_time(UNIX_EPOCH + Duration::from_secs(4_107_542_400)), "2100-03-01T00:00:00.000Z");
+
+        assert_eq!(format_duration(12.48), "PT12.480S");
+        assert_eq!(format_duration(-1.0), "PT0.000S");
+    }
+
+    #[test]
+    fn starts_periods_at_discontinuities_and_init_changes() {
+        let mut manifest = DashManifest::new();
+        manifest.add_segment(&segment(0, 0, 2.0, "init.mp4"));
+        manifest.add_segment(&segment(1, 2000, 2.0, "init.mp4"));
+        manifest.add_segment(&Segment { discontinuity: true, ..segment(2, 100, 2.0, "init.mp4") });
+        manifest.add_segment(&segment(3, 2100, 1.5, "init_1.mp4"));
+        manifest.add_segment(&segment(4, 3600, 1.5, "init_1.mp4"));
+        assert_eq!(periods(&manifest), [(0, 0.0, 0), (2, 4.0, 100), (3, 6.0, 2100)]);
+        assert_eq!(manifest.elapsed, 9.0);
+    }
+
+    #[test]
+    fn skips_the_time_a_stream_was_away() {
+        let mut manifest = DashManifest::new();
+        manifest.add_segment(&segment(0, 0, 2.0, "init.mp4"));
+        manifest.availability_start = Some(SystemTime::now() - Duration::from_secs(100));
+
+        // The segment after the discontinuity was written just now, so it started two seconds ago
+        manifest.add_segment(&Segment { discontinuity: true, ..segment(1, 0, 2.0, "init.mp4") });
+        let start = manifest.periods[1].start;
+        assert!((98.0..98.5).contains(&start), "{}", start);
+        assert_eq!(manifest.elapsed, start + 2.0);
+    }
+
+    #[test]
+    fn expires_periods_that_ended() {
+        let mut manifest = DashManifest::new();
+        for sequence in 0..6 {
+            let init = if sequence < 2 { "init.mp4" } else if sequence < 4 { "init_1.mp4" } else { "init_2.mp4" };
+            manifest.add_segment(&segment(sequence, sequence as u32 * 2000, 2.0, init));
+        }
+        manifest.expire(1);
+        assert_eq!(periods(&manifest).len(), 3);
+        manifest.expire(2);
+        assert_eq!(periods(&manifest), [(2, 4.0, 4000), (4, 8.0, 8000)]);
+        // The last period stays however far the playlist has moved on
+        manifest.expire(10);
+        assert_eq!(periods(&manifest), [(4, 8.0, 8000)]);
+    }
+
+    #[test]
+    fn renders_periods_with_folded_timelines() {
+        let mut manifest = DashManifest::new();
+        manifest.add_init("init.mp4".to_string(), media_info("avc1.64001f,mp4a.40.2", 1280, 720));
+        manifest.add_init("init_1.mp4".to_string(), media_info("mp4a.40.2", 0, 0));
+        let segments: VecDeque<Segment> = [
+            segment(5, 10_000, 2.0, "init.mp4"),
+            segment(6, 12_000, 2.0, "init.mp4"),
+            segment(7, 14_000, 2.0, "init.mp4"),
+            // A gap in the timestamps starts a new run
+            segment(8, 16_500, 2.0, "init.mp4"),
+            segment(9, 18_500, 1.0, "init_1.mp4"),
+        ]
+        .into();
+        for segment in &segments {
+            manifest.add_segment(segment);
+        }
+
+        let mpd = manifest.render(&segments, 2, 2);
+        assert!(mpd.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<MPD "));
+        assert!(mpd.contains(" type=\"dynamic\" "));
+        assert!(mpd.contains(" minimumUpdatePeriod=\"PT2.000S\" minBufferTime=\"PT2.000S\" timeShiftBufferDepth=\"PT9.000S\" suggestedPresentationDelay=\"PT6.000S\" maxSegmentDuration=\"PT2.000S\">\n"));
+        let periods = &mpd[mpd.find("  <Period").unwrap()..];
+        assert_eq!(
+            periods,
+            "  <Period id=\"5\" start=\"PT0.000S\">\n\
+             \x20   <AdaptationSet segmentAlignment=\"true\" startWithSAP=\"1\">\n\
+             \x20     <Representation id=\"0\" mimeType=\"video/mp4\" codecs=\"avc1.64001f,mp4a.40.2\" bandwidth=\"1000000\" width=\"1280\" height=\"720\">\n\
+             \x20       <SegmentTemplate timescale=\"1000\" presentationTimeOffset=\"10000\" initialization=\"init.mp4\" media=\"segment_$Number%06d$.m4s\" startNumber=\"5\">\n\
+             \x20         <SegmentTimeline>\n\
+             \x20           <S t=\"10000\" d=\"2000\" r=\"2\"/>\n\
+             \x20           <S t=\"16500\" d=\"2000\"/>\n\
+             \x20         </SegmentTimeline>\n\
+             \x20       </SegmentTemplate>\n\
+             \x20     </Representation>\n\
+             \x20   </AdaptationSet>\n\
+             \x20 </Period>\n\
+             \x20 <Period id=\"9\" start=\"PT8.000S\">\n\
+             \x20   <AdaptationSet segmentAlignment=\"true\" startWithSAP=\"1\">\n\
+             \x20     <Representation id=\"0\" mimeType=\"audio/mp4\" codecs=\"mp4a.40.2\" bandwidth=\"2000000\">\n\
+             \x20       <SegmentTemplate timescale=\"1000\" presentationTimeOffset=\"18500\" initialization=\"init_1.mp4\" media=\"segment_$Number%06d$.m4s\" startNumber=\"9\">\n\
+             \x20         <SegmentTimeline>\n\
+             \x20           <S t=\"18500\" d=\"1000\"/>\n\
+             \x20         </SegmentTimeline>\n\
+             \x20       </SegmentTemplate>\n\
+             \x20     </Representation>\n\
+             \x20   </AdaptationSet>\n\
+             \x20 </Period>\n\
+             </MPD>\n"
+        );
+
+        // Periods whose segments have all left the playlist are left out
+        let mpd = manifest.render(&segments.iter().skip(4).cloned().collect(), 2, 2);
+        assert!(!mpd.contains("<Period id=\"5\""));
+        assert!(mpd.contains("<Period id=\"9\" start=\"PT8.000S\">"));
+    }
+}
@@ -14,17 +14,26 @@ pub enum StreamError {
     #[error("Stream not found: {0}")]
     StreamNotFound(String),
 
+    #[error("Stream is already being published: {0}")]
+    StreamAlreadyPublishing(String),
+
     #[error("Maximum streams exceeded")]
     MaxStreamsExceeded,
 
     #[error("Invalid stream key: {0}")]
     InvalidStreamKey(String),
 
+    #[error("FLV error: {0}")]
+    Flv(String),
+
     #[error("FFmpeg error: {0}")]
     Ffmpeg(String),
 
     #[error("Configuration error: {0}")]
     Config(String),
+
+    #[error("Hook error: {0}")]
+    Hook(String),
 }
 
 pub type Result<T> = std::result::Result<T, StreamError>; 
\ No newline at end of file
@@ -0,0 +1,175 @@
+use crate::error::{Result, StreamError};
+use crate::media::{MediaKind, MediaPacket};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// Tag header: type, data size, timestamp, extended timestamp, stream ID
+const TAG_HEADER_SIZE: usize = 11;
+
+/// FLV file header followed by the first (zero) PreviousTagSize field.
+pub fn encode_header(has_audio: bool, has_video: bool) -> Bytes {
+    let mut header = BytesMut::with_capacity(13);
+    header.put_slice(b"FLV");
+    header.put_u8(1); // version
+    header.put_u8((if has_audio { 0x04 } else { 0 }) | (if has_video { 0x01 } else { 0 }));
+    header.put_u32(9); // header size
+    header.put_u32(0); // PreviousTagSize0
+    header.freeze()
+}
+
+/// Encodes a packet as an FLV tag followed by its PreviousTagSize field.
+pub fn encode_tag(packet: &MediaPacket) -> Bytes {
+    let data_size = packet.payload.len() as u32;
+    let mut tag = BytesMut::with_capacity(TAG_HEADER_SIZE + packet.payload.len() + 4);
+
+    tag.put_u8(packet.kind.type_id());
+    tag.put_slice(&data_size.to_be_bytes()[1..]);
+    tag.put_slice(&packet.timestamp.to_be_bytes()[1..]); // lower 24 bits
+    tag.put_u8((packet.timestamp >> 24) as u8); // TimestampExtended
+    tag.put_slice(&[0, 0, 0]); // stream ID, always 0
+    tag.put_slice(&packet.payload);
+    tag.put_u32(TAG_HEADER_SIZE as u32 + data_size);
+
+    tag.freeze()
+}
+
+/// Reads the tags of an FLV file as media packets.
+pub struct FlvReader<R> {
+    reader: R,
+}
+
+impl<R: AsyncRead + Unpin> FlvReader<R> {
+    /// Reads and checks the file header.
+    pub async fn new(mut reader: R) -> Result<Self> {
+        let mut header = [0u8; 9];
+        reader.read_exact(&mut header).await?;
+        if &header[..3] != b"FLV" {
+            return Err(StreamError::Flv("Missing FLV signature".to_string()));
+        }
+
+        // The header may be followed by extra bytes, and then PreviousTagSize0
+        let header_size = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+        let skip = header_size.saturating_sub(header.len()) + 4;
+        let mut remaining = vec![0u8; skip];
+        reader.read_exact(&mut remaining).await?;
+
+        Ok(Self { reader })
+    }
+
+    /// Returns the next audio, video or script data tag, or `None` at the end of the file.
+    /// A file cut off mid-tag, such as a recording that was interrupted, ends at its last whole tag.
+    pub async fn read_packet(&mut self) -> Result<Option<MediaPacket>> {
+        loop {
+            let mut header = [0u8; TAG_HEADER_SIZE];
+            if !self.read_or_eof(&mut header).await? {
+                return Ok(None);
+            }
+
+            let data_size = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+            let timestamp = u32::from_be_bytes([header[7], header[4], header[5], header[6]]);
+
+            let mut payload = vec![0u8; data_size + 4]; // followed by PreviousTagSize
+            if !self.read_or_eof(&mut payload).await? {
+                return Ok(None);
+            }
+            payload.truncate(data_size);
+
+            // The upper bits flag filtered (encrypted) tags, which can't be played
+            if header[0] & 0x20 != 0 {
+                continue;
+            }
+            if let Some(kind) = MediaKind::from_type_id(header[0] & 0x1F) {
+                return Ok(Some(MediaPacket::new(kind, timestamp, Bytes::from(payload))));
+            }
+        }
+    }
+
+    async fn read_or_eof(&mut self, buffer: &mut [u8]) -> Result<bool> {
+        match self.reader.read_exact(buffer).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(kind: MediaKind, timestamp: u32, payload: &'static [u8]) -> MediaPacket {
+        MediaPacket::new(kind, timestamp, Bytes::from_static(payload))
+    }
+
+    async fn read_all(file: &[u8]) -> Result<Vec<(MediaKind, u32, Bytes)>> {
+        let mut reader = FlvReader::new(file).await?;
+        let mut packets = Vec::new();
+        while let Some(packet) = reader.read_packet().await? {
+            packets.push((packet.kind, packet.timestamp, packet.payload));
+        }
+        Ok(packets)
+    }
+
+    #[test]
+    fn encodes_headers_and_tags() {
+        assert_eq!(encode_header(true, true)[..], [b'F', b'L', b'V', 1, 0x05, 0, 0, 0, 9, 0, 0, 0, 0]);
+        assert_eq!(encode_header(true, false)[4], 0x04);
+        assert_eq!(encode_header(false, true)[4], 0x01);
+
+        let tag = encode_tag(&packet(MediaKind::Audio, 0x0123_4567, &[0xAF, 0x01, 0x21]));
+        assert_eq!(tag[..], [8, 0, 0, 3, 0x23, 0x45, 0x67, 0x01, 0, 0, 0, 0xAF, 0x01, 0x21, 0, 0, 0, 14]);
+    }
+
+    #[tokio::test]
+    async fn reads_back_encoded_tags() {
+        let packets = [
+            packet(MediaKind::Metadata, 0, b"\x02\x00\x0AonMetaData"),
+            packet(MediaKind::Video, 0, &[0x17, 0x00, 0, 0, 0]),
+            packet(MediaKind::Audio, 23, &[0xAF, 0x01, 0x21]),
+            // Past 24 bits, the timestamp continues in its extended byte
+            packet(MediaKind::Video, 0x0123_4567, &[0x27, 0x01, 0, 0, 0]),
+        ];
+        let mut file = encode_header(true, true).to_vec();
+        for packet in &packets {
+            file.extend_from_slice(&encode_tag(packet));
+        }
+
+        let expected: Vec<_> = packets.iter().map(|packet| (packet.kind, packet.timestamp, packet.payload.clone())).collect();
+        assert_eq!(read_all(&file).await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn skips_extra_header_bytes_and_unplayable_tags() {
+        // A 12 byte header, then a filtered (encrypted) video tag and a tag of unknown type
+        let mut file = vec![b'F', b'L', b'V', 1, 0x01, 0, 0, 0, 12, 0xAA, 0xBB, 0xCC, 0, 0, 0, 0];
+        let mut encrypted = encode_tag(&packet(MediaKind::Video, 0, &[0x17, 0x01])).to_vec();
+        encrypted[0] |= 0x20;
+        file.extend_from_slice(&encrypted);
+        let mut unknown = encode_tag(&packet(MediaKind::Video, 0, &[0x17, 0x01])).to_vec();
+        unknown[0] = 0x10;
+        file.extend_from_slice(&unknown);
+        file.extend_from_slice(&encode_tag(&packet(MediaKind::Video, 40, &[0x27, 0x01])));
+
+        assert_eq!(read_all(&file).await.unwrap(), [(MediaKind::Video, 40, Bytes::from_static(&[0x27, 0x01]))]);
+    }
+
+    #[tokio::test]
+    async fn ends_truncated_files_at_the_last_whole_tag() {
+        let mut file = encode_header(false, true).to_vec();
+        file.extend_from_slice(&encode_tag(&packet(MediaKind::Video, 0, &[0x17, 0x01, 0, 0, 0])));
+        let whole = file.len();
+        file.extend_from_slice(&encode_tag(&packet(MediaKind::Video, 40, &[0x27, 0x01, 0, 0, 0])));
+
+        for cut in [whole + 5, file.len() - 1] {
+            assert_eq!(read_all(&file[..cut]).await.unwrap().len(), 1, "cut at {}", cut);
+        }
+        assert_eq!(read_all(&file[..encode_header(false, true).len()]).await.unwrap(), []);
+    }
+
+    #[tokio::test]
+    async fn rejects_files_that_are_not_flv() {
+        assert!(matches!(FlvReader::new(&b"GIF89a\x01\x00\x01\x00\x00\x00\x00"[..]).await, Err(StreamError::Flv(_))));
+        assert!(matches!(FlvReader::new(&b"FLV\x01"[..]).await, Err(StreamError::Io(_))));
+    }
+}
@@ -0,0 +1,359 @@
+use serde::Serialize;
+use std::{
+    path::Path,
+    process::{ExitStatus, Stdio},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    process::Child,
+    task::JoinHandle,
+};
+use tracing::{debug, error, trace, warn, Instrument, Span};
+
+use super::SharedHlsStatus;
+use crate::error::StreamError;
+
+/// Options making FFmpeg tag each log line with its level and report progress as `key=value`
+/// lines, both on stderr.
+pub const LOG_ARGS: [&str; 6] = ["-hide_banner", "-loglevel", "level+info", "-nostats", "-progress", "pipe:2"];
+
+/// Placeholders of the HLS command template.
+pub const HLS_PLACEHOLDERS: [&str; 6] = ["input", "stream_dir", "stream_key", "segment_duration", "playlist_size", "hls_flags"];
+
+// Log levels as printed by `level+`
+const LEVELS: [&str; 9] = ["quiet", "panic", "fatal", "error", "warning", "info", "verbose", "debug", "trace"];
+
+/// FFmpeg's latest `-progress` report.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FfmpegProgress {
+    pub frames: u64,
+    pub fps: f64,
+    // kbit/s
+    pub bitrate: f64,
+    // Relative to real time
+    pub speed: f64,
+    pub dropped_frames: u64,
+    pub duplicated_frames: u64,
+}
+
+/// A running FFmpeg, with its stderr logged and its progress reported in the stream's HLS
+/// status.
+pub struct FfmpegProcess {
+    pub child: Child,
+    // Resolves to the error FFmpeg gave up on, once its stderr closes
+    errors: JoinHandle<Option<String>>,
+}
+
+impl FfmpegProcess {
+    /// Reads the stderr of `child` under `span`, reporting progress in `status` if given.
+    pub fn new(mut child: Child, span: Span, status: Option<SharedHlsStatus>) -> Self {
+        let errors = match child.stderr.take() {
+            Some(stderr) => tokio::spawn(read_stderr(stderr, status).instrument(span)),
+            None => tokio::spawn(async { None }),
+        };
+        Self { child, errors }
+    }
+
+    /// Why FFmpeg exited: the last error it logged, or else its exit status.
+    pub async fn exit_error(self, exit_status: ExitStatus) -> StreamError {
+        let message = match self.errors.await {
+            Ok(Some(message)) if !exit_status.success() => message,
+            _ => format!("exited with {}", exit_status),
+        };
+        StreamError::Ffmpeg(message)
+    }
+}
+
+/// Checks that `path` runs as FFmpeg.
+pub fn check_binary(path: &Path) -> std::result::Result<(), String> {
+    let status = std::process::Command::new(path)
+        .arg("-version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_err(|e| format!("FFmpeg not found at '{}': {}", path.display(), e))?;
+    if !status.success() {
+        return Err(format!("'{} -version' exited with {}", path.display(), status));
+    }
+    Ok(())
+}
+
+/// Checks a command template: `{input}`, and each of `required`, must appear, and only
+/// `placeholders` may be used. FFmpeg's own `%{...}` expansions are left alone.
+pub fn validate_args(args: &[String], placeholders: &[&str], required: &[&str]) -> std::result::Result<(), String> {
+    for arg in args {
+        for name in placeholder_names(arg) {
+            if !placeholders.contains(&name) {
+                return Err(format!("unknown placeholder {{{}}} in '{}'", name, arg));
+            }
+        }
+    }
+    for name in ["input"].iter().chain(required) {
+        if !args.iter().any(|arg| placeholder_names(arg).any(|used| used == *name)) {
+            return Err(format!("{{{}}} is missing", name));
+        }
+    }
+    Ok(())
+}
+
+/// Fills in the placeholders of a command template.
+pub fn render_args(args: &[String], values: &[(&str, &str)]) -> Vec<String> {
+    args.iter()
+        .map(|arg| {
+            let mut rendered = String::with_capacity(arg.len());
+            let mut rest = arg.as_str();
+            while let Some(start) = find_placeholder(rest) {
+                let end = start + rest[start..].find('}').unwrap_or(0);
+                let name = &rest[start + 1..end];
+                rendered.push_str(&rest[..start]);
+                match values.iter().find(|(key, _)| *key == name) {
+                    Some((_, value)) => rendered.push_str(value),
+                    None => rendered.push_str(&rest[start..=end]),
+                }
+                rest = &rest[end + 1..];
+            }
+            rendered.push_str(rest);
+            rendered
+        })
+        .collect()
+}
+
+fn placeholder_names(arg: &str) -> impl Iterator<Item = &str> {
+    let mut rest = arg;
+    std::iter::from_fn(move || {
+        let start = find_placeholder(rest)?;
+        let end = start + rest[start..].find('}')?;
+        let name = &rest[start + 1..end];
+        rest = &rest[end + 1..];
+        Some(name)
+    })
+}
+
+// The next `{name}`, skipping `%{...}` and braces that are never closed
+fn find_placeholder(text: &str) -> Option<usize> {
+    let mut offset = 0;
+    while let Some(start) = text[offset..].find('{').map(|start| offset + start) {
+        let after = &text[start + 1..];
+        let closed = after.find(['{', '}']).is_some_and(|end| after[end..].starts_with('}'));
+        if closed && !text[..start].ends_with('%') {
+            return Some(start);
+        }
+        offset = start + 1;
+    }
+    None
+}
+
+async fn read_stderr<R: AsyncRead + Unpin>(stderr: R, status: Option<SharedHlsStatus>) -> Option<String> {
+    let mut lines = BufReader::new(stderr).lines();
+    let mut progress = FfmpegProgress::default();
+    // The last error, and the first fatal message, which is often just "Conversion failed!"
+    // after the actual error
+    let mut last_error = None;
+    let mut first_fatal = None;
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (level, message) = split_level(line);
+        match level {
+            Some("error") => {
+                error!("{}", message);
+                last_error = Some(message);
+            }
+            Some("quiet" | "panic" | "fatal") => {
+                error!("{}", message);
+                first_fatal.get_or_insert(message);
+            }
+            Some("warning") => warn!("{}", message),
+            Some(_) => debug!("{}", message),
+            // `-progress` output, which has no level
+            None => match line.split_once('=') {
+                Some(("progress", _)) => {
+                    trace!("{:?}", progress);
+                    if let Some(status) = &status {
+                        status.lock().unwrap().ffmpeg = Some(progress.clone());
+                    }
+                }
+                Some((key, value)) => update_progress(&mut progress, key, value.trim()),
+                None => debug!("{}", line),
+            },
+        }
+    }
+
+    last_error.or(first_fatal)
+}
+
+// FFmpeg's `level+` prefix follows the optional context: `[hls @ 0x55d0c8] [error] message`.
+// Returns the level and the line without it.
+fn split_level(line: &str) -> (Option<&'static str>, String) {
+    let mut rest = line;
+    while let Some(group) = rest.strip_prefix('[') {
+        let Some(end) = group.find("] ") else {
+            break;
+        };
+        if let Some(level) = LEVELS.iter().find(|level| **level == &group[..end]) {
+            let context = &line[..line.len() - rest.len()];
+            return (Some(level), format!("{}{}", context, &group[end + 2..]));
+        }
+        rest = &group[end + 2..];
+    }
+    (None, line.to_string())
+}
+
+// Values are "N/A" until FFmpeg knows them
+fn update_progress(progress: &mut FfmpegProgress, key: &str, value: &str) {
+    match key {
+        "frame" => progress.frames = value.parse().unwrap_or(0),
+        "fps" => progress.fps = value.parse().unwrap_or(0.0),
+        "bitrate" => progress.bitrate = value.trim_end_matches("kbits/s").parse().unwrap_or(0.0),
+        "speed" => progress.speed = value.trim_end_matches('x').parse().unwrap_or(0.0),
+        "drop_frames" => progress.dropped_frames = value.parse().unwrap_or(0),
+        "dup_frames" => progress.duplicated_frames = value.parse().unwrap_or(0),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hls::{HlsState, HlsStatus};
+    use std::sync::{Arc, Mutex};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn validates_templates() {
+        let template = args(&[
+            "-i", "{input}",
+            "-hls_segment_filename", "{stream_dir}/segment_%06d.ts", "{stream_dir}/playlist.m3u8",
+        ]);
+        assert_eq!(validate_args(&template, &HLS_PLACEHOLDERS, &["stream_dir"]), Ok(()));
+
+        assert_eq!(
+            validate_args(&args(&["-i", "{input}", "{stream_dir}/{stream}.m3u8"]), &HLS_PLACEHOLDERS, &[]),
+            Err("unknown placeholder {stream} in '{stream_dir}/{stream}.m3u8'".to_string())
+        );
+        assert_eq!(validate_args(&args(&["-i", "pipe:0"]), &HLS_PLACEHOLDERS, &[]), Err("{input} is missing".to_string()));
+        assert_eq!(
+            validate_args(&args(&["-i", "{input}", "out.m3u8"]), &HLS_PLACEHOLDERS, &["stream_dir"]),
+            Err("{stream_dir} is missing".to_string())
+        );
+    }
+
+    #[test]
+    fn leaves_ffmpeg_expansions_and_stray_braces_alone() {
+        let template = args(&[
+            "-i", "{input}",
+            "-vf", "drawtext=text='%{pts\\:hms}',crop={iw",
+            "-strftime", "1", "{stream_dir}/%{localtime}.jpg",
+            "}{",
+        ]);
+        assert_eq!(validate_args(&template, &["input", "stream_dir"], &[]), Ok(()));
+
+        let rendered = render_args(&template, &[("input", "pipe:0"), ("stream_dir", "/srv/live/cam")]);
+        assert_eq!(rendered, args(&[
+            "-i", "pipe:0",
+            "-vf", "drawtext=text='%{pts\\:hms}',crop={iw",
+            "-strftime", "1", "/srv/live/cam/%{localtime}.jpg",
+            "}{",
+        ]));
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let template = args(&["{stream_dir}/{stream_key}_{stream_key}.ts", "{segment_duration}", "plain", ""]);
+        let rendered = render_args(&template, &[("stream_dir", "/srv/live"), ("stream_key", "cam"), ("segment_duration", "4")]);
+        assert_eq!(rendered, args(&["/srv/live/cam_cam.ts", "4", "plain", ""]));
+
+        // Placeholders without a value are kept, and an unclosed brace doesn't hide the next one
+        let rendered = render_args(&args(&["{playlist_size}", "a{ {input}", "{input"]), &[("input", "pipe:0")]);
+        assert_eq!(rendered, args(&["{playlist_size}", "a{ pipe:0", "{input"]));
+    }
+
+    #[test]
+    fn finds_placeholders() {
+        assert_eq!(find_placeholder("x{input}"), Some(1));
+        assert_eq!(find_placeholder("%{pts} {input}"), Some(7));
+        assert_eq!(find_placeholder("{a {input}"), Some(3));
+        assert_eq!(find_placeholder("{input"), None);
+        assert_eq!(find_placeholder("}{"), None);
+        assert_eq!(placeholder_names("{a}{b}%{c}{d").collect::<Vec<_>>(), ["a", "b"]);
+    }
+
+    #[test]
+    fn splits_levels() {
+        assert_eq!(split_level("[info] Input #0, flv, from 'pipe:0':"), (Some("info"), "Input #0, flv, from 'pipe:0':".into()));
+        assert_eq!(
+            split_level("[hls @ 0x55d0c8e0a340] [warning] Non-monotonous DTS in output stream 0:0"),
+            (Some("warning"), "[hls @ 0x55d0c8e0a340] Non-monotonous DTS in output stream 0:0".to_string())
+        );
+        assert_eq!(
+            split_level("[flv @ 0x1] [h264 @ 0x2] [error] no frame!"),
+            (Some("error"), "[flv @ 0x1] [h264 @ 0x2] no frame!".to_string())
+        );
+        assert_eq!(split_level("[fatal] Conversion failed!"), (Some("fatal"), "Conversion failed!".to_string()));
+    }
+
+    #[test]
+    fn leaves_lines_without_a_level() {
+        for line in ["frame=120", "  Stream #0:0: Video: h264", "[hls @ 0x1] no level here", "[error]", "[notalevel] text"] {
+            assert_eq!(split_level(line), (None, line.to_string()));
+        }
+    }
+
+    #[test]
+    fn parses_progress() {
+        let mut progress = FfmpegProgress::default();
+        let block = "frame=120\nfps=29.97\nbitrate=1500.3kbits/s\nspeed=1.01x\ndrop_frames=2\ndup_frames=1\nout_time=00:00:04.000000";
+        for line in block.lines() {
+            let (key, value) = line.split_once('=').unwrap();
+            update_progress(&mut progress, key, value);
+        }
+        assert_eq!(progress.frames, 120);
+        assert_eq!((progress.fps, progress.bitrate, progress.speed), (29.97, 1500.3, 1.01));
+        assert_eq!((progress.dropped_frames, progress.duplicated_frames), (2, 1));
+
+        // Unknown values are reported as zero
+        for (key, value) in [("bitrate", "N/A"), ("speed", "N/A"), ("fps", "N/A"), ("frame", "N/A")] {
+            update_progress(&mut progress, key, value);
+        }
+        assert_eq!((progress.frames, progress.fps, progress.bitrate, progress.speed), (0, 0.0, 0.0, 0.0));
+    }
+
+    #[tokio::test]
+    async fn reports_progress_at_the_end_of_each_block() {
+        let status = Arc::new(Mutex::new(HlsStatus {
+            state: HlsState::Running,
+            last_error: None,
+            restarts: 0,
+            dropped_packets: 0,
+            ffmpeg: None,
+        }));
+        let stderr = b"[info] Output #0, hls, to 'playlist.m3u8':\n\
+            frame=50\nfps=25.0\nbitrate=N/A\nspeed=1x\nprogress=continue\n\
+            frame=75\n";
+
+        assert_eq!(read_stderr(&stderr[..], Some(status.clone())).await, None);
+        // The unfinished block isn't reported
+        let progress = status.lock().unwrap().ffmpeg.clone().unwrap();
+        assert_eq!((progress.frames, progress.fps, progress.bitrate, progress.speed), (50, 25.0, 0.0, 1.0));
+    }
+
+    #[tokio::test]
+    async fn prefers_the_last_error_over_the_first_fatal_message() {
+        let stderr = b"[error] first\n[hls @ 0x1] [error] Failed to open segment\n[fatal] Conversion failed!\n[fatal] Exiting\n";
+        assert_eq!(read_stderr(&stderr[..], None).await.as_deref(), Some("[hls @ 0x1] Failed to open segment"));
+
+        let stderr = b"[warning] deprecated option\n[fatal] pipe:0: Invalid data found\n[panic] Exiting\n";
+        assert_eq!(read_stderr(&stderr[..], None).await.as_deref(), Some("pipe:0: Invalid data found"));
+
+        let stderr = b"[info] all good\n[warning] only a warning\r\n\n";
+        assert_eq!(read_stderr(&stderr[..], None).await, None);
+    }
+}
@@ -0,0 +1,774 @@
+use crate::media::{MediaKind, MediaPacket, AUDIO_CODEC_AAC, VIDEO_CODEC_AVC, VIDEO_EX_HEADER};
+use bytes::{BufMut, Bytes, BytesMut};
+use tracing::warn;
+
+const VIDEO_TRACK_ID: u32 = 1;
+const AUDIO_TRACK_ID: u32 = 2;
+const VIDEO_TIMESCALE: u32 = 90_000;
+const AAC_FRAME_SAMPLES: u32 = 1024;
+const AAC_SAMPLING_RATES: [u32; 13] = [96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350];
+
+// FLV AVCPacketType / AACPacketType
+const SEQUENCE_HEADER: u8 = 0;
+const CODED_FRAME: u8 = 1;
+// Enhanced RTMP packet types besides SequenceStart
+const EX_PACKET_CODED_FRAMES: u8 = 1;
+const EX_PACKET_CODED_FRAMES_X: u8 = 3;
+
+const SAMPLE_FLAGS_SYNC: u32 = 0x0200_0000; // depends on no other sample
+const SAMPLE_FLAGS_NON_SYNC: u32 = 0x0101_0000; // depends on others, not a sync sample
+const UNITY_MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VideoCodec {
+    Avc,
+    Hevc,
+    Av1,
+}
+
+impl VideoCodec {
+    fn from_fourcc(fourcc: &[u8]) -> Option<Self> {
+        match fourcc {
+            b"avc1" => Some(VideoCodec::Avc),
+            b"hvc1" => Some(VideoCodec::Hevc),
+            b"av01" => Some(VideoCodec::Av1),
+            _ => None,
+        }
+    }
+
+    // Sample entry and decoder configuration box types
+    fn box_types(self) -> (&'static [u8; 4], &'static [u8; 4]) {
+        match self {
+            VideoCodec::Avc => (b"avc1", b"avcC"),
+            VideoCodec::Hevc => (b"hvc1", b"hvcC"),
+            VideoCodec::Av1 => (b"av01", b"av1C"),
+        }
+    }
+}
+
+struct VideoTrack {
+    codec: VideoCodec,
+    // avcC, hvcC or av1C contents, as sent in the sequence header
+    config: Bytes,
+}
+
+struct AudioTrack {
+    // AudioSpecificConfig
+    config: Bytes,
+    sample_rate: u32,
+    channels: u16,
+}
+
+/// What a manifest needs to know about the stream an init segment describes.
+#[derive(Debug, Clone, Default)]
+pub struct MediaInfo {
+    // RFC 6381 codecs, as in HLS CODECS and DASH @codecs
+    pub codecs: String,
+    pub width: u16,
+    pub height: u16,
+}
+
+struct Sample {
+    // Decode time, in milliseconds
+    timestamp: u32,
+    composition_offset: i32,
+    keyframe: bool,
+    data: Bytes,
+}
+
+/// Writes H.264, HEVC, AV1 and AAC packets as fragmented MP4 (CMAF): an init segment holding
+/// the codec configuration, and a `moof` + `mdat` fragment per media segment.
+#[derive(Default)]
+pub struct Fmp4Muxer {
+    video: Option<VideoTrack>,
+    audio: Option<AudioTrack>,
+    // From onMetaData; only informational in the init segment
+    width: u16,
+    height: u16,
+    video_samples: Vec<Sample>,
+    audio_samples: Vec<Sample>,
+    fragment_sequence: u32,
+    // Set when the codec configuration changed since the last init segment
+    config_changed: bool,
+}
+
+impl Fmp4Muxer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn has_video(&self) -> bool {
+        self.video.is_some()
+    }
+
+    pub fn media_info(&self) -> MediaInfo {
+        let codecs = [
+            self.video.as_ref().and_then(|video| video_codec_string(video.codec, &video.config)),
+            self.audio.as_ref().map(|audio| format!("mp4a.40.{}", audio.config[0] >> 3)),
+        ];
+        MediaInfo {
+            codecs: codecs.into_iter().flatten().collect::<Vec<_>>().join(","),
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    /// Takes in a packet: configuration updates the tracks, and frames are held until the
+    /// fragment is written.
+    pub fn write_packet(&mut self, packet: &MediaPacket) {
+        let payload = &packet.payload;
+        match packet.kind {
+            MediaKind::Video if payload.first().is_some_and(|byte| byte & VIDEO_EX_HEADER != 0) => {
+                if payload.len() < 5 {
+                    return;
+                }
+                let Some(codec) = VideoCodec::from_fourcc(&payload[1..5]) else {
+                    return;
+                };
+                match payload[0] & 0x0F {
+                    SEQUENCE_HEADER => self.set_video_config(codec, payload.slice(5..)),
+                    // AV1 frames have no composition time offset
+                    EX_PACKET_CODED_FRAMES if codec != VideoCodec::Av1 && payload.len() >= 8 => {
+                        let composition_offset = read_i24(&payload[5..8]);
+                        self.push_video_sample(packet, composition_offset, payload.slice(8..));
+                    }
+                    EX_PACKET_CODED_FRAMES | EX_PACKET_CODED_FRAMES_X => {
+                        self.push_video_sample(packet, 0, payload.slice(5..));
+                    }
+                    _ => {}
+                }
+            }
+            MediaKind::Video if payload.len() >= 5 && payload[0] & 0x0F == VIDEO_CODEC_AVC => match payload[1] {
+                SEQUENCE_HEADER => self.set_video_config(VideoCodec::Avc, payload.slice(5..)),
+                CODED_FRAME => {
+                    let composition_offset = read_i24(&payload[2..5]);
+                    self.push_video_sample(packet, composition_offset, payload.slice(5..));
+                }
+                _ => {}
+            },
+            MediaKind::Audio if payload.len() >= 2 && payload[0] >> 4 == AUDIO_CODEC_AAC => match payload[1] {
+                SEQUENCE_HEADER => self.set_audio_config(payload.slice(2..)),
+                CODED_FRAME if self.audio.is_some() => self.audio_samples.push(Sample {
+                    timestamp: packet.timestamp,
+                    composition_offset: 0,
+                    keyframe: true,
+                    data: payload.slice(2..),
+                }),
+                _ => {}
+            },
+            MediaKind::Metadata => {
+                if let Some(width) = metadata_number(payload, "width") {
+                    self.width = width as u16;
+                }
+                if let Some(height) = metadata_number(payload, "height") {
+                    self.height = height as u16;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn set_video_config(&mut self, codec: VideoCodec, config: Bytes) {
+        if self.video.as_ref().is_some_and(|video| video.codec == codec && video.config == config) {
+            return;
+        }
+        self.video = Some(VideoTrack { codec, config });
+        self.config_changed = true;
+    }
+
+    fn set_audio_config(&mut self, config: Bytes) {
+        if self.audio.as_ref().is_some_and(|audio| audio.config == config) {
+            return;
+        }
+        if config.len() < 2 {
+            warn!("Ignoring malformed AAC configuration");
+            return;
+        }
+
+        let sampling_index = ((config[0] & 0x07) << 1 | config[1] >> 7) as usize;
+        let Some(&sample_rate) = AAC_SAMPLING_RATES.get(sampling_index) else {
+            warn!("Ignoring unsupported AAC configuration");
+            return;
+        };
+        let channels = ((config[1] >> 3) & 0x0F) as u16;
+
+        self.audio = Some(AudioTrack { config, sample_rate, channels });
+        self.config_changed = true;
+    }
+
+    fn push_video_sample(&mut self, packet: &MediaPacket, composition_offset: i32, data: Bytes) {
+        if self.video.is_none() {
+            return;
+        }
+        self.video_samples.push(Sample {
+            timestamp: packet.timestamp,
+            composition_offset,
+            keyframe: packet.is_keyframe(),
+            data,
+        });
+    }
+
+    /// Returns a new init segment if the codec configuration changed since the last one.
+    pub fn take_init_segment(&mut self) -> Option<Bytes> {
+        if !std::mem::take(&mut self.config_changed) {
+            return None;
+        }
+
+        let mut out = BytesMut::new();
+        write_box(&mut out, b"ftyp", |out| {
+            out.put_slice(b"iso6"); // major brand
+            out.put_u32(0); // minor version
+            for brand in [b"iso6", b"cmfc", b"mp41"] {
+                out.put_slice(brand);
+            }
+        });
+
+        write_box(&mut out, b"moov", |out| {
+            write_full_box(out, b"mvhd", 0, 0, |out| {
+                out.put_u32(0); // creation_time
+                out.put_u32(0); // modification_time
+                out.put_u32(1000); // timescale
+                out.put_u32(0); // duration
+                out.put_u32(0x0001_0000); // rate 1.0
+                out.put_u16(0x0100); // volume 1.0
+                out.put_slice(&[0; 10]);
+                put_matrix(out);
+                out.put_slice(&[0; 24]); // pre_defined
+                out.put_u32(AUDIO_TRACK_ID + 1); // next_track_ID
+            });
+
+            if let Some(video) = &self.video {
+                write_video_track(out, video, self.width, self.height);
+            }
+            if let Some(audio) = &self.audio {
+                write_audio_track(out, audio);
+            }
+
+            write_box(out, b"mvex", |out| {
+                for (track_id, present) in [(VIDEO_TRACK_ID, self.video.is_some()), (AUDIO_TRACK_ID, self.audio.is_some())] {
+                    if !present {
+                        continue;
+                    }
+                    write_full_box(out, b"trex", 0, 0, |out| {
+                        out.put_u32(track_id);
+                        out.put_u32(1); // default_sample_description_index
+                        out.put_u32(0); // default_sample_duration
+                        out.put_u32(0); // default_sample_size
+                        out.put_u32(0); // default_sample_flags
+                    });
+                }
+            });
+        });
+
+        Some(out.freeze())
+    }
+
+    /// Writes the held frames as one `moof` + `mdat` fragment. `end` is where the next
+    /// segment starts, and sets the duration of the last video frame.
+    pub fn write_fragment(&mut self, end: u32, out: &mut BytesMut) {
+        let video_samples = std::mem::take(&mut self.video_samples);
+        let audio_samples = std::mem::take(&mut self.audio_samples);
+        if video_samples.is_empty() && audio_samples.is_empty() {
+            return;
+        }
+        self.fragment_sequence += 1;
+
+        let mut runs = Vec::new();
+        if let Some(first) = video_samples.first() {
+            let durations = video_durations(&video_samples, end);
+            runs.push(TrackRun {
+                track_id: VIDEO_TRACK_ID,
+                base_decode_time: first.timestamp as u64 * (VIDEO_TIMESCALE / 1000) as u64,
+                samples: video_samples,
+                durations,
+                composition_scale: (VIDEO_TIMESCALE / 1000) as i32,
+            });
+        }
+        if let (Some(first), Some(audio)) = (audio_samples.first(), &self.audio) {
+            runs.push(TrackRun {
+                track_id: AUDIO_TRACK_ID,
+                base_decode_time: first.timestamp as u64 * audio.sample_rate as u64 / 1000,
+                durations: vec![AAC_FRAME_SAMPLES; audio_samples.len()],
+                samples: audio_samples,
+                composition_scale: 0,
+            });
+        }
+
+        // The moof's size doesn't depend on the data offsets, so it is sized with zeros first
+        let moof_size = self.moof(&runs, &vec![0; runs.len()]).len();
+        let mut data_offsets = Vec::with_capacity(runs.len());
+        let mut data_offset = moof_size + 8; // past the mdat header
+        for run in &runs {
+            data_offsets.push(data_offset as u32);
+            data_offset += run.samples.iter().map(|sample| sample.data.len()).sum::<usize>();
+        }
+
+        out.put_slice(&self.moof(&runs, &data_offsets));
+        out.put_u32((data_offset - moof_size) as u32);
+        out.put_slice(b"mdat");
+        for sample in runs.iter().flat_map(|run| &run.samples) {
+            out.put_slice(&sample.data);
+        }
+    }
+
+    fn moof(&self, runs: &[TrackRun], data_offsets: &[u32]) -> BytesMut {
+        let mut out = BytesMut::new();
+        write_box(&mut out, b"moof", |out| {
+            write_full_box(out, b"mfhd", 0, 0, |out| out.put_u32(self.fragment_sequence));
+
+            for (run, data_offset) in runs.iter().zip(data_offsets) {
+                write_box(out, b"traf", |out| {
+                    // default-base-is-moof
+                    write_full_box(out, b"tfhd", 0, 0x02_0000, |out| out.put_u32(run.track_id));
+                    write_full_box(out, b"tfdt", 1, 0, |out| out.put_u64(run.base_decode_time));
+                    // data offset, and per-sample duration, size, flags and composition offset
+                    write_full_box(out, b"trun", 1, 0x0F01, |out| {
+                        out.put_u32(run.samples.len() as u32);
+                        out.put_u32(*data_offset);
+                        for (sample, duration) in run.samples.iter().zip(&run.durations) {
+                            out.put_u32(*duration);
+                            out.put_u32(sample.data.len() as u32);
+                            out.put_u32(if sample.keyframe { SAMPLE_FLAGS_SYNC } else { SAMPLE_FLAGS_NON_SYNC });
+                            out.put_i32(sample.composition_offset * run.composition_scale);
+                        }
+                    });
+                });
+            }
+        });
+        out
+    }
+}
+
+struct TrackRun {
+    track_id: u32,
+    base_decode_time: u64,
+    samples: Vec<Sample>,
+    durations: Vec<u32>,
+    // Milliseconds to track timescale units for composition offsets
+    composition_scale: i32,
+}
+
+// In 90kHz units; the last frame lasts until `end`, or as long as the one before it
+fn video_durations(samples: &[Sample], end: u32) -> Vec<u32> {
+    let mut durations: Vec<u32> = samples
+        .windows(2)
+        .map(|pair| pair[1].timestamp.wrapping_sub(pair[0].timestamp) * (VIDEO_TIMESCALE / 1000))
+        .collect();
+
+    let last = samples[samples.len() - 1].timestamp;
+    let last_duration = match end.wrapping_sub(last) {
+        0 => durations.last().copied().unwrap_or(0),
+        gap if gap < u32::MAX / 2 => gap * (VIDEO_TIMESCALE / 1000),
+        _ => durations.last().copied().unwrap_or(0),
+    };
+    durations.push(last_duration);
+    durations
+}
+
+// From the decoder configuration record, e.g. avc1.64001f, hvc1.1.6.L93.B0 or av01.0.08M.08
+fn video_codec_string(codec: VideoCodec, config: &[u8]) -> Option<String> {
+    match codec {
+        VideoCodec::Avc => {
+            let profile = config.get(1..4)?;
+            Some(format!("avc1.{:02x}{:02x}{:02x}", profile[0], profile[1], profile[2]))
+        }
+        VideoCodec::Hevc => {
+            let config = config.get(..13)?;
+            let profile_space = ["", "A", "B", "C"][(config[1] >> 6) as usize];
+            let tier = if config[1] & 0x20 != 0 { 'H' } else { 'L' };
+            let compatibility = u32::from_be_bytes([config[2], config[3], config[4], config[5]]).reverse_bits();
+            let mut codec = format!("hvc1.{}{}.{:X}.{}{}", profile_space, config[1] & 0x1F, compatibility, tier, config[12]);
+            // Constraint flags, without trailing zero bytes
+            let constraints = &config[6..12];
+            let used = constraints.iter().rposition(|byte| *byte != 0).map_or(0, |last| last + 1);
+            for byte in &constraints[..used] {
+                codec.push_str(&format!(".{:X}", byte));
+            }
+            Some(codec)
+        }
+        VideoCodec::Av1 => {
+            let config = config.get(..3)?;
+            let tier = if config[2] & 0x80 != 0 { 'H' } else { 'M' };
+            let bit_depth = match (config[2] & 0x40 != 0, config[2] & 0x20 != 0) {
+                (true, true) => 12,
+                (true, false) => 10,
+                _ => 8,
+            };
+            Some(format!("av01.{}.{:02}{}.{:02}", config[1] >> 5, config[1] & 0x1F, tier, bit_depth))
+        }
+    }
+}
+
+fn write_video_track(out: &mut BytesMut, video: &VideoTrack, width: u16, height: u16) {
+    let (sample_entry, config_box) = video.codec.box_types();
+
+    let track = TrackHeader {
+        track_id: VIDEO_TRACK_ID,
+        timescale: VIDEO_TIMESCALE,
+        handler: b"vide",
+        handler_name: "VideoHandler",
+        width,
+        height,
+    };
+    write_track(out, &track, |out| {
+        write_full_box(out, b"vmhd", 0, 1, |out| out.put_slice(&[0; 8])); // graphicsmode, opcolor
+    }, |out| {
+        write_box(out, sample_entry, |out| {
+            out.put_slice(&[0; 6]);
+            out.put_u16(1); // data_reference_index
+            out.put_slice(&[0; 16]);
+            out.put_u16(width);
+            out.put_u16(height);
+            out.put_u32(0x0048_0000); // 72 dpi
+            out.put_u32(0x0048_0000);
+            out.put_u32(0);
+            out.put_u16(1); // frame_count
+            out.put_slice(&[0; 32]); // compressorname
+            out.put_u16(0x0018); // depth
+            out.put_i16(-1);
+            write_box(out, config_box, |out| out.put_slice(&video.config));
+        });
+    });
+}
+
+fn write_audio_track(out: &mut BytesMut, audio: &AudioTrack) {
+    let track = TrackHeader {
+        track_id: AUDIO_TRACK_ID,
+        timescale: audio.sample_rate,
+        handler: b"soun",
+        handler_name: "SoundHandler",
+        width: 0,
+        height: 0,
+    };
+    write_track(out, &track, |out| {
+        write_full_box(out, b"smhd", 0, 0, |out| out.put_u32(0)); // balance
+    }, |out| {
+        write_box(out, b"mp4a", |out| {
+            out.put_slice(&[0; 6]);
+            out.put_u16(1); // data_reference_index
+            out.put_slice(&[0; 8]);
+            out.put_u16(audio.channels);
+            out.put_u16(16); // samplesize
+            out.put_u32(0);
+            out.put_u32(audio.sample_rate.min(u16::MAX as u32) << 16);
+
+            write_full_box(out, b"esds", 0, 0, |out| {
+                let config_len = audio.config.len();
+                let decoder_config_len = 13 + 2 + config_len;
+                out.put_u8(0x03); // ES_Descriptor
+                out.put_u8((3 + 2 + decoder_config_len + 3) as u8);
+                out.put_u16(0); // ES_ID
+                out.put_u8(0);
+                out.put_u8(0x04); // DecoderConfigDescriptor
+                out.put_u8(decoder_config_len as u8);
+                out.put_u8(0x40); // MPEG-4 audio
+                out.put_u8(0x15); // audio stream
+                out.put_slice(&[0; 3]); // bufferSizeDB
+                out.put_u32(0); // maxBitrate
+                out.put_u32(0); // avgBitrate
+                out.put_u8(0x05); // DecoderSpecificInfo
+                out.put_u8(config_len as u8);
+                out.put_slice(&audio.config);
+                out.put_u8(0x06); // SLConfigDescriptor
+                out.put_u8(1);
+                out.put_u8(0x02);
+            });
+        });
+    });
+}
+
+struct TrackHeader {
+    track_id: u32,
+    timescale: u32,
+    handler: &'static [u8; 4],
+    handler_name: &'static str,
+    width: u16,
+    height: u16,
+}
+
+fn write_track(out: &mut BytesMut, track: &TrackHeader, media_header: impl FnOnce(&mut BytesMut), sample_entry: impl FnOnce(&mut BytesMut)) {
+    write_box(out, b"trak", |out| {
+        // enabled, in movie
+        write_full_box(out, b"tkhd", 0, 0x03, |out| {
+            out.put_u32(0); // creation_time
+            out.put_u32(0); // modification_time
+            out.put_u32(track.track_id);
+            out.put_u32(0);
+            out.put_u32(0); // duration
+            out.put_slice(&[0; 8]);
+            out.put_u16(0); // layer
+            out.put_u16(0); // alternate_group
+            out.put_u16(if track.handler == b"soun" { 0x0100 } else { 0 }); // volume
+            out.put_u16(0);
+            put_matrix(out);
+            out.put_u32((track.width as u32) << 16);
+            out.put_u32((track.height as u32) << 16);
+        });
+
+        write_box(out, b"mdia", |out| {
+            write_full_box(out, b"mdhd", 0, 0, |out| {
+                out.put_u32(0); // creation_time
+                out.put_u32(0); // modification_time
+                out.put_u32(track.timescale);
+                out.put_u32(0); // duration
+                out.put_u16(0x55C4); // language "und"
+                out.put_u16(0);
+            });
+            write_full_box(out, b"hdlr", 0, 0, |out| {
+                out.put_u32(0);
+                out.put_slice(track.handler);
+                out.put_slice(&[0; 12]);
+                out.put_slice(track.handler_name.as_bytes());
+                out.put_u8(0);
+            });
+
+            write_box(out, b"minf", |out| {
+                media_header(out);
+                write_box(out, b"dinf", |out| {
+                    write_full_box(out, b"dref", 0, 0, |out| {
+                        out.put_u32(1);
+                        write_full_box(out, b"url ", 0, 1, |_| {}); // media is in this file
+                    });
+                });
+
+                // Samples are described by the fragments, so the tables are empty
+                write_box(out, b"stbl", |out| {
+                    write_full_box(out, b"stsd", 0, 0, |out| {
+                        out.put_u32(1);
+                        sample_entry(out);
+                    });
+                    for table in [b"stts", b"stsc", b"stco"] {
+                        write_full_box(out, table, 0, 0, |out| out.put_u32(0));
+                    }
+                    write_full_box(out, b"stsz", 0, 0, |out| {
+                        out.put_u32(0);
+                        out.put_u32(0);
+                    });
+                });
+            });
+        });
+    });
+}
+
+fn write_box(out: &mut BytesMut, box_type: &[u8; 4], content: impl FnOnce(&mut BytesMut)) {
+    let start = out.len();
+    out.put_u32(0); // size, filled in below
+    out.put_slice(box_type);
+    content(out);
+
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+fn write_full_box(out: &mut BytesMut, box_type: &[u8; 4], version: u8, flags: u32, content: impl FnOnce(&mut BytesMut)) {
+    write_box(out, box_type, |out| {
+        out.put_u32((version as u32) << 24 | flags);
+        content(out);
+    });
+}
+
+fn put_matrix(out: &mut BytesMut) {
+    for value in UNITY_MATRIX {
+        out.put_u32(value);
+    }
+}
+
+fn read_i24(bytes: &[u8]) -> i32 {
+    i32::from_be_bytes([bytes[0], bytes[1], bytes[2], 0]) >> 8
+}
+
+// A number property of an onMetaData payload, found by its AMF0 key
+fn metadata_number(payload: &[u8], name: &str) -> Option<f64> {
+    let mut key = Vec::with_capacity(name.len() + 3);
+    key.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    key.extend_from_slice(name.as_bytes());
+    key.push(0x00); // number marker
+
+    let position = payload.windows(key.len()).position(|window| window == key.as_slice())?;
+    let value = payload.get(position + key.len()..position + key.len() + 8)?;
+    Some(f64::from_be_bytes(value.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // High profile, level 3.1, one SPS and one PPS
+    const AVC_CONFIG: &[u8] = &[
+        0x01, 0x64, 0x00, 0x1F, 0xFF, 0xE1, 0x00, 0x04, 0x67, 0x64, 0x00, 0x1F, 0x01, 0x00, 0x02, 0x68, 0xEE,
+    ];
+    // AAC LC, 44.1kHz, stereo
+    const AAC_CONFIG: &[u8] = &[0x12, 0x10];
+
+    fn packet(kind: MediaKind, timestamp: u32, payload: &[u8]) -> MediaPacket {
+        MediaPacket::new(kind, timestamp, Bytes::copy_from_slice(payload))
+    }
+
+    fn configured_muxer() -> Fmp4Muxer {
+        let mut muxer = Fmp4Muxer::new();
+        muxer.write_packet(&packet(MediaKind::Video, 0, &[&[0x17, 0x00, 0, 0, 0][..], AVC_CONFIG].concat()));
+        muxer.write_packet(&packet(MediaKind::Audio, 0, &[&[0xAF, 0x00][..], AAC_CONFIG].concat()));
+        muxer
+    }
+
+    // The type and contents of each box in `data`
+    fn boxes(mut data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut boxes = Vec::new();
+        while !data.is_empty() {
+            let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+            boxes.push((data[4..8].try_into().unwrap(), &data[8..size]));
+            data = &data[size..];
+        }
+        boxes
+    }
+
+    fn box_types(data: &[u8]) -> Vec<[u8; 4]> {
+        boxes(data).into_iter().map(|(box_type, _)| box_type).collect()
+    }
+
+    fn child<'a>(data: &'a [u8], box_type: &[u8; 4]) -> &'a [u8] {
+        boxes(data).into_iter().find(|(found, _)| found == box_type).unwrap().1
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn writes_init_segment() {
+        let mut muxer = configured_muxer();
+        let init = muxer.take_init_segment().unwrap();
+        assert_eq!(box_types(&init), [*b"ftyp", *b"moov"]);
+        assert_eq!(child(&init, b"ftyp"), b"iso6\0\0\0\0iso6cmfcmp41");
+
+        let moov = child(&init, b"moov");
+        assert_eq!(box_types(moov), [*b"mvhd", *b"trak", *b"trak", *b"mvex"]);
+        assert_eq!(u32_at(child(moov, b"mvhd"), 4 + 8), 1000); // timescale
+
+        let traks: Vec<_> = boxes(moov)
+            .into_iter()
+            .filter(|(box_type, _)| box_type == b"trak")
+            .map(|(_, trak)| trak)
+            .collect();
+        let tracks = [(traks[0], VIDEO_TRACK_ID, VIDEO_TIMESCALE), (traks[1], AUDIO_TRACK_ID, 44_100)];
+        for (trak, track_id, timescale) in tracks {
+            assert_eq!(u32_at(child(trak, b"tkhd"), 4 + 8), track_id);
+            assert_eq!(u32_at(child(child(trak, b"mdia"), b"mdhd"), 4 + 8), timescale);
+        }
+
+        let stsd = |trak| child(child(child(child(trak, b"mdia"), b"minf"), b"stbl"), b"stsd");
+        let avc1 = child(&stsd(traks[0])[8..], b"avc1");
+        assert_eq!(child(&avc1[78..], b"avcC"), AVC_CONFIG);
+        let mp4a = child(&stsd(traks[1])[8..], b"mp4a");
+        assert_eq!(u16::from_be_bytes([mp4a[16], mp4a[17]]), 2); // channels
+        assert!(child(&mp4a[28..], b"esds").ends_with(&[0x05, 2, 0x12, 0x10, 0x06, 1, 0x02]));
+
+        let mvex = child(moov, b"mvex");
+        assert_eq!(box_types(mvex), [*b"trex", *b"trex"]);
+        for ((_, trex), track_id) in boxes(mvex).into_iter().zip([VIDEO_TRACK_ID, AUDIO_TRACK_ID]) {
+            assert_eq!(trex, [&[0; 4][..], &track_id.to_be_bytes(), &1u32.to_be_bytes(), &[0; 12]].concat());
+        }
+    }
+
+    #[test]
+    fn writes_init_segment_only_when_configuration_changes() {
+        let mut muxer = configured_muxer();
+        assert!(muxer.take_init_segment().is_some());
+        assert!(muxer.take_init_segment().is_none());
+
+        // Repeated sequence headers don't need a new init segment
+        muxer.write_packet(&packet(MediaKind::Audio, 40, &[&[0xAF, 0x00][..], AAC_CONFIG].concat()));
+        assert!(muxer.take_init_segment().is_none());
+
+        muxer.write_packet(&packet(MediaKind::Audio, 80, &[0xAF, 0x00, 0x11, 0x90]));
+        let init = muxer.take_init_segment().unwrap();
+        assert_eq!(box_types(child(&init, b"moov")), [*b"mvhd", *b"trak", *b"trak", *b"mvex"]);
+    }
+
+    #[test]
+    fn writes_fragment_offsets_and_sizes() {
+        let mut muxer = configured_muxer();
+        muxer.write_packet(&packet(MediaKind::Video, 1000, &[0x17, 0x01, 0, 0, 0, 0, 0, 0, 2, 0x65, 0x88]));
+        muxer.write_packet(&packet(MediaKind::Video, 1040, &[0x27, 0x01, 0, 0, 40, 0, 0, 0, 1, 0x41]));
+        muxer.write_packet(&packet(MediaKind::Video, 1080, &[0x27, 0x01, 0, 0, 0, 0, 0, 0, 3, 0x41, 0x9A, 0x00]));
+        muxer.write_packet(&packet(MediaKind::Audio, 1000, &[0xAF, 0x01, 0x21, 0x10, 0x05]));
+        muxer.write_packet(&packet(MediaKind::Audio, 1023, &[0xAF, 0x01, 0x21, 0x20]));
+
+        let mut out = BytesMut::new();
+        muxer.write_fragment(1120, &mut out);
+        assert_eq!(box_types(&out), [*b"moof", *b"mdat"]);
+        let moof = child(&out, b"moof");
+        assert_eq!(box_types(moof), [*b"mfhd", *b"traf", *b"traf"]);
+        assert_eq!(u32_at(child(moof, b"mfhd"), 4), 1);
+
+        let samples: [&[&[u8]]; 2] = [
+            &[&[0, 0, 0, 2, 0x65, 0x88], &[0, 0, 0, 1, 0x41], &[0, 0, 0, 3, 0x41, 0x9A, 0x00]],
+            &[&[0x21, 0x10, 0x05], &[0x21, 0x20]],
+        ];
+        let trafs: Vec<_> = boxes(moof).into_iter().skip(1).map(|(_, traf)| traf).collect();
+        let tracks = [(VIDEO_TRACK_ID, 90_000u64), (AUDIO_TRACK_ID, 44_100)];
+        for ((traf, samples), (track_id, base_decode_time)) in trafs.into_iter().zip(samples).zip(tracks) {
+            assert_eq!(u32_at(child(traf, b"tfhd"), 4), track_id);
+            assert_eq!(child(traf, b"tfdt")[4..], base_decode_time.to_be_bytes());
+
+            let trun = child(traf, b"trun");
+            assert_eq!(u32_at(trun, 4) as usize, samples.len());
+            // Offsets are from the start of the moof, and each sample's data follows the last
+            let mut offset = u32_at(trun, 8) as usize;
+            for (index, sample) in samples.iter().enumerate() {
+                let size = u32_at(trun, 12 + index * 16 + 4) as usize;
+                assert_eq!(size, sample.len());
+                assert_eq!(&out[offset..offset + size], *sample);
+                offset += size;
+            }
+        }
+
+        let video_trun = child(child(moof, b"traf"), b"trun");
+        let fields = |index: usize| [0, 8, 12].map(|field| u32_at(video_trun, 12 + index * 16 + field));
+        assert_eq!(fields(0), [3600, SAMPLE_FLAGS_SYNC, 0]);
+        assert_eq!(fields(1), [3600, SAMPLE_FLAGS_NON_SYNC, 3600]);
+        assert_eq!(fields(2), [3600, SAMPLE_FLAGS_NON_SYNC, 0]);
+
+        // The data ends with the mdat, and nothing is held for the next fragment
+        let mdat = child(&out, b"mdat");
+        let data_size: usize = samples.iter().flat_map(|track| track.iter()).map(|sample| sample.len()).sum();
+        assert_eq!(mdat.len(), data_size);
+        let length = out.len();
+        muxer.write_fragment(1200, &mut out);
+        assert_eq!(out.len(), length);
+    }
+
+    #[test]
+    fn builds_codec_strings() {
+        assert_eq!(video_codec_string(VideoCodec::Avc, AVC_CONFIG).unwrap(), "avc1.64001f");
+        assert_eq!(video_codec_string(VideoCodec::Avc, &[0x01, 0x42, 0xC0, 0x1E]).unwrap(), "avc1.42c01e");
+
+        // Main, level 3.1, progressive source only
+        let main = [0x01, 0x01, 0x60, 0x00, 0x00, 0x00, 0x90, 0x00, 0x00, 0x00, 0x00, 0x00, 93];
+        assert_eq!(video_codec_string(VideoCodec::Hevc, &main).unwrap(), "hvc1.1.6.L93.90");
+        // Main 10, high tier, level 4
+        let main10 = [0x01, 0x22, 0x20, 0x00, 0x00, 0x00, 0xB0, 0x00, 0x00, 0x00, 0x00, 0x00, 120];
+        assert_eq!(video_codec_string(VideoCodec::Hevc, &main10).unwrap(), "hvc1.2.4.H120.B0");
+
+        assert_eq!(video_codec_string(VideoCodec::Av1, &[0x81, 0x08, 0x0C]).unwrap(), "av01.0.08M.08");
+        assert_eq!(video_codec_string(VideoCodec::Av1, &[0x81, 0x0D, 0x40]).unwrap(), "av01.0.13M.10");
+        assert_eq!(video_codec_string(VideoCodec::Av1, &[0x81, 0x4D, 0xE0]).unwrap(), "av01.2.13H.12");
+
+        // Truncated configuration records
+        assert!(video_codec_string(VideoCodec::Avc, &[0x01, 0x64]).is_none());
+        assert!(video_codec_string(VideoCodec::Hevc, &main[..12]).is_none());
+        assert!(video_codec_string(VideoCodec::Av1, &[0x81]).is_none());
+    }
+
+    #[test]
+    fn reports_media_info() {
+        let mut muxer = configured_muxer();
+        assert_eq!(muxer.media_info().codecs, "avc1.64001f,mp4a.40.2");
+
+        let mut enhanced = vec![0x90];
+        enhanced.extend_from_slice(b"av01");
+        enhanced.extend_from_slice(&[0x81, 0x08, 0x0C]);
+        muxer.write_packet(&packet(MediaKind::Video, 0, &enhanced));
+        assert_eq!(muxer.media_info().codecs, "av01.0.08M.08,mp4a.40.2");
+    }
+}
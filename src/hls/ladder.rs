@@ -0,0 +1,248 @@
+use serde::Deserialize;
+use std::path::Path;
+use tokio::process::Command;
+
+use crate::stream_key::validate_path_component;
+
+// Added to the encoder bitrates for the peak BANDWIDTH, for container overhead
+const CONTAINER_OVERHEAD: f64 = 1.1;
+const AUDIO_CODEC: &str = "mp4a.40.2";
+
+/// One rendition of an adaptive bitrate ladder, transcoded by FFmpeg into its own
+/// `<stream_dir>/<name>/` variant playlist.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RenditionConfig {
+    pub name: String,
+    // Video size and bitrate (kbit/s); a rendition without them is audio-only
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub video_bitrate: Option<u32>,
+    // Output frame rate; the source's when unset
+    pub frame_rate: Option<f64>,
+    #[serde(default = "default_audio_bitrate")]
+    pub audio_bitrate: u32,
+}
+
+fn default_audio_bitrate() -> u32 {
+    128
+}
+
+impl RenditionConfig {
+    fn video(&self) -> Option<(u32, u32, u32)> {
+        Some((self.width?, self.height?, self.video_bitrate?))
+    }
+
+    // The lowest H.264 level that fits, as (level_idc, FFmpeg's name for it). Without a
+    // frame rate, up to 60fps is assumed.
+    fn h264_level(&self, height: u32) -> (u8, &'static str) {
+        let high_frame_rate = self.frame_rate.is_none_or(|frame_rate| frame_rate > 30.0);
+        match (height, high_frame_rate) {
+            (0..=720, false) => (31, "3.1"),
+            (0..=720, true) => (32, "3.2"),
+            (721..=1080, false) => (40, "4.0"),
+            (721..=1080, true) => (42, "4.2"),
+            _ => (51, "5.1"),
+        }
+    }
+
+    fn codecs(&self) -> String {
+        match self.video() {
+            // High profile, no constraint flags
+            Some((_, height, _)) => format!("avc1.6400{:02x},{}", self.h264_level(height).0, AUDIO_CODEC),
+            None => AUDIO_CODEC.to_string(),
+        }
+    }
+
+    fn bandwidth(&self) -> u64 {
+        let video_bitrate = self.video().map_or(0, |(_, _, bitrate)| bitrate);
+        (video_bitrate + self.audio_bitrate) as u64 * 1000
+    }
+}
+
+/// Checks an application's ladder: names must be unique directory names, and a video
+/// rendition needs a width, height and bitrate.
+pub fn validate(renditions: &[RenditionConfig]) -> std::result::Result<(), String> {
+    for (index, rendition) in renditions.iter().enumerate() {
+        validate_path_component(&rendition.name, 64)
+            .map_err(|e| format!("rendition name '{}' {}", rendition.name, e))?;
+        if renditions[..index].iter().any(|other| other.name == rendition.name) {
+            return Err(format!("rendition '{}' is defined twice", rendition.name));
+        }
+
+        let video_settings = [rendition.width.is_some(), rendition.height.is_some(), rendition.video_bitrate.is_some()];
+        if video_settings.contains(&true) && video_settings.contains(&false) {
+            return Err(format!(
+                "rendition '{}' needs all of width, height and video_bitrate, or none for audio only",
+                rendition.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The master playlist listing each rendition's variant playlist.
+pub fn master_playlist(renditions: &[RenditionConfig]) -> String {
+    let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-INDEPENDENT-SEGMENTS\n");
+
+    for rendition in renditions {
+        let bandwidth = rendition.bandwidth();
+        let mut stream_inf = format!(
+            "#EXT-X-STREAM-INF:BANDWIDTH={},AVERAGE-BANDWIDTH={},CODECS=\"{}\"",
+            (bandwidth as f64 * CONTAINER_OVERHEAD) as u64,
+            bandwidth,
+            rendition.codecs()
+        );
+        if let Some((width, height, _)) = rendition.video() {
+            stream_inf.push_str(&format!(",RESOLUTION={}x{}", width, height));
+        }
+        if let Some(frame_rate) = rendition.frame_rate.filter(|_| rendition.video().is_some()) {
+            stream_inf.push_str(&format!(",FRAME-RATE={:.3}", frame_rate));
+        }
+
+        playlist.push_str(&stream_inf);
+        playlist.push_str(&format!("\n{}/playlist.m3u8\n", rendition.name));
+    }
+
+    playlist
+}
+
+/// Adds the decoding, scaling and per-rendition encoding to an FFmpeg command reading the
+/// stream. Every rendition gets keyframes at the same times, so segments line up across the
+/// ladder. `hls_output` adds the HLS muxer options and output path for a rendition's directory.
+pub fn add_transcode_args(cmd: &mut Command, renditions: &[RenditionConfig], segment_duration: u32, stream_dir: &Path, hls_output: impl Fn(&mut Command, &Path)) {
+    let video_renditions = renditions.iter().filter(|rendition| rendition.video().is_some()).count();
+    if video_renditions > 0 {
+        // Decode once, then scale a copy for each video rendition
+        let mut filter = format!("[0:v]split={}", video_renditions);
+        for index in 0..video_renditions {
+            filter.push_str(&format!("[split{}]", index));
+        }
+        let scaled = renditions.iter().filter_map(RenditionConfig::video).enumerate();
+        for (index, (width, height, _)) in scaled {
+            filter.push_str(&format!(";[split{}]scale={}:{}[video{}]", index, width, height, index));
+        }
+        cmd.args(["-filter_complex", &filter]);
+    }
+
+    let mut video_index = 0;
+    for rendition in renditions {
+        match rendition.video() {
+            Some((_, height, bitrate)) => {
+                let (_, level) = rendition.h264_level(height);
+                cmd.args(["-map", &format!("[video{}]", video_index)]);
+                cmd.args(["-c:v", "libx264", "-preset", "veryfast", "-profile:v", "high", "-level:v", level]);
+                cmd.args(["-b:v", &format!("{}k", bitrate), "-maxrate", &format!("{}k", bitrate), "-bufsize", &format!("{}k", 2 * bitrate)]);
+                cmd.args(["-force_key_frames", &format!("expr:gte(t,n_forced*{})", segment_duration), "-sc_threshold", "0"]);
+                if let Some(frame_rate) = rendition.frame_rate {
+                    cmd.args(["-r", &frame_rate.to_string()]);
+                }
+                video_index += 1;
+            }
+            None => {
+                cmd.arg("-vn");
+            }
+        }
+        cmd.args(["-map", "0:a?", "-c:a", "aac", "-b:a", &format!("{}k", rendition.audio_bitrate)]);
+
+        hls_output(cmd, &stream_dir.join(&rendition.name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(name: &str, width: u32, height: u32, video_bitrate: u32, frame_rate: Option<f64>) -> RenditionConfig {
+        RenditionConfig {
+            name: name.to_string(),
+            width: Some(width),
+            height: Some(height),
+            video_bitrate: Some(video_bitrate),
+            frame_rate,
+            audio_bitrate: 128,
+        }
+    }
+
+    fn audio_only(name: &str, audio_bitrate: u32) -> RenditionConfig {
+        RenditionConfig { name: name.to_string(), width: None, height: None, video_bitrate: None, frame_rate: None, audio_bitrate }
+    }
+
+    fn args(cmd: &Command) -> Vec<String> {
+        cmd.as_std().get_args().map(|arg| arg.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn validates_ladders() {
+        assert!(validate(&[video("720p", 1280, 720, 3000, None), audio_only("audio", 96)]).is_ok());
+        assert!(validate(&[]).is_ok());
+
+        let error = validate(&[video("hd", 1280, 720, 3000, None), video("hd", 640, 360, 800, None)]).unwrap_err();
+        assert!(error.contains("defined twice"), "{}", error);
+        for name in ["", "..", "a/b", "low res"] {
+            assert!(validate(&[audio_only(name, 96)]).is_err(), "{:?} should be rejected", name);
+        }
+        let partial = RenditionConfig { video_bitrate: None, ..video("360p", 640, 360, 800, None) };
+        let error = validate(&[partial]).unwrap_err();
+        assert!(error.contains("needs all of width, height and video_bitrate"), "{}", error);
+    }
+
+    #[test]
+    fn picks_the_lowest_fitting_h264_level() {
+        let levels: Vec<_> = [(720, Some(30.0)), (720, Some(60.0)), (720, None), (1080, Some(25.0)), (1080, Some(50.0)), (1440, Some(30.0))]
+            .into_iter()
+            .map(|(height, frame_rate)| video("v", 1, height, 1, frame_rate).h264_level(height))
+            .collect();
+        assert_eq!(levels, [(31, "3.1"), (32, "3.2"), (32, "3.2"), (40, "4.0"), (42, "4.2"), (51, "5.1")]);
+
+        assert_eq!(video("v", 1920, 1080, 5000, Some(30.0)).codecs(), "avc1.640028,mp4a.40.2");
+        assert_eq!(video("v", 640, 360, 800, None).codecs(), "avc1.640020,mp4a.40.2");
+        assert_eq!(audio_only("a", 64).codecs(), "mp4a.40.2");
+    }
+
+    #[test]
+    fn lists_each_rendition_in_the_master_playlist() {
+        let renditions = [video("1080p", 1920, 1080, 5000, Some(29.97)), video("360p", 640, 360, 800, None), audio_only("audio", 64)];
+        assert_eq!(
+            master_playlist(&renditions),
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-INDEPENDENT-SEGMENTS\n\
+             #EXT-X-STREAM-INF:BANDWIDTH=5640800,AVERAGE-BANDWIDTH=5128000,CODECS=\"avc1.640028,mp4a.40.2\",RESOLUTION=1920x1080,FRAME-RATE=29.970\n\
+             1080p/playlist.m3u8\n\
+             #EXT-X-STREAM-INF:BANDWIDTH=1020800,AVERAGE-BANDWIDTH=928000,CODECS=\"avc1.640020,mp4a.40.2\",RESOLUTION=640x360\n\
+             360p/playlist.m3u8\n\
+             #EXT-X-STREAM-INF:BANDWIDTH=70400,AVERAGE-BANDWIDTH=64000,CODECS=\"mp4a.40.2\"\n\
+             audio/playlist.m3u8\n"
+        );
+    }
+
+    #[test]
+    fn scales_and_encodes_each_rendition() {
+        let renditions = [video("720p", 1280, 720, 3000, Some(30.0)), audio_only("audio", 64), video("360p", 640, 360, 800, None)];
+        let mut cmd = Command::new("ffmpeg");
+        add_transcode_args(&mut cmd, &renditions, 4, Path::new("/streams/live/test"), |cmd, dir| {
+            cmd.arg(dir.join("playlist.m3u8"));
+        });
+
+        let expected = [
+            "-filter_complex", "[0:v]split=2[split0][split1];[split0]scale=1280:720[video0];[split1]scale=640:360[video1]",
+            "-map", "[video0]", "-c:v", "libx264", "-preset", "veryfast", "-profile:v", "high", "-level:v", "3.1",
+            "-b:v", "3000k", "-maxrate", "3000k", "-bufsize", "6000k",
+            "-force_key_frames", "expr:gte(t,n_forced*4)", "-sc_threshold", "0", "-r", "30",
+            "-map", "0:a?", "-c:a", "aac", "-b:a", "128k", "/streams/live/test/720p/playlist.m3u8",
+            "-vn", "-map", "0:a?", "-c:a", "aac", "-b:a", "64k", "/streams/live/test/audio/playlist.m3u8",
+            "-map", "[video1]", "-c:v", "libx264", "-preset", "veryfast", "-profile:v", "high", "-level:v", "3.2",
+            "-b:v", "800k", "-maxrate", "800k", "-bufsize", "1600k",
+            "-force_key_frames", "expr:gte(t,n_forced*4)", "-sc_threshold", "0",
+            "-map", "0:a?", "-c:a", "aac", "-b:a", "128k", "/streams/live/test/360p/playlist.m3u8",
+        ];
+        assert_eq!(args(&cmd), expected);
+    }
+
+    #[test]
+    fn skips_the_filter_graph_for_audio_only_ladders() {
+        let mut cmd = Command::new("ffmpeg");
+        add_transcode_args(&mut cmd, &[audio_only("audio", 96)], 2, Path::new("out"), |cmd, dir| {
+            cmd.arg(dir);
+        });
+        assert_eq!(args(&cmd), ["-vn", "-map", "0:a?", "-c:a", "aac", "-b:a", "96k", "out/audio"]);
+    }
+}
@@ -0,0 +1,193 @@
+use std::{collections::HashMap, sync::Arc};
+use tokio::{
+    sync::Mutex,
+    time::{timeout, Duration},
+};
+use warp::{http::StatusCode, Filter, Reply};
+
+use super::playlist::PlaylistManager;
+use crate::application::{split_hls_path, Applications};
+use crate::stream_key::StreamKey;
+
+/// `GET <app>/<stream_key>/playlist.m3u8` under `/stream`, for streams written with LL-HLS parts.
+/// Supports blocking reloads (`_HLS_msn`, `_HLS_part`) and delta updates (`_HLS_skip`); other
+/// streams are left to the static files.
+pub fn playlist_route(applications: Applications) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path::tail())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(move |path: warp::path::Tail, query: HashMap<String, String>| {
+            let applications = applications.clone();
+            async move {
+                let playlist = path.as_str().strip_suffix("/playlist.m3u8").and_then(|path| live_playlist(&applications, path));
+                match playlist {
+                    Some(playlist) => Ok(serve_playlist(playlist, query).await),
+                    None => Err(warp::reject::not_found()),
+                }
+            }
+        })
+}
+
+// The playlist of `<app>/<stream_key>`, while it is being written
+fn live_playlist(applications: &Applications, path: &str) -> Option<Arc<Mutex<PlaylistManager>>> {
+    let (application, stream_key) = split_hls_path(applications, path)?;
+    application.hls_playlist(&StreamKey::new(stream_key).ok()?)
+}
+
+async fn serve_playlist(playlist: Arc<Mutex<PlaylistManager>>, query: HashMap<String, String>) -> warp::reply::Response {
+    let msn = query.get("_HLS_msn").map(|msn| msn.parse::<u64>());
+    let part = query.get("_HLS_part").map(|part| part.parse::<usize>());
+    let (msn, part) = match (msn, part) {
+        (Some(Err(_)), _) | (_, Some(Err(_))) | (None, Some(_)) => {
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+        (msn, part) => (msn.and_then(Result::ok), part.and_then(Result::ok)),
+    };
+    let skip = query.get("_HLS_skip").is_some_and(|skip| skip == "YES");
+
+    // Held until the playlist has the requested segment or part, for up to three target
+    // durations
+    if let Some(msn) = msn {
+        let (mut position, target_duration) = {
+            let playlist = playlist.lock().await;
+            (playlist.subscribe(), playlist.target_duration())
+        };
+        if msn > position.borrow().partial_sequence + 2 {
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+
+        let wait = Duration::from_secs(3 * target_duration as u64);
+        if !matches!(timeout(wait, position.wait_for(|position| position.contains(msn, part))).await, Ok(Ok(_))) {
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+    }
+
+    let content = {
+        let playlist = playlist.lock().await;
+        if skip {
+            Ok(playlist.get_delta_content())
+        } else {
+            playlist.get_content().await
+        }
+    };
+    match content {
+        Ok(content) => warp::reply::with_header(content, "Content-Type", "application/vnd.apple.mpegurl").into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::hls::playlist::{Part, Segment};
+
+    fn streams_dir(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("streamx-low-latency-{}-{}", name, std::process::id()))
+    }
+
+    // One second segments, so that blocking requests give up after three seconds
+    async fn playlist(name: &str) -> Arc<Mutex<PlaylistManager>> {
+        let config = Config {
+            streams_dir: streams_dir(name),
+            segment_duration: 1,
+            playlist_size: 12,
+            hls_part_duration: 500,
+            ..Config::default()
+        };
+        let stream_key = StreamKey::new("test").unwrap();
+        std::fs::create_dir_all(config.stream_dir(&stream_key)).unwrap();
+        let mut playlist = PlaylistManager::new(config, stream_key).await.unwrap();
+        playlist.add_segment(segment(0)).await.unwrap();
+        Arc::new(Mutex::new(playlist))
+    }
+
+    fn segment(sequence: u64) -> Segment {
+        Segment {
+            filename: format!("segment_{:06}.ts", sequence),
+            duration: 1.0,
+            sequence,
+            discontinuity: false,
+            init: None,
+            parts: Vec::new(),
+            timestamp: 0,
+            size: 0,
+        }
+    }
+
+    async fn add_part(playlist: &Mutex<PlaylistManager>, sequence: u64, index: u32) {
+        let part = Part { filename: format!("segment_{:06}.part{}.ts", sequence, index), duration: 0.5, independent: index == 0 };
+        playlist.lock().await.add_part(&segment(sequence), part, None).await.unwrap();
+    }
+
+    async fn get(playlist: &Arc<Mutex<PlaylistManager>>, query: &str) -> (StatusCode, String) {
+        let query = query.split('&').filter_map(|pair| pair.split_once('=')).map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let response = serve_playlist(playlist.clone(), query).await;
+        let status = response.status();
+        let body = warp::hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_blocking_requests() {
+        let playlist = playlist("bad-requests").await;
+        for query in ["_HLS_msn=next", "_HLS_msn=1&_HLS_part=-1", "_HLS_part=0", "_HLS_msn=4"] {
+            assert_eq!(get(&playlist, query).await.0, StatusCode::BAD_REQUEST, "{}", query);
+        }
+        let _ = std::fs::remove_dir_all(streams_dir("bad-requests"));
+    }
+
+    #[tokio::test]
+    async fn serves_available_segments_right_away() {
+        let playlist = playlist("available").await;
+        let (status, body) = get(&playlist, "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.ends_with("#EXTINF:1.000,\nsegment_000000.ts\n"));
+        assert_eq!(get(&playlist, "_HLS_msn=0").await, (StatusCode::OK, body));
+        let _ = std::fs::remove_dir_all(streams_dir("available"));
+    }
+
+    #[tokio::test]
+    async fn blocks_until_the_requested_part_is_written() {
+        let playlist = playlist("blocking").await;
+        let request = tokio::spawn({
+            let playlist = playlist.clone();
+            async move { get(&playlist, "_HLS_msn=1&_HLS_part=1").await }
+        });
+
+        add_part(&playlist, 1, 0).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!request.is_finished());
+        add_part(&playlist, 1, 1).await;
+
+        let (status, body) = timeout(Duration::from_secs(1), request).await.unwrap().unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.ends_with("#EXT-X-PART:DURATION=0.500,URI=\"segment_000001.part1.ts\"\n"));
+        let _ = std::fs::remove_dir_all(streams_dir("blocking"));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_three_target_durations() {
+        let playlist = playlist("timeout").await;
+        let started = tokio::time::Instant::now();
+        assert_eq!(get(&playlist, "_HLS_msn=2").await.0, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(started.elapsed() >= Duration::from_secs(3));
+        let _ = std::fs::remove_dir_all(streams_dir("timeout"));
+    }
+
+    #[tokio::test]
+    async fn serves_delta_updates_on_request() {
+        let playlist = playlist("delta").await;
+        for sequence in 1..12 {
+            playlist.lock().await.add_segment(segment(sequence)).await.unwrap();
+        }
+        let (_, full) = get(&playlist, "").await;
+        let (status, delta) = get(&playlist, "_HLS_skip=YES").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!full.contains("#EXT-X-SKIP"));
+        // Segments ending more than six target durations before the end are skipped
+        assert!(delta.contains("#EXT-X-SKIP:SKIPPED-SEGMENTS=5\n#EXTINF:1.000,\nsegment_000005.ts\n"));
+        assert!(full.contains("#EXT-X-MEDIA-SEQUENCE:0\n#EXTINF:1.000,\nsegment_000000.ts\n"));
+        let _ = std::fs::remove_dir_all(streams_dir("delta"));
+    }
+}
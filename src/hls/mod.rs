@@ -1,32 +1,246 @@
-use crate::{config::Config, error::{Result, StreamError}};
-use bytes::Bytes;
+use crate::{
+    config::Config,
+    error::{Result, StreamError},
+    flv,
+    media::{MediaKind, MediaPacket},
+    registry::{LiveStream, StreamHeaders, Subscription},
+    relay::Backoff,
+    stream_key::{validate_path_component, StreamKey},
+};
+use serde::{Deserialize, Serialize};
 use std::{
-    path::PathBuf,
+    collections::HashMap,
+    path::{Path, PathBuf},
     process::Stdio,
     sync::Arc,
 };
 use tokio::{
+    fs,
     io::{AsyncWriteExt, BufWriter},
-    process::{Child, Command},
-    sync::{mpsc, Mutex},
-    time::{interval, Duration},
+    process::{ChildStdin, Command},
+    sync::{broadcast::error::RecvError, mpsc::{self, error::TrySendError}, Mutex},
+    time::{interval, sleep, timeout, Duration, Instant},
 };
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info, info_span, warn};
 
-mod playlist;
+pub mod ffmpeg;
+pub mod fmp4;
+pub mod ladder;
+pub mod low_latency;
+pub mod playlist;
+pub mod segmenter;
+pub mod ts;
 
+use ffmpeg::{FfmpegProcess, FfmpegProgress};
 use playlist::PlaylistManager;
+use segmenter::Segmenter;
+
+// How long FFmpeg gets to finish its last segment before it is killed
+pub(crate) const FFMPEG_EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+// An FFmpeg that ran this long before exiting counts as working again, so its restarts start
+// over from the shortest delay
+const FFMPEG_STABLE_RUN: Duration = Duration::from_secs(60);
+// A write FFmpeg doesn't take within this means it stopped reading its input
+const FFMPEG_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+// Packets queued for a processor. Once this many are waiting, video is dropped until the
+// next keyframe; once the queue is full, the processor is disconnected.
+const HLS_INPUT_CAPACITY: usize = 1024;
+const HLS_INPUT_DROP_VIDEO: usize = 768;
+
+/// What the HLS processor is fed.
+pub enum HlsInput {
+    Media(MediaPacket),
+    // The stream switched to another source; the playlist continues after an
+    // #EXT-X-DISCONTINUITY
+    Discontinuity,
+    // The stream's publisher is gone
+    End,
+    // The processor fell too far behind the stream and is cut off from it
+    Disconnect,
+}
+
+/// How HLS segments are produced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HlsMuxer {
+    // An FFmpeg child, fed the stream as FLV
+    #[default]
+    Ffmpeg,
+    // The built-in muxers, which handle H.264 and AAC, and HEVC and AV1 in fMP4
+    Native,
+}
+
+/// The container HLS segments are written in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HlsSegmentFormat {
+    // `.ts` segments
+    #[default]
+    Mpegts,
+    // CMAF: an `init.mp4` and `.m4s` fragments, referenced with #EXT-X-MAP
+    Fmp4,
+}
+
+impl HlsSegmentFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            HlsSegmentFormat::Mpegts => "ts",
+            HlsSegmentFormat::Fmp4 => "m4s",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HlsState {
+    Running,
+    // FFmpeg exited and is waiting out its backoff delay
+    Restarting,
+    // Output stopped, after an error or too many FFmpeg restarts
+    Failed,
+}
+
+/// What a stream's HLS output is doing, as reported by the streams API.
+#[derive(Debug, Clone, Serialize)]
+pub struct HlsStatus {
+    pub state: HlsState,
+    pub last_error: Option<String>,
+    pub restarts: u32,
+    // Packets the processor fell too far behind to be given
+    pub dropped_packets: u64,
+    // FFmpeg's latest progress; None with the native muxer
+    pub ffmpeg: Option<FfmpegProgress>,
+}
+
+pub type SharedHlsStatus = Arc<std::sync::Mutex<HlsStatus>>;
+
+// Where a processor's packets go
+enum HlsWriter {
+    // FFmpeg's stdin; None while FFmpeg is stopped
+    Ffmpeg(Option<BufWriter<ChildStdin>>),
+    Native(Box<Segmenter>),
+}
+
+// Processors whose stream ended less than `reconnect_grace` ago, with their status, by
+// stream key
+type LingeringProcessors = Arc<std::sync::Mutex<HashMap<StreamKey, (mpsc::Sender<HlsInput>, SharedHlsStatus)>>>;
+
+// Playlists served from memory for LL-HLS blocking reloads, by stream key
+type LivePlaylists = Arc<std::sync::Mutex<HashMap<StreamKey, Arc<Mutex<PlaylistManager>>>>>;
+
+/// HLS output for one application's streams. With `reconnect_grace` set, a processor outlives
+/// its stream by that long, so a publisher reconnecting to the same key continues its playlist.
+#[derive(Default)]
+pub struct HlsOutputs {
+    lingering: LingeringProcessors,
+    playlists: LivePlaylists,
+}
+
+impl HlsOutputs {
+    /// Starts HLS output for a live stream.
+    pub async fn start(&self, stream: &LiveStream, config: Config) -> Result<()> {
+        let subscription = stream.subscribe()
+            .ok_or_else(|| StreamError::StreamNotFound(stream.key.to_string()))?;
+
+        {
+            let mut lingering = self.lingering.lock().unwrap();
+            if let Some((data_sender, status)) = lingering.remove(&stream.key) {
+                if data_sender.try_send(HlsInput::Discontinuity).is_ok() {
+                    info!("Continuing HLS playlist for reconnected stream: {}", stream.key);
+                    stream.set_hls_status(status.clone());
+                    tokio::spawn(forward_packets(subscription, data_sender, stream.key.clone(), status));
+                    return Ok(());
+                }
+            }
+        }
+
+        let low_latency = config.hls_part_duration > 0;
+        let processor = HlsProcessor::new(stream.key.clone(), config).await?;
+        let (data_sender, data_receiver) = mpsc::channel(HLS_INPUT_CAPACITY);
+        stream.set_hls_status(processor.status.clone());
+        if low_latency {
+            self.playlists.lock().unwrap().insert(stream.key.clone(), processor.playlist_manager.clone());
+        }
+
+        let lingering = self.lingering.clone();
+        let playlists = self.playlists.clone();
+        let relink_sender = data_sender.clone();
+        let status = processor.status.clone();
+        tokio::spawn(async move {
+            if let Err(e) = processor.process_stream(data_receiver, relink_sender, lingering).await {
+                error!("HLS processing failed for {}: {}", processor.stream_key, e);
+                processor.fail(&e);
+            }
+
+            let mut playlists = playlists.lock().unwrap();
+            if playlists.get(&processor.stream_key).is_some_and(|playlist| Arc::ptr_eq(playlist, &processor.playlist_manager)) {
+                playlists.remove(&processor.stream_key);
+            }
+        });
+        tokio::spawn(forward_packets(subscription, data_sender, stream.key.clone(), status));
+
+        Ok(())
+    }
+
+    /// The LL-HLS playlist of a stream with HLS output in progress.
+    pub fn playlist(&self, stream_key: &StreamKey) -> Option<Arc<Mutex<PlaylistManager>>> {
+        self.playlists.lock().unwrap().get(stream_key).cloned()
+    }
+}
 
 #[derive(Clone)]
 pub struct HlsProcessor {
-    stream_key: String,
+    stream_key: StreamKey,
     config: Config,
     playlist_manager: Arc<Mutex<PlaylistManager>>,
-    ffmpeg_process: Arc<Mutex<Option<Child>>>,
+    ffmpeg_process: Arc<Mutex<Option<FfmpegProcess>>>,
+    status: SharedHlsStatus,
+}
+
+// How an FFmpeg restart turned out
+enum Restart {
+    Done,
+    // Called off for an input that has to be handled first; None if the input channel closed
+    Interrupted(Option<HlsInput>),
+}
+
+// Restarts FFmpeg when it exits on its own, with the stream's headers replayed to it
+struct FfmpegSupervisor {
+    backoff: Backoff,
+    // Restarts since FFmpeg last ran for `FFMPEG_STABLE_RUN`
+    attempts: u32,
+    started: Instant,
+    headers: StreamHeaders,
+    // Video is held back after a restart until FFmpeg can decode it
+    awaiting_keyframe: bool,
+}
+
+impl FfmpegSupervisor {
+    fn new() -> Self {
+        Self {
+            backoff: Backoff::new(),
+            attempts: 0,
+            started: Instant::now(),
+            headers: StreamHeaders::default(),
+            awaiting_keyframe: false,
+        }
+    }
+
+    // Whether a packet is passed on to FFmpeg
+    fn admit(&mut self, packet: &MediaPacket) -> bool {
+        self.headers.update(packet);
+        if self.awaiting_keyframe && packet.kind == MediaKind::Video && !packet.is_sequence_header() {
+            if !packet.is_keyframe() {
+                return false;
+            }
+            self.awaiting_keyframe = false;
+        }
+        true
+    }
 }
 
 impl HlsProcessor {
-    pub async fn new(stream_key: String, config: Config) -> Result<Self> {
+    pub async fn new(stream_key: StreamKey, config: Config) -> Result<Self> {
         let playlist_manager = PlaylistManager::new(config.clone(), stream_key.clone()).await?;
 
         Ok(Self {
@@ -34,76 +248,359 @@ impl HlsProcessor {
             config,
             playlist_manager: Arc::new(Mutex::new(playlist_manager)),
             ffmpeg_process: Arc::new(Mutex::new(None)),
+            status: Arc::new(std::sync::Mutex::new(HlsStatus {
+                state: HlsState::Running,
+                last_error: None,
+                restarts: 0,
+                dropped_packets: 0,
+                ffmpeg: None,
+            })),
         })
     }
 
-    pub async fn process_stream(&self, mut data_receiver: mpsc::UnboundedReceiver<Bytes>) -> Result<()> {
+    // `relink_sender` is parked in `lingering` while waiting for the publisher to come back
+    async fn process_stream(&self, mut data_receiver: mpsc::Receiver<HlsInput>, relink_sender: mpsc::Sender<HlsInput>, lingering: LingeringProcessors) -> Result<()> {
         info!("Starting HLS processing for stream: {}", self.stream_key);
 
-        // Start FFmpeg process for HLS segmentation
-        let ffmpeg_child = self.start_ffmpeg_process().await?;
-        
-        // Store the FFmpeg process
-        *self.ffmpeg_process.lock().await = Some(ffmpeg_child);
+        fs::create_dir_all(self.config.stream_dir(&self.stream_key)).await?;
 
-        // Get stdin handle for writing stream data
-        let stdin = self.ffmpeg_process.lock().await
-            .as_mut()
-            .and_then(|child| child.stdin.take())
-            .ok_or_else(|| StreamError::Ffmpeg("Failed to get FFmpeg stdin".to_string()))?;
+        let mut writer = match self.config.hls_muxer {
+            HlsMuxer::Ffmpeg => HlsWriter::Ffmpeg(Some(self.launch_ffmpeg(false).await?)),
+            HlsMuxer::Native => HlsWriter::Native(Box::new(Segmenter::new(&self.config, &self.stream_key, self.playlist_manager.clone()))),
+        };
+        let mut supervisor = FfmpegSupervisor::new();
+        let mut waiting_for_publisher = false;
+        let reconnect_grace = Duration::from_secs(self.config.reconnect_grace);
 
-        let mut stdin_writer = BufWriter::new(stdin);
-
-        // Start playlist update task
-        let playlist_updater = self.clone();
-        let playlist_task = tokio::spawn(async move {
-            playlist_updater.playlist_update_loop().await;
+        // FFmpeg's playlist has to be polled; the native segmenter hands segments over itself
+        let playlist_task = matches!(writer, HlsWriter::Ffmpeg(_)).then(|| {
+            let playlist_updater = self.clone();
+            tokio::spawn(async move {
+                playlist_updater.playlist_update_loop().await;
+            })
         });
 
+        // Input taken out of the channel while restarting FFmpeg
+        let mut pending = None;
+
         // Process incoming stream data
-        while let Some(data) = data_receiver.recv().await {
-            if let Err(e) = stdin_writer.write_all(&data).await {
-                error!("Failed to write data to FFmpeg: {}", e);
-                break;
-            }
-            
-            if let Err(e) = stdin_writer.flush().await {
-                error!("Failed to flush data to FFmpeg: {}", e);
+        loop {
+            let input = if let Some(input) = pending.take() {
+                input
+            } else if !waiting_for_publisher {
+                data_receiver.recv().await
+            } else {
+                match timeout(reconnect_grace, data_receiver.recv()).await {
+                    Ok(input) => input,
+                    Err(_) => {
+                        let mut lingering = lingering.lock().unwrap();
+                        if lingering.get(&self.stream_key).is_some_and(|(sender, _)| sender.same_channel(&relink_sender)) {
+                            lingering.remove(&self.stream_key);
+                            info!("Publisher did not return in time for stream: {}", self.stream_key);
+                            break;
+                        }
+                        // A reconnecting publisher took it, and its Discontinuity is queued
+                        continue;
+                    }
+                }
+            };
+            let Some(input) = input else {
                 break;
+            };
+
+            match input {
+                HlsInput::Media(packet) => {
+                    if waiting_for_publisher || !supervisor.admit(&packet) {
+                        continue;
+                    }
+                    // Writes are flushed once nothing else is queued
+                    if let Err(e) = self.write(&mut writer, &packet, data_receiver.is_empty()).await {
+                        // A write only fails on FFmpeg's stdin once FFmpeg has gone away or
+                        // stopped reading
+                        let e = match &mut writer {
+                            HlsWriter::Ffmpeg(stdin_writer) => match self.recover_ffmpeg(stdin_writer, &mut supervisor, &mut data_receiver, e).await {
+                                Ok(Restart::Done) => continue,
+                                Ok(Restart::Interrupted(input)) => {
+                                    pending = Some(input);
+                                    continue;
+                                }
+                                Err(e) => e,
+                            },
+                            HlsWriter::Native(_) => e,
+                        };
+                        error!("Failed to write HLS output for {}: {}", self.stream_key, e);
+                        self.fail(&e);
+                        break;
+                    }
+                }
+                HlsInput::Discontinuity => {
+                    info!("Continuing HLS playlist after a discontinuity for stream: {}", self.stream_key);
+                    // The new source sends its own headers
+                    supervisor.headers = StreamHeaders::default();
+                    supervisor.awaiting_keyframe = false;
+                    waiting_for_publisher = false;
+                    if let Err(e) = self.restart(&mut writer).await {
+                        error!("Failed to continue HLS output for {}: {}", self.stream_key, e);
+                        self.fail(&e);
+                        break;
+                    }
+                    self.update_status(|status| status.state = HlsState::Running);
+                }
+                HlsInput::End => {
+                    if reconnect_grace.is_zero() {
+                        break;
+                    }
+                    info!("Keeping HLS playlist for {} open {}s for the publisher to reconnect", self.stream_key, self.config.reconnect_grace);
+                    self.pause(&mut writer).await;
+                    waiting_for_publisher = true;
+                    lingering.lock().unwrap().insert(self.stream_key.clone(), (relink_sender.clone(), self.status.clone()));
+                }
+                HlsInput::Disconnect => break,
             }
         }
 
         info!("Stream ended for: {}", self.stream_key);
 
         // Clean up
-        if let Some(mut child) = self.ffmpeg_process.lock().await.take() {
-            let _ = child.kill().await;
+        if let Some(mut process) = self.ffmpeg_process.lock().await.take() {
+            let _ = process.child.kill().await;
+        }
+        if !waiting_for_publisher {
+            self.pause(&mut writer).await;
+        }
+
+        if let Some(playlist_task) = playlist_task {
+            playlist_task.abort();
         }
+        Ok(())
+    }
 
-        playlist_task.abort();
+    async fn write(&self, writer: &mut HlsWriter, packet: &MediaPacket, flush: bool) -> Result<()> {
+        match writer {
+            HlsWriter::Ffmpeg(Some(stdin_writer)) => {
+                let write = async {
+                    stdin_writer.write_all(&flv::encode_tag(packet)).await?;
+                    if flush {
+                        stdin_writer.flush().await?;
+                    }
+                    Ok::<_, std::io::Error>(())
+                };
+                timeout(FFMPEG_WRITE_TIMEOUT, write)
+                    .await
+                    .map_err(|_| StreamError::Ffmpeg("stopped reading its input".to_string()))??;
+            }
+            HlsWriter::Ffmpeg(None) => {}
+            HlsWriter::Native(segmenter) => segmenter.write(packet).await?,
+        }
         Ok(())
     }
 
-    async fn start_ffmpeg_process(&self) -> Result<Child> {
+    // Restarts FFmpeg after it exited on its own, once its backoff delay has passed. The
+    // playlist continues after a discontinuity, and the new FFmpeg is given the stream's
+    // headers so it can decode what follows. Fails once FFmpeg has been restarted
+    // `ffmpeg_max_restarts` times without running for `FFMPEG_STABLE_RUN`. Media arriving
+    // during the delay is dropped; any other input calls the restart off.
+    async fn recover_ffmpeg(&self, stdin_writer: &mut Option<BufWriter<ChildStdin>>, supervisor: &mut FfmpegSupervisor, data_receiver: &mut mpsc::Receiver<HlsInput>, cause: StreamError) -> Result<Restart> {
+        stdin_writer.take();
+        let mut last_error = self.reap_ffmpeg().await.unwrap_or(cause);
+        if supervisor.started.elapsed() >= FFMPEG_STABLE_RUN {
+            supervisor.backoff.reset();
+            supervisor.attempts = 0;
+        }
+
+        loop {
+            if supervisor.attempts >= self.config.ffmpeg_max_restarts {
+                warn!("Giving up on FFmpeg for {} after {} restarts", self.stream_key, supervisor.attempts);
+                return Err(last_error);
+            }
+
+            let delay = supervisor.backoff.next_delay();
+            warn!("FFmpeg for {} stopped ({}), restarting in {}s", self.stream_key, last_error, delay.as_secs());
+            self.update_status(|status| {
+                status.state = HlsState::Restarting;
+                status.last_error = Some(last_error.to_string());
+            });
+            let backoff = sleep(delay);
+            tokio::pin!(backoff);
+            loop {
+                tokio::select! {
+                    _ = &mut backoff => break,
+                    input = data_receiver.recv() => match input {
+                        Some(HlsInput::Media(packet)) => supervisor.headers.update(&packet),
+                        input => return Ok(Restart::Interrupted(input)),
+                    },
+                }
+            }
+            supervisor.attempts += 1;
+            self.update_status(|status| status.restarts += 1);
+
+            match self.relaunch_ffmpeg(&supervisor.headers).await {
+                Ok(new_writer) => {
+                    *stdin_writer = Some(new_writer);
+                    supervisor.started = Instant::now();
+                    supervisor.awaiting_keyframe = true;
+                    self.update_status(|status| status.state = HlsState::Running);
+                    info!("FFmpeg restarted for stream: {}", self.stream_key);
+                    return Ok(Restart::Done);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+    }
+
+    // Starts FFmpeg on the existing playlist and replays the stream's headers to it
+    async fn relaunch_ffmpeg(&self, headers: &StreamHeaders) -> Result<BufWriter<ChildStdin>> {
+        let mut stdin_writer = self.launch_ffmpeg(true).await?;
+        for packet in headers.packets() {
+            stdin_writer.write_all(&flv::encode_tag(&packet)).await?;
+        }
+        stdin_writer.flush().await?;
+        Ok(stdin_writer)
+    }
+
+    // Collects an FFmpeg whose stdin broke, returning why it exited
+    async fn reap_ffmpeg(&self) -> Option<StreamError> {
+        let mut process = self.ffmpeg_process.lock().await.take()?;
+        match timeout(FFMPEG_EXIT_TIMEOUT, process.child.wait()).await {
+            Ok(Ok(status)) => Some(process.exit_error(status).await),
+            Ok(Err(e)) => Some(StreamError::Ffmpeg(format!("FFmpeg status error: {}", e))),
+            Err(_) => {
+                let _ = process.child.kill().await;
+                None
+            }
+        }
+    }
+
+    fn update_status(&self, update: impl FnOnce(&mut HlsStatus)) {
+        update(&mut self.status.lock().unwrap());
+    }
+
+    fn fail(&self, error: &StreamError) {
+        self.update_status(|status| {
+            status.state = HlsState::Failed;
+            status.last_error = Some(error.to_string());
+        });
+    }
+
+    // Picks the playlist up again after a discontinuity, restarting FFmpeg if it is used
+    async fn restart(&self, writer: &mut HlsWriter) -> Result<()> {
+        match writer {
+            HlsWriter::Ffmpeg(stdin_writer) => {
+                if let Some(stdin_writer) = stdin_writer.take() {
+                    self.stop_ffmpeg(stdin_writer).await;
+                }
+                *stdin_writer = Some(self.launch_ffmpeg(true).await?);
+            }
+            HlsWriter::Native(segmenter) => segmenter.discontinuity().await?,
+        }
+        Ok(())
+    }
+
+    // Writes out everything received so far, stopping FFmpeg if it is used
+    async fn pause(&self, writer: &mut HlsWriter) {
+        match writer {
+            HlsWriter::Ffmpeg(stdin_writer) => {
+                if let Some(stdin_writer) = stdin_writer.take() {
+                    self.stop_ffmpeg(stdin_writer).await;
+                }
+            }
+            HlsWriter::Native(segmenter) => {
+                if let Err(e) = segmenter.finish().await {
+                    warn!("Failed to write the last segment for {}: {}", self.stream_key, e);
+                }
+            }
+        }
+    }
+
+    // Starts FFmpeg and returns a writer for its stdin, after the FLV header
+    async fn launch_ffmpeg(&self, continue_playlist: bool) -> Result<BufWriter<ChildStdin>> {
+        let mut ffmpeg_process = self.start_ffmpeg_process(continue_playlist).await?;
+
+        let stdin = ffmpeg_process.child.stdin.take()
+            .ok_or_else(|| StreamError::Ffmpeg("Failed to get FFmpeg stdin".to_string()))?;
+        *self.ffmpeg_process.lock().await = Some(ffmpeg_process);
+
+        let mut stdin_writer = BufWriter::new(stdin);
+        stdin_writer.write_all(&flv::encode_header(true, true)).await?;
+        Ok(stdin_writer)
+    }
+
+    // Closes FFmpeg's stdin and lets it write out its last segment
+    async fn stop_ffmpeg(&self, mut stdin_writer: BufWriter<ChildStdin>) {
+        let _ = stdin_writer.flush().await;
+        drop(stdin_writer);
+
+        let Some(mut process) = self.ffmpeg_process.lock().await.take() else {
+            return;
+        };
+
+        if timeout(FFMPEG_EXIT_TIMEOUT, process.child.wait()).await.is_err() {
+            warn!("FFmpeg did not exit in time for stream: {}", self.stream_key);
+            let _ = process.child.kill().await;
+        }
+    }
+
+    async fn start_ffmpeg_process(&self, continue_playlist: bool) -> Result<FfmpegProcess> {
         let stream_dir = self.config.stream_dir(&self.stream_key);
-        let segment_pattern = stream_dir.join("segment_%03d.ts");
-        let playlist_path = stream_dir.join("playlist.m3u8");
-
-        let mut cmd = Command::new("ffmpeg");
-        cmd.args([
-            "-f", "flv",                           // Input format (FLV from RTMP)
-            "-i", "pipe:0",                        // Input from stdin
-            "-c", "copy",                          // Copy codecs without re-encoding
-            "-f", "hls",                           // Output format HLS
-            "-hls_time", &self.config.segment_duration.to_string(), // Segment duration
-            "-hls_list_size", &self.config.playlist_size.to_string(), // Playlist size
-            "-hls_flags", "delete_segments",       // Delete old segments
-            "-hls_segment_filename", segment_pattern.to_str().unwrap(),
-        ])
-        .arg(playlist_path.to_str().unwrap())
-        .stdin(Stdio::piped())
-        .stdout(Stdio::null())
-        .stderr(Stdio::piped());
+        // Paths are passed to FFmpeg as arguments, and to templates as text
+        let Some(stream_dir_arg) = stream_dir.to_str() else {
+            return Err(StreamError::Ffmpeg(format!("Stream directory is not valid UTF-8: {}", stream_dir.display())));
+        };
+        let segment_format = self.config.hls_segment_format;
+        // append_list picks up the segments and numbering of the existing playlist. FFmpeg may
+        // be restarted on the same playlist, so it must never mark it as ended.
+        let hls_flags = if continue_playlist {
+            "delete_segments+omit_endlist+append_list+discont_start"
+        } else {
+            "delete_segments+omit_endlist"
+        };
+
+        // The HLS muxer options and playlist path for an output written to `dir`
+        let hls_output = |cmd: &mut Command, dir: &Path| {
+            let segment_pattern = dir.join(format!("segment_%06d.{}", segment_format.extension()));
+            cmd.args([
+                "-f", "hls",                           // Output format HLS
+                "-hls_time", &self.config.segment_duration.to_string(), // Segment duration
+                "-hls_list_size", &self.config.playlist_size.to_string(), // Playlist size
+                "-hls_flags", hls_flags,               // Delete old segments
+                "-hls_segment_filename", &segment_pattern.to_string_lossy(),
+            ]);
+            if segment_format == HlsSegmentFormat::Fmp4 {
+                cmd.args(["-hls_segment_type", "fmp4", "-hls_fmp4_init_filename", "init.mp4"]);
+            }
+            cmd.arg(dir.join("playlist.m3u8"));
+        };
+
+        let mut cmd = Command::new(&self.config.ffmpeg_path);
+        cmd.args(ffmpeg::LOG_ARGS);
+        if let Some(template) = &self.config.ffmpeg_args {
+            cmd.args(ffmpeg::render_args(template, &[
+                ("input", "pipe:0"),
+                ("stream_dir", stream_dir_arg),
+                ("stream_key", self.stream_key.as_str()),
+                ("segment_duration", &self.config.segment_duration.to_string()),
+                ("playlist_size", &self.config.playlist_size.to_string()),
+                ("hls_flags", hls_flags),
+            ]));
+        } else {
+            cmd.args([
+                "-f", "flv",                           // Input format (FLV from RTMP)
+                "-i", "pipe:0",                        // Input from stdin
+            ]);
+            if self.config.renditions.is_empty() {
+                cmd.args(["-c", "copy"]);              // Copy codecs without re-encoding
+                hls_output(&mut cmd, &stream_dir);
+            } else {
+                for rendition in &self.config.renditions {
+                    fs::create_dir_all(stream_dir.join(&rendition.name)).await?;
+                }
+                write_atomically(&stream_dir.join("index.m3u8"), ladder::master_playlist(&self.config.renditions).as_bytes()).await?;
+                ladder::add_transcode_args(&mut cmd, &self.config.renditions, self.config.segment_duration, &stream_dir, hls_output);
+            }
+        }
+        cmd.stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
 
         debug!("Starting FFmpeg with command: {:?}", cmd);
 
@@ -111,7 +608,8 @@ impl HlsProcessor {
             .map_err(|e| StreamError::Ffmpeg(format!("Failed to start FFmpeg: {}", e)))?;
 
         info!("FFmpeg process started for stream: {}", self.stream_key);
-        Ok(child)
+        let span = info_span!("ffmpeg", stream = %self.stream_key);
+        Ok(FfmpegProcess::new(child, span, Some(self.status.clone())))
     }
 
     async fn playlist_update_loop(&self) {
@@ -128,10 +626,11 @@ impl HlsProcessor {
 
     async fn update_playlist(&self) -> Result<()> {
         // Check if FFmpeg is still running
-        if let Some(child) = self.ffmpeg_process.lock().await.as_mut() {
-            match child.try_wait() {
+        if let Some(process) = self.ffmpeg_process.lock().await.as_mut() {
+            match process.child.try_wait() {
                 Ok(Some(status)) => {
-                    info!("FFmpeg process exited with status: {}", status);
+                    // Restarted by the processor on its next write
+                    debug!("FFmpeg process exited with status: {}", status);
                     return Ok(());
                 }
                 Ok(None) => {
@@ -155,6 +654,11 @@ impl HlsProcessor {
     }
 
     pub async fn get_segment_path(&self, segment_name: &str) -> Result<PathBuf> {
+        // Segment names come from HTTP requests, so keep them inside the stream directory
+        if validate_path_component(segment_name, 255).is_err() {
+            return Err(StreamError::StreamNotFound(format!("Segment not found: {}", segment_name)));
+        }
+
         let stream_dir = self.config.stream_dir(&self.stream_key);
         let segment_path = stream_dir.join(segment_name);
 
@@ -164,4 +668,474 @@ impl HlsProcessor {
             Err(StreamError::StreamNotFound(format!("Segment not found: {}", segment_name)))
         }
     }
-} 
\ No newline at end of file
+}
+
+// Writes to a temporary file that is then renamed over `path`, so readers never see a
+// partially written file
+async fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+
+    fs::write(&temp_path, data).await?;
+    fs::rename(&temp_path, path).await?;
+    Ok(())
+}
+
+// Feeds a stream subscription to the HLS processor, ending when the publisher stops. The
+// publisher is never held up: a processor falling behind first loses video up to the next
+// keyframe, then is disconnected.
+async fn forward_packets(mut subscription: Subscription, data_sender: mpsc::Sender<HlsInput>, stream_key: StreamKey, status: SharedHlsStatus) {
+    for packet in subscription.headers.drain(..) {
+        if data_sender.send(HlsInput::Media(packet)).await.is_err() {
+            return;
+        }
+    }
+
+    let count_dropped = |dropped: u64| status.lock().unwrap().dropped_packets += dropped;
+    let mut dropping_video = false;
+    loop {
+        let packet = match subscription.receiver.recv().await {
+            Ok(packet) => packet,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("HLS input for {} fell behind, skipped {} packets", stream_key, skipped);
+                count_dropped(skipped);
+                dropping_video = true;
+                continue;
+            }
+            Err(RecvError::Closed) => {
+                let _ = data_sender.send(HlsInput::End).await;
+                return;
+            }
+        };
+
+        if packet.kind == MediaKind::Video && !packet.is_sequence_header() {
+            let queued = HLS_INPUT_CAPACITY - data_sender.capacity();
+            if queued >= HLS_INPUT_DROP_VIDEO {
+                if !dropping_video {
+                    warn!("HLS output for {} is falling behind, dropping video until the next keyframe", stream_key);
+                }
+                dropping_video = true;
+            } else if packet.is_keyframe() {
+                dropping_video = false;
+            }
+            if dropping_video {
+                count_dropped(1);
+                continue;
+            }
+        }
+
+        let discontinuity = packet.discontinuity.then_some(HlsInput::Discontinuity);
+        for input in discontinuity.into_iter().chain([HlsInput::Media(packet)]) {
+            match data_sender.try_send(input) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    error!("HLS output for {} fell too far behind the stream, disconnecting it", stream_key);
+                    {
+                        let mut status = status.lock().unwrap();
+                        status.state = HlsState::Failed;
+                        status.last_error = Some("fell too far behind the stream".to_string());
+                        status.dropped_packets += 1;
+                    }
+                    // Stops receiving the stream, then waits for the processor to get to the end
+                    // of its queue
+                    drop(subscription);
+                    let _ = data_sender.send(HlsInput::Disconnect).await;
+                    return;
+                }
+                Err(TrySendError::Closed(_)) => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::{PublisherRole, StreamRegistry};
+    use bytes::Bytes;
+    use std::os::unix::fs::PermissionsExt;
+
+    // AVC, with an SPS and PPS and 4-byte NAL lengths
+    const SEQUENCE_HEADER: &[u8] = &[
+        0x17, 0x00, 0, 0, 0, 0x01, 0x64, 0x00, 0x1F, 0xFF, 0xE1, 0x00, 0x04, 0x67, 0x64, 0x00, 0x1F, 0x01, 0x00, 0x02, 0x68, 0xEE,
+    ];
+    const KEYFRAME: &[u8] = &[0x17, 0x01, 0, 0, 0, 0, 0, 0, 1, 0x65];
+    const INTER_FRAME: &[u8] = &[0x27, 0x01, 0, 0, 0, 0, 0, 0, 1, 0x41];
+    const AUDIO_FRAME: &[u8] = &[0xAF, 0x01, 0x21, 0x00];
+
+    // Stands in for FFmpeg: saves its input, then exits with an error once its stdin closes
+    const STUB_FFMPEG: &str = "#!/bin/sh\ncat > \"$(dirname \"$0\")/input.flv\"\necho \"[error] Conversion failed: input ended\" >&2\nexit 1\n";
+
+    fn video(payload: &'static [u8]) -> MediaPacket {
+        MediaPacket::new(MediaKind::Video, 0, Bytes::from_static(payload))
+    }
+
+    fn audio() -> MediaPacket {
+        MediaPacket::new(MediaKind::Audio, 0, Bytes::from_static(AUDIO_FRAME))
+    }
+
+    struct StubFfmpeg {
+        dir: PathBuf,
+    }
+
+    impl StubFfmpeg {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("streamx-ffmpeg-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            let script = dir.join("ffmpeg");
+            std::fs::write(&script, STUB_FFMPEG).unwrap();
+            std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+            Self { dir }
+        }
+
+        async fn processor(&self, ffmpeg_max_restarts: u32) -> HlsProcessor {
+            let config = Config {
+                streams_dir: self.dir.join("streams"),
+                ffmpeg_path: self.dir.join("ffmpeg"),
+                ffmpeg_max_restarts,
+                ..Config::default()
+            };
+            HlsProcessor::new(StreamKey::new("test").unwrap(), config).await.unwrap()
+        }
+    }
+
+    impl Drop for StubFfmpeg {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn status(processor: &HlsProcessor) -> (HlsState, Option<String>, u32) {
+        let status = processor.status.lock().unwrap();
+        (status.state, status.last_error.clone(), status.restarts)
+    }
+
+    fn stopped() -> StreamError {
+        StreamError::Ffmpeg("stopped reading its input".to_string())
+    }
+
+    #[tokio::test]
+    async fn restarts_ffmpeg_with_the_stream_headers_until_it_gives_up() {
+        let stub = StubFfmpeg::new("restarts");
+        let processor = stub.processor(1).await;
+        let mut supervisor = FfmpegSupervisor::new();
+        assert!(supervisor.admit(&video(SEQUENCE_HEADER)));
+        let (_sender, mut receiver) = mpsc::channel(8);
+        let mut stdin_writer = None;
+
+        let restart = processor.recover_ffmpeg(&mut stdin_writer, &mut supervisor, &mut receiver, stopped()).await.unwrap();
+        assert!(matches!(restart, Restart::Done));
+        assert!(stdin_writer.is_some());
+        assert_eq!(status(&processor), (HlsState::Running, Some("FFmpeg error: stopped reading its input".to_string()), 1));
+
+        // Video is held back until the new FFmpeg can decode it
+        assert!(!supervisor.admit(&video(INTER_FRAME)));
+        assert!(supervisor.admit(&video(KEYFRAME)));
+        assert!(supervisor.admit(&video(INTER_FRAME)));
+
+        // The new FFmpeg exits as its input is closed, and has used up its restarts
+        let Err(error) = processor.recover_ffmpeg(&mut stdin_writer, &mut supervisor, &mut receiver, stopped()).await else {
+            panic!("FFmpeg was restarted past ffmpeg_max_restarts");
+        };
+        assert_eq!(error.to_string(), "FFmpeg error: Conversion failed: input ended");
+        assert!(processor.ffmpeg_process.lock().await.is_none());
+
+        // It was given the stream's headers after the FLV header
+        let mut replayed = flv::encode_header(true, true).to_vec();
+        replayed.extend_from_slice(&flv::encode_tag(&video(SEQUENCE_HEADER)));
+        assert_eq!(std::fs::read(stub.dir.join("input.flv")).unwrap(), replayed);
+    }
+
+    #[tokio::test]
+    async fn starts_restarts_over_after_a_stable_run() {
+        let stub = StubFfmpeg::new("stable-run");
+        let processor = stub.processor(1).await;
+        let mut supervisor = FfmpegSupervisor::new();
+        supervisor.attempts = 1;
+        for _ in 0..3 {
+            supervisor.backoff.next_delay();
+        }
+        supervisor.started = Instant::now().checked_sub(FFMPEG_STABLE_RUN).unwrap();
+        let (_sender, mut receiver) = mpsc::channel(8);
+
+        // Back to the shortest delay, and a full set of restarts
+        let started = Instant::now();
+        let restart = processor.recover_ffmpeg(&mut None, &mut supervisor, &mut receiver, stopped()).await.unwrap();
+        assert!(matches!(restart, Restart::Done));
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(supervisor.attempts, 1);
+
+        let process = processor.ffmpeg_process.lock().await.take();
+        if let Some(mut process) = process {
+            let _ = process.child.kill().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn calls_restarts_off_for_other_input() {
+        let stub = StubFfmpeg::new("interrupted");
+        let processor = stub.processor(0).await;
+        let (sender, mut receiver) = mpsc::channel(8);
+        let Err(error) = processor.recover_ffmpeg(&mut None, &mut FfmpegSupervisor::new(), &mut receiver, stopped()).await else {
+            panic!("FFmpeg was restarted with ffmpeg_max_restarts at 0");
+        };
+        assert_eq!(error.to_string(), stopped().to_string());
+
+        // Media arriving during the delay only updates the headers
+        let processor = stub.processor(2).await;
+        let mut supervisor = FfmpegSupervisor::new();
+        sender.send(HlsInput::Media(video(SEQUENCE_HEADER))).await.unwrap();
+        sender.send(HlsInput::End).await.unwrap();
+        let restart = processor.recover_ffmpeg(&mut None, &mut supervisor, &mut receiver, stopped()).await.unwrap();
+        assert!(matches!(restart, Restart::Interrupted(Some(HlsInput::End))));
+        assert_eq!(supervisor.attempts, 0);
+        assert_eq!(supervisor.headers.packets().len(), 1);
+        assert_eq!(status(&processor).0, HlsState::Restarting);
+        assert!(processor.ffmpeg_process.lock().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn fails_when_ffmpeg_cannot_continue_after_a_discontinuity() {
+        let stub = StubFfmpeg::new("discontinuity");
+        let processor = stub.processor(5).await;
+        let (sender, receiver) = mpsc::channel(8);
+        let task = tokio::spawn({
+            let processor = processor.clone();
+            let relink_sender = sender.clone();
+            async move { processor.process_stream(receiver, relink_sender, LingeringProcessors::default()).await }
+        });
+        timeout(Duration::from_secs(10), async {
+            while processor.ffmpeg_process.lock().await.is_none() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        std::fs::remove_file(stub.dir.join("ffmpeg")).unwrap();
+        sender.send(HlsInput::Discontinuity).await.unwrap();
+        timeout(Duration::from_secs(10), task).await.unwrap().unwrap().unwrap();
+
+        let (state, last_error, _) = status(&processor);
+        assert_eq!(state, HlsState::Failed);
+        assert!(last_error.unwrap().contains("Failed to start FFmpeg"));
+        assert!(processor.ffmpeg_process.lock().await.is_none());
+    }
+
+    fn shared_status() -> SharedHlsStatus {
+        Arc::new(std::sync::Mutex::new(HlsStatus {
+            state: HlsState::Running,
+            last_error: None,
+            restarts: 0,
+            dropped_packets: 0,
+            ffmpeg: None,
+        }))
+    }
+
+    fn describe(input: &HlsInput) -> &'static str {
+        match input {
+            HlsInput::Media(packet) if packet.kind == MediaKind::Audio => "audio",
+            HlsInput::Media(packet) if packet.is_sequence_header() => "sequence header",
+            HlsInput::Media(packet) if packet.is_keyframe() => "keyframe",
+            HlsInput::Media(_) => "inter frame",
+            HlsInput::Discontinuity => "discontinuity",
+            HlsInput::End => "end",
+            HlsInput::Disconnect => "disconnect",
+        }
+    }
+
+    // A processor that has not taken anything out of its queue yet
+    fn backed_up_queue(queued: usize) -> (mpsc::Sender<HlsInput>, mpsc::Receiver<HlsInput>) {
+        let (sender, receiver) = mpsc::channel(HLS_INPUT_CAPACITY);
+        for _ in 0..queued {
+            sender.try_send(HlsInput::Media(audio())).unwrap();
+        }
+        (sender, receiver)
+    }
+
+    async fn wait_for(condition: impl Fn() -> bool) {
+        timeout(Duration::from_secs(10), async {
+            while !condition() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("condition not met in time");
+    }
+
+    #[tokio::test]
+    async fn drops_video_until_the_next_keyframe_while_behind() {
+        let (publisher, receiver) = tokio::sync::broadcast::channel(64);
+        let subscription = Subscription { headers: vec![video(SEQUENCE_HEADER)], receiver };
+        let (data_sender, mut data_receiver) = backed_up_queue(HLS_INPUT_DROP_VIDEO - 1);
+        let status = shared_status();
+        tokio::spawn(forward_packets(subscription, data_sender, StreamKey::new("test").unwrap(), status.clone()));
+
+        // The header fills the queue up to where video is dropped; audio still gets through
+        for packet in [video(INTER_FRAME), video(KEYFRAME), audio()] {
+            publisher.send(packet).unwrap();
+        }
+        wait_for(|| status.lock().unwrap().dropped_packets == 2).await;
+        let mut received = Vec::new();
+        for _ in 0..HLS_INPUT_DROP_VIDEO + 1 {
+            received.push(describe(&data_receiver.recv().await.unwrap()));
+        }
+        assert_eq!(received[HLS_INPUT_DROP_VIDEO - 1..], ["sequence header", "audio"]);
+
+        // Caught up, video comes back at the next keyframe
+        for packet in [video(INTER_FRAME), video(KEYFRAME), video(INTER_FRAME), audio()] {
+            publisher.send(packet).unwrap();
+        }
+        drop(publisher);
+        let mut received = Vec::new();
+        while let Some(input) = data_receiver.recv().await {
+            received.push(describe(&input));
+        }
+        assert_eq!(received, ["keyframe", "inter frame", "audio", "end"]);
+
+        let status = status.lock().unwrap();
+        assert_eq!((status.state, status.dropped_packets), (HlsState::Running, 3));
+    }
+
+    #[tokio::test]
+    async fn disconnects_the_processor_once_its_queue_is_full() {
+        let (publisher, receiver) = tokio::sync::broadcast::channel(64);
+        let subscription = Subscription { headers: Vec::new(), receiver };
+        let (data_sender, mut data_receiver) = backed_up_queue(HLS_INPUT_CAPACITY);
+        let status = shared_status();
+        tokio::spawn(forward_packets(subscription, data_sender, StreamKey::new("test").unwrap(), status.clone()));
+
+        // The publisher is let go of rather than held up by the processor
+        publisher.send(audio()).unwrap();
+        wait_for(|| publisher.receiver_count() == 0).await;
+        assert!(publisher.send(audio()).is_err());
+        {
+            let status = status.lock().unwrap();
+            assert_eq!(status.state, HlsState::Failed);
+            assert_eq!(status.last_error.as_deref(), Some("fell too far behind the stream"));
+            assert_eq!(status.dropped_packets, 1);
+        }
+
+        // The processor gets through what it was given, then is told to stop
+        for _ in 0..HLS_INPUT_CAPACITY {
+            assert_eq!(describe(&data_receiver.recv().await.unwrap()), "audio");
+        }
+        assert_eq!(describe(&data_receiver.recv().await.unwrap()), "disconnect");
+        assert!(data_receiver.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn counts_packets_skipped_by_a_lagging_subscription() {
+        let (publisher, receiver) = tokio::sync::broadcast::channel(4);
+        let subscription = Subscription { headers: Vec::new(), receiver };
+        // Six packets into a buffer of four: two are skipped, and the video after them is
+        // dropped up to the next keyframe
+        for _ in 0..6 {
+            publisher.send(video(INTER_FRAME)).unwrap();
+        }
+        let (data_sender, mut data_receiver) = mpsc::channel(HLS_INPUT_CAPACITY);
+        let status = shared_status();
+        tokio::spawn(forward_packets(subscription, data_sender, StreamKey::new("test").unwrap(), status.clone()));
+
+        let mut keyframe = video(KEYFRAME);
+        keyframe.discontinuity = true;
+        publisher.send(keyframe).unwrap();
+        drop(publisher);
+        let mut received = Vec::new();
+        while let Some(input) = data_receiver.recv().await {
+            received.push(describe(&input));
+        }
+        assert_eq!(received, ["discontinuity", "keyframe", "end"]);
+        assert_eq!(status.lock().unwrap().dropped_packets, 6);
+    }
+
+    // Native MPEG-TS output of one-second segments, kept open `reconnect_grace` seconds
+    fn grace_config(name: &str, reconnect_grace: u64) -> Config {
+        Config {
+            streams_dir: std::env::temp_dir().join(format!("streamx-grace-{}-{}", name, std::process::id())),
+            segment_duration: 1,
+            hls_muxer: HlsMuxer::Native,
+            reconnect_grace,
+            ..Config::default()
+        }
+    }
+
+    // Publishes `test` with a keyframe every 500ms from 0 to `until`, then leaves
+    async fn publish_and_leave(registry: &StreamRegistry, outputs: &HlsOutputs, config: &Config, until: u32) {
+        let publisher = registry.publish("live", &StreamKey::new("test").unwrap(), PublisherRole::Primary).unwrap();
+        outputs.start(&publisher.stream, config.clone()).await.unwrap();
+        publisher.send(video(SEQUENCE_HEADER));
+        for timestamp in (0..=until).step_by(500) {
+            publisher.send(MediaPacket::new(MediaKind::Video, timestamp, Bytes::from_static(KEYFRAME)));
+        }
+        registry.unpublish(&publisher);
+    }
+
+    fn lingering(outputs: &HlsOutputs) -> bool {
+        outputs.lingering.lock().unwrap().contains_key(&StreamKey::new("test").unwrap())
+    }
+
+    async fn wait_for_playlist(config: &Config, segment: &str) -> String {
+        let path = config.playlist_path(&StreamKey::new("test").unwrap());
+        timeout(Duration::from_secs(10), async {
+            loop {
+                match std::fs::read_to_string(&path) {
+                    Ok(playlist) if playlist.contains(segment) => return playlist,
+                    _ => sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .expect("segment never listed")
+    }
+
+    #[tokio::test]
+    async fn continues_the_playlist_for_a_returning_publisher() {
+        let config = grace_config("continue", 10);
+        let registry = StreamRegistry::new(10);
+        let outputs = HlsOutputs::default();
+
+        publish_and_leave(&registry, &outputs, &config, 2500).await;
+        wait_for(|| lingering(&outputs)).await;
+        publish_and_leave(&registry, &outputs, &config, 1500).await;
+
+        // The returning publisher's timestamps start over, after a discontinuity
+        let playlist = wait_for_playlist(&config, "segment_000004.ts").await;
+        let segments: Vec<_> = playlist.lines().filter(|line| !line.starts_with("#EXTINF")).skip_while(|line| !line.starts_with("segment_")).collect();
+        assert_eq!(
+            segments,
+            [
+                "segment_000000.ts",
+                "segment_000001.ts",
+                "segment_000002.ts",
+                "#EXT-X-DISCONTINUITY",
+                "segment_000003.ts",
+                "segment_000004.ts",
+            ]
+        );
+        assert!(playlist.contains("#EXT-X-MEDIA-SEQUENCE:0\n"));
+        wait_for(|| lingering(&outputs)).await;
+        let _ = std::fs::remove_dir_all(&config.streams_dir);
+    }
+
+    #[tokio::test]
+    async fn ends_the_playlist_once_the_grace_period_is_over() {
+        let config = grace_config("expire", 1);
+        let registry = StreamRegistry::new(10);
+        let outputs = HlsOutputs::default();
+
+        publish_and_leave(&registry, &outputs, &config, 2500).await;
+        wait_for(|| lingering(&outputs)).await;
+        wait_for(|| !lingering(&outputs)).await;
+        assert!(wait_for_playlist(&config, "segment_000002.ts").await.contains("#EXT-X-MEDIA-SEQUENCE:0\n"));
+
+        // A publisher returning too late starts a playlist of its own
+        publish_and_leave(&registry, &outputs, &config, 1000).await;
+        wait_for(|| lingering(&outputs)).await;
+        let playlist = wait_for_playlist(&config, "segment_000001.ts").await;
+        assert!(!playlist.contains("#EXT-X-DISCONTINUITY"));
+        assert!(!playlist.contains("segment_000002.ts"));
+        let _ = std::fs::remove_dir_all(&config.streams_dir);
+    }
+}
@@ -1,35 +1,198 @@
-use crate::config::Config;
+use crate::{config::Config, dash::DashManifest, stream_key::StreamKey};
 use std::{collections::VecDeque, path::PathBuf};
-use tokio::fs;
+use tokio::{fs, sync::watch};
 use tracing::{debug, warn};
 
+use super::{fmp4::MediaInfo, write_atomically};
+
 #[derive(Debug, Clone)]
 pub struct Segment {
     pub filename: String,
     pub duration: f64,
     pub sequence: u64,
+    // Preceded by an #EXT-X-DISCONTINUITY
+    pub discontinuity: bool,
+    // fMP4 init segment, referenced with #EXT-X-MAP
+    pub init: Option<String>,
+    // LL-HLS partial segments, listed only for the most recent segments
+    pub parts: Vec<Part>,
+    // Stream timestamp of its first frame, in milliseconds, and its size in bytes; only
+    // known for native segments
+    pub timestamp: u32,
+    pub size: usize,
+}
+
+/// An LL-HLS partial segment, listed with #EXT-X-PART.
+#[derive(Debug, Clone)]
+pub struct Part {
+    pub filename: String,
+    pub duration: f64,
+    // Starts with a keyframe, or holds audio only
+    pub independent: bool,
+}
+
+/// How far along a playlist is: the last complete segment, and the parts of the one after it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PlaylistPosition {
+    pub last_sequence: Option<u64>,
+    pub partial_sequence: u64,
+    pub partial_parts: usize,
+}
+
+impl PlaylistPosition {
+    /// Whether a playlist at this position holds segment `msn`, or part `part` of it.
+    pub fn contains(&self, msn: u64, part: Option<usize>) -> bool {
+        if self.last_sequence.is_some_and(|last| last >= msn) {
+            return true;
+        }
+        match part {
+            Some(part) => self.partial_sequence > msn || (self.partial_sequence == msn && self.partial_parts > part),
+            None => false,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct PlaylistManager {
     config: Config,
-    stream_key: String,
+    stream_key: StreamKey,
     segments: VecDeque<Segment>,
     sequence_number: u64,
     target_duration: u32,
+    // Discontinuities that have dropped out of the playlist
+    discontinuity_sequence: u64,
+    // Segments (and init segments no longer referenced) are deleted one update after they
+    // drop out, since players may still be fetching them
+    expired: Vec<String>,
+    // LL-HLS: the segment whose parts are being written, and the part expected next
+    partial: Option<Segment>,
+    preload_hint: Option<String>,
+    position: watch::Sender<PlaylistPosition>,
+    // Written alongside the playlist when DASH output is on
+    dash: Option<DashManifest>,
 }
 
 impl PlaylistManager {
-    pub async fn new(config: Config, stream_key: String) -> crate::error::Result<Self> {
+    pub async fn new(config: Config, stream_key: StreamKey) -> crate::error::Result<Self> {
         Ok(Self {
-            config,
             stream_key,
             segments: VecDeque::new(),
             sequence_number: 0,
             target_duration: 10, // Default target duration
+            discontinuity_sequence: 0,
+            expired: Vec::new(),
+            partial: None,
+            preload_hint: None,
+            position: watch::Sender::new(PlaylistPosition::default()),
+            dash: config.dash.then(DashManifest::new),
+            config,
         })
     }
 
+    /// Registers a new fMP4 init segment, described in the DASH manifest.
+    pub fn add_init(&mut self, init: String, media_info: MediaInfo) {
+        if let Some(dash) = &mut self.dash {
+            dash.add_init(init, media_info);
+        }
+    }
+
+    /// Adds a part of the segment in progress, which is given without its parts.
+    pub async fn add_part(&mut self, segment: &Segment, part: Part, preload_hint: Option<String>) -> crate::error::Result<()> {
+        let partial = match &mut self.partial {
+            Some(partial) if partial.sequence == segment.sequence => partial,
+            partial => partial.insert(segment.clone()),
+        };
+        partial.parts.push(part);
+        self.preload_hint = preload_hint;
+
+        self.write().await
+    }
+
+    /// Adds a segment from the native segmenter, deletes the ones that fell out of the
+    /// playlist and rewrites the playlist file. Parts added for the segment are kept with it.
+    pub async fn add_segment(&mut self, mut segment: Segment) -> crate::error::Result<()> {
+        if let Some(partial) = self.partial.take().filter(|partial| partial.sequence == segment.sequence) {
+            segment.parts = partial.parts;
+        }
+        self.sequence_number = segment.sequence + 1;
+        if let Some(dash) = &mut self.dash {
+            dash.add_segment(&segment);
+        }
+        self.segments.push_back(segment);
+
+        let mut dropped_files = Vec::new();
+        while self.segments.len() > self.config.playlist_size.max(1) {
+            let Some(dropped) = self.segments.pop_front() else {
+                break;
+            };
+            if dropped.discontinuity {
+                self.discontinuity_sequence += 1;
+            }
+            dropped_files.push(dropped.filename);
+            dropped_files.extend(dropped.parts.into_iter().map(|part| part.filename));
+            if let Some(init) = dropped.init {
+                if !dropped_files.contains(&init) && !self.segments.iter().any(|segment| segment.init.as_ref() == Some(&init)) {
+                    if let Some(dash) = &mut self.dash {
+                        dash.remove_init(&init);
+                    }
+                    dropped_files.push(init);
+                }
+            }
+        }
+
+        // Parts are only listed for segments less than three target durations from the end
+        let mut age = 0.0;
+        for segment in self.segments.iter_mut().rev() {
+            if age > 3.0 * self.target_duration as f64 {
+                dropped_files.extend(segment.parts.drain(..).map(|part| part.filename));
+            }
+            age += segment.duration;
+        }
+        if !dropped_files.is_empty() {
+            let stream_dir = self.config.stream_dir(&self.stream_key);
+            for expired in std::mem::replace(&mut self.expired, dropped_files) {
+                if let Err(e) = fs::remove_file(stream_dir.join(&expired)).await {
+                    warn!("Failed to delete expired segment {}: {}", expired, e);
+                }
+            }
+        }
+
+        let longest = self.segments.iter().map(|segment| segment.duration).fold(0.0, f64::max);
+        self.target_duration = (longest.ceil() as u32).max(self.config.segment_duration);
+
+        self.write().await?;
+        if let Some(dash) = &mut self.dash {
+            if let Some(first) = self.segments.front() {
+                dash.expire(first.sequence);
+            }
+            let manifest = dash.render(&self.segments, self.config.segment_duration, self.target_duration);
+            write_atomically(&self.config.stream_dir(&self.stream_key).join("manifest.mpd"), manifest.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    // Writes the playlist file, then wakes up blocked playlist requests
+    async fn write(&mut self) -> crate::error::Result<()> {
+        let content = self.get_content().await?;
+        write_atomically(&self.config.playlist_path(&self.stream_key), content.as_bytes()).await?;
+
+        self.position.send_replace(PlaylistPosition {
+            last_sequence: self.segments.back().map(|segment| segment.sequence),
+            partial_sequence: self.sequence_number,
+            partial_parts: self.partial.as_ref().map_or(0, |partial| partial.parts.len()),
+        });
+        Ok(())
+    }
+
+    /// Follows the playlist's progress, for LL-HLS blocking playlist reloads.
+    pub fn subscribe(&self) -> watch::Receiver<PlaylistPosition> {
+        self.position.subscribe()
+    }
+
+    pub fn target_duration(&self) -> u32 {
+        self.target_duration
+    }
+
     pub async fn update(&mut self) -> crate::error::Result<()> {
         let stream_dir = self.config.stream_dir(&self.stream_key);
         let playlist_path = stream_dir.join("playlist.m3u8");
@@ -59,6 +222,8 @@ impl PlaylistManager {
 
         let mut new_segments = VecDeque::new();
         let mut current_duration = 0.0;
+        let mut discontinuity = false;
+        let mut init = None;
         let mut sequence = self.sequence_number;
 
         for line in lines.iter() {
@@ -66,6 +231,15 @@ impl PlaylistManager {
                 if let Ok(duration) = line.split(':').nth(1).unwrap_or("10").parse::<u32>() {
                     self.target_duration = duration;
                 }
+            } else if let Some(discontinuity_sequence) = line.strip_prefix("#EXT-X-DISCONTINUITY-SEQUENCE:") {
+                self.discontinuity_sequence = discontinuity_sequence.parse().unwrap_or(0);
+            } else if *line == "#EXT-X-DISCONTINUITY" {
+                discontinuity = true;
+            } else if let Some(map) = line.strip_prefix("#EXT-X-MAP:") {
+                init = map
+                    .split(',')
+                    .find_map(|attribute| attribute.strip_prefix("URI="))
+                    .map(|uri| uri.trim_matches('"').to_string());
             } else if line.starts_with("#EXTINF:") {
                 // Parse segment duration
                 if let Some(duration_str) = line.strip_prefix("#EXTINF:") {
@@ -73,16 +247,22 @@ impl PlaylistManager {
                         current_duration = duration_part.parse().unwrap_or(self.config.segment_duration as f64);
                     }
                 }
-            } else if line.ends_with(".ts") && !line.starts_with('#') {
+            } else if (line.ends_with(".ts") || line.ends_with(".m4s")) && !line.starts_with('#') {
                 // This is a segment file
                 let segment = Segment {
                     filename: line.to_string(),
                     duration: current_duration,
                     sequence,
+                    discontinuity,
+                    init: init.clone(),
+                    parts: Vec::new(),
+                    timestamp: 0,
+                    size: 0,
                 };
                 new_segments.push_back(segment);
                 sequence += 1;
                 current_duration = 0.0;
+                discontinuity = false;
             }
         }
 
@@ -96,29 +276,95 @@ impl PlaylistManager {
     }
 
     pub async fn get_content(&self) -> crate::error::Result<String> {
-        if self.segments.is_empty() {
-            return Ok(self.generate_empty_playlist());
-        }
+        Ok(self.render(false))
+    }
+
+    /// The playlist as an LL-HLS delta update, with the oldest segments replaced by
+    /// #EXT-X-SKIP.
+    pub fn get_delta_content(&self) -> String {
+        self.render(true)
+    }
+
+    fn render(&self, delta: bool) -> String {
+        let Some(first_sequence) = self.segments.front().or(self.partial.as_ref()).map(|segment| segment.sequence) else {
+            return self.generate_empty_playlist();
+        };
+        // Part target duration, in seconds, when serving LL-HLS
+        let part_target = (self.config.hls_part_duration > 0).then(|| self.config.hls_part_duration as f64 / 1000.0);
+        let skip_until = 6.0 * self.target_duration as f64;
 
         let mut playlist = String::new();
         
         // Header
         playlist.push_str("#EXTM3U\n");
-        playlist.push_str("#EXT-X-VERSION:3\n");
+        // fMP4 segments need #EXT-X-MAP, which CMAF playlists declare as version 7, and delta
+        // updates need version 9
+        let version = if part_target.is_some() {
+            9
+        } else if self.segments.iter().any(|segment| segment.init.is_some()) {
+            7
+        } else {
+            3
+        };
+        playlist.push_str(&format!("#EXT-X-VERSION:{}\n", version));
         playlist.push_str(&format!("#EXT-X-TARGETDURATION:{}\n", self.target_duration));
+        if let Some(part_target) = part_target {
+            playlist.push_str(&format!(
+                "#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK={:.3},CAN-SKIP-UNTIL={:.1}\n",
+                3.0 * part_target,
+                skip_until
+            ));
+            playlist.push_str(&format!("#EXT-X-PART-INF:PART-TARGET={:.3}\n", part_target));
+        }
         
         // Sequence number (use the sequence of the first segment)
-        if let Some(first_segment) = self.segments.front() {
-            playlist.push_str(&format!("#EXT-X-MEDIA-SEQUENCE:{}\n", first_segment.sequence));
+        playlist.push_str(&format!("#EXT-X-MEDIA-SEQUENCE:{}\n", first_sequence));
+        if self.discontinuity_sequence > 0 {
+            playlist.push_str(&format!("#EXT-X-DISCONTINUITY-SEQUENCE:{}\n", self.discontinuity_sequence));
+        }
+
+        // Segments that end more than CAN-SKIP-UNTIL before the end of the playlist
+        let mut skipped = 0;
+        if delta && part_target.is_some() {
+            let mut remaining: f64 = self.segments.iter().map(|segment| segment.duration).sum();
+            for segment in &self.segments {
+                remaining -= segment.duration;
+                if remaining <= skip_until {
+                    break;
+                }
+                skipped += 1;
+            }
+        }
+        if skipped > 0 {
+            playlist.push_str(&format!("#EXT-X-SKIP:SKIPPED-SEGMENTS={}\n", skipped));
         }
 
         // Segments
-        for segment in &self.segments {
+        let mut map = None;
+        for segment in self.segments.iter().skip(skipped).chain(&self.partial) {
+            if segment.discontinuity {
+                playlist.push_str("#EXT-X-DISCONTINUITY\n");
+            }
+            if let Some(init) = segment.init.as_ref().filter(|init| map != Some(*init)) {
+                playlist.push_str(&format!("#EXT-X-MAP:URI=\"{}\"\n", init));
+                map = Some(init);
+            }
+            for part in &segment.parts {
+                playlist.push_str(&format!("#EXT-X-PART:DURATION={:.3},URI=\"{}\"", part.duration, part.filename));
+                playlist.push_str(if part.independent { ",INDEPENDENT=YES\n" } else { "\n" });
+            }
+            // The segment in progress is only listed by its parts
+            if self.partial.as_ref().is_some_and(|partial| std::ptr::eq(partial, segment)) {
+                continue;
+            }
             playlist.push_str(&format!("#EXTINF:{:.3},\n", segment.duration));
             playlist.push_str(&format!("{}\n", segment.filename));
         }
+        if let Some(preload_hint) = &self.preload_hint {
+            playlist.push_str(&format!("#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"{}\"\n", preload_hint));
+        }
 
-        Ok(playlist)
+        playlist
     }
 
     fn generate_empty_playlist(&self) -> String {
@@ -133,6 +379,126 @@ impl PlaylistManager {
     }
 
     pub fn is_live(&self) -> bool {
-        !self.segments.is_empty()
+        !self.segments.is_empty() || self.partial.is_some()
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn manager(name: &str, part_duration: u32) -> PlaylistManager {
+        let config = Config {
+            streams_dir: std::env::temp_dir().join(format!("streamx-playlist-{}-{}", name, std::process::id())),
+            segment_duration: 2,
+            playlist_size: 10,
+            hls_part_duration: part_duration,
+            ..Config::default()
+        };
+        let stream_key = StreamKey::new("test").unwrap();
+        std::fs::create_dir_all(config.stream_dir(&stream_key)).unwrap();
+        PlaylistManager::new(config, stream_key).await.unwrap()
+    }
+
+    fn segment(sequence: u64, duration: f64) -> Segment {
+        Segment {
+            filename: format!("segment_{:06}.ts", sequence),
+            duration,
+            sequence,
+            discontinuity: false,
+            init: None,
+            parts: Vec::new(),
+            timestamp: 0,
+            size: 0,
+        }
+    }
+
+    fn part(sequence: u64, index: u32, independent: bool) -> Part {
+        Part { filename: format!("segment_{:06}.part{}.ts", sequence, index), duration: 0.5, independent }
+    }
+
+    fn remove(playlist: &PlaylistManager) {
+        let _ = std::fs::remove_dir_all(&playlist.config.streams_dir);
+    }
+
+    #[test]
+    fn positions_contain_earlier_segments_and_parts() {
+        let position = PlaylistPosition { last_sequence: Some(4), partial_sequence: 5, partial_parts: 2 };
+        assert!(position.contains(4, None));
+        assert!(position.contains(3, Some(7)));
+        assert!(position.contains(5, Some(0)));
+        assert!(position.contains(5, Some(1)));
+        assert!(!position.contains(5, Some(2)));
+        assert!(!position.contains(5, None));
+        assert!(!position.contains(6, Some(0)));
+
+        let empty = PlaylistPosition::default();
+        assert!(!empty.contains(0, None));
+        assert!(!empty.contains(0, Some(0)));
+        assert!(PlaylistPosition { partial_sequence: 1, ..empty }.contains(0, Some(3)));
+    }
+
+    #[tokio::test]
+    async fn lists_parts_with_a_preload_hint() {
+        let mut playlist = manager("parts", 500).await;
+        playlist.add_part(&segment(0, 0.0), part(0, 0, true), Some("segment_000000.part1.ts".to_string())).await.unwrap();
+        // The segment in progress is only listed by its parts
+        assert_eq!(
+            playlist.get_content().await.unwrap(),
+            "#EXTM3U\n#EXT-X-VERSION:9\n#EXT-X-TARGETDURATION:10\n\
+             #EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK=1.500,CAN-SKIP-UNTIL=60.0\n\
+             #EXT-X-PART-INF:PART-TARGET=0.500\n#EXT-X-MEDIA-SEQUENCE:0\n\
+             #EXT-X-PART:DURATION=0.500,URI=\"segment_000000.part0.ts\",INDEPENDENT=YES\n\
+             #EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"segment_000000.part1.ts\"\n"
+        );
+        assert_eq!(*playlist.subscribe().borrow(), PlaylistPosition { last_sequence: None, partial_sequence: 0, partial_parts: 1 });
+
+        playlist.add_part(&segment(0, 0.0), part(0, 1, false), Some("segment_000001.part0.ts".to_string())).await.unwrap();
+        playlist.add_segment(segment(0, 1.0)).await.unwrap();
+        assert_eq!(
+            playlist.get_content().await.unwrap(),
+            "#EXTM3U\n#EXT-X-VERSION:9\n#EXT-X-TARGETDURATION:2\n\
+             #EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK=1.500,CAN-SKIP-UNTIL=12.0\n\
+             #EXT-X-PART-INF:PART-TARGET=0.500\n#EXT-X-MEDIA-SEQUENCE:0\n\
+             #EXT-X-PART:DURATION=0.500,URI=\"segment_000000.part0.ts\",INDEPENDENT=YES\n\
+             #EXT-X-PART:DURATION=0.500,URI=\"segment_000000.part1.ts\"\n\
+             #EXTINF:1.000,\nsegment_000000.ts\n\
+             #EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"segment_000001.part0.ts\"\n"
+        );
+        assert_eq!(*playlist.subscribe().borrow(), PlaylistPosition { last_sequence: Some(0), partial_sequence: 1, partial_parts: 0 });
+        // The playlist file is written as well
+        let written = std::fs::read_to_string(playlist.config.playlist_path(&playlist.stream_key)).unwrap();
+        assert_eq!(written, playlist.get_content().await.unwrap());
+        remove(&playlist);
+    }
+
+    #[tokio::test]
+    async fn skips_old_segments_in_delta_updates() {
+        let mut playlist = manager("delta", 500).await;
+        for sequence in 0..10 {
+            playlist.add_segment(segment(sequence, 2.0)).await.unwrap();
+        }
+
+        // With a two second target, segments ending more than 12 seconds before the end are
+        // skipped
+        let delta = playlist.get_delta_content();
+        assert!(delta.contains("#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-SKIP:SKIPPED-SEGMENTS=3\n#EXTINF:2.000,\nsegment_000003.ts\n"));
+        assert!(!delta.contains("segment_000002.ts"));
+        assert!(delta.ends_with("segment_000009.ts\n"));
+
+        let full = playlist.get_content().await.unwrap();
+        assert!(!full.contains("#EXT-X-SKIP"));
+        assert!(full.contains("#EXT-X-MEDIA-SEQUENCE:0\n#EXTINF:2.000,\nsegment_000000.ts\n"));
+        remove(&playlist);
+    }
+
+    #[tokio::test]
+    async fn leaves_out_low_latency_tags_without_parts() {
+        let mut playlist = manager("no-parts", 0).await;
+        playlist.add_segment(segment(0, 2.0)).await.unwrap();
+        let content = playlist.get_content().await.unwrap();
+        assert_eq!(content, "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:0\n#EXTINF:2.000,\nsegment_000000.ts\n");
+        assert_eq!(playlist.get_delta_content(), content);
+        remove(&playlist);
+    }
+}
@@ -0,0 +1,496 @@
+use crate::{
+    config::Config,
+    error::Result,
+    media::{MediaKind, MediaPacket},
+    stream_key::StreamKey,
+};
+use bytes::{Bytes, BytesMut};
+use std::{path::PathBuf, sync::Arc};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use super::{
+    fmp4::{Fmp4Muxer, MediaInfo},
+    playlist::{Part, PlaylistManager, Segment},
+    ts::TsMuxer,
+    write_atomically,
+    HlsSegmentFormat,
+};
+
+enum SegmentMuxer {
+    Ts(TsMuxer),
+    Fmp4(Fmp4Muxer),
+}
+
+impl SegmentMuxer {
+    fn new(format: HlsSegmentFormat) -> Self {
+        match format {
+            HlsSegmentFormat::Mpegts => SegmentMuxer::Ts(TsMuxer::new()),
+            HlsSegmentFormat::Fmp4 => SegmentMuxer::Fmp4(Fmp4Muxer::new()),
+        }
+    }
+
+    fn has_video(&self) -> bool {
+        match self {
+            SegmentMuxer::Ts(muxer) => muxer.has_video(),
+            SegmentMuxer::Fmp4(muxer) => muxer.has_video(),
+        }
+    }
+
+    // fMP4 segments share an init segment, which is returned when the codec configuration
+    // changed
+    fn take_init_segment(&mut self) -> Option<(Bytes, MediaInfo)> {
+        match self {
+            SegmentMuxer::Ts(_) => None,
+            SegmentMuxer::Fmp4(muxer) => Some((muxer.take_init_segment()?, muxer.media_info())),
+        }
+    }
+
+    // MPEG-TS segments and parts each start with the tables
+    fn start_part(&mut self, out: &mut BytesMut) {
+        if let SegmentMuxer::Ts(muxer) = self {
+            muxer.write_tables(out);
+        }
+    }
+
+    fn write_packet(&mut self, packet: &MediaPacket, out: &mut BytesMut) {
+        match self {
+            SegmentMuxer::Ts(muxer) => muxer.write_packet(packet, out),
+            SegmentMuxer::Fmp4(muxer) => muxer.write_packet(packet),
+        }
+    }
+
+    // fMP4 segments and parts are each one fragment
+    fn finish_part(&mut self, end: u32, out: &mut BytesMut) {
+        if let SegmentMuxer::Fmp4(muxer) = self {
+            muxer.write_fragment(end, out);
+        }
+    }
+}
+
+struct OpenSegment {
+    data: BytesMut,
+    sequence: u64,
+    start: u32,
+    end: u32,
+    discontinuity: bool,
+    init: Option<String>,
+    // The LL-HLS part being written: where it starts in `data`, its start time and number
+    part_offset: usize,
+    part_start: u32,
+    part_index: u32,
+    part_independent: bool,
+    // The last frame a part could have started at
+    last_boundary: u32,
+}
+
+/// Muxes a stream to MPEG-TS or fMP4 in-process. A segment starts at the first keyframe after
+/// `segment_duration`, and is handed to the playlist once it is complete on disk. With LL-HLS,
+/// each segment is also written as parts of up to `hls_part_duration`, cut at any frame.
+pub struct Segmenter {
+    stream_dir: PathBuf,
+    // In milliseconds
+    segment_duration: u32,
+    // In milliseconds; 0 when parts aren't written
+    part_duration: u32,
+    format: HlsSegmentFormat,
+    muxer: SegmentMuxer,
+    current: Option<OpenSegment>,
+    next_sequence: u64,
+    next_discontinuity: bool,
+    // The fMP4 init segment in use, its contents, and how many have been written
+    init: Option<String>,
+    init_data: Bytes,
+    init_count: u32,
+    playlist: Arc<Mutex<PlaylistManager>>,
+}
+
+impl Segmenter {
+    pub fn new(config: &Config, stream_key: &StreamKey, playlist: Arc<Mutex<PlaylistManager>>) -> Self {
+        Self {
+            stream_dir: config.stream_dir(stream_key),
+            segment_duration: config.segment_duration * 1000,
+            part_duration: config.hls_part_duration,
+            format: config.hls_segment_format,
+            muxer: SegmentMuxer::new(config.hls_segment_format),
+            current: None,
+            next_sequence: 0,
+            next_discontinuity: false,
+            init: None,
+            init_data: Bytes::new(),
+            init_count: 0,
+            playlist,
+        }
+    }
+
+    pub async fn write(&mut self, packet: &MediaPacket) -> Result<()> {
+        if packet.kind == MediaKind::Metadata || packet.is_sequence_header() {
+            self.muxer.write_packet(packet, &mut BytesMut::new());
+            return Ok(());
+        }
+
+        // Parts may start at any frame of the video track, or of the audio track when there
+        // is no video. Segments must start where decoding can: at a keyframe, or at any audio
+        // frame when there is no video.
+        let has_video = self.muxer.has_video();
+        let boundary = packet.kind == if has_video { MediaKind::Video } else { MediaKind::Audio };
+        let starts_segment = boundary && (!has_video || packet.is_keyframe());
+        let due = self
+            .current
+            .as_ref()
+            .is_none_or(|segment| packet.timestamp.wrapping_sub(segment.start) >= self.segment_duration);
+        if starts_segment && due {
+            self.close_segment(Some(packet.timestamp)).await?;
+            self.open_segment(packet.timestamp).await?;
+        } else if boundary && self.part_due(packet.timestamp) {
+            self.next_part(packet.timestamp, starts_segment).await?;
+        }
+
+        // Anything before the first keyframe can't be decoded
+        let Some(segment) = self.current.as_mut() else {
+            return Ok(());
+        };
+        self.muxer.write_packet(packet, &mut segment.data);
+        if packet.timestamp.wrapping_sub(segment.start) > segment.end.wrapping_sub(segment.start) {
+            segment.end = packet.timestamp;
+        }
+        if boundary {
+            segment.last_boundary = packet.timestamp;
+        }
+
+        Ok(())
+    }
+
+    // Parts are cut before the frame after which the next one would make them longer than
+    // `part_duration`, assuming frames keep coming at the same rate
+    fn part_due(&self, timestamp: u32) -> bool {
+        let Some(segment) = self.current.as_ref().filter(|_| self.part_duration > 0) else {
+            return false;
+        };
+        let elapsed = timestamp.wrapping_sub(segment.part_start);
+        let frame_interval = timestamp.wrapping_sub(segment.last_boundary);
+        elapsed > 0 && elapsed < u32::MAX / 2 && elapsed.saturating_add(frame_interval) > self.part_duration
+    }
+
+    async fn next_part(&mut self, start: u32, independent: bool) -> Result<()> {
+        let Some(segment) = self.current.as_ref() else {
+            return Ok(());
+        };
+        let preload_hint = part_filename(self.format, segment.sequence, segment.part_index + 1);
+        self.close_part(start, Some(preload_hint)).await?;
+
+        let Some(segment) = self.current.as_mut() else {
+            return Ok(());
+        };
+        segment.part_offset = segment.data.len();
+        segment.part_start = start;
+        segment.part_index += 1;
+        segment.part_independent = independent;
+        self.muxer.start_part(&mut segment.data);
+        Ok(())
+    }
+
+    // Ends the part in progress, writing it out as its own file when parts are enabled
+    async fn close_part(&mut self, end: u32, preload_hint: Option<String>) -> Result<()> {
+        let Some(segment) = self.current.as_mut() else {
+            return Ok(());
+        };
+        self.muxer.finish_part(end, &mut segment.data);
+        if self.part_duration == 0 {
+            return Ok(());
+        }
+
+        let filename = part_filename(self.format, segment.sequence, segment.part_index);
+        write_atomically(&self.stream_dir.join(&filename), &segment.data[segment.part_offset..]).await?;
+        let part = Part {
+            filename,
+            duration: end.wrapping_sub(segment.part_start) as f64 / 1000.0,
+            independent: segment.part_independent,
+        };
+
+        let header = Segment {
+            filename: segment_filename(self.format, segment.sequence),
+            duration: 0.0,
+            sequence: segment.sequence,
+            discontinuity: segment.discontinuity,
+            init: segment.init.clone(),
+            parts: Vec::new(),
+            timestamp: segment.start,
+            size: 0,
+        };
+        self.playlist.lock().await.add_part(&header, part, preload_hint).await
+    }
+
+    /// Ends the current segment; the next one is marked as a discontinuity and may use
+    /// different codec configuration.
+    pub async fn discontinuity(&mut self) -> Result<()> {
+        self.close_segment(None).await?;
+        self.muxer = SegmentMuxer::new(self.format);
+        self.next_discontinuity = true;
+        Ok(())
+    }
+
+    /// Writes out the segment in progress.
+    pub async fn finish(&mut self) -> Result<()> {
+        self.close_segment(None).await
+    }
+
+    async fn open_segment(&mut self, start: u32) -> Result<()> {
+        if let Some((init, media_info)) = self.muxer.take_init_segment().filter(|(init, _)| *init != self.init_data) {
+            // Segments already in the playlist keep referring to the previous init segment
+            let filename = match self.init_count {
+                0 => "init.mp4".to_string(),
+                count => format!("init_{}.mp4", count),
+            };
+            write_atomically(&self.stream_dir.join(&filename), &init).await?;
+            debug!("Wrote {}", filename);
+            self.playlist.lock().await.add_init(filename.clone(), media_info);
+            self.init = Some(filename);
+            self.init_data = init;
+            self.init_count += 1;
+        }
+
+        let mut data = BytesMut::new();
+        self.muxer.start_part(&mut data);
+
+        self.current = Some(OpenSegment {
+            data,
+            sequence: self.next_sequence,
+            start,
+            end: start,
+            discontinuity: std::mem::take(&mut self.next_discontinuity),
+            init: self.init.clone(),
+            part_offset: 0,
+            part_start: start,
+            part_index: 0,
+            part_independent: true,
+            last_boundary: start,
+        });
+        self.next_sequence += 1;
+        Ok(())
+    }
+
+    // A segment lasts until the next one starts, or until its last packet if none follows
+    async fn close_segment(&mut self, next_start: Option<u32>) -> Result<()> {
+        let Some(end) = self.current.as_ref().map(|segment| next_start.unwrap_or(segment.end)) else {
+            return Ok(());
+        };
+        // The next part is the first of the next segment, if one follows
+        let preload_hint = next_start.map(|_| part_filename(self.format, self.next_sequence, 0));
+        self.close_part(end, preload_hint).await?;
+
+        let Some(segment) = self.current.take() else {
+            return Ok(());
+        };
+        let duration = end.wrapping_sub(segment.start) as f64 / 1000.0;
+        let filename = segment_filename(self.format, segment.sequence);
+        write_atomically(&self.stream_dir.join(&filename), &segment.data).await?;
+        debug!("Wrote {} ({:.3}s)", filename, duration);
+
+        self.playlist
+            .lock()
+            .await
+            .add_segment(Segment {
+                filename,
+                duration,
+                sequence: segment.sequence,
+                discontinuity: segment.discontinuity,
+                init: segment.init,
+                parts: Vec::new(),
+                timestamp: segment.start,
+                size: segment.data.len(),
+            })
+            .await
+    }
+}
+
+fn segment_filename(format: HlsSegmentFormat, sequence: u64) -> String {
+    format!("segment_{:06}.{}", sequence, format.extension())
+}
+
+fn part_filename(format: HlsSegmentFormat, sequence: u64, part: u32) -> String {
+    format!("segment_{:06}.part{}.{}", sequence, part, format.extension())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hls::playlist::Segment;
+
+    // AVC sequence header with 4-byte NAL lengths, and AAC LC at 44.1kHz
+    const AVC_SEQUENCE_HEADER: &[u8] = &[
+        0x17, 0x00, 0, 0, 0, 0x01, 0x64, 0x00, 0x1F, 0xFF, 0xE1, 0x00, 0x04, 0x67, 0x64, 0x00, 0x1F, 0x01, 0x00, 0x02, 0x68, 0xEE,
+    ];
+    const AAC_SEQUENCE_HEADER: &[u8] = &[0xAF, 0x00, 0x12, 0x10];
+    const KEYFRAME: &[u8] = &[0x17, 0x01, 0, 0, 0, 0, 0, 0, 2, 0x65, 0x88];
+    const INTER_FRAME: &[u8] = &[0x27, 0x01, 0, 0, 0, 0, 0, 0, 2, 0x41, 0x9A];
+    const AUDIO_FRAME: &[u8] = &[0xAF, 0x01, 0x21, 0x00];
+
+    struct TestStream {
+        segmenter: Segmenter,
+        playlist: Arc<Mutex<PlaylistManager>>,
+        dir: PathBuf,
+    }
+
+    impl TestStream {
+        async fn new(name: &str, format: HlsSegmentFormat) -> Self {
+            let config = Config {
+                streams_dir: std::env::temp_dir().join(format!("streamx-segmenter-{}-{}", name, std::process::id())),
+                segment_duration: 2,
+                playlist_size: 10,
+                hls_segment_format: format,
+                ..Config::default()
+            };
+            let stream_key = StreamKey::new("test").unwrap();
+            let dir = config.stream_dir(&stream_key);
+            let _ = std::fs::remove_dir_all(&config.streams_dir);
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let playlist = Arc::new(Mutex::new(PlaylistManager::new(config.clone(), stream_key.clone()).await.unwrap()));
+            let segmenter = Segmenter::new(&config, &stream_key, playlist.clone());
+            Self { segmenter, playlist, dir }
+        }
+
+        async fn write(&mut self, kind: MediaKind, timestamp: u32, payload: &'static [u8]) {
+            let packet = MediaPacket::new(kind, timestamp, Bytes::from_static(payload));
+            self.segmenter.write(&packet).await.unwrap();
+        }
+
+        async fn segments(&self) -> Vec<Segment> {
+            self.playlist.lock().await.get_segments().iter().cloned().collect()
+        }
+
+        fn files(&self) -> Vec<String> {
+            let mut files: Vec<String> = std::fs::read_dir(&self.dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            files.sort();
+            files
+        }
+    }
+
+    impl Drop for TestStream {
+        fn drop(&mut self) {
+            if let Some(streams_dir) = self.dir.parent() {
+                let _ = std::fs::remove_dir_all(streams_dir);
+            }
+        }
+    }
+
+    fn durations(segments: &[Segment]) -> Vec<f64> {
+        segments.iter().map(|segment| segment.duration).collect()
+    }
+
+    #[tokio::test]
+    async fn cuts_at_the_first_keyframe_after_segment_duration() {
+        let mut stream = TestStream::new("keyframes", HlsSegmentFormat::Mpegts).await;
+        stream.write(MediaKind::Video, 0, AVC_SEQUENCE_HEADER).await;
+        let frames = [
+            (0, KEYFRAME),
+            (1000, INTER_FRAME),
+            (1500, KEYFRAME), // too early for a new segment
+            (2000, INTER_FRAME),
+            (2345, KEYFRAME),
+            (3000, INTER_FRAME),
+            (4344, KEYFRAME),
+            (4345, KEYFRAME),
+            (4800, INTER_FRAME),
+        ];
+        for (timestamp, frame) in frames {
+            stream.write(MediaKind::Video, timestamp, frame).await;
+        }
+        assert_eq!(stream.segments().await.len(), 2);
+        stream.segmenter.finish().await.unwrap();
+
+        let segments = stream.segments().await;
+        assert_eq!(durations(&segments), [2.345, 2.0, 0.455]);
+        assert_eq!(segments.iter().map(|segment| segment.timestamp).collect::<Vec<_>>(), [0, 2345, 4345]);
+        assert_eq!(segments.iter().map(|segment| segment.sequence).collect::<Vec<_>>(), [0, 1, 2]);
+        assert!(segments.iter().all(|segment| !segment.discontinuity && segment.init.is_none()));
+    }
+
+    #[tokio::test]
+    async fn writes_complete_segments_before_listing_them() {
+        let mut stream = TestStream::new("atomic", HlsSegmentFormat::Mpegts).await;
+        stream.write(MediaKind::Video, 0, AVC_SEQUENCE_HEADER).await;
+        stream.write(MediaKind::Audio, 0, AAC_SEQUENCE_HEADER).await;
+        for timestamp in (0..=4000).step_by(500) {
+            stream.write(MediaKind::Video, timestamp, if timestamp % 2000 == 0 { KEYFRAME } else { INTER_FRAME }).await;
+            stream.write(MediaKind::Audio, timestamp, AUDIO_FRAME).await;
+        }
+
+        // The segment in progress is neither on disk nor in the playlist
+        let segments = stream.segments().await;
+        assert_eq!(durations(&segments), [2.0, 2.0]);
+        assert_eq!(stream.files(), ["playlist.m3u8", "segment_000000.ts", "segment_000001.ts"]);
+        for segment in &segments {
+            let data = std::fs::read(stream.dir.join(&segment.filename)).unwrap();
+            assert_eq!(data.len(), segment.size);
+            assert_eq!(data.len() % 188, 0);
+            assert_eq!(data[0], 0x47);
+        }
+        let playlist = std::fs::read_to_string(stream.dir.join("playlist.m3u8")).unwrap();
+        assert!(playlist.contains("#EXTINF:2.000,\nsegment_000000.ts\n#EXTINF:2.000,\nsegment_000001.ts\n"));
+        assert!(!playlist.contains("segment_000002.ts"));
+
+        stream.segmenter.finish().await.unwrap();
+        assert_eq!(stream.files().last().map(String::as_str), Some("segment_000002.ts"));
+        assert_eq!(durations(&stream.segments().await), [2.0, 2.0, 0.0]);
+    }
+
+    #[tokio::test]
+    async fn drops_data_before_the_first_keyframe() {
+        let mut stream = TestStream::new("first-keyframe", HlsSegmentFormat::Mpegts).await;
+        stream.write(MediaKind::Video, 0, AVC_SEQUENCE_HEADER).await;
+        stream.write(MediaKind::Audio, 0, AAC_SEQUENCE_HEADER).await;
+        stream.write(MediaKind::Video, 0, INTER_FRAME).await;
+        stream.write(MediaKind::Audio, 200, AUDIO_FRAME).await;
+        stream.write(MediaKind::Video, 500, INTER_FRAME).await;
+        stream.segmenter.finish().await.unwrap();
+        assert!(stream.segments().await.is_empty());
+        assert_eq!(stream.files(), Vec::<String>::new());
+
+        stream.write(MediaKind::Video, 1000, KEYFRAME).await;
+        stream.write(MediaKind::Video, 1500, INTER_FRAME).await;
+        stream.segmenter.finish().await.unwrap();
+        let segments = stream.segments().await;
+        assert_eq!(segments.len(), 1);
+        assert_eq!((segments[0].timestamp, segments[0].duration), (1000, 0.5));
+    }
+
+    #[tokio::test]
+    async fn cuts_audio_only_streams_at_any_audio_frame() {
+        let mut stream = TestStream::new("audio-only", HlsSegmentFormat::Mpegts).await;
+        stream.write(MediaKind::Audio, 0, AAC_SEQUENCE_HEADER).await;
+        for timestamp in (0..=4500).step_by(100) {
+            stream.write(MediaKind::Audio, timestamp, AUDIO_FRAME).await;
+        }
+        stream.segmenter.finish().await.unwrap();
+
+        let segments = stream.segments().await;
+        assert_eq!(durations(&segments), [2.0, 2.0, 0.5]);
+        assert_eq!(segments.iter().map(|segment| segment.timestamp).collect::<Vec<_>>(), [0, 2000, 4000]);
+    }
+
+    #[tokio::test]
+    async fn writes_fmp4_init_segments_once_per_configuration() {
+        let mut stream = TestStream::new("fmp4", HlsSegmentFormat::Fmp4).await;
+        stream.write(MediaKind::Video, 0, AVC_SEQUENCE_HEADER).await;
+        for timestamp in (0..=2000).step_by(500) {
+            stream.write(MediaKind::Video, timestamp, if timestamp % 2000 == 0 { KEYFRAME } else { INTER_FRAME }).await;
+        }
+        stream.segmenter.discontinuity().await.unwrap();
+        stream.write(MediaKind::Audio, 3000, AAC_SEQUENCE_HEADER).await;
+        stream.write(MediaKind::Audio, 3000, AUDIO_FRAME).await;
+        stream.segmenter.finish().await.unwrap();
+
+        let segments = stream.segments().await;
+        let inits: Vec<_> = segments.iter().map(|segment| (segment.init.as_deref(), segment.discontinuity)).collect();
+        assert_eq!(inits, [(Some("init.mp4"), false), (Some("init.mp4"), false), (Some("init_1.mp4"), true)]);
+        assert_eq!(
+            stream.files(),
+            ["init.mp4", "init_1.mp4", "playlist.m3u8", "segment_000000.m4s", "segment_000001.m4s", "segment_000002.m4s"]
+        );
+    }
+}
@@ -0,0 +1,585 @@
+use crate::media::{MediaKind, MediaPacket, AUDIO_CODEC_AAC, VIDEO_CODEC_AVC, VIDEO_EX_HEADER};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::iter;
+use tracing::warn;
+
+const TS_PACKET_SIZE: usize = 188;
+const TS_PAYLOAD_SIZE: usize = TS_PACKET_SIZE - 4;
+const SYNC_BYTE: u8 = 0x47;
+
+const PAT_PID: u16 = 0x0000;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x0100;
+const AUDIO_PID: u16 = 0x0101;
+// Marks the PMT's PCR PID as unused when there are no streams yet
+const NULL_PID: u16 = 0x1FFF;
+const PROGRAM_NUMBER: u16 = 1;
+
+const STREAM_TYPE_H264: u8 = 0x1B;
+const STREAM_TYPE_AAC: u8 = 0x0F;
+const STREAM_ID_VIDEO: u8 = 0xE0;
+const STREAM_ID_AUDIO: u8 = 0xC0;
+
+// PTS/DTS run this far (700ms at 90kHz) ahead of the PCR, so every frame arrives before it is due
+const MUX_DELAY: u64 = 63_000;
+const TIMESTAMP_MASK: u64 = 0x1_FFFF_FFFF;
+
+// FLV AVCPacketType / AACPacketType
+const SEQUENCE_HEADER: u8 = 0;
+const CODED_FRAME: u8 = 1;
+
+const NAL_TYPE_IDR: u8 = 5;
+const NAL_TYPE_SPS: u8 = 7;
+const NAL_TYPE_PPS: u8 = 8;
+const NAL_TYPE_AUD: u8 = 9;
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+// Access unit delimiter, primary_pic_type "any"
+const AUD_NAL: [u8; 2] = [0x09, 0xF0];
+
+// From the AVCDecoderConfigurationRecord
+struct AvcConfig {
+    nal_length_size: usize,
+    sps: Vec<Bytes>,
+    pps: Vec<Bytes>,
+}
+
+impl AvcConfig {
+    fn parse(record: &[u8]) -> Option<Self> {
+        let nal_length_size = (*record.get(4)? & 0x03) as usize + 1;
+        let mut offset = 5;
+
+        let mut read_sets = |count_mask: u8| -> Option<Vec<Bytes>> {
+            let count = *record.get(offset)? & count_mask;
+            offset += 1;
+            let mut sets = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                let len = u16::from_be_bytes([*record.get(offset)?, *record.get(offset + 1)?]) as usize;
+                sets.push(Bytes::copy_from_slice(record.get(offset + 2..offset + 2 + len)?));
+                offset += 2 + len;
+            }
+            Some(sets)
+        };
+        let sps = read_sets(0x1F)?;
+        let pps = read_sets(0xFF)?;
+
+        Some(Self { nal_length_size, sps, pps })
+    }
+
+    // Length-prefixed NAL units to an Annex-B access unit, starting with an AUD and with the
+    // parameter sets ahead of an IDR that doesn't carry its own
+    fn to_annex_b(&self, data: &[u8]) -> BytesMut {
+        let mut nal_units = Vec::new();
+        let mut offset = 0;
+        while offset + self.nal_length_size <= data.len() {
+            let len = data[offset..offset + self.nal_length_size]
+                .iter()
+                .fold(0usize, |len, byte| len << 8 | *byte as usize);
+            offset += self.nal_length_size;
+            let Some(nal_unit) = data.get(offset..offset + len) else {
+                break;
+            };
+            nal_units.push(nal_unit);
+            offset += len;
+        }
+
+        let nal_type = |nal_unit: &[u8]| nal_unit.first().map_or(0, |header| header & 0x1F);
+        let mut needs_parameter_sets = !nal_units.iter().any(|nal_unit| nal_type(nal_unit) == NAL_TYPE_SPS);
+
+        let mut access_unit = BytesMut::with_capacity(data.len() + 64);
+        access_unit.put_slice(&START_CODE);
+        access_unit.put_slice(&AUD_NAL);
+        for nal_unit in nal_units {
+            match nal_type(nal_unit) {
+                NAL_TYPE_AUD => continue,
+                NAL_TYPE_IDR if needs_parameter_sets => {
+                    for parameter_set in self.sps.iter().chain(&self.pps) {
+                        access_unit.put_slice(&START_CODE);
+                        access_unit.put_slice(parameter_set);
+                    }
+                    needs_parameter_sets = false;
+                }
+                NAL_TYPE_PPS => needs_parameter_sets = false,
+                _ => {}
+            }
+            access_unit.put_slice(&START_CODE);
+            access_unit.put_slice(nal_unit);
+        }
+
+        access_unit
+    }
+}
+
+// From the AudioSpecificConfig
+struct AacConfig {
+    profile: u8,
+    sampling_index: u8,
+    channel_config: u8,
+}
+
+impl AacConfig {
+    fn parse(config: &[u8]) -> Option<Self> {
+        let object_type = config.first()? >> 3;
+        let sampling_index = (config[0] & 0x07) << 1 | config.get(1)? >> 7;
+        let channel_config = (config[1] >> 3) & 0x0F;
+        // Escaped object types and explicit sampling rates can't be expressed in ADTS
+        if object_type == 31 || sampling_index == 15 {
+            return None;
+        }
+
+        // ADTS only has room for Main, LC, SSR and LTP; HE-AAC is carried as LC with implicit SBR
+        let profile = if (1..=4).contains(&object_type) { object_type - 1 } else { 1 };
+        Some(Self { profile, sampling_index, channel_config })
+    }
+
+    fn adts_header(&self, frame_size: usize) -> [u8; 7] {
+        let frame_length = frame_size + 7;
+        [
+            0xFF,
+            0xF1, // MPEG-4, no CRC
+            self.profile << 6 | self.sampling_index << 2 | self.channel_config >> 2,
+            (self.channel_config & 0x03) << 6 | ((frame_length >> 11) & 0x03) as u8,
+            (frame_length >> 3) as u8,
+            ((frame_length & 0x07) << 5) as u8 | 0x1F,
+            0xFC, // buffer fullness 0x7FF (VBR), one raw data block
+        ]
+    }
+}
+
+/// Muxes H.264 and AAC packets, as carried in RTMP and FLV, into an MPEG transport stream.
+#[derive(Default)]
+pub struct TsMuxer {
+    avc: Option<AvcConfig>,
+    aac: Option<AacConfig>,
+    pat_continuity: u8,
+    pmt_continuity: u8,
+    video_continuity: u8,
+    audio_continuity: u8,
+}
+
+impl TsMuxer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the stream's video has been configured, so segments must start at keyframes.
+    pub fn has_video(&self) -> bool {
+        self.avc.is_some()
+    }
+
+    /// Writes the PAT and PMT, which every segment must start with.
+    pub fn write_tables(&mut self, out: &mut BytesMut) {
+        let mut pat = BytesMut::with_capacity(16);
+        pat.put_u8(0x00); // table_id
+        pat.put_u16(0xB000 | 13); // section_syntax_indicator, section_length
+        pat.put_u16(1); // transport_stream_id
+        pat.put_u8(0xC1); // version 0, current_next_indicator
+        pat.put_u16(0); // section_number, last_section_number
+        pat.put_u16(PROGRAM_NUMBER);
+        pat.put_u16(0xE000 | PMT_PID);
+        self.write_section(PAT_PID, pat, out);
+
+        let mut streams = Vec::new();
+        if self.avc.is_some() {
+            streams.push((STREAM_TYPE_H264, VIDEO_PID));
+        }
+        if self.aac.is_some() {
+            streams.push((STREAM_TYPE_AAC, AUDIO_PID));
+        }
+
+        let mut pmt = BytesMut::with_capacity(32);
+        pmt.put_u8(0x02); // table_id
+        pmt.put_u16(0xB000 | (13 + 5 * streams.len() as u16));
+        pmt.put_u16(PROGRAM_NUMBER);
+        pmt.put_u8(0xC1);
+        pmt.put_u16(0);
+        pmt.put_u16(0xE000 | self.pcr_pid().unwrap_or(NULL_PID));
+        pmt.put_u16(0xF000); // no program descriptors
+        for (stream_type, pid) in streams {
+            pmt.put_u8(stream_type);
+            pmt.put_u16(0xE000 | pid);
+            pmt.put_u16(0xF000); // no ES descriptors
+        }
+        self.write_section(PMT_PID, pmt, out);
+    }
+
+    /// Writes a packet's transport stream packets. Sequence headers only configure the muxer,
+    /// and packets of other codecs are dropped.
+    pub fn write_packet(&mut self, packet: &MediaPacket, out: &mut BytesMut) {
+        let payload = &packet.payload;
+        match packet.kind {
+            MediaKind::Video if payload.len() >= 5 && payload[0] & VIDEO_EX_HEADER == 0 && payload[0] & 0x0F == VIDEO_CODEC_AVC => match payload[1] {
+                SEQUENCE_HEADER => {
+                    self.avc = AvcConfig::parse(&payload[5..]);
+                    if self.avc.is_none() {
+                        warn!("Ignoring malformed AVC decoder configuration");
+                    }
+                }
+                CODED_FRAME => {
+                    let Some(avc) = &self.avc else {
+                        return;
+                    };
+                    let access_unit = avc.to_annex_b(&payload[5..]);
+
+                    // Composition time offset, a signed 24-bit value
+                    let composition_time = i32::from_be_bytes([payload[2], payload[3], payload[4], 0]) >> 8;
+                    let dts = packet.timestamp as u64 * 90 + MUX_DELAY;
+                    let pts = dts.saturating_add_signed(composition_time as i64 * 90);
+
+                    let pes = pes_packet(STREAM_ID_VIDEO, pts, Some(dts), &access_unit);
+                    let pcr = (self.pcr_pid() == Some(VIDEO_PID)).then_some(packet.timestamp as u64 * 90);
+                    self.write_pes(VIDEO_PID, &pes, pcr, packet.is_keyframe(), out);
+                }
+                _ => {}
+            },
+            MediaKind::Audio if payload.len() >= 2 && payload[0] >> 4 == AUDIO_CODEC_AAC => match payload[1] {
+                SEQUENCE_HEADER => {
+                    self.aac = AacConfig::parse(&payload[2..]);
+                    if self.aac.is_none() {
+                        warn!("Ignoring unsupported AAC configuration");
+                    }
+                }
+                CODED_FRAME => {
+                    let Some(aac) = &self.aac else {
+                        return;
+                    };
+                    let frame = &payload[2..];
+                    let mut adts_frame = BytesMut::with_capacity(7 + frame.len());
+                    adts_frame.put_slice(&aac.adts_header(frame.len()));
+                    adts_frame.put_slice(frame);
+
+                    let pts = packet.timestamp as u64 * 90 + MUX_DELAY;
+                    let pes = pes_packet(STREAM_ID_AUDIO, pts, None, &adts_frame);
+                    let pcr = (self.pcr_pid() == Some(AUDIO_PID)).then_some(packet.timestamp as u64 * 90);
+                    self.write_pes(AUDIO_PID, &pes, pcr, pcr.is_some(), out);
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    // The PCR rides on the video stream, or on the audio when there is no video
+    fn pcr_pid(&self) -> Option<u16> {
+        if self.avc.is_some() {
+            Some(VIDEO_PID)
+        } else if self.aac.is_some() {
+            Some(AUDIO_PID)
+        } else {
+            None
+        }
+    }
+
+    fn next_continuity(&mut self, pid: u16) -> u8 {
+        let counter = match pid {
+            PAT_PID => &mut self.pat_continuity,
+            PMT_PID => &mut self.pmt_continuity,
+            VIDEO_PID => &mut self.video_continuity,
+            _ => &mut self.audio_continuity,
+        };
+        let current = *counter;
+        *counter = (*counter + 1) & 0x0F;
+        current
+    }
+
+    // A PSI section in a single packet, after a zero pointer field and followed by its CRC
+    fn write_section(&mut self, pid: u16, mut section: BytesMut, out: &mut BytesMut) {
+        section.put_u32(crc32_mpeg2(&section));
+
+        out.put_u8(SYNC_BYTE);
+        out.put_u16(0x4000 | pid); // payload_unit_start_indicator
+        out.put_u8(0x10 | self.next_continuity(pid)); // payload only
+        out.put_u8(0); // pointer_field
+        out.put_slice(&section);
+        out.extend(iter::repeat_n(0xFF, TS_PAYLOAD_SIZE - 1 - section.len()));
+    }
+
+    // Splits a PES packet over transport stream packets; the first may carry the PCR and the
+    // last is padded with adaptation field stuffing
+    fn write_pes(&mut self, pid: u16, pes: &[u8], pcr: Option<u64>, random_access: bool, out: &mut BytesMut) {
+        let mut offset = 0;
+        let mut first = true;
+
+        while offset < pes.len() {
+            // Adaptation field after its length byte
+            let mut adaptation: Option<Vec<u8>> = None;
+            if first && (pcr.is_some() || random_access) {
+                let mut field = vec![(if random_access { 0x40 } else { 0 }) | (if pcr.is_some() { 0x10 } else { 0 })];
+                if let Some(pcr) = pcr {
+                    let base = pcr & TIMESTAMP_MASK;
+                    field.extend_from_slice(&[
+                        (base >> 25) as u8,
+                        (base >> 17) as u8,
+                        (base >> 9) as u8,
+                        (base >> 1) as u8,
+                        ((base & 1) << 7) as u8 | 0x7E, // reserved bits, extension 0
+                        0,
+                    ]);
+                }
+                adaptation = Some(field);
+            }
+
+            let header_len = adaptation.as_ref().map_or(0, |field| 1 + field.len());
+            let payload_len = (pes.len() - offset).min(TS_PAYLOAD_SIZE - header_len);
+            let stuffing = TS_PAYLOAD_SIZE - header_len - payload_len;
+            if stuffing > 0 {
+                match &mut adaptation {
+                    Some(field) => field.extend(iter::repeat_n(0xFF, stuffing)),
+                    None => {
+                        // The length byte alone takes up one byte of stuffing
+                        let mut field = Vec::with_capacity(stuffing);
+                        if stuffing > 1 {
+                            field.push(0);
+                            field.extend(iter::repeat_n(0xFF, stuffing - 2));
+                        }
+                        adaptation = Some(field);
+                    }
+                }
+            }
+
+            out.put_u8(SYNC_BYTE);
+            out.put_u16(if first { 0x4000 } else { 0 } | pid);
+            let adaptation_control = if adaptation.is_some() { 0x30 } else { 0x10 };
+            out.put_u8(adaptation_control | self.next_continuity(pid));
+            if let Some(field) = adaptation {
+                out.put_u8(field.len() as u8);
+                out.put_slice(&field);
+            }
+            out.put_slice(&pes[offset..offset + payload_len]);
+
+            offset += payload_len;
+            first = false;
+        }
+    }
+}
+
+fn pes_packet(stream_id: u8, pts: u64, dts: Option<u64>, payload: &[u8]) -> BytesMut {
+    let header_data_len = if dts.is_some() { 10 } else { 5 };
+    let mut pes = BytesMut::with_capacity(9 + header_data_len + payload.len());
+
+    pes.put_slice(&[0, 0, 1, stream_id]);
+    // Video PES packets may be unbounded; audio frames always fit
+    let packet_len = 3 + header_data_len + payload.len();
+    let packet_len = if stream_id == STREAM_ID_VIDEO || packet_len > u16::MAX as usize { 0 } else { packet_len as u16 };
+    pes.put_u16(packet_len);
+    pes.put_u8(0x80); // marker bits
+    pes.put_u8(if dts.is_some() { 0xC0 } else { 0x80 }); // PTS_DTS_flags
+    pes.put_u8(header_data_len as u8);
+    match dts {
+        Some(dts) => {
+            put_timestamp(&mut pes, 0x3, pts);
+            put_timestamp(&mut pes, 0x1, dts);
+        }
+        None => put_timestamp(&mut pes, 0x2, pts),
+    }
+    pes.put_slice(payload);
+
+    pes
+}
+
+// A 33-bit PTS or DTS, split around marker bits
+fn put_timestamp(buffer: &mut BytesMut, prefix: u8, timestamp: u64) {
+    let timestamp = timestamp & TIMESTAMP_MASK;
+    buffer.put_u8(prefix << 4 | ((timestamp >> 30) as u8 & 0x07) << 1 | 1);
+    buffer.put_u16(((timestamp >> 15) as u16 & 0x7FFF) << 1 | 1);
+    buffer.put_u16((timestamp as u16 & 0x7FFF) << 1 | 1);
+}
+
+fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04C1_1DB7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // AVC sequence header: High profile, 4-byte NAL lengths, one SPS and one PPS
+    const AVC_SEQUENCE_HEADER: &[u8] = &[
+        0x17, 0x00, 0, 0, 0, // keyframe, AVC sequence header, composition time
+        0x01, 0x64, 0x00, 0x1F, 0xFF, 0xE1, 0x00, 0x04, 0x67, 0x64, 0x00, 0x1F, 0x01, 0x00, 0x02, 0x68, 0xEE,
+    ];
+    // AAC LC, 44.1kHz, stereo
+    const AAC_SEQUENCE_HEADER: &[u8] = &[0xAF, 0x00, 0x12, 0x10];
+
+    fn packet(kind: MediaKind, timestamp: u32, payload: &[u8]) -> MediaPacket {
+        MediaPacket::new(kind, timestamp, Bytes::copy_from_slice(payload))
+    }
+
+    fn configured_muxer() -> TsMuxer {
+        let mut muxer = TsMuxer::new();
+        let mut out = BytesMut::new();
+        muxer.write_packet(&packet(MediaKind::Video, 0, AVC_SEQUENCE_HEADER), &mut out);
+        muxer.write_packet(&packet(MediaKind::Audio, 0, AAC_SEQUENCE_HEADER), &mut out);
+        assert!(out.is_empty());
+        muxer
+    }
+
+    fn pid(ts_packet: &[u8]) -> u16 {
+        u16::from_be_bytes([ts_packet[1], ts_packet[2]]) & 0x1FFF
+    }
+
+    // The section of a PSI packet, including its CRC
+    fn section(ts_packet: &[u8]) -> &[u8] {
+        let section_length = (u16::from_be_bytes([ts_packet[6], ts_packet[7]]) & 0x0FFF) as usize;
+        &ts_packet[5..8 + section_length]
+    }
+
+    fn read_timestamp(bytes: &[u8]) -> u64 {
+        assert_eq!(bytes[0] & 1, 1);
+        assert_eq!(bytes[2] & 1, 1);
+        assert_eq!(bytes[4] & 1, 1);
+        ((bytes[0] as u64 >> 1) & 0x07) << 30
+            | (u16::from_be_bytes([bytes[1], bytes[2]]) as u64 >> 1) << 15
+            | u16::from_be_bytes([bytes[3], bytes[4]]) as u64 >> 1
+    }
+
+    #[test]
+    fn computes_mpeg2_crc() {
+        assert_eq!(crc32_mpeg2(b"123456789"), 0x0376_E6E7);
+        assert_eq!(crc32_mpeg2(&[]), 0xFFFF_FFFF);
+    }
+
+    #[test]
+    fn writes_pat_and_pmt() {
+        let mut out = BytesMut::new();
+        configured_muxer().write_tables(&mut out);
+        assert_eq!(out.len(), 2 * TS_PACKET_SIZE);
+        let (pat, pmt) = out.split_at(TS_PACKET_SIZE);
+
+        assert_eq!(pat[..5], [SYNC_BYTE, 0x40, 0x00, 0x10, 0x00]);
+        let pat_section = section(pat);
+        assert_eq!(pat_section[0], 0x00);
+        assert_eq!(pat_section[8..12], [0x00, 0x01, 0xF0, 0x00]); // program 1 on PMT_PID
+        // A section followed by its own CRC checks out to zero
+        assert_eq!(crc32_mpeg2(pat_section), 0);
+
+        assert_eq!(pid(pmt), PMT_PID);
+        let pmt_section = section(pmt);
+        assert_eq!(pmt_section[0], 0x02);
+        assert_eq!(u16::from_be_bytes([pmt_section[8], pmt_section[9]]) & 0x1FFF, VIDEO_PID); // PCR PID
+        assert_eq!(pmt_section[12..17], [STREAM_TYPE_H264, 0xE1, 0x00, 0xF0, 0x00]);
+        assert_eq!(pmt_section[17..22], [STREAM_TYPE_AAC, 0xE1, 0x01, 0xF0, 0x00]);
+        assert_eq!(crc32_mpeg2(pmt_section), 0);
+    }
+
+    #[test]
+    fn pmt_without_streams_has_no_pcr_pid() {
+        let mut out = BytesMut::new();
+        TsMuxer::new().write_tables(&mut out);
+        let pmt_section = section(&out[TS_PACKET_SIZE..]);
+
+        assert_eq!(u16::from_be_bytes([pmt_section[8], pmt_section[9]]) & 0x1FFF, NULL_PID);
+        assert_eq!(pmt_section.len(), 12 + 4);
+        assert_eq!(crc32_mpeg2(pmt_section), 0);
+    }
+
+    #[test]
+    fn writes_pes_headers() {
+        let audio = pes_packet(STREAM_ID_AUDIO, 90_000, None, &[0xAA; 10]);
+        assert_eq!(audio[..9], [0, 0, 1, STREAM_ID_AUDIO, 0, 3 + 5 + 10, 0x80, 0x80, 5]);
+        assert_eq!(audio[9] >> 4, 0x2);
+        assert_eq!(read_timestamp(&audio[9..14]), 90_000);
+        assert_eq!(audio[14..], [0xAA; 10]);
+
+        // Video PES packets are unbounded, and carry a DTS
+        let video = pes_packet(STREAM_ID_VIDEO, 93_000, Some(90_000), &[0xBB; 10]);
+        assert_eq!(video[..9], [0, 0, 1, STREAM_ID_VIDEO, 0, 0, 0x80, 0xC0, 10]);
+        assert_eq!((video[9] >> 4, video[14] >> 4), (0x3, 0x1));
+        assert_eq!(read_timestamp(&video[9..14]), 93_000);
+        assert_eq!(read_timestamp(&video[14..19]), 90_000);
+    }
+
+    #[test]
+    fn encodes_33_bit_timestamps() {
+        for timestamp in [0, 1, 0x7FFF, 0x8000, 0x1_2345_6789, TIMESTAMP_MASK] {
+            let mut buffer = BytesMut::new();
+            put_timestamp(&mut buffer, 0x2, timestamp);
+            assert_eq!(read_timestamp(&buffer), timestamp);
+        }
+
+        // Wraps at 33 bits
+        let mut buffer = BytesMut::new();
+        put_timestamp(&mut buffer, 0x2, TIMESTAMP_MASK + 2);
+        assert_eq!(read_timestamp(&buffer), 1);
+    }
+
+    #[test]
+    fn writes_adts_headers() {
+        let aac = AacConfig::parse(&AAC_SEQUENCE_HEADER[2..]).unwrap();
+        assert_eq!((aac.profile, aac.sampling_index, aac.channel_config), (1, 4, 2));
+        // 100 bytes of raw data, so a frame length of 107
+        assert_eq!(aac.adts_header(100), [0xFF, 0xF1, 0x50, 0x80, 0x0D, 0x7F, 0xFC]);
+
+        // HE-AAC is signalled as LC
+        assert_eq!(AacConfig::parse(&[0x2B, 0x10]).unwrap().profile, 1);
+        // Escaped object types and explicit sampling rates don't fit in ADTS
+        assert!(AacConfig::parse(&[0xF8, 0x00, 0x00]).is_none());
+        assert!(AacConfig::parse(&[0x17, 0x80]).is_none());
+    }
+
+    #[test]
+    fn converts_access_units_to_annex_b() {
+        let avc = AvcConfig::parse(&AVC_SEQUENCE_HEADER[5..]).unwrap();
+        assert_eq!(avc.nal_length_size, 4);
+
+        let access_unit = avc.to_annex_b(&[0, 0, 0, 2, 0x09, 0x10, 0, 0, 0, 3, 0x65, 0x88, 0x84]);
+        let expected: Vec<u8> = [
+            &START_CODE[..], &AUD_NAL,
+            &START_CODE, &[0x67, 0x64, 0x00, 0x1F],
+            &START_CODE, &[0x68, 0xEE],
+            &START_CODE, &[0x65, 0x88, 0x84],
+        ]
+        .concat();
+        assert_eq!(access_unit[..], expected[..]);
+    }
+
+    #[test]
+    fn counts_continuity_per_pid() {
+        let mut muxer = configured_muxer();
+        let mut out = BytesMut::new();
+        for _ in 0..17 {
+            muxer.write_tables(&mut out);
+        }
+        // A keyframe spanning several packets, then an audio frame
+        let mut keyframe = vec![0x17, 0x01, 0, 0, 0, 0, 0, 0x01, 0xF4, 0x65];
+        keyframe.extend(iter::repeat_n(0x42, 499));
+        muxer.write_packet(&packet(MediaKind::Video, 40, &keyframe), &mut out);
+        muxer.write_packet(&packet(MediaKind::Audio, 40, &[0xAF, 0x01, 0x21, 0x00]), &mut out);
+
+        assert_eq!(out.len() % TS_PACKET_SIZE, 0);
+        let mut counters: HashMap<u16, Vec<u8>> = HashMap::new();
+        for ts_packet in out.chunks(TS_PACKET_SIZE) {
+            assert_eq!(ts_packet[0], SYNC_BYTE);
+            counters.entry(pid(ts_packet)).or_default().push(ts_packet[3] & 0x0F);
+        }
+
+        let expected: Vec<u8> = (0..17).map(|n| n & 0x0F).collect();
+        assert_eq!(counters[&PAT_PID], expected);
+        assert_eq!(counters[&PMT_PID], expected);
+        assert_eq!(counters[&VIDEO_PID], (0..counters[&VIDEO_PID].len() as u8).collect::<Vec<_>>());
+        assert!(counters[&VIDEO_PID].len() > 1);
+        assert_eq!(counters[&AUDIO_PID], [0]);
+    }
+
+    #[test]
+    fn marks_keyframes_with_pcr() {
+        let mut muxer = configured_muxer();
+        let mut out = BytesMut::new();
+        muxer.write_packet(&packet(MediaKind::Video, 1000, &[0x17, 0x01, 0, 0, 0, 0, 0, 0, 1, 0x65]), &mut out);
+
+        // Adaptation field with random_access_indicator and PCR, then the PES start code
+        assert_eq!(out[3] & 0x30, 0x30);
+        assert_eq!(out[5], 0x50);
+        let pcr_base = u32::from_be_bytes([out[6], out[7], out[8], out[9]]) as u64;
+        assert_eq!(pcr_base << 1 | (out[10] >> 7) as u64, 90_000);
+        let pes_start = 5 + out[4] as usize;
+        assert_eq!(out[pes_start..pes_start + 4], [0, 0, 1, STREAM_ID_VIDEO]);
+    }
+}
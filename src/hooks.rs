@@ -0,0 +1,243 @@
+use crate::error::{Result, StreamError};
+use crate::rtmp::stream_path::StreamPath;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+use tracing::{debug, warn};
+
+/// What to do when a hook endpoint cannot be reached or times out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookFailurePolicy {
+    #[default]
+    Deny,
+    Allow,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HookConfig {
+    pub on_connect: Option<String>,
+    pub on_publish: Option<String>,
+    pub on_play: Option<String>,
+    pub on_done: Option<String>,
+    pub timeout_ms: u64,
+    pub on_failure: HookFailurePolicy,
+}
+
+impl Default for HookConfig {
+    fn default() -> Self {
+        Self {
+            on_connect: None,
+            on_publish: None,
+            on_play: None,
+            on_done: None,
+            timeout_ms: 3000,
+            on_failure: HookFailurePolicy::Deny,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+    Connect,
+    Publish,
+    Play,
+    Done,
+}
+
+/// JSON body POSTed to the hook endpoint.
+#[derive(Debug, Clone, Serialize)]
+pub struct HookRequest {
+    pub call: HookEvent,
+    pub app: String,
+    pub name: String,
+    pub addr: String,
+    pub tc_url: String,
+    pub args: HashMap<String, String>,
+}
+
+impl HookRequest {
+    pub fn new(call: HookEvent, path: &StreamPath, addr: SocketAddr, tc_url: &str) -> Self {
+        Self {
+            call,
+            app: path.app.clone(),
+            name: path.name.clone(),
+            addr: addr.ip().to_string(),
+            tc_url: tc_url.to_string(),
+            args: path.args.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HookDecision {
+    Allow { redirect: Option<String> },
+    Deny(String),
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct HookResponse {
+    redirect: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct HookClient {
+    config: HookConfig,
+    client: reqwest::Client,
+}
+
+impl HookClient {
+    pub fn new(config: HookConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .build()
+            .map_err(|e| StreamError::Hook(format!("Failed to build HTTP client: {}", e)))?;
+
+        Ok(Self { config, client })
+    }
+
+    fn url_for(&self, event: HookEvent) -> Option<&str> {
+        match event {
+            HookEvent::Connect => self.config.on_connect.as_deref(),
+            HookEvent::Publish => self.config.on_publish.as_deref(),
+            HookEvent::Play => self.config.on_play.as_deref(),
+            HookEvent::Done => self.config.on_done.as_deref(),
+        }
+    }
+
+    pub async fn call(&self, request: &HookRequest) -> HookDecision {
+        let Some(url) = self.url_for(request.call) else {
+            return HookDecision::Allow { redirect: None };
+        };
+
+        match self.post(url, request).await {
+            Ok(decision) => decision,
+            Err(e) => {
+                warn!("Hook {:?} to {} failed: {}", request.call, url, e);
+                match self.config.on_failure {
+                    HookFailurePolicy::Allow => HookDecision::Allow { redirect: None },
+                    HookFailurePolicy::Deny => HookDecision::Deny(e.to_string()),
+                }
+            }
+        }
+    }
+
+    async fn post(&self, url: &str, request: &HookRequest) -> Result<HookDecision> {
+        debug!("Calling hook {:?} at {}: {:?}", request.call, url, request);
+
+        let response = self.client.post(url)
+            .json(request)
+            .send()
+            .await
+            .map_err(|e| StreamError::Hook(e.to_string()))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Ok(HookDecision::Deny(format!("{} returned {}", url, status)));
+        }
+
+        let body = response.bytes()
+            .await
+            .map_err(|e| StreamError::Hook(e.to_string()))?;
+
+        // An empty or non-JSON body simply means "allow as-is"
+        let redirect = serde_json::from_slice::<HookResponse>(&body)
+            .unwrap_or_default()
+            .redirect
+            .filter(|name| !name.is_empty());
+
+        Ok(HookDecision::Allow { redirect })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::{http::StatusCode, Filter};
+
+    // Serves `status` and `body` after `delay` from a local stand-in for the hook endpoint
+    async fn serve(status: StatusCode, body: &'static str, delay: Duration) -> String {
+        let route = warp::post()
+            .and(warp::path("hook"))
+            .and(warp::body::json())
+            .then(move |request: serde_json::Value| async move {
+                assert_eq!(request["call"], "publish");
+                assert_eq!(request["args"]["token"], "abc");
+                tokio::time::sleep(delay).await;
+                warp::reply::with_status(body, status)
+            });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        format!("http://{}/hook", addr)
+    }
+
+    fn publish_request() -> HookRequest {
+        let path = StreamPath::new("live", "rtmp://localhost/live", "mystream?token=abc");
+        HookRequest::new(HookEvent::Publish, &path, "127.0.0.1:50000".parse().unwrap(), "rtmp://localhost/live")
+    }
+
+    fn client(url: Option<String>, timeout_ms: u64, on_failure: HookFailurePolicy) -> HookClient {
+        HookClient::new(HookConfig { on_publish: url, timeout_ms, on_failure, ..HookConfig::default() }).unwrap()
+    }
+
+    #[tokio::test]
+    async fn allows_without_hook() {
+        let decision = client(None, 1000, HookFailurePolicy::Deny).call(&publish_request()).await;
+        assert_eq!(decision, HookDecision::Allow { redirect: None });
+    }
+
+    #[tokio::test]
+    async fn allows_on_success_status() {
+        for status in [StatusCode::OK, StatusCode::NO_CONTENT] {
+            let url = serve(status, "", Duration::ZERO).await;
+            let decision = client(Some(url), 1000, HookFailurePolicy::Deny).call(&publish_request()).await;
+            assert_eq!(decision, HookDecision::Allow { redirect: None });
+        }
+    }
+
+    #[tokio::test]
+    async fn denies_on_other_status() {
+        for status in [StatusCode::FOUND, StatusCode::FORBIDDEN, StatusCode::INTERNAL_SERVER_ERROR] {
+            let url = serve(status, "", Duration::ZERO).await;
+            // Denied even when failures are allowed, since the endpoint did answer
+            let decision = client(Some(url), 1000, HookFailurePolicy::Allow).call(&publish_request()).await;
+            assert!(matches!(decision, HookDecision::Deny(_)), "{} gave {:?}", status, decision);
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_redirect_in_body() {
+        let url = serve(StatusCode::OK, r#"{"redirect":"renamed"}"#, Duration::ZERO).await;
+        let decision = client(Some(url), 1000, HookFailurePolicy::Deny).call(&publish_request()).await;
+        assert_eq!(decision, HookDecision::Allow { redirect: Some("renamed".to_string()) });
+
+        let url = serve(StatusCode::OK, r#"{"redirect":""}"#, Duration::ZERO).await;
+        let decision = client(Some(url), 1000, HookFailurePolicy::Deny).call(&publish_request()).await;
+        assert_eq!(decision, HookDecision::Allow { redirect: None });
+    }
+
+    #[tokio::test]
+    async fn applies_failure_policy_on_timeout() {
+        let url = serve(StatusCode::OK, "", Duration::from_secs(2)).await;
+
+        let decision = client(Some(url.clone()), 100, HookFailurePolicy::Deny).call(&publish_request()).await;
+        assert!(matches!(decision, HookDecision::Deny(_)));
+
+        let decision = client(Some(url), 100, HookFailurePolicy::Allow).call(&publish_request()).await;
+        assert_eq!(decision, HookDecision::Allow { redirect: None });
+    }
+
+    #[tokio::test]
+    async fn applies_failure_policy_when_unreachable() {
+        // Bind and drop a listener to get a port nothing listens on
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let url = format!("http://{}/hook", addr);
+
+        let decision = client(Some(url.clone()), 1000, HookFailurePolicy::Deny).call(&publish_request()).await;
+        assert!(matches!(decision, HookDecision::Deny(_)));
+
+        let decision = client(Some(url), 1000, HookFailurePolicy::Allow).call(&publish_request()).await;
+        assert_eq!(decision, HookDecision::Allow { redirect: None });
+    }
+}
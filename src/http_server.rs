@@ -1,18 +1,28 @@
-use std::convert::Infallible;
 use warp::Filter;
 use tracing::info;
 
+use crate::application::{split_hls_path, Applications};
+use crate::hls::low_latency;
+use crate::playout::{self, Playouts};
+use crate::registry::StreamRegistry;
+use crate::rtmp::tunnel::{self, RtmptTunnels};
+use crate::stream_key::StreamKey;
+
 pub struct HttpServer {
     port: u16,
     streams_dir: String,
+    tunnels: RtmptTunnels,
+    registry: StreamRegistry,
+    applications: Applications,
+    playouts: Playouts,
 }
 
 impl HttpServer {
-    pub fn new(port: u16, streams_dir: String) -> Self {
-        Self { port, streams_dir }
+    pub fn new(port: u16, streams_dir: String, tunnels: RtmptTunnels, registry: StreamRegistry, applications: Applications, playouts: Playouts) -> Self {
+        Self { port, streams_dir, tunnels, registry, applications, playouts }
     }
 
-    pub async fn start(&self) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn start(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         info!("HTTP server listening on port {}", self.port);
 
         let routes = self.create_routes();
@@ -31,6 +41,51 @@ impl HttpServer {
         let index = warp::path::end()
             .map(|| warp::reply::html(include_str!("../static/index.html")));
 
+        // HLS playlists and segments written by FFmpeg or the native muxer, with LL-HLS
+        // playlists served from memory. On an edge, a request also starts or keeps alive the
+        // pull of the stream from the origin.
+        let edge_applications = self.applications.clone();
+        let edge_registry = self.registry.clone();
+        let hls_files = warp::path("stream")
+            .and(warp::path::peek())
+            .map(move |path: warp::path::Peek| {
+                let Some((application, rest)) = split_hls_path(&edge_applications, path.as_str()) else {
+                    return;
+                };
+                if let Some(stream_key) = rest.split('/').next().and_then(|key| StreamKey::new(key).ok()) {
+                    application.request_hls(&edge_registry, &stream_key);
+                }
+            })
+            .untuple_one()
+            .and(low_latency::playlist_route(self.applications.clone()).or(warp::fs::dir(self.streams_dir.clone())));
+
+        let registry = self.registry.clone();
+        let streams = warp::path!("streams")
+            .and(warp::get())
+            .map(move || warp::reply::json(&list_streams(&registry)));
+
         index.or(static_files)
+            .or(hls_files)
+            .or(streams)
+            .or(playout::routes(self.playouts.clone()))
+            .or(tunnel::routes(self.tunnels.clone()))
     }
-} 
\ No newline at end of file
+}
+
+fn list_streams(registry: &StreamRegistry) -> serde_json::Value {
+    let streams: Vec<_> = registry
+        .list()
+        .iter()
+        .map(|stream| {
+            serde_json::json!({
+                "app": stream.app,
+                "key": stream.key.as_str(),
+                "subscribers": stream.subscriber_count(),
+                "push": stream.push_status(),
+                "hls": stream.hls_status(),
+            })
+        })
+        .collect();
+
+    serde_json::Value::Array(streams)
+}
@@ -0,0 +1,18 @@
+pub mod access;
+pub mod application;
+pub mod config;
+pub mod dash;
+pub mod error;
+pub mod flv;
+pub mod hls;
+pub mod hooks;
+pub mod http_server;
+pub mod media;
+pub mod pipeline;
+pub mod playout;
+pub mod proxy_protocol;
+pub mod record;
+pub mod registry;
+pub mod relay;
+pub mod rtmp;
+pub mod stream_key;
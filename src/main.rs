@@ -1,9 +1,30 @@
-use tracing::info;
+use tracing::{error, info};
 use anyhow::Result;
+use clap::Parser;
+use std::path::PathBuf;
 
-mod rtmp;
+use streamx::config::Config;
+use streamx::http_server::HttpServer;
+use streamx::playout::{PlayoutConfig, Playouts};
+use streamx::registry::StreamRegistry;
+use streamx::relay::pull::spawn_pulls;
+use streamx::rtmp::{tunnel::RtmptTunnels, RtmpServer};
 
-use rtmp::RtmpServer;
+#[derive(Parser)]
+#[command(name = "streamx", about = "StreamX RTMP/HLS streaming server")]
+struct Args {
+    /// Path to a JSON configuration file
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Publish a local FLV file as a live stream, as <app>/<stream_key>=<file>; repeatable
+    #[arg(long = "playout", value_name = "APP/KEY=FILE")]
+    playouts: Vec<PlayoutConfig>,
+
+    /// Loop the files given with --playout
+    #[arg(long)]
+    loop_playouts: bool,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -12,14 +33,38 @@ async fn main() -> Result<()> {
         .with_max_level(tracing::Level::DEBUG)
         .init();
 
+    let args = Args::parse();
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
     info!("Starting StreamX RTMP server");
 
-    let rtmp_server = RtmpServer::new(1935);
+    let rtmp_port = config.rtmp_port;
+    let registry = StreamRegistry::new(config.max_streams);
+    let rtmp_server = RtmpServer::new(config.clone(), registry.clone())?;
+    spawn_pulls(&config.pulls, &rtmp_server.applications(), &registry)?;
+
+    let playouts = Playouts::new(rtmp_server.applications(), registry.clone());
+    let cli_playouts = args.playouts.into_iter().map(|playout| PlayoutConfig { looping: args.loop_playouts, ..playout });
+    for playout in config.playouts.iter().cloned().chain(cli_playouts) {
+        playouts.start(playout).await?;
+    }
+
+    // HTTP serves the web UI, HLS output and RTMPT tunnels
+    let tunnels = RtmptTunnels::new(rtmp_server.applications(), registry.clone(), config.max_rtmpt_tunnels);
+    let http_server = HttpServer::new(config.http_port, config.streams_dir.to_string_lossy().to_string(), tunnels, registry, rtmp_server.applications(), playouts);
+    tokio::spawn(async move {
+        if let Err(e) = http_server.start().await {
+            error!("HTTP server error: {}", e);
+        }
+    });
     
-    info!("RTMP server starting on port 1935");
-    info!("Connect with: rtmp://localhost:1935/live/STREAM_KEY");
+    info!("RTMP server starting on port {}", rtmp_port);
+    info!("Connect with: rtmp://localhost:{}/live/STREAM_KEY", rtmp_port);
 
     rtmp_server.start().await?;
 
     Ok(())
-} 
\ No newline at end of file
+}
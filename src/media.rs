@@ -0,0 +1,78 @@
+use bytes::Bytes;
+
+// FLV/RTMP codec IDs
+pub const VIDEO_CODEC_AVC: u8 = 7;
+pub const AUDIO_CODEC_AAC: u8 = 10;
+// Enhanced RTMP video: the low nibble of the first byte is a packet type, followed by a FourCC
+pub const VIDEO_EX_HEADER: u8 = 0x80;
+pub const EX_PACKET_SEQUENCE_START: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKind {
+    Audio,
+    Video,
+    Metadata,
+}
+
+impl MediaKind {
+    /// RTMP message type ID, which is also the FLV tag type.
+    pub fn type_id(self) -> u8 {
+        match self {
+            MediaKind::Audio => 8,
+            MediaKind::Video => 9,
+            MediaKind::Metadata => 18,
+        }
+    }
+
+    pub fn from_type_id(type_id: u8) -> Option<Self> {
+        match type_id {
+            8 => Some(MediaKind::Audio),
+            9 => Some(MediaKind::Video),
+            18 => Some(MediaKind::Metadata),
+            _ => None,
+        }
+    }
+}
+
+/// One audio/video/metadata message as carried in RTMP and FLV, with its payload untouched.
+#[derive(Debug, Clone)]
+pub struct MediaPacket {
+    pub kind: MediaKind,
+    pub timestamp: u32,
+    pub payload: Bytes,
+    // Set on the first packet after the stream switched to another source; codec
+    // configuration may change from here on
+    pub discontinuity: bool,
+}
+
+impl MediaPacket {
+    pub fn new(kind: MediaKind, timestamp: u32, payload: Bytes) -> Self {
+        Self { kind, timestamp, payload, discontinuity: false }
+    }
+
+    pub fn is_keyframe(&self) -> bool {
+        self.kind == MediaKind::Video
+            && self.payload.first().is_some_and(|byte| (byte >> 4) & 0x07 == 1)
+    }
+
+    /// Video decoder configuration (AVC, or any Enhanced RTMP codec) or AAC
+    /// AudioSpecificConfig, needed by any decoder that joins mid-stream.
+    pub fn is_sequence_header(&self) -> bool {
+        match self.kind {
+            MediaKind::Video if self.payload.first().is_some_and(|byte| byte & VIDEO_EX_HEADER != 0) => {
+                self.payload.len() >= 5 && self.payload[0] & 0x0F == EX_PACKET_SEQUENCE_START
+            }
+            MediaKind::Video => {
+                self.payload.len() >= 2
+                    && self.payload[0] & 0x0F == VIDEO_CODEC_AVC
+                    && self.payload[1] == 0
+            }
+            MediaKind::Audio => {
+                self.payload.len() >= 2
+                    && self.payload[0] >> 4 == AUDIO_CODEC_AAC
+                    && self.payload[1] == 0
+            }
+            MediaKind::Metadata => false,
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use crate::config::Config;
+use crate::error::{Result, StreamError};
+use crate::flv;
+use crate::hls::{ffmpeg::{self, FfmpegProcess}, FFMPEG_EXIT_TIMEOUT};
+use crate::registry::{LiveStream, Subscription};
+use serde::Deserialize;
+use std::{path::PathBuf, process::Stdio};
+use tokio::{
+    fs,
+    io::{AsyncWriteExt, BufWriter},
+    process::Command,
+    sync::broadcast::error::RecvError,
+    time::timeout,
+};
+use tracing::{debug, error, info, info_span, warn};
+
+// Placeholders of pipeline commands
+const PLACEHOLDERS: [&str; 4] = ["input", "stream_dir", "stream_key", "segment_duration"];
+
+/// An extra FFmpeg command run on every stream an application publishes, e.g. to write
+/// thumbnails. It reads the stream as FLV from `{input}`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PipelineConfig {
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+/// Checks an application's pipelines: names must be unique, and commands may only use the
+/// pipeline placeholders.
+pub fn validate(pipelines: &[PipelineConfig]) -> std::result::Result<(), String> {
+    for (index, pipeline) in pipelines.iter().enumerate() {
+        if pipelines[..index].iter().any(|other| other.name == pipeline.name) {
+            return Err(format!("pipeline '{}' is defined twice", pipeline.name));
+        }
+        ffmpeg::validate_args(&pipeline.args, &PLACEHOLDERS, &[])
+            .map_err(|e| format!("pipeline '{}': {}", pipeline.name, e))?;
+    }
+    Ok(())
+}
+
+/// Starts each pipeline on `stream`. They run until the stream ends, and are not restarted
+/// if FFmpeg exits early.
+pub fn spawn_pipelines(stream: &LiveStream, config: &Config, pipelines: &[PipelineConfig]) {
+    for pipeline in pipelines {
+        let Some(subscription) = stream.subscribe() else {
+            return;
+        };
+
+        let stream_dir = config.stream_dir(&stream.key);
+        let args = ffmpeg::render_args(&pipeline.args, &[
+            ("input", "pipe:0"),
+            ("stream_dir", &stream_dir.to_string_lossy()),
+            ("stream_key", stream.key.as_str()),
+            ("segment_duration", &config.segment_duration.to_string()),
+        ]);
+        let mut cmd = Command::new(&config.ffmpeg_path);
+        cmd.args(ffmpeg::LOG_ARGS).args(args);
+
+        let name = pipeline.name.clone();
+        let span = info_span!("ffmpeg", stream = %stream.key, pipeline = %name);
+        let label = format!("{}/{}", stream.app, stream.key);
+        tokio::spawn(async move {
+            match run(cmd, span, subscription, stream_dir).await {
+                Ok(()) => info!("⏹️ Pipeline '{}' finished for {}", name, label),
+                Err(e) => error!("❌ Pipeline '{}' failed for {}: {}", name, label, e),
+            }
+        });
+    }
+}
+
+async fn run(mut cmd: Command, span: tracing::Span, mut subscription: Subscription, stream_dir: PathBuf) -> Result<()> {
+    fs::create_dir_all(&stream_dir).await?;
+
+    cmd.stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    debug!("Starting pipeline with command: {:?}", cmd);
+    let mut child = cmd.spawn()
+        .map_err(|e| StreamError::Ffmpeg(format!("Failed to start FFmpeg: {}", e)))?;
+    let stdin = child.stdin.take()
+        .ok_or_else(|| StreamError::Ffmpeg("Failed to get FFmpeg stdin".to_string()))?;
+    let mut process = FfmpegProcess::new(child, span, None);
+
+    let mut writer = BufWriter::new(stdin);
+    let fed = feed(&mut writer, &mut subscription).await;
+    let _ = writer.flush().await;
+    drop(writer);
+
+    // A failed write means FFmpeg has already gone away
+    match timeout(FFMPEG_EXIT_TIMEOUT, process.child.wait()).await {
+        Ok(Ok(status)) if !status.success() || fed.is_err() => Err(process.exit_error(status).await),
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => {
+            warn!("FFmpeg did not exit in time");
+            let _ = process.child.kill().await;
+            Ok(())
+        }
+    }
+}
+
+// Writes the stream to FFmpeg as FLV until the stream ends
+async fn feed(writer: &mut BufWriter<tokio::process::ChildStdin>, subscription: &mut Subscription) -> Result<()> {
+    writer.write_all(&flv::encode_header(true, true)).await?;
+    for packet in &subscription.headers {
+        writer.write_all(&flv::encode_tag(packet)).await?;
+    }
+
+    loop {
+        match subscription.receiver.recv().await {
+            Ok(packet) => writer.write_all(&flv::encode_tag(&packet)).await?,
+            Err(RecvError::Lagged(skipped)) => warn!("Pipeline fell behind, skipped {} packets", skipped),
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
@@ -0,0 +1,457 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    fs::File,
+    io::BufReader,
+    sync::watch,
+    time::{sleep_until, Instant},
+};
+use tracing::{info, warn};
+use warp::{http::StatusCode, Filter, Reply};
+
+use crate::application::Applications;
+use crate::error::{Result, StreamError};
+use crate::flv::FlvReader;
+use crate::registry::{Publisher, PublisherRole, StreamRegistry};
+use crate::stream_key::StreamKey;
+
+// Time left between the last packet of a pass over the file and the first packet of the next
+const LOOP_GAP_MS: u64 = 40;
+
+/// A local FLV file published as if it were a live stream, paced by its timestamps.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PlayoutConfig {
+    pub file: PathBuf,
+    pub app: String,
+    pub stream_key: String,
+    // Start over from the beginning at the end of the file
+    #[serde(default, rename = "loop")]
+    pub looping: bool,
+}
+
+// `<app>/<stream_key>=<file>`, as given on the command line
+impl FromStr for PlayoutConfig {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        let (stream, file) = value.split_once('=').ok_or("expected <app>/<stream_key>=<file>")?;
+        let (app, stream_key) = stream.split_once('/').ok_or("expected <app>/<stream_key>=<file>")?;
+
+        Ok(Self {
+            file: PathBuf::from(file),
+            app: app.to_string(),
+            stream_key: stream_key.to_string(),
+            looping: false,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlayoutState {
+    Playing,
+    Finished,
+    Stopped,
+    Failed,
+}
+
+/// What a playout is doing, as reported by the playouts API.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlayoutStatus {
+    #[serde(flatten)]
+    pub config: PlayoutConfig,
+    pub state: PlayoutState,
+    // Completed passes over the file
+    pub passes: u64,
+    pub last_error: Option<String>,
+}
+
+struct Playout {
+    status: Mutex<PlayoutStatus>,
+    stop: watch::Sender<bool>,
+}
+
+impl Playout {
+    fn update(&self, update: impl FnOnce(&mut PlayoutStatus)) {
+        update(&mut self.status.lock().unwrap());
+    }
+}
+
+// (application, stream key)
+type PlayoutId = (String, StreamKey);
+
+/// Files being published as live streams, along with finished ones until they are removed
+/// or started again.
+#[derive(Clone)]
+pub struct Playouts {
+    playouts: Arc<Mutex<HashMap<PlayoutId, Arc<Playout>>>>,
+    applications: Applications,
+    registry: StreamRegistry,
+}
+
+impl Playouts {
+    pub fn new(applications: Applications, registry: StreamRegistry) -> Self {
+        Self {
+            playouts: Arc::new(Mutex::new(HashMap::new())),
+            applications,
+            registry,
+        }
+    }
+
+    /// Starts publishing a file. Fails if it isn't a readable FLV file or the stream is
+    /// already live.
+    pub async fn start(&self, config: PlayoutConfig) -> Result<PlayoutStatus> {
+        let application = self
+            .applications
+            .get(&config.app)
+            .cloned()
+            .ok_or_else(|| StreamError::Config(format!("Playout of {} targets unknown application '{}'", config.file.display(), config.app)))?;
+        let stream_key = StreamKey::new(&config.stream_key)?;
+        open(&config.file).await?;
+
+        let publisher = self.registry.publish(&application.name, &stream_key, PublisherRole::Primary)?;
+        let (stop, stopped) = watch::channel(false);
+        let status = PlayoutStatus {
+            config,
+            state: PlayoutState::Playing,
+            passes: 0,
+            last_error: None,
+        };
+        let playout = Arc::new(Playout {
+            status: Mutex::new(status.clone()),
+            stop,
+        });
+        self.playouts.lock().unwrap().insert((application.name.clone(), stream_key.clone()), playout.clone());
+
+        info!("▶️ Playing {} as {}/{}", status.config.file.display(), application.name, stream_key);
+        if publisher.created {
+            application.start_outputs(&publisher.stream).await;
+        }
+
+        let registry = self.registry.clone();
+        tokio::spawn(async move {
+            run_playout(playout, publisher, registry, stopped).await;
+        });
+
+        Ok(status)
+    }
+
+    /// Stops a playout if it is still running and forgets it. Returns false if there is none.
+    pub fn stop(&self, app: &str, stream_key: &StreamKey) -> bool {
+        let Some(playout) = self.playouts.lock().unwrap().remove(&(app.to_string(), stream_key.clone())) else {
+            return false;
+        };
+
+        playout.stop.send_replace(true);
+        true
+    }
+
+    pub fn list(&self) -> Vec<PlayoutStatus> {
+        self.playouts
+            .lock()
+            .unwrap()
+            .values()
+            .map(|playout| playout.status.lock().unwrap().clone())
+            .collect()
+    }
+}
+
+async fn run_playout(playout: Arc<Playout>, publisher: Publisher, registry: StreamRegistry, mut stopped: watch::Receiver<bool>) {
+    let stream = &publisher.stream;
+
+    tokio::select! {
+        result = play(&playout, &publisher) => match result {
+            Ok(()) => {
+                info!("⏹️ Playout of {}/{} finished", stream.app, stream.key);
+                playout.update(|status| status.state = PlayoutState::Finished);
+            }
+            Err(e) => {
+                warn!("⚠️ Playout of {}/{} failed: {}", stream.app, stream.key, e);
+                playout.update(|status| {
+                    status.state = PlayoutState::Failed;
+                    status.last_error = Some(e.to_string());
+                });
+            }
+        },
+        _ = stopped.wait_for(|stop| *stop) => {
+            info!("⏹️ Playout of {}/{} stopped", stream.app, stream.key);
+            playout.update(|status| status.state = PlayoutState::Stopped);
+        }
+    }
+
+    registry.unpublish(&publisher);
+}
+
+// Sends the file's packets at the pace of their timestamps, which continue across passes
+async fn play(playout: &Playout, publisher: &Publisher) -> Result<()> {
+    let (path, looping) = {
+        let status = playout.status.lock().unwrap();
+        (status.config.file.clone(), status.config.looping)
+    };
+
+    let started = Instant::now();
+    // Stream time, in milliseconds, at which the current pass starts
+    let mut pass_start = 0u64;
+
+    loop {
+        let mut reader = open(&path).await?;
+        let mut first_timestamp = None;
+        let mut pass_end = pass_start;
+
+        while let Some(mut packet) = reader.read_packet().await? {
+            let first = *first_timestamp.get_or_insert(packet.timestamp);
+            let stream_time = pass_start + packet.timestamp.saturating_sub(first) as u64;
+            sleep_until(started + Duration::from_millis(stream_time)).await;
+
+            // RTMP timestamps wrap around after about 49 days
+            packet.timestamp = stream_time as u32;
+            publisher.send(packet);
+            pass_end = pass_end.max(stream_time);
+        }
+
+        if first_timestamp.is_none() {
+            return Err(StreamError::Flv(format!("{} has no media", path.display())));
+        }
+
+        playout.update(|status| status.passes += 1);
+        if !looping {
+            return Ok(());
+        }
+        pass_start = pass_end + LOOP_GAP_MS;
+    }
+}
+
+async fn open(path: &Path) -> Result<FlvReader<BufReader<File>>> {
+    let file = File::open(path)
+        .await
+        .map_err(|e| StreamError::Flv(format!("Failed to open {}: {}", path.display(), e)))?;
+
+    FlvReader::new(BufReader::new(file)).await.map_err(|e| match e {
+        StreamError::Flv(reason) => StreamError::Flv(format!("{}: {}", path.display(), reason)),
+        e => StreamError::Flv(format!("Failed to read {}: {}", path.display(), e)),
+    })
+}
+
+/// `GET /playouts`, `POST /playouts` and `DELETE /playouts/<app>/<stream_key>`.
+pub fn routes(playouts: Playouts) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    let with_playouts = warp::any().map(move || playouts.clone());
+
+    let list = warp::get()
+        .and(warp::path!("playouts"))
+        .and(with_playouts.clone())
+        .map(|playouts: Playouts| warp::reply::json(&playouts.list()).into_response());
+
+    let start = warp::post()
+        .and(warp::path!("playouts"))
+        .and(warp::body::json())
+        .and(with_playouts.clone())
+        .then(|config: PlayoutConfig, playouts: Playouts| async move {
+            match playouts.start(config).await {
+                Ok(status) => warp::reply::with_status(warp::reply::json(&status), StatusCode::CREATED).into_response(),
+                Err(e) => {
+                    let status = match e {
+                        StreamError::StreamAlreadyPublishing(_) | StreamError::MaxStreamsExceeded => StatusCode::CONFLICT,
+                        _ => StatusCode::BAD_REQUEST,
+                    };
+                    let body = serde_json::json!({ "error": e.to_string() });
+                    warp::reply::with_status(warp::reply::json(&body), status).into_response()
+                }
+            }
+        });
+
+    let stop = warp::delete()
+        .and(warp::path!("playouts" / String / String))
+        .and(with_playouts)
+        .map(|app: String, stream_key: String, playouts: Playouts| {
+            let stopped = StreamKey::new(&stream_key).is_ok_and(|stream_key| playouts.stop(&app, &stream_key));
+            if stopped {
+                StatusCode::NO_CONTENT.into_response()
+            } else {
+                StatusCode::NOT_FOUND.into_response()
+            }
+        });
+
+    list.or(start).unify()
+        .or(stop).unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::{build_applications, ApplicationConfig};
+    use crate::config::Config;
+    use crate::flv;
+    use crate::media::{MediaKind, MediaPacket};
+    use bytes::Bytes;
+
+    // A directory of FLV files for a test
+    struct TestFiles {
+        dir: PathBuf,
+    }
+
+    impl TestFiles {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("streamx-playout-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self { dir }
+        }
+
+        // An audio-only file with a packet at each timestamp
+        fn flv(&self, name: &str, timestamps: &[u32]) -> PathBuf {
+            let mut file = flv::encode_header(true, false).to_vec();
+            for &timestamp in timestamps {
+                file.extend_from_slice(&flv::encode_tag(&MediaPacket::new(MediaKind::Audio, timestamp, Bytes::from_static(&[0xAF, 0x01, 0x21]))));
+            }
+            let path = self.dir.join(name);
+            std::fs::write(&path, file).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TestFiles {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn playout(file: PathBuf, looping: bool) -> Arc<Playout> {
+        let status = PlayoutStatus {
+            config: PlayoutConfig { file, app: "live".to_string(), stream_key: "test".to_string(), looping },
+            state: PlayoutState::Playing,
+            passes: 0,
+            last_error: None,
+        };
+        Arc::new(Playout { status: Mutex::new(status), stop: watch::channel(false).0 })
+    }
+
+    fn publisher(registry: &StreamRegistry) -> Publisher {
+        registry.publish("live", &StreamKey::new("test").unwrap(), PublisherRole::Primary).unwrap()
+    }
+
+    #[test]
+    fn parses_command_line_playouts() {
+        let config: PlayoutConfig = "live/news=/media/news.flv".parse().unwrap();
+        assert_eq!((config.app.as_str(), config.stream_key.as_str()), ("live", "news"));
+        assert_eq!(config.file, Path::new("/media/news.flv"));
+        assert!(!config.looping);
+
+        for bad in ["live/news", "news=/media/news.flv", ""] {
+            assert!(bad.parse::<PlayoutConfig>().is_err(), "{:?} should be rejected", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn paces_passes_with_continuing_timestamps() {
+        let files = TestFiles::new("loop");
+        let registry = StreamRegistry::new(10);
+        let publisher = publisher(&registry);
+        let mut subscription = publisher.stream.subscribe().unwrap();
+        let playout = playout(files.flv("loop.flv", &[1000, 1100]), true);
+
+        let started = Instant::now();
+        let task = tokio::spawn({
+            let playout = playout.clone();
+            async move { play(&playout, &publisher).await }
+        });
+        let mut timestamps = Vec::new();
+        for _ in 0..6 {
+            timestamps.push(subscription.receiver.recv().await.unwrap().timestamp);
+        }
+        task.abort();
+
+        // Each pass starts LOOP_GAP_MS after the last packet of the one before
+        assert_eq!(timestamps, [0, 100, 140, 240, 280, 380]);
+        assert!(started.elapsed() >= Duration::from_millis(380));
+        assert!(playout.status.lock().unwrap().passes >= 2);
+    }
+
+    #[tokio::test]
+    async fn plays_once_without_looping() {
+        let files = TestFiles::new("once");
+        let registry = StreamRegistry::new(10);
+        let publisher = publisher(&registry);
+        let mut subscription = publisher.stream.subscribe().unwrap();
+        let playout = playout(files.flv("once.flv", &[0, 40]), false);
+
+        play(&playout, &publisher).await.unwrap();
+        assert_eq!(playout.status.lock().unwrap().passes, 1);
+        assert_eq!(subscription.receiver.recv().await.unwrap().timestamp, 0);
+        assert_eq!(subscription.receiver.recv().await.unwrap().timestamp, 40);
+        assert!(subscription.receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn fails_on_files_without_media() {
+        let files = TestFiles::new("empty");
+        let registry = StreamRegistry::new(10);
+        let publisher = publisher(&registry);
+        let path = files.flv("empty.flv", &[]);
+
+        let error = play(&playout(path.clone(), true), &publisher).await.unwrap_err();
+        assert_eq!(error.to_string(), format!("FLV error: {} has no media", path.display()));
+
+        let error = play(&playout(files.dir.join("missing.flv"), false), &publisher).await.unwrap_err();
+        assert!(error.to_string().contains("Failed to open"), "{}", error);
+    }
+
+    async fn request(playouts: &Playouts, method: &str, path: &str, body: serde_json::Value) -> (StatusCode, serde_json::Value) {
+        let response = warp::test::request()
+            .method(method)
+            .path(path)
+            .json(&body)
+            .reply(&routes(playouts.clone()))
+            .await;
+        let body = serde_json::from_slice(response.body()).unwrap_or(serde_json::Value::Null);
+        (response.status(), body)
+    }
+
+    #[tokio::test]
+    async fn starts_lists_and_stops_playouts() {
+        let files = TestFiles::new("routes");
+        let config = Config {
+            applications: HashMap::from([("live".to_string(), ApplicationConfig { hls: false, ..ApplicationConfig::default() })]),
+            ..Config::default()
+        };
+        let registry = StreamRegistry::new(config.max_streams);
+        let playouts = Playouts::new(build_applications(&config).unwrap(), registry.clone());
+        let file = files.flv("routes.flv", &[0, 1000]);
+        let start = serde_json::json!({ "file": file, "app": "live", "stream_key": "test", "loop": true });
+
+        let (status, body) = request(&playouts, "POST", "/playouts", start.clone()).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!((&body["state"], &body["loop"], &body["passes"]), (&"playing".into(), &true.into(), &0.into()));
+        assert!(registry.get("live", &StreamKey::new("test").unwrap()).is_some());
+
+        let (status, body) = request(&playouts, "GET", "/playouts", serde_json::Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["stream_key"], "test");
+
+        // The stream is already live
+        assert_eq!(request(&playouts, "POST", "/playouts", start).await.0, StatusCode::CONFLICT);
+
+        let unknown_app = serde_json::json!({ "file": file, "app": "vod", "stream_key": "test" });
+        let (status, body) = request(&playouts, "POST", "/playouts", unknown_app).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().contains("unknown application 'vod'"));
+        let missing_file = serde_json::json!({ "file": files.dir.join("missing.flv"), "app": "live", "stream_key": "other" });
+        assert_eq!(request(&playouts, "POST", "/playouts", missing_file).await.0, StatusCode::BAD_REQUEST);
+
+        assert_eq!(request(&playouts, "DELETE", "/playouts/live/test", serde_json::Value::Null).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(request(&playouts, "DELETE", "/playouts/live/test", serde_json::Value::Null).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(request(&playouts, "GET", "/playouts", serde_json::Value::Null).await.1, serde_json::json!([]));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while registry.get("live", &StreamKey::new("test").unwrap()).is_some() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("stopped playout is still published");
+    }
+}
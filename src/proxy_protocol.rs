@@ -0,0 +1,251 @@
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A];
+// "PROXY TCP6 <39> <39> <5> <5>\r\n" is the longest v1 header
+const V1_MAX_LENGTH: usize = 107;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ProxyProtocolConfig {
+    pub enabled: bool,
+    // Peers allowed to send a header. Any peer could forge a client address, so none is
+    // trusted by default
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+impl ProxyProtocolConfig {
+    pub fn expects_header_from(&self, peer: IpAddr) -> bool {
+        self.enabled && self.trusted_proxies.iter().any(|network| network.contains(&peer))
+    }
+}
+
+/// Reads a PROXY protocol v1 or v2 header and returns the original client address.
+///
+/// Returns `None` for `LOCAL`/`UNKNOWN` headers (e.g. load balancer health checks),
+/// in which case the peer address should be used as-is.
+pub async fn read_proxy_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    // Both versions are at least 12 bytes long, so this never over-reads
+    let mut prefix = [0u8; 12];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if prefix.starts_with(b"PROXY ") {
+        read_v1(stream, &prefix).await
+    } else {
+        Err(invalid_data("Missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S, prefix: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let mut line = prefix.to_vec();
+
+    // Read byte by byte so nothing after the header is consumed
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid_data("PROXY v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid_data("PROXY v1 header is not ASCII"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src_ip, _dst_ip, src_port, _dst_port] => {
+            let ip: IpAddr = src_ip.parse().map_err(|_| invalid_data("Invalid PROXY v1 source address"))?;
+            let port: u16 = src_port.parse().map_err(|_| invalid_data("Invalid PROXY v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid_data("Malformed PROXY v1 header")),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+
+    let version = header[0] >> 4;
+    let command = header[0] & 0x0F;
+    let family = header[1];
+    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+
+    if version != 2 {
+        return Err(invalid_data("Unsupported PROXY protocol version"));
+    }
+
+    // The address block (plus any TLVs) must always be consumed
+    let mut addresses = vec![0u8; length];
+    stream.read_exact(&mut addresses).await?;
+
+    // LOCAL connections carry no client address
+    if command == 0x0 {
+        return Ok(None);
+    }
+    if command != 0x1 {
+        return Err(invalid_data("Unsupported PROXY v2 command"));
+    }
+
+    match family {
+        // TCP over IPv4
+        0x11 if length >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // TCP over IPv6
+        0x21 if length >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[0..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        }
+        // UNSPEC, UDP or unix sockets: no usable TCP client address
+        _ => Ok(None),
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    // Parses `input`, returning the address and whatever the parser left unread
+    async fn parse(input: &[u8]) -> (io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut stream = input;
+        let result = read_proxy_header(&mut stream).await;
+        (result, stream.to_vec())
+    }
+
+    #[tokio::test]
+    async fn reads_v1_headers() {
+        let (addr, rest) = parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 1935\r\n\x03").await;
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, [0x03]);
+
+        let (addr, _) = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 1935\r\n").await;
+        assert_eq!(addr.unwrap(), Some("[2001:db8::1]:4000".parse().unwrap()));
+
+        let (addr, rest) = parse(b"PROXY UNKNOWN\r\n\x03").await;
+        assert_eq!(addr.unwrap(), None);
+        assert_eq!(rest, [0x03]);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_v1_headers() {
+        for input in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n"[..],
+            b"PROXY TCP4 192.0.2.999 198.51.100.1 56324 1935\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 99999 1935\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 1935\r\n",
+        ] {
+            assert_eq!(parse(input).await.0.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+
+        // Stops at the longest valid header instead of reading on for a line end
+        let mut too_long = b"PROXY TCP4 ".to_vec();
+        too_long.extend([b'1'; 200]);
+        let (addr, rest) = parse(&too_long).await;
+        assert_eq!(addr.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(rest.len(), too_long.len() - V1_MAX_LENGTH);
+
+        let (addr, _) = parse(b"PROXY TCP4 192.0.2.1").await;
+        assert_eq!(addr.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers() {
+        let ipv4 = [192, 0, 2, 1, 198, 51, 100, 1, 0xDC, 0x04, 0x07, 0x8F];
+        let mut input = v2_header(0x1, 0x11, &ipv4);
+        input.push(0x03);
+        let (addr, rest) = parse(&input).await;
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, [0x03]);
+
+        let mut ipv6 = [0u8; 36];
+        ipv6[0..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6[32..34].copy_from_slice(&4000u16.to_be_bytes());
+        let (addr, _) = parse(&v2_header(0x1, 0x21, &ipv6)).await;
+        assert_eq!(addr.unwrap(), Some("[2001:db8::1]:4000".parse().unwrap()));
+
+        // TLVs after the addresses are skipped
+        let mut with_tlvs = ipv4.to_vec();
+        with_tlvs.extend_from_slice(&[0x04, 0x00, 0x02, 0xAB, 0xCD]);
+        let mut input = v2_header(0x1, 0x11, &with_tlvs);
+        input.push(0x03);
+        let (addr, rest) = parse(&input).await;
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, [0x03]);
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers_without_a_client_address() {
+        // LOCAL, as sent by health checks, still has its address block consumed
+        let mut input = v2_header(0x0, 0x11, &[0; 12]);
+        input.push(0x03);
+        let (addr, rest) = parse(&input).await;
+        assert_eq!(addr.unwrap(), None);
+        assert_eq!(rest, [0x03]);
+
+        // UNSPEC and unix sockets
+        assert_eq!(parse(&v2_header(0x1, 0x00, &[])).await.0.unwrap(), None);
+        assert_eq!(parse(&v2_header(0x1, 0x31, &[0; 216])).await.0.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_v2_headers() {
+        let mut wrong_version = v2_header(0x1, 0x11, &[0; 12]);
+        wrong_version[12] = 0x11;
+        assert_eq!(parse(&wrong_version).await.0.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let unknown_command = v2_header(0x2, 0x11, &[0; 12]);
+        assert_eq!(parse(&unknown_command).await.0.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // Fewer address bytes than the header announces
+        let mut truncated = v2_header(0x1, 0x11, &[0; 12]);
+        truncated.truncate(20);
+        assert_eq!(parse(&truncated).await.0.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(parse(&V2_SIGNATURE[..8]).await.0.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn rejects_connections_without_a_header() {
+        let (addr, _) = parse(&[0x03; 1537]).await;
+        assert_eq!(addr.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn only_trusts_listed_proxies() {
+        let mut config = ProxyProtocolConfig {
+            enabled: true,
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+        };
+        assert!(config.expects_header_from("10.1.2.3".parse().unwrap()));
+        assert!(!config.expects_header_from("192.0.2.1".parse().unwrap()));
+
+        config.trusted_proxies.clear();
+        assert!(!config.expects_header_from("10.1.2.3".parse().unwrap()));
+
+        config.trusted_proxies.push("10.0.0.0/8".parse().unwrap());
+        config.enabled = false;
+        assert!(!config.expects_header_from("10.1.2.3".parse().unwrap()));
+    }
+}
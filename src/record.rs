@@ -0,0 +1,58 @@
+use crate::error::Result;
+use crate::flv;
+use crate::registry::{LiveStream, Subscription};
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncWriteExt, BufWriter},
+    sync::broadcast::error::RecvError,
+};
+use tracing::{error, info, warn};
+
+/// Records a live stream to `<record_dir>/<stream_key>-<unix time>.flv` until it ends.
+pub fn spawn_recorder(stream: &LiveStream, record_dir: &Path) {
+    let Some(subscription) = stream.subscribe() else {
+        return;
+    };
+
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    let path = record_dir.join(format!("{}-{}.flv", stream.key, started));
+
+    tokio::spawn(async move {
+        if let Err(e) = record(subscription, &path).await {
+            error!("Recording to {} failed: {}", path.display(), e);
+        }
+    });
+}
+
+async fn record(mut subscription: Subscription, path: &PathBuf) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    let mut writer = BufWriter::new(File::create(path).await?);
+    info!("⏺️ Recording to {}", path.display());
+
+    writer.write_all(&flv::encode_header(true, true)).await?;
+    for packet in &subscription.headers {
+        writer.write_all(&flv::encode_tag(packet)).await?;
+    }
+
+    loop {
+        match subscription.receiver.recv().await {
+            Ok(packet) => writer.write_all(&flv::encode_tag(&packet)).await?,
+            Err(RecvError::Lagged(skipped)) => warn!("Recorder fell behind, skipped {} packets", skipped),
+            Err(RecvError::Closed) => break,
+        }
+    }
+
+    writer.flush().await?;
+    info!("⏹️ Recording finished: {}", path.display());
+    Ok(())
+}
@@ -0,0 +1,519 @@
+use crate::error::{Result, StreamError};
+use crate::hls::{HlsStatus, SharedHlsStatus};
+use crate::media::{MediaKind, MediaPacket};
+use crate::relay::push::{PushRelay, PushStatus};
+use crate::stream_key::StreamKey;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+use tokio::sync::broadcast;
+use tracing::info;
+
+// Packets a subscriber may fall behind by before it starts losing them
+const BROADCAST_CAPACITY: usize = 1024;
+
+// Latest metadata and codec configuration, replayed to every new subscriber
+#[derive(Default)]
+pub(crate) struct StreamHeaders {
+    metadata: Option<MediaPacket>,
+    video: Option<MediaPacket>,
+    audio: Option<MediaPacket>,
+}
+
+impl StreamHeaders {
+    pub(crate) fn update(&mut self, packet: &MediaPacket) {
+        match packet.kind {
+            MediaKind::Metadata => self.metadata = Some(packet.clone()),
+            MediaKind::Video if packet.is_sequence_header() => self.video = Some(packet.clone()),
+            MediaKind::Audio if packet.is_sequence_header() => self.audio = Some(packet.clone()),
+            _ => {}
+        }
+    }
+
+    pub(crate) fn packets(&self) -> Vec<MediaPacket> {
+        [&self.metadata, &self.video, &self.audio]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PublisherRole {
+    Primary,
+    // Takes over while the primary publisher is gone
+    Backup,
+}
+
+#[derive(Default)]
+struct Source {
+    connected: bool,
+    headers: StreamHeaders,
+    // Added to this source's timestamps so the stream's timeline continues across switches;
+    // unset until the first packet after becoming active
+    timestamp_offset: Option<i64>,
+}
+
+// The primary and backup publishers feeding one stream, only one of which is passed through
+struct Sources {
+    primary: Source,
+    backup: Source,
+    active: PublisherRole,
+    // Set when the active source changed and it has not been passed through yet
+    switching: bool,
+    last_timestamp: u32,
+}
+
+impl Sources {
+    fn get_mut(&mut self, role: PublisherRole) -> &mut Source {
+        match role {
+            PublisherRole::Primary => &mut self.primary,
+            PublisherRole::Backup => &mut self.backup,
+        }
+    }
+
+    fn active(&self) -> &Source {
+        match self.active {
+            PublisherRole::Primary => &self.primary,
+            PublisherRole::Backup => &self.backup,
+        }
+    }
+
+    // Whether a returning primary can take over from the backup at this packet: at a keyframe
+    // after its video configuration, or at its first audio frame if the stream has no video
+    fn primary_ready(&self, packet: &MediaPacket) -> bool {
+        if self.primary.headers.video.is_some() {
+            packet.is_keyframe() && !packet.is_sequence_header()
+        } else {
+            self.backup.headers.video.is_none() && packet.kind == MediaKind::Audio && !packet.is_sequence_header()
+        }
+    }
+
+    fn switch_to(&mut self, role: PublisherRole) {
+        self.active = role;
+        self.switching = true;
+        self.get_mut(role).timestamp_offset = None;
+    }
+}
+
+pub struct Subscription {
+    pub headers: Vec<MediaPacket>,
+    pub receiver: broadcast::Receiver<MediaPacket>,
+}
+
+/// A stream currently being published, fanned out to players and outputs.
+pub struct LiveStream {
+    pub app: String,
+    pub key: StreamKey,
+    sender: Mutex<Option<broadcast::Sender<MediaPacket>>>,
+    sources: Mutex<Sources>,
+    pushes: Mutex<Vec<Arc<PushRelay>>>,
+    hls: Mutex<Option<SharedHlsStatus>>,
+    // Players watching over RTMP, as opposed to outputs such as HLS or recording
+    viewers: AtomicUsize,
+}
+
+impl LiveStream {
+    fn new(app: &str, key: StreamKey, role: PublisherRole) -> Self {
+        let (sender, _) = broadcast::channel(BROADCAST_CAPACITY);
+        let mut sources = Sources {
+            primary: Source::default(),
+            backup: Source::default(),
+            active: role,
+            switching: false,
+            last_timestamp: 0,
+        };
+        // The first publisher's timestamps are used as they are
+        let source = sources.get_mut(role);
+        source.connected = true;
+        source.timestamp_offset = Some(0);
+
+        Self {
+            app: app.to_string(),
+            key,
+            sender: Mutex::new(Some(sender)),
+            sources: Mutex::new(sources),
+            pushes: Mutex::new(Vec::new()),
+            hls: Mutex::new(None),
+            viewers: AtomicUsize::new(0),
+        }
+    }
+
+    fn send(&self, role: PublisherRole, mut packet: MediaPacket) {
+        let mut sources = self.sources.lock().unwrap();
+        sources.get_mut(role).headers.update(&packet);
+        // The backup carries on until the returning primary can be switched to seamlessly
+        if role == PublisherRole::Primary && sources.active == PublisherRole::Backup && sources.primary_ready(&packet) {
+            info!("🔀 {}/{} switching to Primary publisher", self.app, self.key);
+            sources.switch_to(PublisherRole::Primary);
+        }
+        if sources.active != role {
+            return;
+        }
+
+        let mut packets = Vec::new();
+        if sources.switching {
+            // Decoders can only pick up the new source at a keyframe, after its codec configuration
+            let has_video = sources.active().headers.video.is_some();
+            if has_video && !packet.is_keyframe() {
+                return;
+            }
+
+            sources.switching = false;
+            packets = sources.active().headers.packets();
+            if let Some(first) = packets.first_mut() {
+                first.discontinuity = true;
+            }
+        }
+
+        let last_timestamp = sources.last_timestamp;
+        let offset = *sources
+            .get_mut(role)
+            .timestamp_offset
+            .get_or_insert(last_timestamp as i64 - packet.timestamp as i64);
+        packet.timestamp = (packet.timestamp as i64 + offset).max(0) as u32;
+        sources.last_timestamp = packet.timestamp;
+        drop(sources);
+
+        for header in &mut packets {
+            header.timestamp = packet.timestamp;
+        }
+        // A header that triggered the switch has just been queued with the others
+        let is_header = packet.kind == MediaKind::Metadata || packet.is_sequence_header();
+        if packets.is_empty() || !is_header {
+            packets.push(packet);
+        }
+
+        if let Some(sender) = self.sender.lock().unwrap().as_ref() {
+            for packet in packets {
+                // No receivers is fine, the packet is simply dropped
+                let _ = sender.send(packet);
+            }
+        }
+    }
+
+    /// Returns `None` once the publisher has gone away.
+    pub fn subscribe(&self) -> Option<Subscription> {
+        let receiver = self.sender.lock().unwrap().as_ref()?.subscribe();
+        let headers = self.sources.lock().unwrap().active().headers.packets();
+
+        Some(Subscription { headers, receiver })
+    }
+
+    pub fn has_video(&self) -> bool {
+        self.sources.lock().unwrap().active().headers.video.is_some()
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.sender.lock().unwrap().as_ref().map_or(0, |sender| sender.receiver_count())
+    }
+
+    pub fn add_viewer(self: &Arc<Self>) -> Viewer {
+        self.viewers.fetch_add(1, Ordering::Relaxed);
+        Viewer { stream: self.clone() }
+    }
+
+    pub fn viewer_count(&self) -> usize {
+        self.viewers.load(Ordering::Relaxed)
+    }
+
+    pub fn add_push(&self, relay: Arc<PushRelay>) {
+        self.pushes.lock().unwrap().push(relay);
+    }
+
+    pub fn push_status(&self) -> Vec<PushStatus> {
+        self.pushes.lock().unwrap().iter().map(|relay| relay.status()).collect()
+    }
+
+    pub fn set_hls_status(&self, status: SharedHlsStatus) {
+        *self.hls.lock().unwrap() = Some(status);
+    }
+
+    pub fn hls_status(&self) -> Option<HlsStatus> {
+        self.hls.lock().unwrap().as_ref().map(|status| status.lock().unwrap().clone())
+    }
+
+    // Dropping the sender ends every subscription
+    fn close(&self) {
+        self.sender.lock().unwrap().take();
+    }
+}
+
+/// A publisher's handle on the stream it feeds.
+pub struct Publisher {
+    pub stream: Arc<LiveStream>,
+    pub role: PublisherRole,
+    // Whether this publisher brought the stream up, so its outputs still need starting
+    pub created: bool,
+}
+
+impl Publisher {
+    pub fn send(&self, packet: MediaPacket) {
+        self.stream.send(self.role, packet);
+    }
+}
+
+/// Counts as a viewer of the stream for as long as it is held.
+pub struct Viewer {
+    stream: Arc<LiveStream>,
+}
+
+impl Drop for Viewer {
+    fn drop(&mut self) {
+        self.stream.viewers.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// (application, stream key)
+type StreamId = (String, StreamKey);
+
+/// All streams currently being published, keyed by application and stream key.
+#[derive(Clone)]
+pub struct StreamRegistry {
+    streams: Arc<RwLock<HashMap<StreamId, Arc<LiveStream>>>>,
+    max_streams: usize,
+}
+
+impl StreamRegistry {
+    pub fn new(max_streams: usize) -> Self {
+        Self {
+            streams: Arc::new(RwLock::new(HashMap::new())),
+            max_streams,
+        }
+    }
+
+    /// Starts feeding a stream as its primary or backup publisher. A backup only reaches
+    /// subscribers while the primary is gone, or until a returning primary sends a keyframe.
+    pub fn publish(&self, app: &str, key: &StreamKey, role: PublisherRole) -> Result<Publisher> {
+        let mut streams = self.streams.write().unwrap();
+        let id = (app.to_string(), key.clone());
+
+        if let Some(stream) = streams.get(&id) {
+            let mut sources = stream.sources.lock().unwrap();
+            if sources.get_mut(role).connected {
+                return Err(StreamError::StreamAlreadyPublishing(format!("{}/{}", app, key)));
+            }
+
+            sources.get_mut(role).connected = true;
+            info!("📡 {:?} publisher joined {}/{}", role, app, key);
+
+            return Ok(Publisher { stream: stream.clone(), role, created: false });
+        }
+
+        if streams.len() >= self.max_streams {
+            return Err(StreamError::MaxStreamsExceeded);
+        }
+
+        let stream = Arc::new(LiveStream::new(app, key.clone(), role));
+        streams.insert(id, stream.clone());
+        info!("📡 Stream {}/{} is now live ({} active)", app, key, streams.len());
+
+        Ok(Publisher { stream, role, created: true })
+    }
+
+    /// Stops a publisher. The stream fails over to the other publisher if there is one,
+    /// and ends otherwise.
+    pub fn unpublish(&self, publisher: &Publisher) {
+        let stream = &publisher.stream;
+        let mut streams = self.streams.write().unwrap();
+
+        {
+            let mut sources = stream.sources.lock().unwrap();
+            let other = match publisher.role {
+                PublisherRole::Primary => PublisherRole::Backup,
+                PublisherRole::Backup => PublisherRole::Primary,
+            };
+            *sources.get_mut(publisher.role) = Source::default();
+
+            if sources.get_mut(other).connected {
+                info!("📴 {:?} publisher left {}/{}", publisher.role, stream.app, stream.key);
+                if sources.active == publisher.role {
+                    info!("🔀 {}/{} switching to {:?} publisher", stream.app, stream.key, other);
+                    sources.switch_to(other);
+                }
+                return;
+            }
+        }
+
+        stream.close();
+
+        let id = (stream.app.clone(), stream.key.clone());
+        // Only remove the entry if it is still this publisher's
+        if streams.get(&id).is_some_and(|current| Arc::ptr_eq(current, stream)) {
+            streams.remove(&id);
+            info!("📴 Stream {}/{} ended ({} active)", stream.app, stream.key, streams.len());
+        }
+    }
+
+    pub fn get(&self, app: &str, key: &StreamKey) -> Option<Arc<LiveStream>> {
+        self.streams.read().unwrap().get(&(app.to_string(), key.clone())).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Arc<LiveStream>> {
+        self.streams.read().unwrap().values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    const VIDEO_HEADER: &[u8] = &[0x17, 0x00, 0, 0, 0, 0x01];
+    const AUDIO_HEADER: &[u8] = &[0xAF, 0x00, 0x12, 0x10];
+
+    fn packet(kind: MediaKind, timestamp: u32, payload: &[u8]) -> MediaPacket {
+        MediaPacket::new(kind, timestamp, Bytes::copy_from_slice(payload))
+    }
+
+    fn keyframe(timestamp: u32) -> MediaPacket {
+        packet(MediaKind::Video, timestamp, &[0x17, 0x01, 0, 0, 0])
+    }
+
+    fn interframe(timestamp: u32) -> MediaPacket {
+        packet(MediaKind::Video, timestamp, &[0x27, 0x01, 0, 0, 0])
+    }
+
+    fn start(publisher: &Publisher, timestamp: u32) {
+        publisher.send(packet(MediaKind::Video, timestamp, VIDEO_HEADER));
+        publisher.send(packet(MediaKind::Audio, timestamp, AUDIO_HEADER));
+        publisher.send(keyframe(timestamp));
+    }
+
+    fn received(subscription: &mut Subscription) -> Vec<MediaPacket> {
+        let mut packets = Vec::new();
+        while let Ok(packet) = subscription.receiver.try_recv() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    // (payload's first two bytes, timestamp, discontinuity) of each packet
+    fn summary(packets: &[MediaPacket]) -> Vec<([u8; 2], u32, bool)> {
+        packets
+            .iter()
+            .map(|packet| ([packet.payload[0], packet.payload[1]], packet.timestamp, packet.discontinuity))
+            .collect()
+    }
+
+    fn key() -> StreamKey {
+        StreamKey::new("stream").unwrap()
+    }
+
+    #[test]
+    fn only_passes_the_active_publisher() {
+        let registry = StreamRegistry::new(10);
+        let primary = registry.publish("live", &key(), PublisherRole::Primary).unwrap();
+        let backup = registry.publish("live", &key(), PublisherRole::Backup).unwrap();
+        assert!(primary.created && !backup.created);
+        assert!(matches!(
+            registry.publish("live", &key(), PublisherRole::Backup),
+            Err(StreamError::StreamAlreadyPublishing(_))
+        ));
+
+        let mut subscription = primary.stream.subscribe().unwrap();
+        start(&primary, 0);
+        start(&backup, 0);
+        backup.send(interframe(40));
+        primary.send(interframe(40));
+
+        assert_eq!(
+            summary(&received(&mut subscription)),
+            [([0x17, 0x00], 0, false), ([0xAF, 0x00], 0, false), ([0x17, 0x01], 0, false), ([0x27, 0x01], 40, false)]
+        );
+    }
+
+    #[test]
+    fn backup_takes_over_at_its_next_keyframe() {
+        let registry = StreamRegistry::new(10);
+        let primary = registry.publish("live", &key(), PublisherRole::Primary).unwrap();
+        let backup = registry.publish("live", &key(), PublisherRole::Backup).unwrap();
+        start(&primary, 0);
+        start(&backup, 0);
+        primary.send(interframe(5000));
+
+        let mut subscription = primary.stream.subscribe().unwrap();
+        registry.unpublish(&primary);
+        assert!(registry.get("live", &key()).is_some());
+
+        // Frames that depend on an earlier keyframe can't be decoded after the switch
+        backup.send(interframe(120));
+        assert!(received(&mut subscription).is_empty());
+
+        // The backup's headers are replayed, and its timestamps continue the stream's
+        backup.send(keyframe(160));
+        backup.send(interframe(200));
+        assert_eq!(
+            summary(&received(&mut subscription)),
+            [([0x17, 0x00], 5000, true), ([0xAF, 0x00], 5000, false), ([0x17, 0x01], 5000, false), ([0x27, 0x01], 5040, false)]
+        );
+    }
+
+    #[test]
+    fn keeps_the_backup_until_a_returning_primary_sends_a_keyframe() {
+        let registry = StreamRegistry::new(10);
+        let primary = registry.publish("live", &key(), PublisherRole::Primary).unwrap();
+        let backup = registry.publish("live", &key(), PublisherRole::Backup).unwrap();
+        start(&primary, 0);
+        registry.unpublish(&primary);
+        start(&backup, 1000);
+
+        let mut subscription = backup.stream.subscribe().unwrap();
+        let primary = registry.publish("live", &key(), PublisherRole::Primary).unwrap();
+        assert!(!primary.created);
+
+        // Until then the backup carries on, and the primary's headers are held back
+        primary.send(packet(MediaKind::Video, 300, VIDEO_HEADER));
+        primary.send(packet(MediaKind::Audio, 300, AUDIO_HEADER));
+        primary.send(interframe(300));
+        backup.send(interframe(1040));
+        assert_eq!(summary(&received(&mut subscription)), [([0x27, 0x01], 40, false)]);
+
+        primary.send(keyframe(340));
+        backup.send(interframe(1080));
+        primary.send(interframe(380));
+        assert_eq!(
+            summary(&received(&mut subscription)),
+            [([0x17, 0x00], 40, true), ([0xAF, 0x00], 40, false), ([0x17, 0x01], 40, false), ([0x27, 0x01], 80, false)]
+        );
+    }
+
+    #[test]
+    fn switches_audio_only_streams_at_an_audio_frame() {
+        let registry = StreamRegistry::new(10);
+        let backup = registry.publish("radio", &key(), PublisherRole::Backup).unwrap();
+        backup.send(packet(MediaKind::Audio, 0, AUDIO_HEADER));
+
+        let mut subscription = backup.stream.subscribe().unwrap();
+        let primary = registry.publish("radio", &key(), PublisherRole::Primary).unwrap();
+        primary.send(packet(MediaKind::Audio, 0, AUDIO_HEADER));
+        primary.send(packet(MediaKind::Audio, 23, &[0xAF, 0x01, 0x21]));
+        backup.send(packet(MediaKind::Audio, 23, &[0xAF, 0x01, 0x21]));
+
+        assert_eq!(summary(&received(&mut subscription)), [([0xAF, 0x00], 0, true), ([0xAF, 0x01], 0, false)]);
+    }
+
+    #[test]
+    fn ends_when_both_publishers_leave() {
+        let registry = StreamRegistry::new(1);
+        let primary = registry.publish("live", &key(), PublisherRole::Primary).unwrap();
+        let backup = registry.publish("live", &key(), PublisherRole::Backup).unwrap();
+        let mut subscription = primary.stream.subscribe().unwrap();
+
+        registry.unpublish(&backup);
+        assert!(registry.get("live", &key()).is_some());
+        registry.unpublish(&primary);
+
+        assert!(registry.get("live", &key()).is_none());
+        assert!(primary.stream.subscribe().is_none());
+        assert_eq!(subscription.receiver.try_recv().unwrap_err(), TryRecvError::Closed);
+        // The stream no longer counts against max_streams
+        assert!(registry.publish("live", &key(), PublisherRole::Backup).unwrap().created);
+    }
+}
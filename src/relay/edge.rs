@@ -0,0 +1,172 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::watch,
+    time::{interval, timeout},
+};
+use tracing::{info, warn};
+
+use crate::application::Application;
+use crate::error::{Result, StreamError};
+use crate::registry::{LiveStream, PublisherRole, StreamRegistry};
+use crate::rtmp::client::{RtmpClient, RtmpUrl};
+use crate::stream_key::StreamKey;
+
+// Handshake, connect, createStream and play must all finish within this
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// An origin that sends nothing for this long is considered dead
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+// A pulled stream nobody has watched for this long is torn down
+const IDLE_TIMEOUT: Duration = Duration::from_secs(15);
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+struct EdgePull {
+    // Holds the stream once the origin is playing it; dropped without one if the pull fails
+    ready: watch::Receiver<Option<Arc<LiveStream>>>,
+    last_viewed: Mutex<Instant>,
+}
+
+impl EdgePull {
+    fn touch(&self) {
+        *self.last_viewed.lock().unwrap() = Instant::now();
+    }
+}
+
+/// The origin an edge application pulls streams from when they are requested but not live locally.
+pub struct Origin {
+    // Origin application URL; the stream key is appended, e.g. rtmp://origin.example.com/live
+    url: String,
+    pulls: Mutex<HashMap<StreamKey, Arc<EdgePull>>>,
+}
+
+impl Origin {
+    pub fn new(url: &str) -> Result<Self> {
+        let url = url.trim_end_matches('/').to_string();
+        RtmpUrl::parse(&format!("{}/key", url))?;
+
+        Ok(Self {
+            url,
+            pulls: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns the stream once the origin is playing it, starting a pull unless one is running.
+    /// Returns `None` if the origin does not have the stream.
+    pub async fn pull(&self, application: &Arc<Application>, registry: &StreamRegistry, stream_key: &StreamKey) -> Option<Arc<LiveStream>> {
+        let mut ready = {
+            let mut pulls = self.pulls.lock().unwrap();
+            match pulls.get(stream_key) {
+                Some(pull) => {
+                    pull.touch();
+                    pull.ready.clone()
+                }
+                None => {
+                    let (sender, receiver) = watch::channel(None);
+                    let pull = Arc::new(EdgePull {
+                        ready: receiver.clone(),
+                        last_viewed: Mutex::new(Instant::now()),
+                    });
+                    pulls.insert(stream_key.clone(), pull.clone());
+
+                    let url = format!("{}/{}", self.url, stream_key);
+                    let application = application.clone();
+                    let registry = registry.clone();
+                    let stream_key = stream_key.clone();
+                    tokio::spawn(async move {
+                        run_edge_pull(url, application, registry, stream_key, pull, sender).await;
+                    });
+
+                    receiver
+                }
+            }
+        };
+
+        let stream = timeout(CONNECT_TIMEOUT, ready.wait_for(|stream| stream.is_some())).await.ok()?.ok()?;
+        stream.clone()
+    }
+
+    /// Records a request for a pulled stream that doesn't hold a `Viewer`, such as an HLS request.
+    pub fn touch(&self, stream_key: &StreamKey) {
+        if let Some(pull) = self.pulls.lock().unwrap().get(stream_key) {
+            pull.touch();
+        }
+    }
+
+    // Forgets a pull, unless it has already been replaced by a newer one
+    fn finish(&self, stream_key: &StreamKey, pull: &Arc<EdgePull>) {
+        let mut pulls = self.pulls.lock().unwrap();
+        if pulls.get(stream_key).is_some_and(|current| Arc::ptr_eq(current, pull)) {
+            pulls.remove(stream_key);
+        }
+    }
+}
+
+async fn run_edge_pull(url: String, application: Arc<Application>, registry: StreamRegistry, stream_key: StreamKey, pull: Arc<EdgePull>, ready: watch::Sender<Option<Arc<LiveStream>>>) {
+    if let Err(e) = edge_pull(&url, &application, &registry, &stream_key, &pull, ready).await {
+        warn!("⚠️ Pull of {}/{} from origin failed: {}", application.name, stream_key, e);
+    }
+
+    if let Some(origin) = &application.origin {
+        origin.finish(&stream_key, &pull);
+    }
+}
+
+async fn edge_pull(url: &str, application: &Arc<Application>, registry: &StreamRegistry, stream_key: &StreamKey, pull: &EdgePull, ready: watch::Sender<Option<Arc<LiveStream>>>) -> Result<()> {
+    let url = RtmpUrl::parse(url)?;
+    let mut client = timeout(CONNECT_TIMEOUT, async {
+        let mut client = RtmpClient::connect(&url).await?;
+        let stream_id = client.create_stream().await?;
+        client.play(stream_id, &url.stream).await?;
+        Ok::<_, io::Error>(client)
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Timed out starting playback"))??;
+
+    let publisher = registry.publish(&application.name, stream_key, PublisherRole::Primary)?;
+    let stream = publisher.stream.clone();
+    info!("⬇️ Pulling {}/{} from origin {}", application.name, stream_key, url.tc_url());
+    if publisher.created {
+        application.start_outputs(&stream).await;
+    }
+    ready.send_replace(Some(stream.clone()));
+
+    let idle = wait_until_idle(&stream, pull);
+    tokio::pin!(idle);
+    let result = loop {
+        let packet = tokio::select! {
+            packet = timeout(READ_TIMEOUT, client.read_media()) => packet,
+            // Only completes when the pull is closing, so a half-read message is never lost
+            _ = &mut idle => {
+                info!("💤 No viewers left for {}/{}, closing origin pull", application.name, stream_key);
+                break Ok(());
+            }
+        };
+        match packet {
+            Ok(Ok(Some(packet))) => publisher.send(packet),
+            Ok(Ok(None)) => break Ok(()),
+            Ok(Err(e)) => break Err(e.into()),
+            Err(_) => break Err(StreamError::Rtmp("Origin stopped sending media".to_string())),
+        }
+    };
+
+    registry.unpublish(&publisher);
+    result
+}
+
+// Returns once nobody has watched the stream for IDLE_TIMEOUT. Checked on a timer rather than
+// per packet, since an origin stream can go quiet without ending.
+async fn wait_until_idle(stream: &LiveStream, pull: &EdgePull) {
+    let mut idle_check = interval(IDLE_CHECK_INTERVAL);
+    loop {
+        idle_check.tick().await;
+        if stream.viewer_count() > 0 {
+            pull.touch();
+        } else if pull.last_viewed.lock().unwrap().elapsed() >= IDLE_TIMEOUT {
+            return;
+        }
+    }
+}
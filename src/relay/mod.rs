@@ -0,0 +1,29 @@
+use std::time::Duration;
+
+pub mod edge;
+pub mod pull;
+pub mod push;
+
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+// Exponential reconnect delay, reset once a connection gets far enough to carry media
+pub(crate) struct Backoff {
+    delay: Duration,
+}
+
+impl Backoff {
+    pub(crate) fn new() -> Self {
+        Self { delay: INITIAL_RECONNECT_DELAY }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.delay = INITIAL_RECONNECT_DELAY;
+    }
+
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(MAX_RECONNECT_DELAY);
+        delay
+    }
+}
@@ -0,0 +1,91 @@
+use serde::Deserialize;
+use std::{io, sync::Arc, time::Duration};
+use tokio::time::{sleep, timeout};
+use tracing::{info, warn};
+
+use crate::application::{Application, Applications};
+use crate::error::{Result, StreamError};
+use crate::registry::{PublisherRole, StreamRegistry};
+use crate::rtmp::client::{RtmpClient, RtmpUrl};
+use crate::stream_key::StreamKey;
+
+use super::Backoff;
+
+// Handshake, connect, createStream and play must all finish within this
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// An upstream that sends nothing for this long is considered dead
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An upstream stream that is played and republished locally.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PullConfig {
+    // e.g. rtmp://origin.example.com/live/camera1
+    pub url: String,
+    // Local application and stream key to publish under
+    pub app: String,
+    pub stream_key: String,
+}
+
+/// Validates every pull and spawns a task that keeps each one connected.
+pub fn spawn_pulls(pulls: &[PullConfig], applications: &Applications, registry: &StreamRegistry) -> Result<()> {
+    for pull in pulls {
+        let url = RtmpUrl::parse(&pull.url)?;
+        let application = applications
+            .get(&pull.app)
+            .cloned()
+            .ok_or_else(|| StreamError::Config(format!("Pull from {} targets unknown application '{}'", pull.url, pull.app)))?;
+        let stream_key = StreamKey::new(&pull.stream_key)?;
+
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            run_pull(url, application, stream_key, registry).await;
+        });
+    }
+
+    Ok(())
+}
+
+async fn run_pull(url: RtmpUrl, application: Arc<Application>, stream_key: StreamKey, registry: StreamRegistry) {
+    let mut backoff = Backoff::new();
+
+    loop {
+        match pull_once(&url, &application, &stream_key, &registry, &mut backoff).await {
+            Ok(()) => info!("⏹️ Pull from {} into {}/{} ended", url.tc_url(), application.name, stream_key),
+            Err(e) => warn!("⚠️ Pull from {} into {}/{} failed: {}", url.tc_url(), application.name, stream_key, e),
+        }
+
+        let delay = backoff.next_delay();
+        info!("🔁 Reconnecting to {} in {:?}", url.tc_url(), delay);
+        sleep(delay).await;
+    }
+}
+
+async fn pull_once(url: &RtmpUrl, application: &Application, stream_key: &StreamKey, registry: &StreamRegistry, backoff: &mut Backoff) -> Result<()> {
+    let mut client = timeout(CONNECT_TIMEOUT, async {
+        let mut client = RtmpClient::connect(url).await?;
+        let stream_id = client.create_stream().await?;
+        client.play(stream_id, &url.stream).await?;
+        Ok::<_, io::Error>(client)
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Timed out starting playback"))??;
+
+    let publisher = registry.publish(&application.name, stream_key, PublisherRole::Primary)?;
+    backoff.reset();
+    info!("⬇️ Pulling {}/{} into {}/{}", url.tc_url(), url.stream, application.name, stream_key);
+    if publisher.created {
+        application.start_outputs(&publisher.stream).await;
+    }
+
+    let result = loop {
+        match timeout(READ_TIMEOUT, client.read_media()).await {
+            Ok(Ok(Some(packet))) => publisher.send(packet),
+            Ok(Ok(None)) => break Ok(()),
+            Ok(Err(e)) => break Err(e.into()),
+            Err(_) => break Err(StreamError::Rtmp("Upstream stopped sending media".to_string())),
+        }
+    };
+
+    registry.unpublish(&publisher);
+    result
+}
@@ -0,0 +1,174 @@
+use serde::Serialize;
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::broadcast::error::RecvError,
+    time::{sleep, timeout},
+};
+use tracing::{info, warn};
+
+use crate::error::Result;
+use crate::media::MediaKind;
+use crate::registry::LiveStream;
+use crate::rtmp::client::{RtmpClient, RtmpUrl};
+
+use super::Backoff;
+
+// Handshake, connect and publish must all finish within this
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PushState {
+    Connecting,
+    Publishing,
+    Reconnecting,
+    Stopped,
+}
+
+/// What a push target is doing, as reported by the streams API.
+#[derive(Debug, Clone, Serialize)]
+pub struct PushStatus {
+    // tcUrl of the target; the stream name is left out since it is usually a secret key
+    pub target: String,
+    pub state: PushState,
+    pub last_error: Option<String>,
+    pub reconnects: u32,
+    pub packets_sent: u64,
+}
+
+/// One outbound publish of a live stream, reconnecting on its own until the stream ends.
+pub struct PushRelay {
+    status: Mutex<PushStatus>,
+}
+
+impl PushRelay {
+    pub fn status(&self) -> PushStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    fn update(&self, update: impl FnOnce(&mut PushStatus)) {
+        update(&mut self.status.lock().unwrap());
+    }
+}
+
+/// Expands the `{app}` and `{key}` placeholders of a push URL template.
+pub fn render_target(template: &str, app: &str, stream_key: &str) -> String {
+    template.replace("{app}", app).replace("{key}", stream_key)
+}
+
+/// Starts relaying `stream` to each push target, registering their status on the stream.
+pub fn spawn_pushes(stream: &Arc<LiveStream>, targets: &[String]) {
+    for template in targets {
+        let target = render_target(template, &stream.app, stream.key.as_str());
+        let url = match RtmpUrl::parse(&target) {
+            Ok(url) => url,
+            Err(e) => {
+                warn!("⚠️ Not pushing {}/{}: {}", stream.app, stream.key, e);
+                continue;
+            }
+        };
+
+        let relay = Arc::new(PushRelay {
+            status: Mutex::new(PushStatus {
+                target: url.tc_url(),
+                state: PushState::Connecting,
+                last_error: None,
+                reconnects: 0,
+                packets_sent: 0,
+            }),
+        });
+        stream.add_push(relay.clone());
+
+        let stream = stream.clone();
+        tokio::spawn(async move {
+            run_push(url, stream, relay).await;
+        });
+    }
+}
+
+async fn run_push(url: RtmpUrl, stream: Arc<LiveStream>, relay: Arc<PushRelay>) {
+    let mut backoff = Backoff::new();
+
+    loop {
+        relay.update(|status| status.state = PushState::Connecting);
+
+        match push_once(&url, &stream, &relay, &mut backoff).await {
+            Ok(()) => {
+                info!("⏹️ Push of {}/{} to {} finished", stream.app, stream.key, url.tc_url());
+                relay.update(|status| status.state = PushState::Stopped);
+                return;
+            }
+            Err(e) => {
+                warn!("⚠️ Push of {}/{} to {} failed: {}", stream.app, stream.key, url.tc_url(), e);
+                relay.update(|status| {
+                    status.state = PushState::Reconnecting;
+                    status.last_error = Some(e.to_string());
+                    status.reconnects += 1;
+                });
+            }
+        }
+
+        sleep(backoff.next_delay()).await;
+    }
+}
+
+// Returns Ok once the stream has ended, or the error that dropped the connection
+async fn push_once(url: &RtmpUrl, stream: &LiveStream, relay: &PushRelay, backoff: &mut Backoff) -> Result<()> {
+    // Subscribing afresh on every attempt replays the headers to the new connection
+    let Some(mut subscription) = stream.subscribe() else {
+        return Ok(());
+    };
+
+    let (mut client, stream_id) = timeout(CONNECT_TIMEOUT, async {
+        let mut client = RtmpClient::connect(url).await?;
+        let stream_id = client.publish(&url.stream).await?;
+        Ok::<_, io::Error>((client, stream_id))
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Timed out starting publish"))??;
+
+    backoff.reset();
+    relay.update(|status| status.state = PushState::Publishing);
+    info!("⬆️ Pushing {}/{} to {}", stream.app, stream.key, url.tc_url());
+
+    for packet in &subscription.headers {
+        client.send_media(stream_id, packet).await?;
+    }
+
+    // Inter frames are useless to the target until the next keyframe arrives
+    let mut waiting_for_keyframe = stream.has_video();
+    loop {
+        tokio::select! {
+            packet = subscription.receiver.recv() => match packet {
+                Ok(packet) => {
+                    if packet.kind == MediaKind::Video && waiting_for_keyframe {
+                        if !packet.is_keyframe() {
+                            continue;
+                        }
+                        waiting_for_keyframe = false;
+                    }
+                    client.send_media(stream_id, &packet).await?;
+                    relay.update(|status| status.packets_sent += 1);
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Push to {} fell behind, skipped {} packets", url.tc_url(), skipped);
+                    waiting_for_keyframe = true;
+                }
+                Err(RecvError::Closed) => break,
+            },
+            read = client.fill_buffer() => {
+                if read? == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Target closed the connection").into());
+                }
+                client.process_buffered().await?;
+            }
+        }
+    }
+
+    client.unpublish(stream_id, &url.stream).await?;
+    Ok(())
+}
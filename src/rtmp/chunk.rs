@@ -0,0 +1,258 @@
+use super::protocol::{MessageType, RtmpHeader, RtmpMessage};
+use bytes::{Buf, Bytes, BytesMut};
+use std::collections::HashMap;
+use std::io;
+
+pub const DEFAULT_CHUNK_SIZE: usize = 128;
+// Chunks never need to be larger than the largest message (24-bit length)
+pub const MAX_CHUNK_SIZE: usize = 0xFFFFFF;
+const EXTENDED_TIMESTAMP: u32 = 0xFFFFFF;
+// Upper bound for a single reassembled message, to stop a peer from exhausting memory
+const MAX_MESSAGE_LENGTH: u32 = 16 * 1024 * 1024;
+
+// Header fields of the last chunk seen on a chunk stream, plus the message being assembled
+#[derive(Default)]
+struct ChunkStreamState {
+    timestamp: u32,
+    timestamp_delta: u32,
+    message_length: u32,
+    message_type_id: u8,
+    message_stream_id: u32,
+    extended_timestamp: bool,
+    payload: BytesMut,
+}
+
+/// Reassembles RTMP messages from interleaved chunks.
+pub struct ChunkDecoder {
+    chunk_size: usize,
+    streams: HashMap<u32, ChunkStreamState>,
+}
+
+impl Default for ChunkDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChunkDecoder {
+    pub fn new() -> Self {
+        Self {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            streams: HashMap::new(),
+        }
+    }
+
+    pub fn set_chunk_size(&mut self, chunk_size: usize) -> io::Result<()> {
+        self.chunk_size = check_chunk_size(chunk_size)?;
+        Ok(())
+    }
+
+    /// Consumes at most one chunk from `buffer`. Returns a message once its last
+    /// chunk has arrived, and `Ok(None)` when more data (or more chunks) are needed.
+    pub fn decode(&mut self, buffer: &mut BytesMut) -> io::Result<Option<RtmpMessage>> {
+        let Some((header, header_size)) = RtmpHeader::parse(buffer) else {
+            return Ok(None);
+        };
+
+        let previous = self.streams.get(&header.chunk_stream_id);
+        if header.format != 0 && previous.is_none() {
+            return Err(invalid_data(format!("Chunk stream {} continued before it started", header.chunk_stream_id)));
+        }
+
+        // Type 3 chunks repeat the previous chunk's extended timestamp field
+        let has_extended = match header.format {
+            3 => previous.is_some_and(|state| state.extended_timestamp),
+            _ => header.timestamp == EXTENDED_TIMESTAMP,
+        };
+        let extended_size = if has_extended { 4 } else { 0 };
+        if buffer.len() < header_size + extended_size {
+            return Ok(None);
+        }
+
+        let raw_timestamp = if has_extended {
+            let bytes = &buffer[header_size..header_size + 4];
+            u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+        } else {
+            header.timestamp
+        };
+
+        let (message_length, pending) = match (header.format, previous) {
+            (0 | 1, _) => (header.message_length, 0),
+            (_, Some(state)) => (state.message_length, state.payload.len()),
+            (_, None) => unreachable!(),
+        };
+        if message_length > MAX_MESSAGE_LENGTH {
+            return Err(invalid_data(format!("Message of {} bytes exceeds limit", message_length)));
+        }
+
+        // Continuation chunks of a partially received message carry no new header values
+        let continuation = header.format == 3 && pending > 0;
+        let payload_size = (message_length as usize - if continuation { pending } else { 0 }).min(self.chunk_size);
+        let total_size = header_size + extended_size + payload_size;
+        if buffer.len() < total_size {
+            return Ok(None);
+        }
+
+        let state = self.streams.entry(header.chunk_stream_id).or_default();
+        if !continuation {
+            match header.format {
+                0 => {
+                    state.timestamp = raw_timestamp;
+                    state.timestamp_delta = 0;
+                    state.message_length = header.message_length;
+                    state.message_type_id = header.message_type_id;
+                    state.message_stream_id = header.message_stream_id;
+                }
+                1 => {
+                    state.timestamp_delta = raw_timestamp;
+                    state.timestamp = state.timestamp.wrapping_add(raw_timestamp);
+                    state.message_length = header.message_length;
+                    state.message_type_id = header.message_type_id;
+                }
+                2 => {
+                    state.timestamp_delta = raw_timestamp;
+                    state.timestamp = state.timestamp.wrapping_add(raw_timestamp);
+                }
+                _ => {
+                    // A new message with every field (including the delta) repeated
+                    state.timestamp = state.timestamp.wrapping_add(state.timestamp_delta);
+                }
+            }
+            state.extended_timestamp = has_extended;
+            state.payload.clear();
+        }
+
+        buffer.advance(header_size + extended_size);
+        state.payload.extend_from_slice(&buffer[..payload_size]);
+        buffer.advance(payload_size);
+
+        if state.payload.len() < state.message_length as usize {
+            return Ok(None);
+        }
+
+        let payload = state.payload.split().freeze();
+        Ok(Some(RtmpMessage {
+            message_type: MessageType::from(state.message_type_id),
+            message_type_id: state.message_type_id,
+            timestamp: state.timestamp,
+            message_stream_id: state.message_stream_id,
+            payload,
+        }))
+    }
+}
+
+/// Splits outgoing messages into chunks of the negotiated size.
+pub struct ChunkEncoder {
+    chunk_size: usize,
+}
+
+impl Default for ChunkEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChunkEncoder {
+    pub fn new() -> Self {
+        Self { chunk_size: DEFAULT_CHUNK_SIZE }
+    }
+
+    pub fn set_chunk_size(&mut self, chunk_size: usize) -> io::Result<()> {
+        self.chunk_size = check_chunk_size(chunk_size)?;
+        Ok(())
+    }
+
+    /// Encodes a message as a type 0 chunk followed by type 3 continuations.
+    /// Only chunk stream IDs 2-63 are used by StreamX.
+    pub fn encode(&self, chunk_stream_id: u8, message_type_id: u8, timestamp: u32, message_stream_id: u32, payload: &[u8]) -> Bytes {
+        let mut chunk = BytesMut::with_capacity(payload.len() + 16 + payload.len() / self.chunk_size);
+        let extended = timestamp >= EXTENDED_TIMESTAMP;
+
+        // Chunk basic header: fmt=0
+        chunk.extend_from_slice(&[chunk_stream_id & 0x3f]);
+
+        // Message header (11 bytes for type 0)
+        let header_timestamp = if extended { EXTENDED_TIMESTAMP } else { timestamp };
+        chunk.extend_from_slice(&header_timestamp.to_be_bytes()[1..]); // timestamp (3 bytes)
+        chunk.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]); // message length (3 bytes)
+        chunk.extend_from_slice(&[message_type_id]);
+        chunk.extend_from_slice(&message_stream_id.to_le_bytes()); // message stream ID (little endian)
+        if extended {
+            chunk.extend_from_slice(&timestamp.to_be_bytes());
+        }
+
+        for (index, piece) in payload.chunks(self.chunk_size).enumerate() {
+            if index > 0 {
+                // Type 3 continuation header
+                chunk.extend_from_slice(&[0xC0 | (chunk_stream_id & 0x3f)]);
+                if extended {
+                    chunk.extend_from_slice(&timestamp.to_be_bytes());
+                }
+            }
+            chunk.extend_from_slice(piece);
+        }
+
+        chunk.freeze()
+    }
+}
+
+fn check_chunk_size(chunk_size: usize) -> io::Result<usize> {
+    if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+        return Err(invalid_data(format!("Invalid chunk size {}", chunk_size)));
+    }
+    Ok(chunk_size)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_messages_across_chunk_sizes() {
+        let payload: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        for chunk_size in [1, 128, 999, 1000, 4096] {
+            for timestamp in [0, 40, EXTENDED_TIMESTAMP, u32::MAX] {
+                let mut encoder = ChunkEncoder::new();
+                let mut decoder = ChunkDecoder::new();
+                encoder.set_chunk_size(chunk_size).unwrap();
+                decoder.set_chunk_size(chunk_size).unwrap();
+
+                let mut buffer = BytesMut::from(&encoder.encode(6, 9, timestamp, 1, &payload)[..]);
+                let message = loop {
+                    if let Some(message) = decoder.decode(&mut buffer).unwrap() {
+                        break message;
+                    }
+                };
+                assert!(buffer.is_empty());
+                assert_eq!(message.message_type, MessageType::Video);
+                assert_eq!((message.timestamp, message.message_stream_id), (timestamp, 1));
+                assert_eq!(message.payload[..], payload[..], "chunk size {}", chunk_size);
+            }
+        }
+    }
+
+    #[test]
+    fn waits_for_the_whole_chunk() {
+        let encoded = ChunkEncoder::new().encode(3, 20, 0, 0, &[1, 2, 3]);
+        let mut decoder = ChunkDecoder::new();
+
+        let mut buffer = BytesMut::from(&encoded[..encoded.len() - 1]);
+        assert!(decoder.decode(&mut buffer).unwrap().is_none());
+        buffer.extend_from_slice(&encoded[encoded.len() - 1..]);
+        assert_eq!(decoder.decode(&mut buffer).unwrap().unwrap().payload[..], [1, 2, 3]);
+    }
+
+    #[test]
+    fn rejects_invalid_chunk_sizes() {
+        for chunk_size in [0, MAX_CHUNK_SIZE + 1] {
+            assert!(ChunkDecoder::new().set_chunk_size(chunk_size).is_err());
+            assert!(ChunkEncoder::new().set_chunk_size(chunk_size).is_err());
+        }
+        assert!(ChunkDecoder::new().set_chunk_size(MAX_CHUNK_SIZE).is_ok());
+        assert!(ChunkEncoder::new().set_chunk_size(1).is_ok());
+    }
+}
@@ -0,0 +1,410 @@
+use bytes::BytesMut;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{debug, info};
+
+use crate::error::{Result, StreamError};
+use crate::media::{MediaKind, MediaPacket};
+
+use super::chunk::{ChunkDecoder, ChunkEncoder};
+use super::handshake::perform_client_handshake;
+use super::protocol::{MessageType, RtmpMessage, CommandResponse, create_connect_command, create_createstream_command, create_play_command, create_releasestream_command, create_fcpublish_command, create_publish_command, create_fcunpublish_command, create_deletestream_command, parse_command_response, strip_set_data_frame};
+
+const DEFAULT_RTMP_PORT: u16 = 1935;
+// Chunk size announced to the server right after the handshake
+const CLIENT_CHUNK_SIZE: usize = 4096;
+
+// Chunk stream IDs used for outgoing messages
+const CONTROL_CHUNK_STREAM: u8 = 2;
+const COMMAND_CHUNK_STREAM: u8 = 3;
+const AUDIO_CHUNK_STREAM: u8 = 4;
+const DATA_CHUNK_STREAM: u8 = 5;
+const VIDEO_CHUNK_STREAM: u8 = 6;
+
+/// A remote stream address, `rtmp://host[:port]/app[/instance]/stream[?query]`.
+#[derive(Debug, Clone)]
+pub struct RtmpUrl {
+    pub host: String,
+    pub port: u16,
+    pub app: String,
+    // Last path segment plus any query, sent as the play/publish stream name
+    pub stream: String,
+}
+
+impl RtmpUrl {
+    pub fn parse(url: &str) -> Result<Self> {
+        let invalid = |reason: &str| StreamError::Rtmp(format!("Invalid RTMP URL {}: {}", url, reason));
+
+        let parsed = url::Url::parse(url).map_err(|e| invalid(&e.to_string()))?;
+        match parsed.scheme() {
+            "rtmp" => {}
+            // No TLS on outgoing connections; a local stunnel can wrap rtmp:// instead
+            "rtmps" => return Err(invalid("rtmps:// is not supported, only rtmp://")),
+            _ => return Err(invalid("only rtmp:// is supported")),
+        }
+        let host = parsed.host_str().ok_or_else(|| invalid("missing host"))?.to_string();
+
+        let path = parsed.path().trim_start_matches('/');
+        let (app, stream) = path.rsplit_once('/').ok_or_else(|| invalid("expected /app/stream"))?;
+        if app.is_empty() || stream.is_empty() {
+            return Err(invalid("expected /app/stream"));
+        }
+        let stream = match parsed.query() {
+            Some(query) => format!("{}?{}", stream, query),
+            None => stream.to_string(),
+        };
+
+        Ok(Self {
+            host,
+            port: parsed.port().unwrap_or(DEFAULT_RTMP_PORT),
+            app: app.to_string(),
+            stream,
+        })
+    }
+
+    pub fn tc_url(&self) -> String {
+        format!("rtmp://{}:{}/{}", self.host, self.port, self.app)
+    }
+}
+
+/// The client side of an RTMP connection, used to pull streams from and push streams to other servers.
+pub struct RtmpClient<S> {
+    socket: S,
+    decoder: ChunkDecoder,
+    encoder: ChunkEncoder,
+    read_buffer: BytesMut,
+    next_transaction_id: f64,
+    // Acknowledgement window announced by the server
+    peer_window_size: u32,
+    bytes_received: u64,
+    bytes_acknowledged: u64,
+}
+
+impl RtmpClient<TcpStream> {
+    /// Opens a TCP connection to `url`, performs the handshake and connects to its application.
+    pub async fn connect(url: &RtmpUrl) -> io::Result<Self> {
+        let socket = TcpStream::connect((url.host.as_str(), url.port)).await?;
+        socket.set_nodelay(true)?;
+        Self::connect_with(socket, url).await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> RtmpClient<S> {
+    pub async fn connect_with(mut socket: S, url: &RtmpUrl) -> io::Result<Self> {
+        perform_client_handshake(&mut socket).await?;
+
+        let mut client = Self {
+            socket,
+            decoder: ChunkDecoder::new(),
+            encoder: ChunkEncoder::new(),
+            read_buffer: BytesMut::with_capacity(8192),
+            // connect is always transaction 1
+            next_transaction_id: 2.0,
+            peer_window_size: 0,
+            bytes_received: 0,
+            bytes_acknowledged: 0,
+        };
+
+        client.send_control_message(1, &(CLIENT_CHUNK_SIZE as u32).to_be_bytes()).await?;
+        client.encoder.set_chunk_size(CLIENT_CHUNK_SIZE)?;
+
+        client.send_command(0, &create_connect_command(&url.app, &url.tc_url())).await?;
+        client.expect_result(1.0).await?;
+        info!("✅ Connected to {}", url.tc_url());
+
+        Ok(client)
+    }
+
+    /// Returns the message stream ID allocated by the server.
+    pub async fn create_stream(&mut self) -> io::Result<u32> {
+        let transaction_id = self.next_transaction_id();
+        self.send_command(0, &create_createstream_command(transaction_id)).await?;
+        let response = self.expect_result(transaction_id).await?;
+
+        response
+            .value
+            .map(|stream_id| stream_id as u32)
+            .ok_or_else(|| protocol_error("createStream result without a stream ID".to_string()))
+    }
+
+    /// Starts playing `stream_name` on `stream_id` and waits for `NetStream.Play.Start`.
+    pub async fn play(&mut self, stream_id: u32, stream_name: &str) -> io::Result<()> {
+        self.send_command(stream_id, &create_play_command(stream_name)).await?;
+
+        loop {
+            let response = self.expect_status().await?;
+            match response.code.as_deref() {
+                Some("NetStream.Play.Start") => return Ok(()),
+                // Sent before Play.Start by some servers
+                Some("NetStream.Play.Reset") => continue,
+                code => return Err(protocol_error(format!("play {} failed: {}", stream_name, code.unwrap_or("no status code")))),
+            }
+        }
+    }
+
+    /// Announces and starts publishing `stream_name` the way FMLE and OBS do, waiting for
+    /// `NetStream.Publish.Start`. Returns the message stream ID to send media on.
+    pub async fn publish(&mut self, stream_name: &str) -> io::Result<u32> {
+        // Neither has a result every server sends, so they are not waited for
+        let transaction_id = self.next_transaction_id();
+        self.send_command(0, &create_releasestream_command(transaction_id, stream_name)).await?;
+        let transaction_id = self.next_transaction_id();
+        self.send_command(0, &create_fcpublish_command(transaction_id, stream_name)).await?;
+
+        let stream_id = self.create_stream().await?;
+        self.send_command(stream_id, &create_publish_command(stream_name)).await?;
+
+        loop {
+            let response = self.expect_status().await?;
+            match response.code.as_deref() {
+                Some("NetStream.Publish.Start") => return Ok(stream_id),
+                // e.g. onFCPublish
+                Some(code) if !code.starts_with("NetStream.Publish.") => continue,
+                code => return Err(protocol_error(format!("publish failed: {}", code.unwrap_or("no status code")))),
+            }
+        }
+    }
+
+    pub async fn send_media(&mut self, stream_id: u32, packet: &MediaPacket) -> io::Result<()> {
+        let (chunk_stream_id, payload) = match packet.kind {
+            MediaKind::Audio => (AUDIO_CHUNK_STREAM, packet.payload.clone()),
+            MediaKind::Video => (VIDEO_CHUNK_STREAM, packet.payload.clone()),
+            // Servers expect metadata from a publisher wrapped in @setDataFrame
+            MediaKind::Metadata => {
+                let mut payload = BytesMut::with_capacity(16 + packet.payload.len());
+                payload.extend_from_slice(&[0x02, 0x00, 0x0D]);
+                payload.extend_from_slice(b"@setDataFrame");
+                payload.extend_from_slice(&packet.payload);
+                (DATA_CHUNK_STREAM, payload.freeze())
+            }
+        };
+
+        let chunk = self.encoder.encode(chunk_stream_id, packet.kind.type_id(), packet.timestamp, stream_id, &payload);
+        self.write(&chunk).await
+    }
+
+    /// Ends a publish started with `publish`.
+    pub async fn unpublish(&mut self, stream_id: u32, stream_name: &str) -> io::Result<()> {
+        let transaction_id = self.next_transaction_id();
+        self.send_command(0, &create_fcunpublish_command(transaction_id, stream_name)).await?;
+        let transaction_id = self.next_transaction_id();
+        self.send_command(0, &create_deletestream_command(transaction_id, stream_id)).await
+    }
+
+    /// Next audio, video or metadata message from the stream being played.
+    /// Returns `Ok(None)` once the server disconnects or the upstream publisher stops.
+    pub async fn read_media(&mut self) -> io::Result<Option<MediaPacket>> {
+        loop {
+            let Some(message) = self.read_message().await? else {
+                return Ok(None);
+            };
+
+            match message.message_type {
+                MessageType::Audio => return Ok(Some(MediaPacket::new(MediaKind::Audio, message.timestamp, message.payload))),
+                MessageType::Video => return Ok(Some(MediaPacket::new(MediaKind::Video, message.timestamp, message.payload))),
+                // AMF3 data messages are not supported
+                MessageType::Data if message.message_type_id == 18 => {
+                    if let Some(payload) = strip_set_data_frame(&message.payload) {
+                        return Ok(Some(MediaPacket::new(MediaKind::Metadata, message.timestamp, payload)));
+                    }
+                }
+                MessageType::Command => {
+                    let Some(response) = parse_command_response(&command_payload(&message)) else {
+                        continue;
+                    };
+                    match response.code.as_deref() {
+                        Some("NetStream.Play.UnpublishNotify") | Some("NetStream.Play.Stop") => {
+                            info!("⏹️ Upstream stream stopped");
+                            return Ok(None);
+                        }
+                        code => debug!("Received '{}' ({:?}) while playing", response.name, code),
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn next_transaction_id(&mut self) -> f64 {
+        let transaction_id = self.next_transaction_id;
+        self.next_transaction_id += 1.0;
+        transaction_id
+    }
+
+    // Waits for the _result of `transaction_id`, failing on its _error
+    async fn expect_result(&mut self, transaction_id: f64) -> io::Result<CommandResponse> {
+        loop {
+            let response = self.next_command().await?;
+            if response.transaction_id != transaction_id {
+                continue;
+            }
+
+            match response.name.as_str() {
+                "_result" => return Ok(response),
+                "_error" => return Err(protocol_error(format!("request rejected: {}", response.code.as_deref().unwrap_or("no status code")))),
+                _ => {}
+            }
+        }
+    }
+
+    // Waits for the next onStatus, failing if it reports an error
+    async fn expect_status(&mut self) -> io::Result<CommandResponse> {
+        loop {
+            let response = self.next_command().await?;
+            if response.name != "onStatus" {
+                continue;
+            }
+
+            if response.level.as_deref() == Some("error") {
+                return Err(protocol_error(format!("server error: {}", response.code.as_deref().unwrap_or("no status code"))));
+            }
+            return Ok(response);
+        }
+    }
+
+    // Skips everything but commands until one arrives
+    async fn next_command(&mut self) -> io::Result<CommandResponse> {
+        loop {
+            let Some(message) = self.read_message().await? else {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Server closed the connection"));
+            };
+
+            if message.message_type == MessageType::Command {
+                if let Some(response) = parse_command_response(&command_payload(&message)) {
+                    debug!("📞 Received '{}' for transaction {}", response.name, response.transaction_id);
+                    return Ok(response);
+                }
+            }
+        }
+    }
+
+    // Next message that isn't protocol control, or None at EOF
+    async fn read_message(&mut self) -> io::Result<Option<RtmpMessage>> {
+        loop {
+            if let Some(message) = self.next_buffered_message().await? {
+                return Ok(Some(message));
+            }
+            if self.fill_buffer().await? == 0 {
+                return Ok(None);
+            }
+        }
+    }
+
+    // Decodes buffered chunks, applying control messages, until another message is complete
+    async fn next_buffered_message(&mut self) -> io::Result<Option<RtmpMessage>> {
+        self.acknowledge().await?;
+
+        loop {
+            let buffered = self.read_buffer.len();
+            if let Some(message) = self.decoder.decode(&mut self.read_buffer)? {
+                if let Some(message) = self.handle_control(message).await? {
+                    return Ok(Some(message));
+                }
+                continue;
+            }
+            if self.read_buffer.len() == buffered {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Reads whatever the server sends next into the buffer, returning 0 at EOF.
+    /// Cancel safe, so it can be raced against the media being published.
+    pub async fn fill_buffer(&mut self) -> io::Result<usize> {
+        let bytes_read = self.socket.read_buf(&mut self.read_buffer).await?;
+        self.bytes_received += bytes_read as u64;
+        Ok(bytes_read)
+    }
+
+    /// Handles the messages buffered by `fill_buffer` while publishing, failing if the
+    /// server reports an error status.
+    pub async fn process_buffered(&mut self) -> io::Result<()> {
+        while let Some(message) = self.next_buffered_message().await? {
+            if message.message_type != MessageType::Command {
+                continue;
+            }
+            let Some(response) = parse_command_response(&command_payload(&message)) else {
+                continue;
+            };
+            if response.level.as_deref() == Some("error") {
+                return Err(protocol_error(format!("server error: {}", response.code.as_deref().unwrap_or("no status code"))));
+            }
+            debug!("Received '{}' ({:?}) while publishing", response.name, response.code);
+        }
+
+        Ok(())
+    }
+
+    // Applies protocol control messages, passing every other message through
+    async fn handle_control(&mut self, message: RtmpMessage) -> io::Result<Option<RtmpMessage>> {
+        let payload = &message.payload;
+        match message.message_type {
+            MessageType::SetChunkSize if payload.len() >= 4 => {
+                let chunk_size = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) & 0x7FFF_FFFF;
+                debug!("📏 Server chunk size: {}", chunk_size);
+                self.decoder.set_chunk_size(chunk_size as usize)?;
+            }
+            MessageType::WindowAcknowledgementSize if payload.len() >= 4 => {
+                self.peer_window_size = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+            }
+            // Ping Request (6) must be answered with a Ping Response (7) carrying the same timestamp
+            MessageType::UserControl if payload.len() >= 6 && payload[..2] == [0, 6] => {
+                let mut response = 7u16.to_be_bytes().to_vec();
+                response.extend_from_slice(&payload[2..6]);
+                self.send_control_message(4, &response).await?;
+            }
+            MessageType::SetChunkSize
+            | MessageType::WindowAcknowledgementSize
+            | MessageType::UserControl
+            | MessageType::SetPeerBandwidth
+            | MessageType::Acknowledgement
+            | MessageType::Abort => {}
+            _ => return Ok(Some(message)),
+        }
+
+        Ok(None)
+    }
+
+    async fn acknowledge(&mut self) -> io::Result<()> {
+        if self.peer_window_size > 0
+            && self.bytes_received - self.bytes_acknowledged >= self.peer_window_size as u64
+        {
+            self.bytes_acknowledged = self.bytes_received;
+            // The sequence number wraps at 32 bits
+            let sequence_number = self.bytes_received as u32;
+            self.send_control_message(3, &sequence_number.to_be_bytes()).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn send_control_message(&mut self, message_type: u8, payload: &[u8]) -> io::Result<()> {
+        let chunk = self.encoder.encode(CONTROL_CHUNK_STREAM, message_type, 0, 0, payload);
+        self.write(&chunk).await
+    }
+
+    async fn send_command(&mut self, message_stream_id: u32, payload: &[u8]) -> io::Result<()> {
+        // Message type 20 = AMF0 command
+        let chunk = self.encoder.encode(COMMAND_CHUNK_STREAM, 20, 0, message_stream_id, payload);
+        self.write(&chunk).await
+    }
+
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.socket.write_all(data).await?;
+        self.socket.flush().await
+    }
+}
+
+// AMF3 commands are AMF0 values behind a single format byte
+fn command_payload(message: &RtmpMessage) -> bytes::Bytes {
+    if message.message_type_id == 17 && !message.payload.is_empty() {
+        message.payload.slice(1..)
+    } else {
+        message.payload.clone()
+    }
+}
+
+fn protocol_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -1,13 +1,8 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use sha2::{Sha256, Digest};
-use hmac::{Hmac, Mac};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tracing::{info, debug};
 use std::io;
 
-type HmacSha256 = Hmac<Sha256>;
-
-pub async fn perform_handshake(stream: &mut TcpStream) -> Result<(), io::Error> {
+pub async fn perform_handshake<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<(), io::Error> {
     // Read C0 (1 byte)
     let mut c0 = [0u8; 1];
     stream.read_exact(&mut c0).await?;
@@ -39,8 +34,8 @@ pub async fn perform_handshake(stream: &mut TcpStream) -> Result<(), io::Error>
     s1[4..8].copy_from_slice(&[0, 0, 0, 0]);
     
     // Fill with random data (simplified)
-    for i in 8..1536 {
-        s1[i] = (i % 256) as u8;
+    for (i, byte) in s1.iter_mut().enumerate().skip(8) {
+        *byte = (i % 256) as u8;
     }
     
     stream.write_all(&s1).await?;
@@ -48,6 +43,7 @@ pub async fn perform_handshake(stream: &mut TcpStream) -> Result<(), io::Error>
     
     // Send S2 (echo of C1)
     stream.write_all(&c1).await?;
+    stream.flush().await?;
     info!("Sent S2");
     
     // Read C2 (1536 bytes) - echo of S1
@@ -57,4 +53,67 @@ pub async fn perform_handshake(stream: &mut TcpStream) -> Result<(), io::Error>
     
     info!("RTMP handshake completed successfully");
     Ok(())
-} 
\ No newline at end of file
+}
+
+/// Client side of the simple handshake: send C0+C1, read S0+S1+S2, echo S1 back as C2.
+pub async fn perform_client_handshake<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<(), io::Error> {
+    // C0 (version) + C1 (timestamp, zero, 1528 bytes of filler)
+    let mut c0c1 = [0u8; 1537];
+    c0c1[0] = 3;
+    for (i, byte) in c0c1.iter_mut().enumerate().skip(9) {
+        *byte = (i % 256) as u8;
+    }
+    stream.write_all(&c0c1).await?;
+    stream.flush().await?;
+    debug!("Sent C0+C1");
+
+    // Read S0 (1 byte)
+    let mut s0 = [0u8; 1];
+    stream.read_exact(&mut s0).await?;
+    if s0[0] != 3 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid RTMP version"));
+    }
+
+    // Read S1 (1536 bytes)
+    let mut s1 = [0u8; 1536];
+    stream.read_exact(&mut s1).await?;
+
+    // Read S2 (1536 bytes) - echo of C1
+    let mut s2 = [0u8; 1536];
+    stream.read_exact(&mut s2).await?;
+    debug!("Received S0+S1+S2");
+
+    // Send C2 (echo of S1)
+    stream.write_all(&s1).await?;
+    stream.flush().await?;
+
+    info!("RTMP client handshake completed successfully");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, BufStream};
+    use tokio::time::{timeout, Duration};
+
+    #[tokio::test]
+    async fn handshake_completes_over_buffered_stream() {
+        let (client, server) = duplex(8192);
+        // S0+S1+S2 sit in the write buffer until the server flushes them
+        let mut server = BufStream::new(server);
+        let mut client = client;
+
+        let handshakes = async { tokio::try_join!(perform_handshake(&mut server), perform_client_handshake(&mut client)) };
+        timeout(Duration::from_secs(5), handshakes).await.expect("handshake stalled").unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_version() {
+        let (mut client, mut server) = duplex(8192);
+        client.write_all(&[6; 1537]).await.unwrap();
+
+        let error = perform_handshake(&mut server).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
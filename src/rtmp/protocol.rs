@@ -3,6 +3,9 @@ use bytes::Bytes;
 #[derive(Debug, Clone)]
 pub struct RtmpMessage {
     pub message_type: MessageType,
+    pub message_type_id: u8,
+    pub timestamp: u32,
+    pub message_stream_id: u32,
     pub payload: Bytes,
 }
 
@@ -17,7 +20,9 @@ pub enum MessageType {
     Acknowledgement,
     WindowAcknowledgementSize,
     SetPeerBandwidth,
+    UserControl,
     Command,
+    Data,
     Unknown,
 }
 
@@ -28,11 +33,14 @@ impl From<u8> for MessageType {
             2 => MessageType::Abort,
             3 => MessageType::Acknowledgement,
             5 => MessageType::WindowAcknowledgementSize,
+            4 => MessageType::UserControl,
             6 => MessageType::SetPeerBandwidth,
             8 => MessageType::Audio,
             9 => MessageType::Video,
             20 => MessageType::Command, // AMF0 Command
             17 => MessageType::Command, // AMF3 Command
+            18 => MessageType::Data, // AMF0 Data (metadata)
+            15 => MessageType::Data, // AMF3 Data
             _ => MessageType::Unknown,
         }
     }
@@ -61,6 +69,11 @@ pub struct PublishCommand {
     pub publish_type: String,
 }
 
+#[derive(Debug)]
+pub struct PlayCommand {
+    pub stream_key: String,
+}
+
 #[derive(Debug)]
 pub struct CreateStreamCommand {
     pub transaction_id: f64,
@@ -329,6 +342,64 @@ pub fn parse_rtmp_publish(payload: &[u8]) -> Option<PublishCommand> {
     Some(PublishCommand { stream_key, publish_type })
 }
 
+pub fn parse_rtmp_play(payload: &[u8]) -> Option<PlayCommand> {
+    if payload.len() < 10 {
+        return None;
+    }
+
+    let mut offset = 0;
+    
+    // AMF0 String marker (0x02)
+    if payload[offset] != 0x02 {
+        return None;
+    }
+    offset += 1;
+    
+    // String length
+    let str_len = u16::from_be_bytes([payload[offset], payload[offset + 1]]) as usize;
+    offset += 2;
+    
+    if offset + str_len > payload.len() {
+        return None;
+    }
+    
+    let command_name = String::from_utf8_lossy(&payload[offset..offset + str_len]);
+    offset += str_len;
+    
+    if command_name != "play" {
+        return None;
+    }
+    
+    // Skip transaction ID (AMF0 Number - 0x00 + 8 bytes)
+    if payload.len() < offset + 9 {
+        return None;
+    }
+    offset += 9;
+    
+    // Skip null (AMF0 Null - 0x05)
+    if payload.len() < offset + 1 || payload[offset] != 0x05 {
+        return None;
+    }
+    offset += 1;
+    
+    // Parse stream name (AMF0 String)
+    if payload.len() < offset + 3 || payload[offset] != 0x02 {
+        return None;
+    }
+    offset += 1;
+    
+    let stream_key_len = u16::from_be_bytes([payload[offset], payload[offset + 1]]) as usize;
+    offset += 2;
+    
+    if offset + stream_key_len > payload.len() {
+        return None;
+    }
+    
+    let stream_key = String::from_utf8_lossy(&payload[offset..offset + stream_key_len]).to_string();
+    
+    Some(PlayCommand { stream_key })
+}
+
 pub fn parse_rtmp_createstream(payload: &[u8]) -> Option<CreateStreamCommand> {
     if payload.len() < 10 {
         return None;
@@ -427,7 +498,7 @@ pub fn create_connect_response() -> Vec<u8> {
     response.extend_from_slice(&11u16.to_be_bytes());
     response.extend_from_slice(b"description");
     response.push(0x02); // String marker
-    response.extend_from_slice(&15u16.to_be_bytes());
+    response.extend_from_slice(&20u16.to_be_bytes());
     response.extend_from_slice(b"Connection succeeded");
     
     // Object end marker
@@ -465,7 +536,7 @@ pub fn create_publish_response(stream_key: &str) -> Vec<u8> {
     response.extend_from_slice(&4u16.to_be_bytes());
     response.extend_from_slice(b"code");
     response.push(0x02); // String value
-    response.extend_from_slice(&26u16.to_be_bytes());
+    response.extend_from_slice(&23u16.to_be_bytes());
     response.extend_from_slice(b"NetStream.Publish.Start");
     
     // description property
@@ -482,6 +553,72 @@ pub fn create_publish_response(stream_key: &str) -> Vec<u8> {
     response
 }
 
+pub fn create_connect_rejected_response(description: &str) -> Vec<u8> {
+    let mut response = Vec::new();
+    
+    // Command name "_error" (AMF0 String)
+    response.push(0x02); // String marker
+    response.extend_from_slice(&6u16.to_be_bytes()); // Length
+    response.extend_from_slice(b"_error");
+    
+    // Transaction ID (1.0) (AMF0 Number)
+    response.push(0x00); // Number marker
+    response.extend_from_slice(&1.0f64.to_be_bytes());
+    
+    // Null (no properties object)
+    response.push(0x05);
+    
+    // Information object
+    response.push(0x03); // Object marker
+    push_string_property(&mut response, "level", "error");
+    push_string_property(&mut response, "code", "NetConnection.Connect.Rejected");
+    push_string_property(&mut response, "description", description);
+    
+    // Object end
+    response.extend_from_slice(&[0x00, 0x00, 0x09]);
+    
+    response
+}
+
+pub fn create_play_response(stream_key: &str) -> Vec<u8> {
+    create_status_message("status", "NetStream.Play.Start", &format!("Started playing stream {}", stream_key))
+}
+
+pub fn create_status_message(level: &str, code: &str, description: &str) -> Vec<u8> {
+    let mut response = Vec::new();
+    
+    // Command name "onStatus"
+    response.push(0x02); // String marker
+    response.extend_from_slice(&8u16.to_be_bytes()); // Length
+    response.extend_from_slice(b"onStatus");
+    
+    // Transaction ID (0.0)
+    response.push(0x00); // Number marker
+    response.extend_from_slice(&0.0f64.to_be_bytes());
+    
+    // Null
+    response.push(0x05);
+    
+    // Information object
+    response.push(0x03); // Object marker
+    push_string_property(&mut response, "level", level);
+    push_string_property(&mut response, "code", code);
+    push_string_property(&mut response, "description", description);
+    
+    // Object end
+    response.extend_from_slice(&[0x00, 0x00, 0x09]);
+    
+    response
+}
+
+fn push_string_property(buffer: &mut Vec<u8>, name: &str, value: &str) {
+    buffer.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(name.as_bytes());
+    buffer.push(0x02); // String marker
+    buffer.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(value.as_bytes());
+}
+
 pub fn create_createstream_response(transaction_id: f64) -> Vec<u8> {
     let mut response = Vec::new();
     
@@ -564,7 +701,7 @@ pub fn parse_checkbw_command(payload: &[u8]) -> Option<f64> {
     Some(transaction_id)
 }
 
-pub fn create_generic_response(command: &str) -> Vec<u8> {
+pub fn create_generic_response(_command: &str) -> Vec<u8> {
     let mut response = Vec::new();
     
     // Command name "_result"
@@ -645,4 +782,280 @@ pub fn create_onbwcheck_message() -> Vec<u8> {
     response.extend_from_slice(&1000000.0f64.to_be_bytes()); // 1Mbps
     
     response
-} 
\ No newline at end of file
+}
+
+pub fn create_connect_command(app: &str, tc_url: &str) -> Vec<u8> {
+    let mut command = Vec::new();
+    
+    // Command name "connect" (AMF0 String)
+    command.push(0x02); // String marker
+    command.extend_from_slice(&7u16.to_be_bytes()); // Length
+    command.extend_from_slice(b"connect");
+    
+    // Transaction ID (1.0) (AMF0 Number)
+    command.push(0x00); // Number marker
+    command.extend_from_slice(&1.0f64.to_be_bytes());
+    
+    // Command object
+    command.push(0x03); // Object marker
+    push_string_property(&mut command, "app", app);
+    push_string_property(&mut command, "type", "nonprivate");
+    push_string_property(&mut command, "flashVer", "FMLE/3.0 (compatible; StreamX)");
+    push_string_property(&mut command, "tcUrl", tc_url);
+    
+    // Object end
+    command.extend_from_slice(&[0x00, 0x00, 0x09]);
+    
+    command
+}
+
+pub fn create_createstream_command(transaction_id: f64) -> Vec<u8> {
+    let mut command = Vec::new();
+    
+    // Command name "createStream"
+    command.push(0x02); // String marker
+    command.extend_from_slice(&12u16.to_be_bytes()); // Length
+    command.extend_from_slice(b"createStream");
+    
+    // Transaction ID
+    command.push(0x00); // Number marker
+    command.extend_from_slice(&transaction_id.to_be_bytes());
+    
+    // Null
+    command.push(0x05);
+    
+    command
+}
+
+pub fn create_play_command(stream_name: &str) -> Vec<u8> {
+    let mut command = Vec::new();
+    
+    // Command name "play"
+    command.push(0x02); // String marker
+    command.extend_from_slice(&4u16.to_be_bytes()); // Length
+    command.extend_from_slice(b"play");
+    
+    // Transaction ID (0.0), play has no _result
+    command.push(0x00); // Number marker
+    command.extend_from_slice(&0.0f64.to_be_bytes());
+    
+    // Null
+    command.push(0x05);
+    
+    // Stream name
+    command.push(0x02); // String marker
+    command.extend_from_slice(&(stream_name.len() as u16).to_be_bytes());
+    command.extend_from_slice(stream_name.as_bytes());
+    
+    // Start (-1000.0): live stream only
+    command.push(0x00); // Number marker
+    command.extend_from_slice(&(-1000.0f64).to_be_bytes());
+    
+    command
+}
+
+/// A `_result`, `_error` or `onStatus` command received from the server.
+#[derive(Debug)]
+pub struct CommandResponse {
+    pub name: String,
+    pub transaction_id: f64,
+    // First number after the command object, e.g. the stream ID of a createStream result
+    pub value: Option<f64>,
+    // `level` and `code` of the information object, e.g. "status" and "NetStream.Play.Start"
+    pub level: Option<String>,
+    pub code: Option<String>,
+}
+
+pub fn parse_command_response(payload: &[u8]) -> Option<CommandResponse> {
+    let mut offset = 0;
+    
+    let name = read_amf0_string(payload, &mut offset)?;
+    
+    // Transaction ID (AMF0 Number)
+    if payload.len() < offset + 9 || payload[offset] != 0x00 {
+        return None;
+    }
+    let transaction_id = read_f64(&payload[offset + 1..]);
+    offset += 9;
+    
+    let mut value = None;
+    let mut level = None;
+    let mut code = None;
+    
+    // Command object (usually null), then the result value or information object
+    while offset < payload.len() {
+        match payload[offset] {
+            0x00 => { // Number
+                if payload.len() < offset + 9 {
+                    break;
+                }
+                value.get_or_insert(read_f64(&payload[offset + 1..]));
+                offset += 9;
+            }
+            0x05 | 0x06 => { // Null, Undefined
+                offset += 1;
+            }
+            0x02 => { // String
+                read_amf0_string(payload, &mut offset)?;
+            }
+            0x03 => { // Object
+                offset += 1;
+                for (name, property) in read_object_string_properties(payload, &mut offset) {
+                    match name.as_str() {
+                        "level" => level = Some(property),
+                        "code" => code = Some(property),
+                        _ => {}
+                    }
+                }
+            }
+            _ => break,
+        }
+    }
+    
+    Some(CommandResponse { name, transaction_id, value, level, code })
+}
+
+// Reads an AMF0 string value (marker, length, bytes) and advances `offset` past it
+fn read_amf0_string(payload: &[u8], offset: &mut usize) -> Option<String> {
+    if payload.len() < *offset + 3 || payload[*offset] != 0x02 {
+        return None;
+    }
+    let len = u16::from_be_bytes([payload[*offset + 1], payload[*offset + 2]]) as usize;
+    let start = *offset + 3;
+    if payload.len() < start + len {
+        return None;
+    }
+    
+    *offset = start + len;
+    Some(String::from_utf8_lossy(&payload[start..start + len]).to_string())
+}
+
+// Walks the properties of an AMF0 object (just past its marker) and collects its string
+// values. Stops at the object end or at a value type it cannot skip.
+fn read_object_string_properties(payload: &[u8], offset: &mut usize) -> Vec<(String, String)> {
+    let mut properties = Vec::new();
+    
+    while payload.len() >= *offset + 3 {
+        // Object end marker (0x00 0x00 0x09)
+        if payload[*offset..*offset + 3] == [0x00, 0x00, 0x09] {
+            *offset += 3;
+            break;
+        }
+        
+        let name_len = u16::from_be_bytes([payload[*offset], payload[*offset + 1]]) as usize;
+        *offset += 2;
+        if payload.len() < *offset + name_len + 1 {
+            break;
+        }
+        let name = String::from_utf8_lossy(&payload[*offset..*offset + name_len]).to_string();
+        *offset += name_len;
+        
+        match payload[*offset] {
+            0x02 => match read_amf0_string(payload, offset) {
+                Some(value) => properties.push((name, value)),
+                None => break,
+            },
+            0x00 => *offset += 9,
+            0x01 => *offset += 2,
+            0x05 | 0x06 => *offset += 1,
+            _ => {
+                *offset = payload.len();
+                break;
+            }
+        }
+    }
+    
+    properties
+}
+
+fn read_f64(data: &[u8]) -> f64 {
+    f64::from_be_bytes([data[0], data[1], data[2], data[3], data[4], data[5], data[6], data[7]])
+}
+
+// Publishers send metadata as `@setDataFrame("onMetaData", {...})`; players expect `onMetaData({...})`
+pub fn strip_set_data_frame(payload: &Bytes) -> Option<Bytes> {
+    const SET_DATA_FRAME: &[u8] = b"@setDataFrame";
+
+    if payload.len() >= 3 + SET_DATA_FRAME.len()
+        && payload[0] == 0x02
+        && &payload[3..3 + SET_DATA_FRAME.len()] == SET_DATA_FRAME
+    {
+        return Some(payload.slice(3 + SET_DATA_FRAME.len()..));
+    }
+
+    // Already in player form
+    if payload.len() >= 13 && payload[0] == 0x02 && &payload[3..13] == b"onMetaData" {
+        return Some(payload.clone());
+    }
+
+    None
+}
+
+pub fn create_releasestream_command(transaction_id: f64, stream_name: &str) -> Vec<u8> {
+    create_stream_name_command("releaseStream", transaction_id, stream_name)
+}
+
+pub fn create_fcpublish_command(transaction_id: f64, stream_name: &str) -> Vec<u8> {
+    create_stream_name_command("FCPublish", transaction_id, stream_name)
+}
+
+pub fn create_fcunpublish_command(transaction_id: f64, stream_name: &str) -> Vec<u8> {
+    create_stream_name_command("FCUnpublish", transaction_id, stream_name)
+}
+
+pub fn create_publish_command(stream_name: &str) -> Vec<u8> {
+    let mut command = create_stream_name_command("publish", 0.0, stream_name);
+    
+    // Publish type
+    command.push(0x02); // String marker
+    command.extend_from_slice(&4u16.to_be_bytes());
+    command.extend_from_slice(b"live");
+    
+    command
+}
+
+pub fn create_deletestream_command(transaction_id: f64, stream_id: u32) -> Vec<u8> {
+    let mut command = Vec::new();
+    
+    // Command name "deleteStream"
+    command.push(0x02); // String marker
+    command.extend_from_slice(&12u16.to_be_bytes()); // Length
+    command.extend_from_slice(b"deleteStream");
+    
+    // Transaction ID
+    command.push(0x00); // Number marker
+    command.extend_from_slice(&transaction_id.to_be_bytes());
+    
+    // Null
+    command.push(0x05);
+    
+    // Stream ID
+    command.push(0x00); // Number marker
+    command.extend_from_slice(&(stream_id as f64).to_be_bytes());
+    
+    command
+}
+
+// `name(transaction_id, null, stream_name)`, the shape of most publish-side commands
+fn create_stream_name_command(name: &str, transaction_id: f64, stream_name: &str) -> Vec<u8> {
+    let mut command = Vec::new();
+    
+    // Command name
+    command.push(0x02); // String marker
+    command.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    command.extend_from_slice(name.as_bytes());
+    
+    // Transaction ID
+    command.push(0x00); // Number marker
+    command.extend_from_slice(&transaction_id.to_be_bytes());
+    
+    // Null
+    command.push(0x05);
+    
+    // Stream name
+    command.push(0x02); // String marker
+    command.extend_from_slice(&(stream_name.len() as u16).to_be_bytes());
+    command.extend_from_slice(stream_name.as_bytes());
+    
+    command
+}
@@ -0,0 +1,763 @@
+use bytes::BytesMut;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, error, info, warn};
+
+use crate::access::AccessAction;
+use crate::application::{Application, Applications};
+use crate::hooks::{HookDecision, HookEvent, HookRequest};
+use crate::media::{MediaKind, MediaPacket};
+use crate::registry::{Publisher, PublisherRole, StreamRegistry, Viewer};
+use crate::stream_key::StreamKey;
+
+use super::chunk::{ChunkDecoder, ChunkEncoder};
+use super::handshake::perform_handshake;
+use super::protocol::{MessageType, RtmpMessage, parse_rtmp_connect, create_connect_response, parse_rtmp_publish, create_publish_response, parse_rtmp_createstream, create_createstream_response, parse_command_name, create_generic_response, create_onbwdone_message, parse_checkbw_command, create_checkbw_response, create_onbwcheck_message, parse_rtmp_play, create_play_response, create_status_message, create_connect_rejected_response, strip_set_data_frame};
+use super::stream_path::StreamPath;
+
+// Chunk size announced to the peer right after the handshake
+const SERVER_CHUNK_SIZE: usize = 4096;
+const WINDOW_ACK_SIZE: u32 = 5_000_000;
+
+// Chunk stream IDs used for outgoing messages
+const CONTROL_CHUNK_STREAM: u8 = 2;
+const COMMAND_CHUNK_STREAM: u8 = 3;
+const AUDIO_CHUNK_STREAM: u8 = 4;
+const DATA_CHUNK_STREAM: u8 = 5;
+const VIDEO_CHUNK_STREAM: u8 = 6;
+
+// The only message stream ID handed out by createStream
+const MEDIA_STREAM_ID: u32 = 1;
+
+enum StreamState {
+    Idle,
+    Publishing(Publisher),
+    Playing {
+        receiver: broadcast::Receiver<MediaPacket>,
+        // Inter frames are useless to a decoder until the next keyframe arrives
+        waiting_for_keyframe: bool,
+        _viewer: Viewer,
+    },
+}
+
+enum SessionEvent {
+    Read(usize),
+    Media(Result<MediaPacket, RecvError>),
+}
+
+/// Server side of one RTMP connection over any byte transport (TCP, TLS, HTTP tunnel, in-memory).
+pub struct RtmpSession<S> {
+    socket: S,
+    addr: SocketAddr,
+    applications: Applications,
+    registry: StreamRegistry,
+
+    // Chunk state
+    decoder: ChunkDecoder,
+    encoder: ChunkEncoder,
+    read_buffer: BytesMut,
+    bytes_received: u64,
+    bytes_acknowledged: u64,
+    peer_window_size: u32,
+
+    // Connection state captured from the connect command
+    app: String,
+    tc_url: String,
+    application: Option<Arc<Application>>,
+    // The publish/play request that was accepted, replayed as on_done when it ends
+    active_stream: Option<HookRequest>,
+
+    stream: StreamState,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> RtmpSession<S> {
+    pub fn new(socket: S, addr: SocketAddr, applications: Applications, registry: StreamRegistry) -> Self {
+        Self {
+            socket,
+            addr,
+            applications,
+            registry,
+            decoder: ChunkDecoder::new(),
+            encoder: ChunkEncoder::new(),
+            read_buffer: BytesMut::with_capacity(SERVER_CHUNK_SIZE * 2),
+            bytes_received: 0,
+            bytes_acknowledged: 0,
+            peer_window_size: 0,
+            app: String::new(),
+            tc_url: String::new(),
+            application: None,
+            active_stream: None,
+            stream: StreamState::Idle,
+        }
+    }
+
+    /// Runs the handshake and the message loop until the peer disconnects.
+    pub async fn run(mut self) -> io::Result<()> {
+        let result = self.process().await;
+        self.stop_stream().await;
+        result
+    }
+
+    async fn process(&mut self) -> io::Result<()> {
+        // Perform RTMP handshake
+        info!("Starting RTMP handshake");
+        perform_handshake(&mut self.socket).await?;
+        info!("✅ RTMP handshake completed successfully");
+
+        // Send initial control messages as per RTMP spec
+        self.send_initial_control_messages().await?;
+
+        loop {
+            // Handle every complete message already buffered
+            loop {
+                let buffered = self.read_buffer.len();
+                let Some(message) = self.decoder.decode(&mut self.read_buffer)? else {
+                    if self.read_buffer.len() == buffered {
+                        break;
+                    }
+                    continue;
+                };
+                if !self.handle_message(message).await? {
+                    return Ok(());
+                }
+            }
+
+            match self.next_event().await? {
+                SessionEvent::Read(0) => {
+                    info!("Client disconnected");
+                    return Ok(());
+                }
+                SessionEvent::Read(bytes_read) => {
+                    debug!("Received {} bytes from client, buffered: {} bytes", bytes_read, self.read_buffer.len());
+                    self.acknowledge(bytes_read).await?;
+                }
+                SessionEvent::Media(packet) => self.forward_to_player(packet).await?,
+            }
+        }
+    }
+
+    async fn next_event(&mut self) -> io::Result<SessionEvent> {
+        let receiver = match &mut self.stream {
+            StreamState::Playing { receiver, .. } => Some(receiver),
+            _ => None,
+        };
+
+        tokio::select! {
+            read = self.socket.read_buf(&mut self.read_buffer) => Ok(SessionEvent::Read(read?)),
+            packet = recv_media(receiver) => Ok(SessionEvent::Media(packet)),
+        }
+    }
+
+    async fn acknowledge(&mut self, bytes_read: usize) -> io::Result<()> {
+        self.bytes_received += bytes_read as u64;
+
+        if self.peer_window_size > 0
+            && self.bytes_received - self.bytes_acknowledged >= self.peer_window_size as u64
+        {
+            self.bytes_acknowledged = self.bytes_received;
+            // The sequence number wraps at 32 bits
+            let sequence_number = self.bytes_received as u32;
+            self.send_control_message(3, &sequence_number.to_be_bytes()).await?;
+            debug!("✅ Sent acknowledgement at {} bytes", self.bytes_received);
+        }
+
+        Ok(())
+    }
+
+    // Returns false when the connection should be closed
+    async fn handle_message(&mut self, message: RtmpMessage) -> io::Result<bool> {
+        match message.message_type {
+            MessageType::Command => {
+                // AMF3 commands are AMF0 values behind a single format byte
+                let payload = match message.message_type_id {
+                    17 if message.payload.is_empty() => {
+                        warn!("❌ Dropping empty AMF3 command");
+                        return Ok(true);
+                    }
+                    17 => message.payload.slice(1..),
+                    _ => message.payload.clone(),
+                };
+                return self.handle_command(&payload).await;
+            }
+            MessageType::SetChunkSize => {
+                if message.payload.len() >= 4 {
+                    let payload = &message.payload;
+                    let chunk_size = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) & 0x7FFF_FFFF;
+                    info!("📏 New chunk size: {}", chunk_size);
+                    self.decoder.set_chunk_size(chunk_size as usize)?;
+                }
+            }
+            MessageType::WindowAcknowledgementSize => {
+                if message.payload.len() >= 4 {
+                    let payload = &message.payload;
+                    self.peer_window_size = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                    info!("🪟 Window acknowledgement size: {}", self.peer_window_size);
+                }
+            }
+            MessageType::Audio | MessageType::Video | MessageType::Data => {
+                self.handle_media(message);
+            }
+            _ => {
+                debug!("Received message type: {:?}", message.message_type);
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn handle_media(&mut self, message: RtmpMessage) {
+        let StreamState::Publishing(publisher) = &self.stream else {
+            debug!("Ignoring {:?} message outside of publishing", message.message_type);
+            return;
+        };
+
+        let (kind, payload) = match message.message_type {
+            MessageType::Audio => (MediaKind::Audio, message.payload),
+            MessageType::Video => (MediaKind::Video, message.payload),
+            _ => match strip_set_data_frame(&message.payload) {
+                Some(payload) => (MediaKind::Metadata, payload),
+                None => {
+                    debug!("Ignoring data message that is not @setDataFrame");
+                    return;
+                }
+            },
+        };
+
+        publisher.send(MediaPacket::new(kind, message.timestamp, payload));
+    }
+
+    async fn handle_command(&mut self, payload: &[u8]) -> io::Result<bool> {
+        debug!("Command payload ({} bytes): {:02x?}", payload.len(), payload);
+
+        let Some(command_name) = parse_command_name(payload) else {
+            error!("❌ Could not parse command name from payload");
+            return Ok(true);
+        };
+        info!("📞 Received RTMP command '{}'", command_name);
+
+        match command_name.as_str() {
+            "connect" => match parse_rtmp_connect(payload) {
+                Some(connect_cmd) => return self.handle_connect(connect_cmd.app, connect_cmd.tc_url).await,
+                None => warn!("❌ Failed to parse connect command"),
+            },
+            "createStream" => {
+                if let Some(createstream_cmd) = parse_rtmp_createstream(payload) {
+                    info!("🎯 Parsed createStream command: {:?}", createstream_cmd);
+
+                    let response = create_createstream_response(createstream_cmd.transaction_id);
+                    self.send_command(0, &response).await?;
+                    info!("✅ Sent createStream response to client");
+                }
+            }
+            "publish" => match parse_rtmp_publish(payload) {
+                Some(publish_cmd) => {
+                    info!("🎯 Parsed publish command: {:?}", publish_cmd);
+                    return self.handle_publish(&publish_cmd.stream_key).await;
+                }
+                None => warn!("❌ Failed to parse publish command"),
+            },
+            "play" => match parse_rtmp_play(payload) {
+                Some(play_cmd) => {
+                    info!("🎯 Parsed play command: {:?}", play_cmd);
+                    return self.handle_play(&play_cmd.stream_key).await;
+                }
+                None => warn!("❌ Failed to parse play command"),
+            },
+            "deleteStream" | "closeStream" | "FCUnpublish" => {
+                self.stop_stream().await;
+            }
+            "_checkbw" => {
+                if let Some(transaction_id) = parse_checkbw_command(payload) {
+                    info!("🎯 Parsed _checkbw command with transaction ID: {}", transaction_id);
+
+                    // Send _checkbw response with bandwidth value
+                    let response = create_checkbw_response(transaction_id);
+                    self.send_command(0, &response).await?;
+                    info!("✅ Sent _checkbw response");
+
+                    // Send onBWCheck message to complete bandwidth negotiation
+                    let onbwcheck = create_onbwcheck_message();
+                    self.send_command(0, &onbwcheck).await?;
+                    info!("✅ Sent onBWCheck - bandwidth negotiation complete!");
+                } else {
+                    warn!("❌ Failed to parse _checkbw transaction ID");
+                    let response = create_generic_response("_checkbw");
+                    self.send_command(0, &response).await?;
+                }
+            }
+            _ => {
+                // Send generic response for other commands (releaseStream, FCPublish, ...)
+                let response = create_generic_response(&command_name);
+                self.send_command(0, &response).await?;
+                info!("✅ Sent generic response for '{}'", command_name);
+            }
+        }
+
+        Ok(true)
+    }
+
+    async fn handle_connect(&mut self, app: String, tc_url: String) -> io::Result<bool> {
+        self.app = app;
+        self.tc_url = tc_url;
+
+        let path = self.stream_path("");
+        info!("📍 Connect path: app='{}' args={:?}", path.app, path.args);
+
+        let Some(application) = self.applications.get(&path.app).cloned() else {
+            warn!("🚫 Connection rejected: unknown application '{}'", path.app);
+            let response = create_connect_rejected_response(&format!("Unknown application '{}'", path.app));
+            self.send_command(0, &response).await?;
+            return Ok(false);
+        };
+
+        let request = HookRequest::new(HookEvent::Connect, &path, self.addr, &self.tc_url);
+        if let HookDecision::Deny(reason) = application.hooks.call(&request).await {
+            warn!("🚫 Connection rejected by on_connect hook: {}", reason);
+            let response = create_connect_rejected_response(&reason);
+            self.send_command(0, &response).await?;
+            return Ok(false);
+        }
+        self.application = Some(application);
+
+        // Send connect response
+        let response = create_connect_response();
+        self.send_command(0, &response).await?;
+        info!("✅ Sent connect response to client");
+
+        // Send Stream Begin user control message
+        self.send_stream_begin(0).await?;
+        info!("✅ Sent Stream Begin message");
+
+        // Send onBWDone message to complete bandwidth negotiation
+        let onbwdone = create_onbwdone_message();
+        self.send_command(0, &onbwdone).await?;
+        info!("✅ Sent onBWDone message - OBS should proceed now!");
+
+        Ok(true)
+    }
+
+    async fn handle_publish(&mut self, stream_name: &str) -> io::Result<bool> {
+        let Some(application) = self.application.clone().filter(|app| app.settings.publish) else {
+            warn!("🚫 Publish not allowed for application '{}'", self.app);
+            self.send_status("error", "NetStream.Publish.Rejected", "Publish is not allowed for this application").await?;
+            return Ok(false);
+        };
+
+        if !application.settings.access.allows(AccessAction::Publish, self.addr.ip()) {
+            warn!("🚫 Publish from {} denied by access rules of '{}'", self.addr.ip(), application.name);
+            self.send_status("error", "NetStream.Publish.Unauthorized", "Access denied").await?;
+            return Ok(false);
+        }
+
+        let mut path = self.stream_path(stream_name);
+        let mut request = HookRequest::new(HookEvent::Publish, &path, self.addr, &self.tc_url);
+        match application.hooks.call(&request).await {
+            HookDecision::Deny(reason) => {
+                warn!("🚫 Publish rejected by on_publish hook: {}", reason);
+                self.send_status("error", "NetStream.Publish.Unauthorized", &reason).await?;
+                return Ok(false);
+            }
+            HookDecision::Allow { redirect } => {
+                if let Some(new_name) = redirect {
+                    info!("↪️ on_publish hook renamed stream '{}' to '{}'", request.name, new_name);
+                    path.name = new_name.clone();
+                    request.name = new_name;
+                }
+            }
+        }
+
+        // `<key><backup_suffix>` feeds `<key>` as its backup publisher
+        let (name, role) = match application.settings.backup_suffix.as_deref() {
+            Some(suffix) if path.name.len() > suffix.len() && path.name.ends_with(suffix) => {
+                (&path.name[..path.name.len() - suffix.len()], PublisherRole::Backup)
+            }
+            _ => (path.name.as_str(), PublisherRole::Primary),
+        };
+
+        let stream_key = match StreamKey::new(name) {
+            Ok(stream_key) => stream_key,
+            Err(e) => {
+                warn!("🚫 Rejecting publish: {}", e);
+                self.send_status("error", "NetStream.Publish.BadName", &e.to_string()).await?;
+                return Ok(false);
+            }
+        };
+
+        let publisher = match self.registry.publish(&application.name, &stream_key, role) {
+            Ok(publisher) => publisher,
+            Err(e) => {
+                warn!("🚫 Rejecting publish: {}", e);
+                self.send_status("error", "NetStream.Publish.BadName", &e.to_string()).await?;
+                return Ok(false);
+            }
+        };
+
+        // Send publish response
+        self.send_stream_begin(MEDIA_STREAM_ID).await?;
+        let response = create_publish_response(stream_key.as_str());
+        self.send_command(MEDIA_STREAM_ID, &response).await?;
+        info!("✅ Sent publish response to client - streaming started!");
+
+        if publisher.created {
+            application.start_outputs(&publisher.stream).await;
+        }
+        self.stream = StreamState::Publishing(publisher);
+        self.active_stream = Some(request);
+
+        Ok(true)
+    }
+
+    async fn handle_play(&mut self, stream_name: &str) -> io::Result<bool> {
+        let Some(application) = self.application.clone().filter(|app| app.settings.play) else {
+            warn!("🚫 Play not allowed for application '{}'", self.app);
+            self.send_status("error", "NetStream.Play.Failed", "Play is not allowed for this application").await?;
+            return Ok(false);
+        };
+
+        if !application.settings.access.allows(AccessAction::Play, self.addr.ip()) {
+            warn!("🚫 Play from {} denied by access rules of '{}'", self.addr.ip(), application.name);
+            self.send_status("error", "NetStream.Play.Failed", "Access denied").await?;
+            return Ok(false);
+        }
+
+        let mut path = self.stream_path(stream_name);
+        let mut request = HookRequest::new(HookEvent::Play, &path, self.addr, &self.tc_url);
+        match application.hooks.call(&request).await {
+            HookDecision::Deny(reason) => {
+                warn!("🚫 Play rejected by on_play hook: {}", reason);
+                self.send_status("error", "NetStream.Play.Failed", &reason).await?;
+                return Ok(false);
+            }
+            HookDecision::Allow { redirect } => {
+                if let Some(new_name) = redirect {
+                    info!("↪️ on_play hook renamed stream '{}' to '{}'", request.name, new_name);
+                    path.name = new_name.clone();
+                    request.name = new_name;
+                }
+            }
+        }
+
+        let stream = match StreamKey::new(&path.name) {
+            Ok(stream_key) => match self.registry.get(&application.name, &stream_key) {
+                Some(stream) => Some(stream),
+                None => application.pull_from_origin(&self.registry, &stream_key).await,
+            },
+            Err(_) => None,
+        };
+        let subscription = stream.and_then(|stream| Some((stream.has_video(), stream.subscribe()?, stream.add_viewer())));
+        let Some((has_video, subscription, viewer)) = subscription else {
+            warn!("🚫 Play requested for unknown stream '{}'", path);
+            self.send_status("error", "NetStream.Play.StreamNotFound", &format!("Stream {} is not live", path)).await?;
+            return Ok(false);
+        };
+
+        self.send_stream_begin(MEDIA_STREAM_ID).await?;
+        let response = create_play_response(&path.name);
+        self.send_command(MEDIA_STREAM_ID, &response).await?;
+        info!("✅ Sent play response to client");
+
+        // Metadata and codec configuration first, so the player can decode what follows
+        for packet in &subscription.headers {
+            self.send_media(packet).await?;
+        }
+
+        self.stream = StreamState::Playing {
+            receiver: subscription.receiver,
+            waiting_for_keyframe: has_video,
+            _viewer: viewer,
+        };
+        self.active_stream = Some(request);
+
+        Ok(true)
+    }
+
+    async fn forward_to_player(&mut self, packet: Result<MediaPacket, RecvError>) -> io::Result<()> {
+        let StreamState::Playing { waiting_for_keyframe, .. } = &mut self.stream else {
+            return Ok(());
+        };
+
+        match packet {
+            Ok(packet) => {
+                if packet.kind == MediaKind::Video && *waiting_for_keyframe {
+                    if !packet.is_keyframe() {
+                        return Ok(());
+                    }
+                    *waiting_for_keyframe = false;
+                }
+                self.send_media(&packet).await
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("⚠️ Player {} fell behind, skipped {} packets", self.addr, skipped);
+                *waiting_for_keyframe = true;
+                Ok(())
+            }
+            Err(RecvError::Closed) => {
+                info!("📴 Stream ended for player {}", self.addr);
+                self.stream = StreamState::Idle;
+                self.send_status("status", "NetStream.Play.UnpublishNotify", "Stream was unpublished").await
+            }
+        }
+    }
+
+    // Ends publishing/playing and fires on_done
+    async fn stop_stream(&mut self) {
+        if let StreamState::Publishing(publisher) = std::mem::replace(&mut self.stream, StreamState::Idle) {
+            self.registry.unpublish(&publisher);
+        }
+
+        if let (Some(application), Some(mut request)) = (self.application.as_ref(), self.active_stream.take()) {
+            request.call = HookEvent::Done;
+            application.hooks.call(&request).await;
+        }
+    }
+
+    fn stream_path(&self, stream_name: &str) -> StreamPath {
+        StreamPath::new(&self.app, &self.tc_url, stream_name)
+    }
+
+    async fn send_initial_control_messages(&mut self) -> io::Result<()> {
+        info!("Sending initial RTMP control messages");
+
+        // 1. Window Acknowledgement Size (5MB)
+        self.send_control_message(5, &WINDOW_ACK_SIZE.to_be_bytes()).await?;
+
+        // 2. Set Peer Bandwidth (5MB, Hard limit)
+        let mut peer_bandwidth = WINDOW_ACK_SIZE.to_be_bytes().to_vec();
+        peer_bandwidth.push(0); // Hard limit type
+        self.send_control_message(6, &peer_bandwidth).await?;
+
+        // 3. Set Chunk Size (4096 bytes)
+        self.send_control_message(1, &(SERVER_CHUNK_SIZE as u32).to_be_bytes()).await?;
+        self.encoder.set_chunk_size(SERVER_CHUNK_SIZE)?;
+
+        info!("✅ Initial control messages sent successfully");
+        Ok(())
+    }
+
+    async fn send_stream_begin(&mut self, stream_id: u32) -> io::Result<()> {
+        // User Control Message (4) - Stream Begin (0)
+        let mut payload = vec![];
+        payload.extend_from_slice(&0u16.to_be_bytes()); // Event type 0 = Stream Begin
+        payload.extend_from_slice(&stream_id.to_be_bytes()); // Stream ID
+
+        self.send_control_message(4, &payload).await
+    }
+
+    async fn send_control_message(&mut self, message_type: u8, payload: &[u8]) -> io::Result<()> {
+        let chunk = self.encoder.encode(CONTROL_CHUNK_STREAM, message_type, 0, 0, payload);
+        self.write(&chunk).await
+    }
+
+    async fn send_command(&mut self, message_stream_id: u32, payload: &[u8]) -> io::Result<()> {
+        // Message type 20 = AMF0 command
+        let chunk = self.encoder.encode(COMMAND_CHUNK_STREAM, 20, 0, message_stream_id, payload);
+        self.write(&chunk).await
+    }
+
+    async fn send_status(&mut self, level: &str, code: &str, description: &str) -> io::Result<()> {
+        let response = create_status_message(level, code, description);
+        self.send_command(MEDIA_STREAM_ID, &response).await
+    }
+
+    async fn send_media(&mut self, packet: &MediaPacket) -> io::Result<()> {
+        let chunk_stream_id = match packet.kind {
+            MediaKind::Audio => AUDIO_CHUNK_STREAM,
+            MediaKind::Video => VIDEO_CHUNK_STREAM,
+            MediaKind::Metadata => DATA_CHUNK_STREAM,
+        };
+        let chunk = self.encoder.encode(chunk_stream_id, packet.kind.type_id(), packet.timestamp, MEDIA_STREAM_ID, &packet.payload);
+        self.write(&chunk).await
+    }
+
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.socket.write_all(data).await?;
+        self.socket.flush().await
+    }
+}
+
+async fn recv_media(receiver: Option<&mut broadcast::Receiver<MediaPacket>>) -> Result<MediaPacket, RecvError> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::{build_applications, ApplicationConfig};
+    use crate::config::Config;
+    use crate::rtmp::client::{RtmpClient, RtmpUrl};
+    use crate::rtmp::handshake::perform_client_handshake;
+    use crate::rtmp::protocol::{create_connect_command, parse_command_response};
+    use bytes::Bytes;
+    use std::collections::HashMap;
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::task::JoinHandle;
+    use tokio::time::{sleep, timeout, Duration};
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    // A `live` application without outputs
+    fn server() -> (Applications, StreamRegistry) {
+        let config = Config {
+            applications: HashMap::from([("live".to_string(), ApplicationConfig { hls: false, ..ApplicationConfig::default() })]),
+            ..Config::default()
+        };
+        (build_applications(&config).unwrap(), StreamRegistry::new(config.max_streams))
+    }
+
+    // Runs a session on one end of an in-memory pipe, returning the other end
+    fn start_session(applications: &Applications, registry: &StreamRegistry) -> (DuplexStream, JoinHandle<io::Result<()>>) {
+        let (client, server) = duplex(64 * 1024);
+        let session = RtmpSession::new(server, "127.0.0.1:50000".parse().unwrap(), applications.clone(), registry.clone());
+        (client, tokio::spawn(session.run()))
+    }
+
+    async fn connect(socket: DuplexStream, url: &str) -> io::Result<RtmpClient<DuplexStream>> {
+        timeout(WAIT, RtmpClient::connect_with(socket, &RtmpUrl::parse(url).unwrap())).await.expect("connect stalled")
+    }
+
+    // Reads the next message from the server, applying its chunk size
+    async fn read_message(socket: &mut DuplexStream, decoder: &mut ChunkDecoder, buffer: &mut BytesMut) -> Option<RtmpMessage> {
+        loop {
+            if let Some(message) = decoder.decode(buffer).unwrap() {
+                if message.message_type == MessageType::SetChunkSize {
+                    let size = u32::from_be_bytes(message.payload[..4].try_into().unwrap());
+                    decoder.set_chunk_size(size as usize).unwrap();
+                }
+                return Some(message);
+            }
+            if timeout(WAIT, socket.read_buf(buffer)).await.expect("read stalled").unwrap() == 0 {
+                return None;
+            }
+        }
+    }
+
+    async fn session_result(session: JoinHandle<io::Result<()>>) -> io::Result<()> {
+        timeout(WAIT, session).await.expect("session did not end").unwrap()
+    }
+
+    fn video(payload: &'static [u8]) -> MediaPacket {
+        MediaPacket::new(MediaKind::Video, 0, Bytes::from_static(payload))
+    }
+
+    #[tokio::test]
+    async fn sends_control_messages_after_handshake() {
+        let (applications, registry) = server();
+        let (mut socket, _session) = start_session(&applications, &registry);
+        timeout(WAIT, perform_client_handshake(&mut socket)).await.unwrap().unwrap();
+
+        let (mut decoder, mut buffer) = (ChunkDecoder::new(), BytesMut::new());
+        let mut types = Vec::new();
+        for _ in 0..3 {
+            let message = read_message(&mut socket, &mut decoder, &mut buffer).await.unwrap();
+            if message.message_type == MessageType::SetChunkSize {
+                assert_eq!(message.payload[..], (SERVER_CHUNK_SIZE as u32).to_be_bytes());
+            }
+            types.push(message.message_type);
+        }
+        assert_eq!(types, [MessageType::WindowAcknowledgementSize, MessageType::SetPeerBandwidth, MessageType::SetChunkSize]);
+    }
+
+    #[tokio::test]
+    async fn accepts_connect_to_known_application() {
+        let (applications, registry) = server();
+        let (socket, _session) = start_session(&applications, &registry);
+        let mut client = connect(socket, "rtmp://localhost/live/test").await.unwrap();
+        assert_eq!(timeout(WAIT, client.create_stream()).await.unwrap().unwrap(), MEDIA_STREAM_ID);
+    }
+
+    #[tokio::test]
+    async fn rejects_connect_to_unknown_application() {
+        let (applications, registry) = server();
+        let (socket, session) = start_session(&applications, &registry);
+        assert!(connect(socket, "rtmp://localhost/other/test").await.is_err());
+        assert!(session_result(session).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn plays_what_is_published() {
+        let (applications, registry) = server();
+        let key = StreamKey::new("test").unwrap();
+
+        let (socket, publisher_session) = start_session(&applications, &registry);
+        let mut publisher = connect(socket, "rtmp://localhost/live/test").await.unwrap();
+        let stream_id = timeout(WAIT, publisher.publish("test")).await.unwrap().unwrap();
+        let sequence_header = video(&[0x17, 0x00, 0x00, 0x00, 0x00, 0x01]);
+        publisher.send_media(stream_id, &sequence_header).await.unwrap();
+
+        // Wait for the session to hand the sequence header to the registry
+        let stream = registry.get("live", &key).expect("stream is not live");
+        timeout(WAIT, async {
+            while !stream.has_video() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let (socket, _player_session) = start_session(&applications, &registry);
+        let mut player = connect(socket, "rtmp://localhost/live/test").await.unwrap();
+        let player_stream_id = player.create_stream().await.unwrap();
+        timeout(WAIT, player.play(player_stream_id, "test")).await.unwrap().unwrap();
+
+        let packet = timeout(WAIT, player.read_media()).await.unwrap().unwrap().unwrap();
+        assert_eq!(packet.payload, sequence_header.payload);
+
+        // Inter frames are held back until the first keyframe
+        publisher.send_media(stream_id, &video(&[0x27, 0x01, 0x00, 0x00, 0x00, 0x02])).await.unwrap();
+        let keyframe = video(&[0x17, 0x01, 0x00, 0x00, 0x00, 0x03]);
+        publisher.send_media(stream_id, &keyframe).await.unwrap();
+        let packet = timeout(WAIT, player.read_media()).await.unwrap().unwrap().unwrap();
+        assert_eq!(packet.payload, keyframe.payload);
+
+        publisher.unpublish(stream_id, "test").await.unwrap();
+        assert!(timeout(WAIT, player.read_media()).await.unwrap().unwrap().is_none());
+        assert!(registry.get("live", &key).is_none());
+
+        drop(publisher);
+        assert!(session_result(publisher_session).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn drops_empty_amf3_command() {
+        let (applications, registry) = server();
+        let (mut socket, _session) = start_session(&applications, &registry);
+        timeout(WAIT, perform_client_handshake(&mut socket)).await.unwrap().unwrap();
+
+        let encoder = ChunkEncoder::new();
+        socket.write_all(&encoder.encode(COMMAND_CHUNK_STREAM, 17, 0, 0, &[])).await.unwrap();
+        let connect = create_connect_command("live", "rtmp://localhost/live");
+        socket.write_all(&encoder.encode(COMMAND_CHUNK_STREAM, 20, 0, 0, &connect)).await.unwrap();
+
+        // The session is still up and answers the connect that follows
+        let (mut decoder, mut buffer) = (ChunkDecoder::new(), BytesMut::new());
+        loop {
+            let message = read_message(&mut socket, &mut decoder, &mut buffer).await.expect("session closed");
+            if message.message_type == MessageType::Command {
+                let response = parse_command_response(&message.payload).unwrap();
+                assert_eq!(response.name, "_result");
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn closes_on_invalid_chunk_size() {
+        for chunk_size in [0u32, 0x0100_0000] {
+            let (applications, registry) = server();
+            let (mut socket, session) = start_session(&applications, &registry);
+            timeout(WAIT, perform_client_handshake(&mut socket)).await.unwrap().unwrap();
+
+            let chunk = ChunkEncoder::new().encode(CONTROL_CHUNK_STREAM, 1, 0, 0, &chunk_size.to_be_bytes());
+            socket.write_all(&chunk).await.unwrap();
+
+            let error = session_result(session).await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "chunk size {}", chunk_size);
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+
+/// Application, stream name and query arguments of a publish/play request.
+///
+/// Clients pass tokens either on the connect URL (`rtmp://host/live?token=x`)
+/// or on the stream name (`mystream?sign=abc&expires=123`). Both are merged into
+/// `args`, with the stream name's arguments taking precedence.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StreamPath {
+    pub app: String,
+    pub name: String,
+    pub args: HashMap<String, String>,
+}
+
+impl StreamPath {
+    /// Builds a path from the `connect` command's `app`/`tcUrl` and a stream name
+    /// (empty for connection-level checks).
+    pub fn new(app: &str, tc_url: &str, stream_name: &str) -> Self {
+        let (tc_app, mut args) = parse_tc_url(tc_url);
+
+        let (app, app_args) = split_query(app);
+        args.extend(app_args);
+
+        let (name, name_args) = split_query(stream_name);
+        args.extend(name_args);
+
+        // Some clients leave `app` empty and rely on tcUrl alone
+        let app = if app.is_empty() { tc_app } else { app.trim_matches('/').to_string() };
+
+        Self {
+            app,
+            name: name.to_string(),
+            args,
+        }
+    }
+}
+
+impl std::fmt::Display for StreamPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.app, self.name)
+    }
+}
+
+/// Extracts the application and query arguments from `rtmp://host[:port]/app[/inst][?query]`.
+pub fn parse_tc_url(tc_url: &str) -> (String, HashMap<String, String>) {
+    let (url, args) = split_query(tc_url);
+
+    let path = url.split_once("://")
+        .map(|(_, rest)| rest)
+        .unwrap_or(url)
+        .split_once('/')
+        .map(|(_, path)| path)
+        .unwrap_or("");
+
+    (path.trim_matches('/').to_string(), args)
+}
+
+/// Splits `name?a=1&b=2` into the bare name and its percent-decoded query arguments.
+pub fn split_query(value: &str) -> (&str, HashMap<String, String>) {
+    match value.split_once('?') {
+        Some((name, query)) => {
+            let args = url::form_urlencoded::parse(query.as_bytes())
+                .map(|(key, value)| (key.into_owned(), value.into_owned()))
+                .collect();
+            (name, args)
+        }
+        None => (value, HashMap::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn parses_tc_url() {
+        assert_eq!(parse_tc_url("rtmp://host/live"), ("live".to_string(), args(&[])));
+        assert_eq!(parse_tc_url("rtmp://host:1935/live/"), ("live".to_string(), args(&[])));
+        assert_eq!(parse_tc_url("rtmps://host/live/inst"), ("live/inst".to_string(), args(&[])));
+        assert_eq!(parse_tc_url("rtmp://host/live?token=x"), ("live".to_string(), args(&[("token", "x")])));
+        assert_eq!(parse_tc_url("rtmp://host"), (String::new(), args(&[])));
+        assert_eq!(parse_tc_url(""), (String::new(), args(&[])));
+    }
+
+    #[test]
+    fn splits_and_decodes_query() {
+        assert_eq!(split_query("mystream"), ("mystream", args(&[])));
+        assert_eq!(split_query("mystream?"), ("mystream", args(&[])));
+        assert_eq!(
+            split_query("mystream?sign=a%2Bb&expires=123&name=x+y"),
+            ("mystream", args(&[("sign", "a+b"), ("expires", "123"), ("name", "x y")])),
+        );
+    }
+
+    #[test]
+    fn takes_app_from_connect_or_tc_url() {
+        let path = StreamPath::new("live", "rtmp://host/other", "mystream");
+        assert_eq!((path.app.as_str(), path.name.as_str()), ("live", "mystream"));
+
+        // App with an instance
+        let path = StreamPath::new("/live/inst/", "rtmp://host/live/inst", "mystream");
+        assert_eq!(path.app, "live/inst");
+        assert_eq!(path.to_string(), "live/inst/mystream");
+
+        // Empty app falls back to tcUrl
+        let path = StreamPath::new("", "rtmp://host:1935/live/inst?token=x", "");
+        assert_eq!(path.app, "live/inst");
+        assert_eq!(path.name, "");
+        assert_eq!(path.args, args(&[("token", "x")]));
+    }
+
+    #[test]
+    fn merges_args_with_stream_name_taking_precedence() {
+        let path = StreamPath::new("live?app=1&token=app", "rtmp://host/live?tc=1&token=tc", "mystream?token=name&sign=abc");
+        assert_eq!(path.app, "live");
+        assert_eq!(path.name, "mystream");
+        assert_eq!(path.args, args(&[("tc", "1"), ("app", "1"), ("sign", "abc"), ("token", "name")]));
+
+        let path = StreamPath::new("live", "rtmp://host/live?token=tc", "mystream");
+        assert_eq!(path.args, args(&[("token", "tc")]));
+    }
+}
@@ -0,0 +1,32 @@
+use crate::error::{Result, StreamError};
+use serde::Deserialize;
+use std::path::PathBuf;
+use tokio_native_tls::{native_tls, TlsAcceptor};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    #[serde(default = "default_rtmps_port")]
+    pub port: u16,
+    // PEM certificate chain and PKCS#8 private key
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+fn default_rtmps_port() -> u16 {
+    1936
+}
+
+pub fn load_tls_acceptor(config: &TlsConfig) -> Result<TlsAcceptor> {
+    let cert = std::fs::read(&config.cert_path)
+        .map_err(|e| StreamError::Config(format!("Failed to read certificate {}: {}", config.cert_path.display(), e)))?;
+    let key = std::fs::read(&config.key_path)
+        .map_err(|e| StreamError::Config(format!("Failed to read private key {}: {}", config.key_path.display(), e)))?;
+
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key)
+        .map_err(|e| StreamError::Config(format!("Invalid TLS certificate or key: {}", e)))?;
+
+    let acceptor = native_tls::TlsAcceptor::new(identity)
+        .map_err(|e| StreamError::Config(format!("Failed to create TLS acceptor: {}", e)))?;
+
+    Ok(TlsAcceptor::from(acceptor))
+}
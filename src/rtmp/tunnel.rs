@@ -0,0 +1,397 @@
+use bytes::{Bytes, BytesMut};
+use rand::{rngs::OsRng, RngCore};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream, WriteHalf},
+    sync::Mutex,
+    task::JoinHandle,
+    time::interval,
+};
+use tracing::{debug, error, info, warn};
+use warp::{http::StatusCode, Filter, Reply};
+
+use crate::application::Applications;
+use crate::registry::StreamRegistry;
+
+use super::session::RtmpSession;
+
+// Tunnels with no request for this long are torn down
+const TUNNEL_TIMEOUT: Duration = Duration::from_secs(30);
+const DUPLEX_BUFFER_SIZE: usize = 64 * 1024;
+const MAX_REQUEST_SIZE: u64 = 1024 * 1024;
+// Session output a client may leave unpolled before its tunnel is closed
+const MAX_OUTBOX_SIZE: usize = 8 * 1024 * 1024;
+// The first byte of every response tells the client how long to wait before polling again
+const MIN_POLL_INTERVAL: u8 = 0x01;
+const MAX_POLL_INTERVAL: u8 = 0x21;
+const CONTENT_TYPE: &str = "application/x-fcs";
+
+struct Tunnel {
+    writer: WriteHalf<DuplexStream>,
+    // Bytes the session has written that the client has not polled yet
+    outbox: Arc<std::sync::Mutex<BytesMut>>,
+    reader_task: JoinHandle<()>,
+    poll_interval: u8,
+    last_activity: Instant,
+    // `<seq>` of the last /send or /idle, which must increase with every request
+    last_sequence: Option<u64>,
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        self.reader_task.abort();
+    }
+}
+
+impl Tunnel {
+    fn check_sequence(&mut self, sequence: u64) -> Result<(), StatusCode> {
+        if self.last_sequence.is_some_and(|last| sequence <= last) {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        self.last_sequence = Some(sequence);
+        Ok(())
+    }
+
+    // Drains pending session output, backing off the poll interval while there is none
+    fn take_response(&mut self) -> Bytes {
+        self.last_activity = Instant::now();
+
+        let pending = self.outbox.lock().unwrap().split();
+        self.poll_interval = if pending.is_empty() {
+            self.poll_interval.saturating_add(1).min(MAX_POLL_INTERVAL)
+        } else {
+            MIN_POLL_INTERVAL
+        };
+
+        let mut response = BytesMut::with_capacity(1 + pending.len());
+        response.extend_from_slice(&[self.poll_interval]);
+        response.extend_from_slice(&pending);
+        response.freeze()
+    }
+}
+
+/// RTMP tunneled over HTTP (RTMPT): each tunnel feeds POST bodies into an `RtmpSession`
+/// and returns whatever the session wrote since the last request.
+#[derive(Clone)]
+pub struct RtmptTunnels {
+    tunnels: Arc<Mutex<HashMap<String, Arc<Mutex<Tunnel>>>>>,
+    applications: Applications,
+    registry: StreamRegistry,
+    max_tunnels: usize,
+}
+
+impl RtmptTunnels {
+    pub fn new(applications: Applications, registry: StreamRegistry, max_tunnels: usize) -> Self {
+        let tunnels = Self {
+            tunnels: Arc::new(Mutex::new(HashMap::new())),
+            applications,
+            registry,
+            max_tunnels,
+        };
+
+        let reaper = tunnels.clone();
+        tokio::spawn(async move {
+            reaper.reap_abandoned_tunnels().await;
+        });
+
+        tunnels
+    }
+
+    async fn open(&self, addr: SocketAddr) -> Result<String, StatusCode> {
+        // Held until the new tunnel is inserted, so concurrent opens can't overshoot the limit
+        let mut open_tunnels = self.tunnels.lock().await;
+        if open_tunnels.len() >= self.max_tunnels {
+            warn!("🚇 Refusing RTMPT tunnel for {}: {} tunnels are open", addr, open_tunnels.len());
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+
+        let session_id = generate_session_id();
+        let (client_side, server_side) = tokio::io::duplex(DUPLEX_BUFFER_SIZE);
+        let (mut reader, writer) = tokio::io::split(client_side);
+
+        let applications = self.applications.clone();
+        let registry = self.registry.clone();
+        tokio::spawn(async move {
+            if let Err(e) = RtmpSession::new(server_side, addr, applications, registry).run().await {
+                error!("RTMPT session error: {}", e);
+            }
+        });
+
+        let outbox = Arc::new(std::sync::Mutex::new(BytesMut::new()));
+        let session_output = outbox.clone();
+        let tunnels = self.clone();
+        let tunnel_id = session_id.clone();
+        let reader_task = tokio::spawn(async move {
+            let mut buffer = vec![0u8; DUPLEX_BUFFER_SIZE];
+            while let Ok(bytes_read) = reader.read(&mut buffer).await {
+                if bytes_read == 0 {
+                    break;
+                }
+                let overflowed = {
+                    let mut output = session_output.lock().unwrap();
+                    let overflowed = output.len() + bytes_read > MAX_OUTBOX_SIZE;
+                    if !overflowed {
+                        output.extend_from_slice(&buffer[..bytes_read]);
+                    }
+                    overflowed
+                };
+                if overflowed {
+                    warn!("🚇 RTMPT tunnel {} is not being polled, closing it", tunnel_id);
+                    tunnels.close(&tunnel_id).await;
+                    break;
+                }
+            }
+        });
+
+        let tunnel = Tunnel {
+            writer,
+            outbox,
+            reader_task,
+            poll_interval: MIN_POLL_INTERVAL,
+            last_activity: Instant::now(),
+            last_sequence: None,
+        };
+        open_tunnels.insert(session_id.clone(), Arc::new(Mutex::new(tunnel)));
+        info!("🚇 Opened RTMPT tunnel {} for {}", session_id, addr);
+
+        Ok(session_id)
+    }
+
+    async fn get(&self, session_id: &str) -> Option<Arc<Mutex<Tunnel>>> {
+        self.tunnels.lock().await.get(session_id).cloned()
+    }
+
+    async fn send(&self, session_id: &str, sequence: u64, data: &[u8]) -> Result<Bytes, StatusCode> {
+        let tunnel = self.get(session_id).await.ok_or(StatusCode::NOT_FOUND)?;
+        let mut tunnel = tunnel.lock().await;
+        tunnel.check_sequence(sequence)?;
+
+        if let Err(e) = tunnel.writer.write_all(data).await {
+            debug!("RTMPT tunnel {} session is gone: {}", session_id, e);
+            drop(tunnel);
+            self.close(session_id).await;
+            return Err(StatusCode::NOT_FOUND);
+        }
+
+        Ok(tunnel.take_response())
+    }
+
+    async fn idle(&self, session_id: &str, sequence: u64) -> Result<Bytes, StatusCode> {
+        let tunnel = self.get(session_id).await.ok_or(StatusCode::NOT_FOUND)?;
+        let mut tunnel = tunnel.lock().await;
+        tunnel.check_sequence(sequence)?;
+        Ok(tunnel.take_response())
+    }
+
+    async fn close(&self, session_id: &str) -> bool {
+        // Dropping the write half gives the session EOF, which ends it
+        let closed = self.tunnels.lock().await.remove(session_id).is_some();
+        if closed {
+            info!("🚇 Closed RTMPT tunnel {}", session_id);
+        }
+        closed
+    }
+
+    async fn reap_abandoned_tunnels(&self) {
+        let mut reap_interval = interval(TUNNEL_TIMEOUT / 2);
+
+        loop {
+            reap_interval.tick().await;
+
+            let mut abandoned = Vec::new();
+            for (session_id, tunnel) in self.tunnels.lock().await.iter() {
+                if let Ok(tunnel) = tunnel.try_lock() {
+                    if tunnel.last_activity.elapsed() > TUNNEL_TIMEOUT {
+                        abandoned.push(session_id.clone());
+                    }
+                }
+            }
+
+            for session_id in abandoned {
+                info!("🚇 RTMPT tunnel {} timed out", session_id);
+                self.close(&session_id).await;
+            }
+        }
+    }
+}
+
+// Session IDs are the only credential of a tunnel, so they must not be guessable
+fn generate_session_id() -> String {
+    let mut id = [0u8; 16];
+    OsRng.fill_bytes(&mut id);
+    hex::encode(id)
+}
+
+/// The `/fcs/ident2`, `/open`, `/send`, `/idle` and `/close` RTMPT endpoints.
+pub fn routes(tunnels: RtmptTunnels) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    let with_tunnels = warp::any().map(move || tunnels.clone());
+
+    // Clients probe this first; a 404 tells them to carry on with /open
+    let ident = warp::post()
+        .and(warp::path!("fcs" / "ident2"))
+        .map(|| StatusCode::NOT_FOUND.into_response());
+
+    let open = warp::post()
+        .and(warp::path!("open" / u32))
+        .and(warp::addr::remote())
+        .and(with_tunnels.clone())
+        .then(|_, addr: Option<SocketAddr>, tunnels: RtmptTunnels| async move {
+            let addr = addr.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+            match tunnels.open(addr).await {
+                Ok(session_id) => fcs_response(Bytes::from(format!("{}\n", session_id))),
+                Err(status) => status.into_response(),
+            }
+        });
+
+    let send = warp::post()
+        .and(warp::path!("send" / String / u64))
+        .and(warp::body::content_length_limit(MAX_REQUEST_SIZE))
+        .and(warp::body::bytes())
+        .and(with_tunnels.clone())
+        .then(|session_id: String, sequence: u64, body: Bytes, tunnels: RtmptTunnels| async move {
+            match tunnels.send(&session_id, sequence, &body).await {
+                Ok(response) => fcs_response(response),
+                Err(status) => status.into_response(),
+            }
+        });
+
+    let idle = warp::post()
+        .and(warp::path!("idle" / String / u64))
+        .and(with_tunnels.clone())
+        .then(|session_id: String, sequence: u64, tunnels: RtmptTunnels| async move {
+            match tunnels.idle(&session_id, sequence).await {
+                Ok(response) => fcs_response(response),
+                Err(status) => status.into_response(),
+            }
+        });
+
+    let close = warp::post()
+        .and(warp::path!("close" / String / u64))
+        .and(with_tunnels)
+        .then(|session_id: String, _sequence: u64, tunnels: RtmptTunnels| async move {
+            if tunnels.close(&session_id).await {
+                fcs_response(Bytes::from_static(&[0]))
+            } else {
+                StatusCode::NOT_FOUND.into_response()
+            }
+        });
+
+    ident.or(open).unify()
+        .or(send).unify()
+        .or(idle).unify()
+        .or(close).unify()
+}
+
+fn fcs_response(body: Bytes) -> warp::reply::Response {
+    warp::reply::with_header(body.to_vec(), "Content-Type", CONTENT_TYPE).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::{build_applications, ApplicationConfig};
+    use crate::config::Config;
+
+    fn tunnels(max_tunnels: usize) -> RtmptTunnels {
+        let config = Config {
+            applications: HashMap::from([("live".to_string(), ApplicationConfig { hls: false, ..ApplicationConfig::default() })]),
+            ..Config::default()
+        };
+        RtmptTunnels::new(build_applications(&config).unwrap(), StreamRegistry::new(config.max_streams), max_tunnels)
+    }
+
+    async fn post(tunnels: &RtmptTunnels, path: &str, body: &[u8]) -> warp::http::Response<Bytes> {
+        warp::test::request()
+            .method("POST")
+            .path(path)
+            .body(body)
+            .reply(&routes(tunnels.clone()))
+            .await
+    }
+
+    async fn open(tunnels: &RtmptTunnels) -> String {
+        let response = post(tunnels, "/open/1", &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        String::from_utf8(response.body().to_vec()).unwrap().trim_end().to_string()
+    }
+
+    #[tokio::test]
+    async fn opens_tunnels_with_random_ids() {
+        let tunnels = tunnels(10);
+        let first = open(&tunnels).await;
+        let second = open(&tunnels).await;
+
+        assert_eq!(first.len(), 32);
+        assert!(first.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(first, second);
+    }
+
+    #[tokio::test]
+    async fn rejects_repeated_and_out_of_order_sequences() {
+        let tunnels = tunnels(10);
+        let id = open(&tunnels).await;
+
+        let mut c0c1 = vec![0u8; 1537];
+        c0c1[0] = 3;
+        assert_eq!(post(&tunnels, &format!("/send/{}/1", id), &c0c1).await.status(), StatusCode::OK);
+        assert_eq!(post(&tunnels, &format!("/send/{}/1", id), &c0c1).await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(post(&tunnels, &format!("/idle/{}/0", id), &[]).await.status(), StatusCode::BAD_REQUEST);
+
+        // A rejected request leaves the tunnel usable
+        let response = post(&tunnels, &format!("/idle/{}/2", id), &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Content-Type"], CONTENT_TYPE);
+        assert_eq!(post(&tunnels, &format!("/idle/{}/5", id), &[]).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_and_closed_tunnels() {
+        let tunnels = tunnels(10);
+        assert_eq!(post(&tunnels, "/idle/0123456789abcdef/1", &[]).await.status(), StatusCode::NOT_FOUND);
+
+        let id = open(&tunnels).await;
+        assert_eq!(post(&tunnels, &format!("/close/{}/1", id), &[]).await.status(), StatusCode::OK);
+        assert_eq!(post(&tunnels, &format!("/send/{}/2", id), &[0]).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn carries_the_handshake_through_the_tunnel() {
+        let tunnels = tunnels(10);
+        let id = open(&tunnels).await;
+
+        let mut c0c1 = vec![3u8];
+        c0c1.extend((0..1536).map(|i| (i * 7 % 251) as u8));
+        let mut response = post(&tunnels, &format!("/send/{}/1", id), &c0c1).await.body().slice(1..).to_vec();
+
+        // The session writes S0+S1+S2 in pieces, so poll until all of it has arrived
+        let mut sequence = 2;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while response.len() < 1 + 1536 * 2 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let polled = post(&tunnels, &format!("/idle/{}/{}", id, sequence), &[]).await;
+            response.extend_from_slice(&polled.body()[1..]);
+            sequence += 1;
+        }
+
+        assert_eq!(response.len(), 1 + 1536 * 2);
+        assert_eq!(response[0], 3);
+        assert_eq!(response[1 + 1536..], c0c1[1..], "S2 does not echo C1");
+    }
+
+    #[tokio::test]
+    async fn refuses_tunnels_past_the_limit() {
+        let tunnels = tunnels(2);
+        let first = open(&tunnels).await;
+        open(&tunnels).await;
+        assert_eq!(post(&tunnels, "/open/1", &[]).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Closing a tunnel makes room for another
+        assert_eq!(post(&tunnels, &format!("/close/{}/1", first), &[]).await.status(), StatusCode::OK);
+        open(&tunnels).await;
+    }
+}
@@ -0,0 +1,96 @@
+use crate::error::{Result, StreamError};
+use std::fmt;
+
+pub const MAX_STREAM_KEY_LENGTH: usize = 128;
+
+/// A stream name that is safe to use as a single directory name under `streams_dir`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StreamKey(String);
+
+impl StreamKey {
+    pub fn new(value: &str) -> Result<Self> {
+        validate_path_component(value, MAX_STREAM_KEY_LENGTH)
+            .map_err(|reason| StreamError::InvalidStreamKey(format!("'{}': {}", value, reason)))?;
+
+        Ok(Self(value.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for StreamKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsRef<str> for StreamKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Checks that `value` is a single relative path component made of `[A-Za-z0-9_.-]`,
+/// with no `.`/`..` segments, separators or absolute prefixes.
+pub fn validate_path_component(value: &str, max_length: usize) -> std::result::Result<(), &'static str> {
+    if value.is_empty() {
+        return Err("must not be empty");
+    }
+
+    if value.len() > max_length {
+        return Err("too long");
+    }
+
+    if value == "." || value == ".." || value.starts_with('.') {
+        return Err("must not start with '.'");
+    }
+
+    if !value.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+        return Err("only letters, digits, '_', '-' and '.' are allowed");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_the_allowed_charset() {
+        for key in ["stream", "Stream_01", "a-b.c", "a..b", "x", "0123456789", "camera.1-hd_backup"] {
+            assert_eq!(StreamKey::new(key).unwrap().as_str(), key);
+        }
+        assert!(StreamKey::new(&"k".repeat(MAX_STREAM_KEY_LENGTH)).is_ok());
+    }
+
+    #[test]
+    fn rejects_path_traversal() {
+        for key in ["..", ".", ".hidden", "../etc", "a/b", "/etc", "a\\b", "..\\x", "C:", "a\0b", "%2e%2e"] {
+            assert!(StreamKey::new(key).is_err(), "{:?} should be rejected", key);
+        }
+    }
+
+    #[test]
+    fn rejects_empty_long_and_non_ascii_keys() {
+        for key in ["", " ", "a b", "stream\n", "stréam", "ｓｔｒｅａｍ", "ключ"] {
+            assert!(StreamKey::new(key).is_err(), "{:?} should be rejected", key);
+        }
+        assert!(matches!(
+            StreamKey::new(&"k".repeat(MAX_STREAM_KEY_LENGTH + 1)),
+            Err(StreamError::InvalidStreamKey(_))
+        ));
+    }
+
+    #[test]
+    fn explains_rejections() {
+        assert_eq!(validate_path_component("", 8), Err("must not be empty"));
+        assert_eq!(validate_path_component("123456789", 8), Err("too long"));
+        assert_eq!(validate_path_component("..", 8), Err("must not start with '.'"));
+        assert_eq!(validate_path_component("a/b", 8), Err("only letters, digits, '_', '-' and '.' are allowed"));
+        // The limit is in bytes, so multi-byte characters count for more
+        assert_eq!(validate_path_component("éééé", 7), Err("too long"));
+    }
+}
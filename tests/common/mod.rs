@@ -0,0 +1,75 @@
+// Each test crate uses a different subset of these
+#![allow(dead_code)]
+
+use bytes::Bytes;
+use std::{collections::HashMap, future::Future, time::Duration};
+use streamx::application::ApplicationConfig;
+use streamx::config::Config;
+use streamx::media::{MediaKind, MediaPacket};
+use streamx::registry::StreamRegistry;
+use streamx::rtmp::client::{RtmpClient, RtmpUrl};
+use streamx::rtmp::RtmpServer;
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+
+pub const WAIT: Duration = Duration::from_secs(10);
+
+pub const SEQUENCE_HEADER: &[u8] = &[0x17, 0x00, 0x00, 0x00, 0x00, 0x01, 0x64, 0x00, 0x1f];
+pub const KEYFRAME: &[u8] = &[0x17, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x65];
+
+/// An application without HLS, so that no FFmpeg is needed.
+pub fn application() -> ApplicationConfig {
+    ApplicationConfig { hls: false, ..ApplicationConfig::default() }
+}
+
+/// Runs a StreamX RTMP server on a free local port until the test ends.
+pub async fn start_server(applications: HashMap<String, ApplicationConfig>) -> (u16, StreamRegistry) {
+    let port = free_port();
+    (port, start_server_on(port, applications).await)
+}
+
+/// A local port nothing is listening on.
+pub fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+pub async fn start_server_on(port: u16, applications: HashMap<String, ApplicationConfig>) -> StreamRegistry {
+    let config = Config { rtmp_port: port, applications, ..Config::default() };
+    let registry = StreamRegistry::new(config.max_streams);
+    let server = RtmpServer::new(config, registry.clone()).unwrap();
+    tokio::spawn(async move { server.start().await });
+
+    wait_until(|| async move { TcpStream::connect(("127.0.0.1", port)).await.is_ok() }).await;
+    registry
+}
+
+pub async fn connect(port: u16, app: &str, stream: &str) -> RtmpClient<TcpStream> {
+    let url = RtmpUrl::parse(&format!("rtmp://127.0.0.1:{}/{}/{}", port, app, stream)).unwrap();
+    timeout(WAIT, RtmpClient::connect(&url)).await.expect("connect stalled").unwrap()
+}
+
+/// Publishes `stream` with an AVC sequence header, returning the client and its stream ID.
+pub async fn publish(port: u16, app: &str, stream: &str) -> (RtmpClient<TcpStream>, u32) {
+    let mut client = connect(port, app, stream).await;
+    let stream_id = timeout(WAIT, client.publish(stream)).await.expect("publish stalled").unwrap();
+    client.send_media(stream_id, &video(SEQUENCE_HEADER)).await.unwrap();
+    (client, stream_id)
+}
+
+pub fn video(payload: &'static [u8]) -> MediaPacket {
+    MediaPacket::new(MediaKind::Video, 0, Bytes::from_static(payload))
+}
+
+pub async fn wait_until<F, Fut>(mut condition: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    timeout(WAIT, async {
+        while !condition().await {
+            sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("condition not met in time");
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use common::{application, connect, publish, start_server, video, wait_until, KEYFRAME, SEQUENCE_HEADER, WAIT};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use streamx::application::ApplicationConfig;
+use streamx::stream_key::StreamKey;
+use tokio::time::{sleep, timeout};
+
+// An origin with a `live` application, and an edge whose `live` pulls from it
+async fn start_origin_and_edge() -> (u16, u16, streamx::registry::StreamRegistry) {
+    let (origin_port, _) = start_server(HashMap::from([("live".to_string(), application())])).await;
+    let edge = ApplicationConfig {
+        origin: Some(format!("rtmp://127.0.0.1:{}/live", origin_port)),
+        ..application()
+    };
+    let (edge_port, edge_registry) = start_server(HashMap::from([("live".to_string(), edge)])).await;
+    (origin_port, edge_port, edge_registry)
+}
+
+#[tokio::test]
+async fn plays_a_stream_from_the_origin() {
+    let (origin_port, edge_port, edge_registry) = start_origin_and_edge().await;
+    let (mut publisher, stream_id) = publish(origin_port, "live", "cam").await;
+
+    let mut player = connect(edge_port, "live", "cam").await;
+    let player_stream_id = player.create_stream().await.unwrap();
+    timeout(WAIT, player.play(player_stream_id, "cam")).await.unwrap().unwrap();
+    assert!(edge_registry.get("live", &StreamKey::new("cam").unwrap()).is_some());
+
+    // The sequence header may still be on its way to the edge when the player joins
+    publisher.send_media(stream_id, &video(KEYFRAME)).await.unwrap();
+    let mut payloads = Vec::new();
+    while payloads.last().is_none_or(|payload: &bytes::Bytes| payload != KEYFRAME) {
+        let packet = timeout(WAIT, player.read_media()).await.unwrap().unwrap().expect("player stopped");
+        payloads.push(packet.payload);
+    }
+    assert_eq!(payloads.first().unwrap(), SEQUENCE_HEADER);
+
+    // Players on the edge are told when the origin stream ends
+    publisher.unpublish(stream_id, "cam").await.unwrap();
+    assert!(timeout(WAIT, player.read_media()).await.unwrap().unwrap().is_none());
+    let key = StreamKey::new("cam").unwrap();
+    wait_until(|| {
+        let registry = edge_registry.clone();
+        let key = key.clone();
+        async move { registry.get("live", &key).is_none() }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn rejects_streams_the_origin_does_not_have() {
+    let (_, edge_port, edge_registry) = start_origin_and_edge().await;
+
+    let mut player = connect(edge_port, "live", "missing").await;
+    let player_stream_id = player.create_stream().await.unwrap();
+    assert!(timeout(WAIT, player.play(player_stream_id, "missing")).await.unwrap().is_err());
+    assert!(edge_registry.list().is_empty());
+}
+
+#[tokio::test]
+async fn closes_the_pull_once_the_last_player_leaves_a_quiet_stream() {
+    let (origin_port, edge_port, edge_registry) = start_origin_and_edge().await;
+    // Nothing is sent after the sequence header, so the edge receives no packets to check idleness on
+    let (_publisher, _) = publish(origin_port, "live", "cam").await;
+
+    let mut player = connect(edge_port, "live", "cam").await;
+    let player_stream_id = player.create_stream().await.unwrap();
+    timeout(WAIT, player.play(player_stream_id, "cam")).await.unwrap().unwrap();
+    drop(player);
+
+    let key = StreamKey::new("cam").unwrap();
+    let left = Instant::now();
+    timeout(Duration::from_secs(20), async {
+        while edge_registry.get("live", &key).is_some() {
+            sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("pull of a quiet stream was never closed");
+    assert!(left.elapsed() >= Duration::from_secs(14), "pull closed after {:?}", left.elapsed());
+}
@@ -0,0 +1,50 @@
+mod common;
+
+use common::{application, publish, start_server, video, wait_until, KEYFRAME, SEQUENCE_HEADER, WAIT};
+use std::collections::HashMap;
+use streamx::application::build_applications;
+use streamx::config::Config;
+use streamx::registry::StreamRegistry;
+use streamx::relay::pull::{spawn_pulls, PullConfig};
+use streamx::stream_key::StreamKey;
+use tokio::time::timeout;
+
+#[tokio::test]
+async fn republishes_a_stream_pulled_from_another_server() {
+    let (origin_port, _) = start_server(HashMap::from([("live".to_string(), application())])).await;
+    let (mut publisher, stream_id) = publish(origin_port, "live", "cam").await;
+
+    let config = Config { applications: HashMap::from([("local".to_string(), application())]), ..Config::default() };
+    let applications = build_applications(&config).unwrap();
+    let registry = StreamRegistry::new(config.max_streams);
+    let pull = PullConfig {
+        url: format!("rtmp://127.0.0.1:{}/live/cam", origin_port),
+        app: "local".to_string(),
+        stream_key: "camera".to_string(),
+    };
+    spawn_pulls(&[pull], &applications, &registry).unwrap();
+
+    let key = StreamKey::new("camera").unwrap();
+    wait_until(|| {
+        let registry = registry.clone();
+        let key = key.clone();
+        async move { registry.get("local", &key).is_some_and(|stream| stream.has_video()) }
+    })
+    .await;
+
+    let mut subscription = registry.get("local", &key).unwrap().subscribe().unwrap();
+    assert_eq!(subscription.headers[0].payload, SEQUENCE_HEADER);
+
+    publisher.send_media(stream_id, &video(KEYFRAME)).await.unwrap();
+    let packet = timeout(WAIT, subscription.receiver.recv()).await.unwrap().unwrap();
+    assert_eq!(packet.payload, KEYFRAME);
+
+    // The local stream ends with the upstream one
+    publisher.unpublish(stream_id, "cam").await.unwrap();
+    wait_until(|| {
+        let registry = registry.clone();
+        let key = key.clone();
+        async move { registry.get("local", &key).is_none() }
+    })
+    .await;
+}
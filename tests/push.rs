@@ -0,0 +1,105 @@
+mod common;
+
+use common::{application, free_port, start_server, start_server_on, video, wait_until, KEYFRAME, SEQUENCE_HEADER, WAIT};
+use std::collections::HashMap;
+use std::sync::Arc;
+use streamx::application::{build_applications, ApplicationConfig, Applications};
+use streamx::config::Config;
+use streamx::registry::{LiveStream, Publisher, PublisherRole, StreamRegistry};
+use streamx::relay::push::{PushState, PushStatus};
+use streamx::stream_key::StreamKey;
+use tokio::time::timeout;
+
+fn applications(push: &str) -> Applications {
+    let local = ApplicationConfig { push: vec![push.to_string()], ..application() };
+    let config = Config { applications: HashMap::from([("local".to_string(), local)]), ..Config::default() };
+    build_applications(&config).unwrap()
+}
+
+// Publishes `local/cam` with an AVC sequence header, starting its push relays
+async fn publish_locally(applications: &Applications, registry: &StreamRegistry) -> Publisher {
+    let publisher = registry.publish("local", &StreamKey::new("cam").unwrap(), PublisherRole::Primary).unwrap();
+    publisher.send(video(SEQUENCE_HEADER));
+    applications["local"].start_outputs(&publisher.stream).await;
+    publisher
+}
+
+async fn wait_for_push(stream: &Arc<LiveStream>, condition: impl Fn(&PushStatus) -> bool) -> PushStatus {
+    wait_until(|| {
+        let ready = stream.push_status().first().is_some_and(&condition);
+        async move { ready }
+    })
+    .await;
+    stream.push_status().remove(0)
+}
+
+#[tokio::test]
+async fn relays_a_published_stream_to_its_push_target() {
+    let (target_port, target_registry) = start_server(HashMap::from([("local".to_string(), application())])).await;
+    let applications = applications(&format!("rtmp://127.0.0.1:{}/{{app}}/{{key}}", target_port));
+    let registry = StreamRegistry::new(10);
+    let publisher = publish_locally(&applications, &registry).await;
+
+    // The target is given the stream under the rendered application and key
+    let key = StreamKey::new("cam").unwrap();
+    wait_until(|| {
+        let target_registry = target_registry.clone();
+        let key = key.clone();
+        async move { target_registry.get("local", &key).is_some_and(|stream| stream.has_video()) }
+    })
+    .await;
+    let mut subscription = target_registry.get("local", &key).unwrap().subscribe().unwrap();
+    assert_eq!(subscription.headers[0].payload, SEQUENCE_HEADER);
+
+    publisher.send(video(KEYFRAME));
+    let packet = timeout(WAIT, subscription.receiver.recv()).await.unwrap().unwrap();
+    assert_eq!(packet.payload, KEYFRAME);
+
+    let status = wait_for_push(&publisher.stream, |status| status.packets_sent > 0).await;
+    assert_eq!(status.target, format!("rtmp://127.0.0.1:{}/local", target_port));
+    assert_eq!((status.state, status.reconnects, status.last_error), (PushState::Publishing, 0, None));
+
+    // The push ends with the stream, and so does the stream on the target
+    registry.unpublish(&publisher);
+    wait_for_push(&publisher.stream, |status| status.state == PushState::Stopped).await;
+    wait_until(|| {
+        let target_registry = target_registry.clone();
+        let key = key.clone();
+        async move { target_registry.get("local", &key).is_none() }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn reconnects_until_the_target_is_up() {
+    let target_port = free_port();
+    let applications = applications(&format!("rtmp://127.0.0.1:{}/live/{{key}}", target_port));
+    let registry = StreamRegistry::new(10);
+    let publisher = publish_locally(&applications, &registry).await;
+
+    let status = wait_for_push(&publisher.stream, |status| status.reconnects > 0).await;
+    assert_eq!(status.state, PushState::Reconnecting);
+    assert!(status.last_error.is_some());
+
+    let target_registry = start_server_on(target_port, HashMap::from([("live".to_string(), application())])).await;
+    wait_for_push(&publisher.stream, |status| status.state == PushState::Publishing).await;
+    let stream = target_registry.get("live", &StreamKey::new("cam").unwrap()).expect("push target has no stream");
+
+    // Headers are sent again on the new connection
+    wait_until(|| {
+        let has_video = stream.has_video();
+        async move { has_video }
+    })
+    .await;
+    registry.unpublish(&publisher);
+}
+
+#[test]
+fn rejects_rtmps_push_targets_at_startup() {
+    let local = ApplicationConfig { push: vec!["rtmps://live.example.com/app/{key}".to_string()], ..application() };
+    let config = Config { applications: HashMap::from([("local".to_string(), local)]), ..Config::default() };
+    let Err(error) = build_applications(&config) else {
+        panic!("rtmps:// push target accepted");
+    };
+    assert!(error.to_string().contains("rtmps:// is not supported"), "{}", error);
+}